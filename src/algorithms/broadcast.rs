@@ -1,7 +1,13 @@
 /// Broadcast node: see maelstrom broadcast docs
 /// https://github.com/jepsen-io/maelstrom/blob/main/doc/03-broadcast/01-broadcast.md
 ///
+/// In total-order mode, a single sequencer (the lowest node id from `init`) assigns
+/// every value a position in a log. The sequencer ships its log to all other nodes,
+/// which deliver it strictly in log order, so every node sees the same sequence.
 ///
+/// The sequencer is fixed, not elected: nothing fails over to another node, so while
+/// the sequencer is partitioned away or slow, no node delivers anything new. Values
+/// handed to it in the meantime are retried until it is reachable again.
 use std::collections::{HashMap, HashSet};
 
 use async_trait::async_trait;
@@ -11,7 +17,10 @@ use tokio::sync::mpsc::Receiver;
 use crate::errors;
 use crate::node::Node;
 use crate::rpc::{self, broadcast};
-use crate::workload::Command;
+use crate::workload::{BroadcastOrder, Command};
+
+/// Largest number of log entries the sequencer ships in one deliver message
+const MAX_DELIVER_BATCH: usize = 512;
 
 /// In lieu of *sending* messages: we print them to screen
 fn send_messages(messages: Vec<rpc::broadcast::BroadcastMsgIn>) {
@@ -29,6 +38,7 @@ fn send_messages(messages: Vec<rpc::broadcast::BroadcastMsgIn>) {
 
 pub struct Broadcast {
     node_id: String,
+    order: BroadcastOrder,
     topology: HashMap<String, Vec<String>>,
     all_nodes: Vec<String>,
     notify_ticks: u8,
@@ -36,12 +46,43 @@ pub struct Broadcast {
     notify_vals: Vec<u64>,
     // maelstrom broadcast values are unique and results do not need to be ordered
     values: HashSet<u64>,
+    // total-order mode: node ids from init, in the order used to pick the sequencer
+    node_ids: Vec<String>,
+    // total-order mode: values in delivery order (on the sequencer, this is *the* log)
+    delivered: Vec<u64>,
+    // total-order mode: values handed to the sequencer which we have not seen delivered
+    pending: Vec<u64>,
+    // total-order mode (sequencer only): how much of the log each peer has acknowledged
+    acked: HashMap<String, u64>,
     last_msg_id: u64,
     rx: Receiver<Command>,
 }
 
 impl Broadcast {
+    pub fn with_order(mut self, order: BroadcastOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Values in the order this node delivered them.
+    /// In total-order mode this sequence is a prefix of every other node's sequence,
+    /// so it may be used to drive a replicated state machine.
+    pub fn delivered(&self) -> &[u64] {
+        &self.delivered
+    }
+
+    fn sequencer(&self) -> Option<&String> {
+        self.node_ids.first()
+    }
+
+    fn is_sequencer(&self) -> bool {
+        self.sequencer() == Some(&self.node_id)
+    }
+
     async fn handle_tick(&mut self) -> Result<(), errors::ErrorMsg> {
+        if self.order == BroadcastOrder::Total {
+            return self.handle_total_order_tick().await;
+        }
         // In order to get over network partitions, we'd need to try this
         // more than once
         self.notify_ticks += 1;
@@ -57,10 +98,41 @@ impl Broadcast {
         Ok(())
     }
 
+    async fn handle_total_order_tick(&mut self) -> Result<(), errors::ErrorMsg> {
+        if self.is_sequencer() {
+            let msgs = self.build_deliver_messages();
+            if !msgs.is_empty() {
+                send_messages(msgs);
+            }
+        } else if let Some(sequencer) = self.sequencer().cloned() {
+            // Values are retried until they come back to us from the sequencer
+            self.pending.retain(|val| !self.values.contains(val));
+            let msgs = self
+                .pending
+                .iter()
+                .map(|val| {
+                    broadcast::BroadcastMsgIn::new_sequence(
+                        self.node_id.clone(),
+                        sequencer.clone(),
+                        *val,
+                    )
+                })
+                .collect::<Vec<_>>();
+            if !msgs.is_empty() {
+                send_messages(msgs);
+            }
+        }
+        Ok(())
+    }
+
     async fn handle_broadcast(
         &mut self,
         msg: &broadcast::BroadcastRequestMsg,
     ) -> Option<HashSet<u64>> {
+        if self.order == BroadcastOrder::Total {
+            self.submit(msg.message);
+            return None;
+        }
         if !self.values.contains(&msg.message) {
             self.values.insert(msg.message);
             // we broadcast every novel thing we see (consider message amplification)
@@ -80,6 +152,72 @@ impl Broadcast {
         Some(self.values.clone())
     }
 
+    /// Total-order mode: the sequencer appends novel values to its log,
+    /// while everyone else hands them to the sequencer.
+    fn submit(&mut self, value: u64) {
+        if self.values.contains(&value) {
+            return;
+        }
+        if self.is_sequencer() {
+            self.values.insert(value);
+            self.delivered.push(value);
+        } else if !self.pending.contains(&value) {
+            self.pending.push(value);
+            if let Some(sequencer) = self.sequencer().cloned() {
+                send_messages(vec![broadcast::BroadcastMsgIn::new_sequence(
+                    self.node_id.clone(),
+                    sequencer,
+                    value,
+                )]);
+            }
+        }
+    }
+
+    async fn handle_sequence(
+        &mut self,
+        msg: &broadcast::SequenceRequestMsg,
+    ) -> Option<HashSet<u64>> {
+        if self.is_sequencer() {
+            self.submit(msg.message);
+        }
+        None
+    }
+
+    async fn handle_deliver(
+        &mut self,
+        src: &str,
+        msg: &broadcast::DeliverRequestMsg,
+    ) -> Option<HashSet<u64>> {
+        // We can only extend our log contiguously: anything past a gap is dropped
+        // and will be shipped again once the sequencer sees our acknowledgement.
+        let start = msg.start as usize;
+        if start <= self.delivered.len() {
+            let skip = self.delivered.len() - start;
+            for val in msg.messages.iter().skip(skip) {
+                self.values.insert(*val);
+                self.delivered.push(*val);
+            }
+        }
+        send_messages(vec![broadcast::BroadcastMsgIn::new_deliver_ok(
+            self.node_id.clone(),
+            src.to_string(),
+            self.delivered.len() as u64,
+        )]);
+        None
+    }
+
+    async fn handle_deliver_ok(
+        &mut self,
+        src: &str,
+        msg: &broadcast::DeliverOkMsg,
+    ) -> Option<HashSet<u64>> {
+        let acked = self.acked.entry(src.to_string()).or_insert(0);
+        if msg.delivered > *acked {
+            *acked = msg.delivered;
+        }
+        None
+    }
+
     async fn handle_topology(
         &mut self,
         msg: &broadcast::TopologyRequestMsg,
@@ -116,6 +254,61 @@ impl Broadcast {
 
         msgs
     }
+
+    /// Sequencer only: ship every peer the part of the log it has not acknowledged
+    fn build_deliver_messages(&self) -> Vec<rpc::broadcast::BroadcastMsgIn> {
+        self.node_ids
+            .iter()
+            .filter(|nid| **nid != self.node_id)
+            .filter_map(|dest| {
+                let start = *self.acked.get(dest).unwrap_or(&0) as usize;
+                if start >= self.delivered.len() {
+                    return None;
+                }
+                let end = self.delivered.len().min(start + MAX_DELIVER_BATCH);
+                Some(rpc::broadcast::BroadcastMsgIn::new_deliver(
+                    self.node_id.clone(),
+                    dest.clone(),
+                    start as u64,
+                    self.delivered[start..end].to_vec(),
+                ))
+            })
+            .collect()
+    }
+
+    /// Handle one message, returning our reply to it (empty if there is none)
+    async fn reply(&mut self, msg: &str) -> Result<String, errors::ErrorMsg> {
+        self.last_msg_id += 1;
+        let msg_in = serde_json::from_str::<broadcast::BroadcastMsgIn>(msg)
+            .map_err(errors::ErrorMsg::json_parse_error)?;
+        let values = match &msg_in.body {
+            broadcast::BroadcastMsgRequestBody::Topology(msg) => self.handle_topology(msg).await,
+            broadcast::BroadcastMsgRequestBody::Broadcast(msg) => self.handle_broadcast(msg).await,
+            broadcast::BroadcastMsgRequestBody::Read(msg) => self.handle_read(msg).await,
+            broadcast::BroadcastMsgRequestBody::BroadcastOk(msg) => {
+                self.handle_broadcast_ok(msg).await
+            }
+            broadcast::BroadcastMsgRequestBody::Sequence(msg) => self.handle_sequence(msg).await,
+            broadcast::BroadcastMsgRequestBody::Deliver(msg) => {
+                self.handle_deliver(&msg_in.src, msg).await
+            }
+            broadcast::BroadcastMsgRequestBody::DeliverOk(msg) => {
+                self.handle_deliver_ok(&msg_in.src, msg).await
+            }
+        };
+        let ordered = match (&msg_in.body, self.order) {
+            (broadcast::BroadcastMsgRequestBody::Read(_), BroadcastOrder::Total) => {
+                Some(self.delivered.clone())
+            }
+            _ => None,
+        };
+        msg_in
+            .into_str_response(values, ordered, self.last_msg_id)
+            .map_err(|_e| {
+                eprintln!("{:?}", _e);
+                _e
+            })
+    }
 }
 
 #[async_trait]
@@ -123,6 +316,7 @@ impl Node for Broadcast {
     fn new(starting_msg_id: u64, rx: Receiver<Command>) -> Self {
         Self {
             rx,
+            order: BroadcastOrder::Unordered,
             notify_ticks: 0,
             last_msg_id: starting_msg_id,
             notify_vals: vec![],
@@ -130,27 +324,15 @@ impl Node for Broadcast {
             topology: HashMap::new(),
             all_nodes: vec![],
             values: HashSet::new(),
+            node_ids: vec![],
+            delivered: vec![],
+            pending: vec![],
+            acked: HashMap::new(),
         }
     }
 
     async fn handle(&mut self, msg: String) -> Result<(), errors::ErrorMsg> {
-        self.last_msg_id += 1;
-        let msg_in = serde_json::from_str::<broadcast::BroadcastMsgIn>(msg.as_str())
-            .map_err(errors::ErrorMsg::json_parse_error)?;
-        let values = match &msg_in.body {
-            broadcast::BroadcastMsgRequestBody::Topology(msg) => self.handle_topology(msg).await,
-            broadcast::BroadcastMsgRequestBody::Broadcast(msg) => self.handle_broadcast(msg).await,
-            broadcast::BroadcastMsgRequestBody::Read(msg) => self.handle_read(msg).await,
-            broadcast::BroadcastMsgRequestBody::BroadcastOk(msg) => {
-                self.handle_broadcast_ok(msg).await
-            }
-        };
-        let result = msg_in
-            .into_str_response(values, self.last_msg_id)
-            .map_err(|_e| {
-                eprintln!("{:?}", _e);
-                _e
-            })?;
+        let result = self.reply(msg.as_str()).await?;
         if !result.is_empty() {
             println!("{}", result);
         }
@@ -159,6 +341,8 @@ impl Node for Broadcast {
 
     async fn on_init(&mut self, msg: rpc::InitMsgIn) -> Result<(), errors::ErrorMsg> {
         self.node_id = msg.body.node_id.clone();
+        self.node_ids = msg.body.node_ids.clone();
+        self.node_ids.sort();
         let msg_out = msg.into_response(self.last_msg_id);
         let result = serde_json::to_string(&msg_out).map_err(errors::ErrorMsg::json_dumps_error)?;
         println!("{}", result);
@@ -181,3 +365,171 @@ impl Node for Broadcast {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    async fn node(node_id: &str, order: BroadcastOrder) -> Broadcast {
+        let (_tx, rx) = mpsc::channel(1);
+        let mut node = Broadcast::new(1, rx).with_order(order);
+        let node_ids = vec!["n2".to_string(), "n1".to_string(), "n3".to_string()];
+        node.on_init(rpc::InitMsgIn {
+            src: "c0".to_string(),
+            dest: node_id.to_string(),
+            body: rpc::InitRequestMsg::new(1, node_id.to_string(), node_ids),
+        })
+        .await
+        .unwrap();
+        node
+    }
+
+    async fn deliver(node: &mut Broadcast, msg: &broadcast::BroadcastMsgIn) {
+        let msg = serde_json::to_string(msg).unwrap();
+        assert_eq!(node.reply(&msg).await.unwrap(), "");
+    }
+
+    async fn broadcast(node: &mut Broadcast, value: u64) {
+        let msg = json!({
+            "src": "c1",
+            "dest": node.node_id,
+            "body": {"type": "broadcast", "msg_id": value, "message": value},
+        });
+        node.reply(&msg.to_string()).await.unwrap();
+    }
+
+    async fn read(node: &mut Broadcast) -> Value {
+        let msg = json!({
+            "src": "c1",
+            "dest": node.node_id,
+            "body": {"type": "read", "msg_id": 1},
+        });
+        let reply = node.reply(&msg.to_string()).await.unwrap();
+        serde_json::from_str::<Value>(&reply).unwrap()["body"].clone()
+    }
+
+    /// The parts of the sequencer's log shipped to each peer, as (peer, start, length)
+    fn deliveries(sequencer: &Broadcast) -> Vec<(String, u64, usize)> {
+        sequencer
+            .build_deliver_messages()
+            .into_iter()
+            .map(|msg| match msg.body {
+                broadcast::BroadcastMsgRequestBody::Deliver(body) => {
+                    (msg.dest, body.start, body.messages.len())
+                }
+                other => panic!("Unexpected message {:?}", other),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_sequencer_order_is_the_same_on_every_node() {
+        let mut nodes = vec![];
+        for node_id in ["n1", "n2", "n3"] {
+            nodes.push(node(node_id, BroadcastOrder::Total).await);
+        }
+        assert!(nodes[0].is_sequencer());
+        // Values arrive at different nodes in different orders
+        for (index, value) in [(2, 30), (1, 20), (0, 10), (2, 31), (1, 21), (0, 11)] {
+            broadcast(&mut nodes[index], value).await;
+        }
+        // Peers hand their values to the sequencer, the last node's first
+        for index in [2, 1] {
+            let values = nodes[index].pending.clone();
+            for value in values {
+                let msg = broadcast::BroadcastMsgIn::new_sequence(
+                    nodes[index].node_id.clone(),
+                    "n1".to_string(),
+                    value,
+                );
+                deliver(&mut nodes[0], &msg).await;
+            }
+        }
+        assert_eq!(nodes[0].delivered(), &[10, 11, 30, 31, 20, 21]);
+        for msg in nodes[0].build_deliver_messages() {
+            let index = if msg.dest == "n2" { 1 } else { 2 };
+            deliver(&mut nodes[index], &msg).await;
+        }
+        for node in nodes.iter_mut() {
+            assert_eq!(node.delivered(), &[10, 11, 30, 31, 20, 21]);
+            assert_eq!(read(node).await["ordered"], json!([10, 11, 30, 31, 20, 21]));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_deliver_drops_gaps_and_skips_overlap() {
+        let mut peer = node("n2", BroadcastOrder::Total).await;
+        let msg = |start, messages| {
+            broadcast::BroadcastMsgIn::new_deliver(
+                "n1".to_string(),
+                "n2".to_string(),
+                start,
+                messages,
+            )
+        };
+        deliver(&mut peer, &msg(0, vec![1, 2])).await;
+        assert_eq!(peer.delivered(), &[1, 2]);
+        // Past the end of what we hold: dropped, to be shipped again
+        deliver(&mut peer, &msg(3, vec![4, 5])).await;
+        assert_eq!(peer.delivered(), &[1, 2]);
+        // Overlapping what we hold: only the new part is delivered
+        deliver(&mut peer, &msg(1, vec![2, 3, 4])).await;
+        assert_eq!(peer.delivered(), &[1, 2, 3, 4]);
+        deliver(&mut peer, &msg(0, vec![1, 2])).await;
+        assert_eq!(peer.delivered(), &[1, 2, 3, 4]);
+        assert!(!peer.values.contains(&5));
+    }
+
+    #[tokio::test]
+    async fn test_deliver_messages_are_chunked() {
+        let mut sequencer = node("n1", BroadcastOrder::Total).await;
+        let total = 2 * MAX_DELIVER_BATCH + 10;
+        for value in 0..total as u64 {
+            broadcast(&mut sequencer, value).await;
+        }
+        let first = vec![
+            ("n2".to_string(), 0, MAX_DELIVER_BATCH),
+            ("n3".to_string(), 0, MAX_DELIVER_BATCH),
+        ];
+        assert_eq!(deliveries(&sequencer), first);
+        // Each peer is shipped on from where it has acknowledged
+        let ack = |delivered| {
+            broadcast::BroadcastMsgIn::new_deliver_ok("n2".to_string(), "n1".to_string(), delivered)
+        };
+        deliver(&mut sequencer, &ack(MAX_DELIVER_BATCH as u64)).await;
+        assert_eq!(
+            deliveries(&sequencer)[0],
+            (
+                "n2".to_string(),
+                MAX_DELIVER_BATCH as u64,
+                MAX_DELIVER_BATCH
+            )
+        );
+        deliver(&mut sequencer, &ack(2 * MAX_DELIVER_BATCH as u64)).await;
+        assert_eq!(
+            deliveries(&sequencer)[0],
+            ("n2".to_string(), 2 * MAX_DELIVER_BATCH as u64, 10)
+        );
+        // A stale acknowledgement does not move us backwards
+        deliver(&mut sequencer, &ack(total as u64)).await;
+        deliver(&mut sequencer, &ack(1)).await;
+        assert_eq!(deliveries(&sequencer), first[1..]);
+    }
+
+    #[tokio::test]
+    async fn test_read_ok_is_ordered_only_in_total_order_mode() {
+        let mut total = node("n1", BroadcastOrder::Total).await;
+        let mut unordered = node("n1", BroadcastOrder::Unordered).await;
+        for value in [3, 1, 2] {
+            broadcast(&mut total, value).await;
+            broadcast(&mut unordered, value).await;
+        }
+        assert_eq!(read(&mut total).await["ordered"], json!([3, 1, 2]));
+        let body = read(&mut unordered).await;
+        assert_eq!(body["type"], "read_ok");
+        assert!(body.get("ordered").is_none());
+    }
+}
@@ -65,7 +65,12 @@ impl GCounter {
         val: u64,
     ) -> Option<u64> {
         self.internal_current += val;
-        self.kvstore.write(self.node_id.clone(), Value::Number(val.into()));
+        if let Err(_e) = self
+            .kvstore
+            .write(self.node_id.clone(), Value::Number(val.into()))
+        {
+            eprintln!("{:?}", _e);
+        }
         None
     }
 
@@ -144,6 +149,7 @@ impl Node for GCounter {
 
     async fn on_init(&mut self, msg: rpc::InitMsgIn) -> Result<(), errors::ErrorMsg> {
         self.node_id = msg.body.node_id.clone();
+        self.kvstore.set_node_id(self.node_id.clone());
         let msg_out = msg.into_response(self.last_msg_id);
         let result = serde_json::to_string(&msg_out).map_err(errors::ErrorMsg::json_dumps_error)?;
         println!("{}", result);
@@ -0,0 +1,1489 @@
+/// Kafka-style log node: see maelstrom kafka docs
+/// https://github.com/jepsen-io/maelstrom/blob/main/doc/workloads.md#workload-kafka
+///
+/// Each key has its own append-only log. Offsets within a log start at zero and
+/// increase by one with every message sent to that key.
+///
+/// With more than one node, the next offset for each key lives in lin-kv and a node
+/// claims an offset with a compare-and-set, so no two nodes can hand out the same
+/// offset. The node which claims an offset replicates the message to its peers,
+/// and committed offsets are kept in lin-kv where every node can see them.
+/// Offsets are claimed without gaps, so a poll only ever returns an unbroken run of
+/// a log: a message we have not received yet holds back everything after it.
+///
+/// Alternatively each key can be owned by one node, picked by hashing the key over
+/// the node ids from `init`. A send to any other node is forwarded to the owner,
+/// which hands out offsets by itself and replicates as above.
+///
+/// As an extension to the maelstrom API, commit_offsets and list_committed_offsets
+/// take an optional consumer `group`. Each group has its own committed offset for
+/// every key, so several consumers can read the same log independently; requests
+/// without a group share the original single offset per key.
+///
+/// Polls are also extended: `max_messages` limits the size of the reply, and a
+/// poll with `wait_ms` which finds nothing new is held until a message arrives for
+/// one of its keys or the wait is over. Replies never hold more than
+/// MAX_POLL_MESSAGES, shared out across the keys asked for.
+///
+/// Logs are kept by a `storage::LogStore`, in memory or in segment files on disk.
+/// Every so often we let each log drop old messages which every group has committed.
+/// With offsets in lin-kv, a node only sees the commits made through it, so each
+/// group is added to a registry in lin-kv before its first commit there, and a
+/// retention pass reads every registered group's offset for each log before
+/// dropping anything. A log which some group has not committed keeps everything.
+/// A consumer which has never committed at all is known to no node: nothing waits
+/// for it.
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant, SystemTime};
+
+use async_trait::async_trait;
+use serde_json::Value;
+use tokio::sync::mpsc::Receiver;
+
+use crate::errors;
+use crate::kv;
+use crate::node::{stable_hash, Deferred, Forwarder, Node, Retrier, FORWARD_EXPIRE_TICKS};
+use crate::rpc::{self, kafka};
+use crate::storage::{LogStore, LogStoreConfig};
+use crate::workload::{Command, KafkaOffsets};
+
+/// Ticks to wait for a peer to acknowledge a replicated message before sending it again
+const REPLICATE_RESEND_TICKS: u64 = 3;
+
+/// Most messages in any one poll reply
+const MAX_POLL_MESSAGES: usize = 1000;
+
+/// Longest we will hold on to a poll waiting for messages
+const MAX_POLL_WAIT_MS: u64 = 5000;
+
+/// Ticks between retention passes over the logs
+const RETENTION_TICKS: u64 = 20;
+
+/// In lieu of *sending* messages: we print them to screen
+fn send_message(msg: kafka::KafkaMessage) -> Result<(), errors::ErrorMsg> {
+    println!("{}", msg.to_json()?);
+    Ok(())
+}
+
+/// lin-kv key holding the next offset to hand out for a log
+fn offset_key(key: &str) -> String {
+    format!("offset-{}", key)
+}
+
+/// lin-kv key holding the registry of groups which have committed offsets: a list
+/// of their names, with null for requests without a group
+const GROUPS_KEY: &str = "groups";
+
+/// lin-kv key holding a group's committed offset for a log.
+/// The group's length keeps e.g. ("a", "b-c") and ("a-b", "c") apart.
+fn commit_key(group: Option<&str>, key: &str) -> String {
+    match group {
+        None => format!("commit-{}", key),
+        Some(group) => format!("group-commit-{}:{}-{}", group.len(), group, key),
+    }
+}
+
+/// Enough of a client request to answer it later
+#[derive(Clone, Debug)]
+struct Client {
+    src: String,
+    msg_id: Option<u64>,
+}
+
+/// What we are waiting on a lin-kv reply for
+#[derive(Clone, Debug)]
+enum KvWaiter {
+    // Claiming an offset for a message: `offset` is our best guess at the next free one
+    Send {
+        client: Client,
+        key: String,
+        msg: Value,
+        offset: u64,
+    },
+    // Moving a group's committed offset forward to `offset`: `from` is the offset
+    // we expect to find (null if none), or None while reading it
+    Commit {
+        gather: u64,
+        group: Option<String>,
+        key: String,
+        offset: u64,
+        from: Option<Value>,
+    },
+    List {
+        gather: u64,
+        group: Option<String>,
+        key: String,
+    },
+    // Adding a group to the registry before its commits: `from` is the registry
+    // as we last read it, or None while reading it
+    Register {
+        gather: u64,
+        group: Option<String>,
+        writes: Vec<(String, u64)>,
+        from: Option<Value>,
+    },
+    // A retention pass reading the registry...
+    RetentionGroups {
+        pass: u64,
+    },
+    // ...and then each group's committed offset for each log
+    RetentionCommit {
+        pass: u64,
+        key: String,
+    },
+}
+
+/// A retention pass waiting on lin-kv: the lowest offset committed for each log
+/// so far, by the groups heard from
+#[derive(Clone, Debug)]
+struct Retention {
+    pass: u64,
+    remaining: usize,
+    floors: HashMap<String, u64>,
+}
+
+/// A commit_offsets or list_committed_offsets request which we answer
+/// once all of the lin-kv requests made on its behalf have been answered
+#[derive(Clone, Debug)]
+struct Gather {
+    client: Client,
+    remaining: usize,
+    listing: bool,
+    offsets: HashMap<String, u64>,
+}
+
+pub struct Kafka {
+    node_id: String,
+    node_ids: Vec<String>,
+    offsets_by: KafkaOffsets,
+    kvstore: kv::KV,
+    peers: Retrier,
+    forwarder: Forwarder,
+    storage: LogStoreConfig,
+    logs: HashMap<String, Box<dyn LogStore>>,
+    // key -> next offset to hand out (for lin-kv offsets, our best guess)
+    next_offset: HashMap<String, u64>,
+    // (group, key) -> latest offset a client has told us it has processed
+    committed: HashMap<(Option<String>, String), u64>,
+    // groups we know to be in the registry in lin-kv
+    registered: HashSet<Option<String>>,
+    retention: Option<Retention>,
+    last_retention_pass: u64,
+    kv_waiting: HashMap<u64, KvWaiter>,
+    // long polls waiting for messages
+    polls: Deferred<(Client, kafka::PollRequestMsg)>,
+    gathers: HashMap<u64, Gather>,
+    last_gather_id: u64,
+    last_msg_id: u64,
+    ticks: u64,
+    rx: Receiver<Command>,
+}
+
+impl Kafka {
+    pub fn with_offsets(mut self, offsets_by: KafkaOffsets) -> Self {
+        self.offsets_by = offsets_by;
+        self
+    }
+
+    pub fn with_storage(mut self, storage: LogStoreConfig) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    fn next_msg_id(&mut self) -> u64 {
+        self.last_msg_id += 1;
+        self.last_msg_id
+    }
+
+    fn reply_to(&mut self, client: &Client, body: kafka::KafkaMessageBody) -> kafka::KafkaMessage {
+        kafka::KafkaMessage {
+            src: self.node_id.clone(),
+            dest: client.src.clone(),
+            body,
+        }
+    }
+
+    /// The node which hands out offsets for `key` when offsets are by owner
+    fn owner_of(&self, key: &str) -> Option<&String> {
+        if self.node_ids.is_empty() {
+            return None;
+        }
+        let index = stable_hash(key) % self.node_ids.len() as u64;
+        self.node_ids.get(index as usize)
+    }
+
+    /// Pick up the logs left in our storage by an earlier run, carrying on after them
+    fn reopen_logs(&mut self) -> Result<(), errors::ErrorMsg> {
+        for key in self.storage.existing()? {
+            let log = self.storage.open(&key)?;
+            self.next_offset.insert(key.clone(), log.end_offset());
+            self.logs.insert(key, log);
+        }
+        Ok(())
+    }
+
+    fn store(&mut self, key: &str, offset: u64, msg: Value) -> Result<(), errors::ErrorMsg> {
+        if !self.logs.contains_key(key) {
+            let log = self.storage.open(key)?;
+            self.logs.insert(key.to_string(), log);
+        }
+        if let Some(log) = self.logs.get_mut(key) {
+            log.insert(offset, &msg)?;
+        }
+        let next = self.next_offset.entry(key.to_string()).or_insert(0);
+        if offset + 1 > *next {
+            *next = offset + 1;
+        }
+        self.serve_polls(Some(key))
+    }
+
+    /// Send requests are always answered later, once the message has an offset
+    async fn handle_send(
+        &mut self,
+        client: Client,
+        raw: &str,
+        msg: &kafka::SendRequestMsg,
+    ) -> Result<Option<kafka::KafkaMessageBody>, errors::ErrorMsg> {
+        let offset = *self.next_offset.get(&msg.key).unwrap_or(&0);
+        match self.offsets_by {
+            KafkaOffsets::Local => {
+                self.offset_claimed(client, msg.key.clone(), msg.msg.clone(), offset)?
+            }
+            KafkaOffsets::LinKv => {
+                self.claim_offset(client, msg.key.clone(), msg.msg.clone(), offset)?
+            }
+            KafkaOffsets::Owner => match self.owner_of(&msg.key).cloned() {
+                Some(owner) if owner != self.node_id => self.forwarder.forward(raw, &owner)?,
+                _ => self.offset_claimed(client, msg.key.clone(), msg.msg.clone(), offset)?,
+            },
+        }
+        Ok(None)
+    }
+
+    fn claim_offset(
+        &mut self,
+        client: Client,
+        key: String,
+        msg: Value,
+        offset: u64,
+    ) -> Result<(), errors::ErrorMsg> {
+        let msg_id = self.kvstore.cas(
+            offset_key(&key),
+            Value::from(offset),
+            Value::from(offset + 1),
+            (offset == 0).then_some(true),
+        )?;
+        self.kv_waiting.insert(
+            msg_id,
+            KvWaiter::Send {
+                client,
+                key,
+                msg,
+                offset,
+            },
+        );
+        Ok(())
+    }
+
+    /// We have claimed `offset`: keep the message, share it, and tell the client
+    fn offset_claimed(
+        &mut self,
+        client: Client,
+        key: String,
+        msg: Value,
+        offset: u64,
+    ) -> Result<(), errors::ErrorMsg> {
+        self.store(&key, offset, msg.clone())?;
+        for peer in self.node_ids.clone().iter() {
+            if *peer != self.node_id {
+                let body = kafka::KafkaMessageBody::Replicate(kafka::ReplicateRequestMsg::new(
+                    key.clone(),
+                    offset,
+                    msg.clone(),
+                ));
+                self.peers.send(peer, &body)?;
+            }
+        }
+        let body = kafka::KafkaMessageBody::SendOk(kafka::SendResponseMsg::new(
+            client.msg_id,
+            self.next_msg_id(),
+            offset,
+        ));
+        let reply = self.reply_to(&client, body);
+        send_message(reply)
+    }
+
+    async fn handle_replicate(
+        &mut self,
+        msg: &kafka::ReplicateRequestMsg,
+    ) -> Result<Option<kafka::KafkaMessageBody>, errors::ErrorMsg> {
+        self.store(&msg.key, msg.offset, msg.msg.clone())?;
+        Ok(Some(kafka::KafkaMessageBody::ReplicateOk(
+            kafka::ReplicateResponseMsg::new(msg.msg_id),
+        )))
+    }
+
+    /// Messages for a poll: at most `limit` in all, shared out over its keys
+    fn read_logs(
+        &mut self,
+        msg: &kafka::PollRequestMsg,
+    ) -> Result<HashMap<String, Vec<(u64, Value)>>, errors::ErrorMsg> {
+        let limit = msg
+            .max_messages
+            .unwrap_or(MAX_POLL_MESSAGES)
+            .min(MAX_POLL_MESSAGES);
+        let mut keys: Vec<(&String, &u64)> = msg.offsets.iter().collect();
+        keys.sort();
+        let per_key = limit.div_ceil(keys.len().max(1));
+        let mut remaining = limit;
+        let mut msgs = HashMap::new();
+        for (key, offset) in keys {
+            if let Some(log) = self.logs.get_mut(key) {
+                // The store stops at the first offset we do not (yet) have
+                let entries = log.read_from(*offset, per_key.min(remaining))?;
+                remaining -= entries.len();
+                msgs.insert(key.clone(), entries);
+            }
+        }
+        Ok(msgs)
+    }
+
+    fn poll_reply(
+        &mut self,
+        msg: &kafka::PollRequestMsg,
+        msgs: HashMap<String, Vec<(u64, Value)>>,
+    ) -> kafka::KafkaMessageBody {
+        kafka::KafkaMessageBody::PollOk(kafka::PollResponseMsg::new(
+            msg.msg_id,
+            self.next_msg_id(),
+            msgs,
+        ))
+    }
+
+    async fn handle_poll(
+        &mut self,
+        client: Client,
+        msg: &kafka::PollRequestMsg,
+    ) -> Result<Option<kafka::KafkaMessageBody>, errors::ErrorMsg> {
+        let msgs = self.read_logs(msg)?;
+        let wait_ms = msg.wait_ms.unwrap_or(0).min(MAX_POLL_WAIT_MS);
+        if wait_ms > 0 && msgs.values().all(Vec::is_empty) {
+            let deadline = Instant::now() + Duration::from_millis(wait_ms);
+            self.polls.park((client, msg.clone()), deadline);
+            return Ok(None);
+        }
+        Ok(Some(self.poll_reply(msg, msgs)))
+    }
+
+    /// Answer held polls which now have messages (if `appended` to one of their
+    /// keys) or which have waited long enough
+    fn serve_polls(&mut self, appended: Option<&str>) -> Result<(), errors::ErrorMsg> {
+        if self.polls.is_empty() {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let woken = self.polls.take(now, |(_, poll)| {
+            appended.is_some_and(|key| poll.offsets.contains_key(key))
+        });
+        for parked in woken {
+            let (client, poll) = &parked.request;
+            let msgs = self.read_logs(poll)?;
+            if msgs.values().all(Vec::is_empty) && !parked.expired(now) {
+                self.polls.park(parked.request, parked.deadline);
+                continue;
+            }
+            let body = self.poll_reply(poll, msgs);
+            let reply = self.reply_to(client, body);
+            send_message(reply)?;
+        }
+        Ok(())
+    }
+
+    async fn handle_commit_offsets(
+        &mut self,
+        client: Client,
+        msg: &kafka::CommitOffsetsRequestMsg,
+    ) -> Result<Option<kafka::KafkaMessageBody>, errors::ErrorMsg> {
+        let mut writes = vec![];
+        for (key, offset) in msg.offsets.iter() {
+            // Committed offsets only move forward
+            let committed_key = (msg.group.clone(), key.clone());
+            let committed = self.committed.get(&committed_key);
+            if committed.is_none() || Some(offset) > committed {
+                writes.push((key.clone(), *offset));
+                if self.offsets_by == KafkaOffsets::Local {
+                    self.committed.insert(committed_key, *offset);
+                }
+            }
+        }
+        if self.offsets_by == KafkaOffsets::Local || writes.is_empty() {
+            return Ok(Some(kafka::KafkaMessageBody::CommitOffsetsOk(
+                kafka::CommitOffsetsResponseMsg::new(msg.msg_id, self.next_msg_id()),
+            )));
+        }
+        let gather = self.start_gather(client, writes.len(), false);
+        if self.registered.contains(&msg.group) {
+            self.write_commits(gather, msg.group.clone(), writes)?;
+        } else {
+            self.register_group(gather, msg.group.clone(), writes, None)?;
+        }
+        Ok(None)
+    }
+
+    /// Make sure `group` is in the registry, then write its commits. With `from`,
+    /// add it to the registry as read; without, read the registry first.
+    fn register_group(
+        &mut self,
+        gather: u64,
+        group: Option<String>,
+        writes: Vec<(String, u64)>,
+        from: Option<Value>,
+    ) -> Result<(), errors::ErrorMsg> {
+        let msg_id = match &from {
+            None => self.kvstore.read(GROUPS_KEY.to_string())?,
+            Some(registry) => {
+                let mut groups = registry.as_array().cloned().unwrap_or_default();
+                groups.push(Value::from(group.clone()));
+                let create = groups.len() == 1;
+                self.kvstore.cas(
+                    GROUPS_KEY.to_string(),
+                    registry.clone(),
+                    Value::from(groups),
+                    create.then_some(true),
+                )?
+            }
+        };
+        self.kv_waiting.insert(
+            msg_id,
+            KvWaiter::Register {
+                gather,
+                group,
+                writes,
+                from,
+            },
+        );
+        Ok(())
+    }
+
+    fn write_commits(
+        &mut self,
+        gather: u64,
+        group: Option<String>,
+        writes: Vec<(String, u64)>,
+    ) -> Result<(), errors::ErrorMsg> {
+        for (key, offset) in writes {
+            let from = self.committed.get(&(group.clone(), key.clone())).copied();
+            self.write_commit(gather, group.clone(), key, offset, Some(Value::from(from)))?;
+        }
+        Ok(())
+    }
+
+    /// Move a committed offset forward with a cas from `from`, so that a slower
+    /// commit can never move it back. Without `from`, read the offset first.
+    fn write_commit(
+        &mut self,
+        gather: u64,
+        group: Option<String>,
+        key: String,
+        offset: u64,
+        from: Option<Value>,
+    ) -> Result<(), errors::ErrorMsg> {
+        let msg_id = match &from {
+            None => self.kvstore.read(commit_key(group.as_deref(), &key))?,
+            Some(committed) => self.kvstore.cas(
+                commit_key(group.as_deref(), &key),
+                committed.clone(),
+                Value::from(offset),
+                committed.is_null().then_some(true),
+            )?,
+        };
+        self.kv_waiting.insert(
+            msg_id,
+            KvWaiter::Commit {
+                gather,
+                group,
+                key,
+                offset,
+                from,
+            },
+        );
+        Ok(())
+    }
+
+    /// A group's offset for `key` is at least `offset` in lin-kv
+    fn commit_written(
+        &mut self,
+        gather: u64,
+        group: Option<String>,
+        key: String,
+        offset: u64,
+    ) -> Result<(), errors::ErrorMsg> {
+        let committed = self.committed.entry((group, key)).or_insert(offset);
+        if offset > *committed {
+            *committed = offset;
+        }
+        self.gathered(gather, None)
+    }
+
+    async fn handle_list_committed_offsets(
+        &mut self,
+        client: Client,
+        msg: &kafka::ListCommittedOffsetsRequestMsg,
+    ) -> Result<Option<kafka::KafkaMessageBody>, errors::ErrorMsg> {
+        if self.offsets_by == KafkaOffsets::Local || msg.keys.is_empty() {
+            let offsets = msg
+                .keys
+                .iter()
+                .filter_map(|key| {
+                    self.committed
+                        .get(&(msg.group.clone(), key.clone()))
+                        .map(|offset| (key.clone(), *offset))
+                })
+                .collect();
+            return Ok(Some(kafka::KafkaMessageBody::ListCommittedOffsetsOk(
+                kafka::ListCommittedOffsetsResponseMsg::new(
+                    msg.msg_id,
+                    self.next_msg_id(),
+                    offsets,
+                ),
+            )));
+        }
+        let gather = self.start_gather(client, msg.keys.len(), true);
+        for key in msg.keys.iter() {
+            self.read_commit(gather, msg.group.clone(), key.clone())?;
+        }
+        Ok(None)
+    }
+
+    fn read_commit(
+        &mut self,
+        gather: u64,
+        group: Option<String>,
+        key: String,
+    ) -> Result<(), errors::ErrorMsg> {
+        let msg_id = self.kvstore.read(commit_key(group.as_deref(), &key))?;
+        self.kv_waiting
+            .insert(msg_id, KvWaiter::List { gather, group, key });
+        Ok(())
+    }
+
+    fn start_gather(&mut self, client: Client, remaining: usize, listing: bool) -> u64 {
+        self.last_gather_id += 1;
+        self.gathers.insert(
+            self.last_gather_id,
+            Gather {
+                client,
+                remaining,
+                listing,
+                offsets: HashMap::new(),
+            },
+        );
+        self.last_gather_id
+    }
+
+    /// One of a gather's lin-kv requests is done: answer the client if it was the last
+    fn gathered(
+        &mut self,
+        gather_id: u64,
+        offset: Option<(String, u64)>,
+    ) -> Result<(), errors::ErrorMsg> {
+        let gather = match self.gathers.get_mut(&gather_id) {
+            Some(gather) => gather,
+            None => return Ok(()),
+        };
+        if let Some((key, offset)) = offset {
+            gather.offsets.insert(key, offset);
+        }
+        gather.remaining -= 1;
+        if gather.remaining > 0 {
+            return Ok(());
+        }
+        let gather = self.gathers.remove(&gather_id).unwrap();
+        let msg_id = self.next_msg_id();
+        let body = if gather.listing {
+            kafka::KafkaMessageBody::ListCommittedOffsetsOk(
+                kafka::ListCommittedOffsetsResponseMsg::new(
+                    gather.client.msg_id,
+                    msg_id,
+                    gather.offsets,
+                ),
+            )
+        } else {
+            kafka::KafkaMessageBody::CommitOffsetsOk(kafka::CommitOffsetsResponseMsg::new(
+                gather.client.msg_id,
+                msg_id,
+            ))
+        };
+        let reply = self.reply_to(&gather.client, body);
+        send_message(reply)
+    }
+
+    async fn handle_kv_reply(&mut self, reply: kv::KvMsgIn) -> Result<(), errors::ErrorMsg> {
+        let waiter = match reply
+            .body
+            .in_reply_to()
+            .and_then(|msg_id| self.kv_waiting.remove(&msg_id))
+        {
+            Some(waiter) => waiter,
+            None => return Ok(()),
+        };
+        match (waiter, reply.body) {
+            (
+                KvWaiter::Send {
+                    client,
+                    key,
+                    msg,
+                    offset,
+                },
+                body,
+            ) => match body {
+                kv::KvResponseBody::CasOk(_) => self.offset_claimed(client, key, msg, offset),
+                kv::KvResponseBody::ReadOk(body) => {
+                    let offset = body.value.as_u64().unwrap_or(0);
+                    self.claim_offset(client, key, msg, offset)
+                }
+                kv::KvResponseBody::Error(body)
+                    if body.code == errors::ErrorType::KeyDoesNotExist =>
+                {
+                    self.claim_offset(client, key, msg, 0)
+                }
+                // Someone else claimed that offset: find out what the next one is
+                kv::KvResponseBody::Error(body)
+                    if body.code == errors::ErrorType::PreconditionFailed =>
+                {
+                    let msg_id = self.kvstore.read(offset_key(&key))?;
+                    self.kv_waiting.insert(
+                        msg_id,
+                        KvWaiter::Send {
+                            client,
+                            key,
+                            msg,
+                            offset,
+                        },
+                    );
+                    Ok(())
+                }
+                // Something else went wrong: try the same offset again
+                _ => self.claim_offset(client, key, msg, offset),
+            },
+            (
+                KvWaiter::Commit {
+                    gather,
+                    group,
+                    key,
+                    offset,
+                    ..
+                },
+                kv::KvResponseBody::CasOk(_),
+            ) => self.commit_written(gather, group, key, offset),
+            (
+                KvWaiter::Commit {
+                    gather,
+                    group,
+                    key,
+                    offset,
+                    from: None,
+                },
+                kv::KvResponseBody::ReadOk(body),
+            ) => match body.value.as_u64() {
+                // Already committed this far: never move it back
+                Some(committed) if committed >= offset => {
+                    self.commit_written(gather, group, key, committed)
+                }
+                _ => self.write_commit(gather, group, key, offset, Some(body.value)),
+            },
+            (
+                KvWaiter::Commit {
+                    gather,
+                    group,
+                    key,
+                    offset,
+                    from: None,
+                },
+                kv::KvResponseBody::Error(body),
+            ) if body.code == errors::ErrorType::KeyDoesNotExist => {
+                self.write_commit(gather, group, key, offset, Some(Value::Null))
+            }
+            // Someone else moved the offset: read where it is now
+            (
+                KvWaiter::Commit {
+                    gather,
+                    group,
+                    key,
+                    offset,
+                    from: Some(_),
+                },
+                kv::KvResponseBody::Error(body),
+            ) if body.code == errors::ErrorType::PreconditionFailed => {
+                self.write_commit(gather, group, key, offset, None)
+            }
+            // Something else went wrong: try the same request again
+            (
+                KvWaiter::Commit {
+                    gather,
+                    group,
+                    key,
+                    offset,
+                    from,
+                },
+                _,
+            ) => self.write_commit(gather, group, key, offset, from),
+            (KvWaiter::List { gather, key, .. }, kv::KvResponseBody::ReadOk(body)) => {
+                let offset = body.value.as_u64().unwrap_or(0);
+                self.gathered(gather, Some((key, offset)))
+            }
+            (KvWaiter::List { gather, .. }, kv::KvResponseBody::Error(body))
+                if body.code == errors::ErrorType::KeyDoesNotExist =>
+            {
+                self.gathered(gather, None)
+            }
+            (KvWaiter::List { gather, group, key }, _) => self.read_commit(gather, group, key),
+            (
+                KvWaiter::Register {
+                    gather,
+                    group,
+                    writes,
+                    from: None,
+                },
+                kv::KvResponseBody::ReadOk(body),
+            ) => {
+                if body
+                    .value
+                    .as_array()
+                    .is_some_and(|groups| groups.contains(&Value::from(group.clone())))
+                {
+                    self.registered.insert(group.clone());
+                    self.write_commits(gather, group, writes)
+                } else {
+                    self.register_group(gather, group, writes, Some(body.value))
+                }
+            }
+            (
+                KvWaiter::Register {
+                    gather,
+                    group,
+                    writes,
+                    from: None,
+                },
+                kv::KvResponseBody::Error(body),
+            ) if body.code == errors::ErrorType::KeyDoesNotExist => {
+                self.register_group(gather, group, writes, Some(Value::Array(vec![])))
+            }
+            (
+                KvWaiter::Register {
+                    gather,
+                    group,
+                    writes,
+                    from: Some(_),
+                },
+                kv::KvResponseBody::CasOk(_),
+            ) => {
+                self.registered.insert(group.clone());
+                self.write_commits(gather, group, writes)
+            }
+            // The registry changed under us (or something went wrong): read it again
+            (
+                KvWaiter::Register {
+                    gather,
+                    group,
+                    writes,
+                    ..
+                },
+                _,
+            ) => self.register_group(gather, group, writes, None),
+            (KvWaiter::RetentionGroups { pass }, body) => self.retention_groups(pass, body),
+            (KvWaiter::RetentionCommit { pass, key }, body) => {
+                let offset = match body {
+                    kv::KvResponseBody::ReadOk(body) => body.value.as_u64().unwrap_or(0),
+                    // Not committed, or we cannot tell: keep the whole log
+                    _ => 0,
+                };
+                self.retention_commit(pass, key, offset)
+            }
+        }
+    }
+
+    /// Let each log drop what its retention policy allows, keeping anything which
+    /// some group has not yet committed. With offsets in lin-kv we first find out
+    /// what every group has committed.
+    fn start_retention(&mut self) -> Result<(), errors::ErrorMsg> {
+        if self.offsets_by != KafkaOffsets::Local {
+            // A pass still waiting on lin-kv is given up: its replies are ignored
+            self.last_retention_pass += 1;
+            self.retention = None;
+            if !self.logs.is_empty() {
+                let msg_id = self.kvstore.read(GROUPS_KEY.to_string())?;
+                let pass = self.last_retention_pass;
+                self.kv_waiting
+                    .insert(msg_id, KvWaiter::RetentionGroups { pass });
+            }
+            return Ok(());
+        }
+        let groups: HashSet<&Option<String>> =
+            self.committed.keys().map(|(group, _)| group).collect();
+        let floors = self
+            .logs
+            .keys()
+            .map(|key| {
+                let floor = groups
+                    .iter()
+                    .map(|group| {
+                        let committed = self.committed.get(&((*group).clone(), key.clone()));
+                        *committed.unwrap_or(&0)
+                    })
+                    .min()
+                    .unwrap_or(0);
+                (key.clone(), floor)
+            })
+            .collect();
+        self.apply_retention(floors)
+    }
+
+    /// The registry has been read: read each group's offset for each of our logs
+    fn retention_groups(
+        &mut self,
+        pass: u64,
+        body: kv::KvResponseBody,
+    ) -> Result<(), errors::ErrorMsg> {
+        let groups: Vec<Option<String>> = match body {
+            kv::KvResponseBody::ReadOk(body) if pass == self.last_retention_pass => body
+                .value
+                .as_array()
+                .map(|groups| {
+                    groups
+                        .iter()
+                        .map(|group| group.as_str().map(String::from))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            // No group has committed anything (or the pass is over): nothing to drop
+            _ => return Ok(()),
+        };
+        if groups.is_empty() {
+            return Ok(());
+        }
+        let keys: Vec<String> = self.logs.keys().cloned().collect();
+        for key in keys.iter() {
+            for group in groups.iter() {
+                let msg_id = self.kvstore.read(commit_key(group.as_deref(), key))?;
+                self.kv_waiting.insert(
+                    msg_id,
+                    KvWaiter::RetentionCommit {
+                        pass,
+                        key: key.clone(),
+                    },
+                );
+            }
+        }
+        self.retention = Some(Retention {
+            pass,
+            remaining: keys.len() * groups.len(),
+            floors: keys.into_iter().map(|key| (key, u64::MAX)).collect(),
+        });
+        Ok(())
+    }
+
+    /// One group's offset for a log: once we have them all, drop what we can
+    fn retention_commit(
+        &mut self,
+        pass: u64,
+        key: String,
+        offset: u64,
+    ) -> Result<(), errors::ErrorMsg> {
+        let retention = match self.retention.as_mut() {
+            Some(retention) if retention.pass == pass => retention,
+            _ => return Ok(()),
+        };
+        if let Some(floor) = retention.floors.get_mut(&key) {
+            *floor = (*floor).min(offset);
+        }
+        retention.remaining -= 1;
+        if retention.remaining > 0 {
+            return Ok(());
+        }
+        let floors = self.retention.take().map(|retention| retention.floors);
+        self.apply_retention(floors.unwrap_or_default())
+    }
+
+    /// Let each log drop what its retention policy allows below its floor
+    fn apply_retention(&mut self, floors: HashMap<String, u64>) -> Result<(), errors::ErrorMsg> {
+        let now = SystemTime::now();
+        for (key, floor) in floors {
+            if let Some(log) = self.logs.get_mut(&key) {
+                log.apply_retention(floor, now)?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_tick(&mut self) -> Result<(), errors::ErrorMsg> {
+        self.serve_polls(None)?;
+        self.ticks += 1;
+        if self.ticks.is_multiple_of(RETENTION_TICKS) {
+            self.start_retention()?;
+        }
+        self.forwarder.tick();
+        self.peers.tick()
+    }
+}
+
+#[async_trait]
+impl Node for Kafka {
+    fn new(starting_msg_id: u64, rx: Receiver<Command>) -> Self {
+        Self {
+            node_id: "n0".to_string(),
+            node_ids: vec![],
+            offsets_by: KafkaOffsets::LinKv,
+            kvstore: kv::KV::new(kv::KvType::LinKV),
+            peers: Retrier::new(REPLICATE_RESEND_TICKS),
+            forwarder: Forwarder::new(FORWARD_EXPIRE_TICKS),
+            storage: LogStoreConfig::default(),
+            logs: HashMap::new(),
+            next_offset: HashMap::new(),
+            committed: HashMap::new(),
+            registered: HashSet::new(),
+            retention: None,
+            last_retention_pass: 0,
+            kv_waiting: HashMap::new(),
+            polls: Deferred::new(),
+            gathers: HashMap::new(),
+            last_gather_id: 0,
+            last_msg_id: starting_msg_id,
+            ticks: 0,
+            rx,
+        }
+    }
+
+    async fn handle(&mut self, msg: String) -> Result<(), errors::ErrorMsg> {
+        if let Some(reply) = kv::KvMsgIn::parse(msg.as_str())? {
+            return self.handle_kv_reply(reply).await;
+        }
+        if self.forwarder.try_complete(msg.as_str())? {
+            return Ok(());
+        }
+        let msg_in = serde_json::from_str::<kafka::KafkaMessage>(msg.as_str())
+            .map_err(errors::ErrorMsg::json_parse_error)?;
+        let client = Client {
+            src: msg_in.src.clone(),
+            msg_id: msg_in.body.msg_id(),
+        };
+        let reply = match &msg_in.body {
+            kafka::KafkaMessageBody::Send(body) => self.handle_send(client, &msg, body).await?,
+            kafka::KafkaMessageBody::Poll(body) => self.handle_poll(client, body).await?,
+            kafka::KafkaMessageBody::CommitOffsets(body) => {
+                self.handle_commit_offsets(client, body).await?
+            }
+            kafka::KafkaMessageBody::ListCommittedOffsets(body) => {
+                self.handle_list_committed_offsets(client, body).await?
+            }
+            kafka::KafkaMessageBody::Replicate(body) => self.handle_replicate(body).await?,
+            kafka::KafkaMessageBody::ReplicateOk(body) => {
+                if let Some(in_reply_to) = body.in_reply_to {
+                    self.peers.ack(in_reply_to);
+                }
+                None
+            }
+            // We never ask clients anything, so there is nothing to do with these
+            _ => None,
+        };
+        if let Some(body) = reply {
+            send_message(msg_in.reply(body))?;
+        }
+        Ok(())
+    }
+
+    async fn on_init(&mut self, msg: rpc::InitMsgIn) -> Result<(), errors::ErrorMsg> {
+        self.node_id = msg.body.node_id.clone();
+        self.node_ids = msg.body.node_ids.clone();
+        self.kvstore.set_node_id(self.node_id.clone());
+        self.peers.set_node_id(self.node_id.clone());
+        self.forwarder.set_node_id(self.node_id.clone());
+        self.storage = self.storage.for_node(&self.node_id)?;
+        self.reopen_logs()?;
+        let msg_out = msg.into_response(self.last_msg_id);
+        let result = serde_json::to_string(&msg_out).map_err(errors::ErrorMsg::json_dumps_error)?;
+        println!("{}", result);
+        Ok(())
+    }
+
+    async fn start(&mut self) -> Result<(), errors::ErrorMsg> {
+        while let Some(cmd) = self.rx.recv().await {
+            match cmd {
+                Command::Init(init_msg) => self.on_init(init_msg).await?,
+                Command::Msg(msg) => self.handle(msg).await?,
+                Command::Tick => self.handle_tick().await?,
+                Command::Shutdown => self.stop().await?,
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    async fn stop(&mut self) -> Result<(), errors::ErrorMsg> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    fn node(offsets_by: KafkaOffsets) -> Kafka {
+        let (_tx, rx) = mpsc::channel(1);
+        Kafka::new(1, rx).with_offsets(offsets_by)
+    }
+
+    fn client() -> Client {
+        Client {
+            src: "c1".to_string(),
+            msg_id: Some(1),
+        }
+    }
+
+    fn send(key: &str, val: u64) -> kafka::SendRequestMsg {
+        kafka::SendRequestMsg {
+            msg_id: Some(1),
+            key: key.to_string(),
+            msg: Value::from(val),
+        }
+    }
+
+    async fn poll(node: &mut Kafka, key: &str, offset: u64) -> Vec<(u64, Value)> {
+        let poll = kafka::PollRequestMsg {
+            msg_id: Some(2),
+            offsets: HashMap::from([(key.to_string(), offset)]),
+            max_messages: None,
+            wait_ms: None,
+        };
+        match node.handle_poll(client(), &poll).await.unwrap() {
+            Some(kafka::KafkaMessageBody::PollOk(mut body)) => {
+                body.msgs.remove(key).unwrap_or_default()
+            }
+            other => panic!("Unexpected reply {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_and_poll() {
+        let mut node = node(KafkaOffsets::Local);
+        for (key, val) in [("a", 10), ("b", 20), ("a", 11), ("a", 12)] {
+            node.handle_send(client(), "", &send(key, val))
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            poll(&mut node, "a", 1).await,
+            vec![(1, Value::from(11)), (2, Value::from(12))]
+        );
+        assert!(poll(&mut node, "c", 0).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_send_and_poll_from_segments() {
+        let dir = std::env::temp_dir().join(format!("maelstrom-kafka-test-{}", std::process::id()));
+        let storage = LogStoreConfig::Segments(crate::storage::segment::SegmentConfig {
+            segment_bytes: 64,
+            ..crate::storage::segment::SegmentConfig::new(dir.clone())
+        });
+        let mut node = node(KafkaOffsets::Local);
+        node.storage = storage.for_node("n1").unwrap();
+        for val in 0..20 {
+            node.handle_send(client(), "", &send("a/b", val))
+                .await
+                .unwrap();
+        }
+        let msgs = poll(&mut node, "a/b", 15).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            msgs,
+            (15..20)
+                .map(|val| (val, Value::from(val)))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_restarted_node_carries_on_from_its_segments() {
+        let dir =
+            std::env::temp_dir().join(format!("maelstrom-kafka-restart-{}", std::process::id()));
+        let storage =
+            LogStoreConfig::Segments(crate::storage::segment::SegmentConfig::new(dir.clone()));
+        let mut first = node(KafkaOffsets::Local);
+        first.storage = storage.for_node("n1").unwrap();
+        for val in 0..5 {
+            first
+                .handle_send(client(), "", &send("a", val))
+                .await
+                .unwrap();
+        }
+        let mut restarted = node(KafkaOffsets::Local);
+        restarted.storage = storage.for_node("n1").unwrap();
+        restarted.reopen_logs().unwrap();
+        restarted
+            .handle_send(client(), "", &send("a", 5))
+            .await
+            .unwrap();
+        let msgs = poll(&mut restarted, "a", 0).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            msgs,
+            (0..6)
+                .map(|val| (val, Value::from(val)))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_owners_spread_over_nodes() {
+        let mut node = node(KafkaOffsets::Owner);
+        node.node_ids = vec!["n1".to_string(), "n2".to_string(), "n3".to_string()];
+        let owners: std::collections::HashSet<&String> = (0..30)
+            .map(|i| node.owner_of(&format!("k{}", i)).unwrap())
+            .collect();
+        assert_eq!(owners.len(), 3);
+        assert_eq!(node.owner_of("k1"), node.owner_of("k1"));
+    }
+
+    #[tokio::test]
+    async fn test_poll_stops_at_missing_offset() {
+        let mut node = node(KafkaOffsets::LinKv);
+        for offset in [0, 1, 3] {
+            let msg = kafka::ReplicateRequestMsg::new("a".to_string(), offset, Value::from(offset));
+            node.handle_replicate(&msg).await.unwrap();
+        }
+        assert_eq!(poll(&mut node, "a", 0).await.len(), 2);
+        assert!(poll(&mut node, "a", 2).await.is_empty());
+        assert_eq!(poll(&mut node, "a", 3).await, vec![(3, Value::from(3))]);
+    }
+
+    #[tokio::test]
+    async fn test_committed_offsets_only_advance() {
+        let mut node = node(KafkaOffsets::Local);
+        for offset in [3, 1] {
+            let commit = kafka::CommitOffsetsRequestMsg {
+                msg_id: None,
+                offsets: HashMap::from([("a".to_string(), offset)]),
+                group: None,
+            };
+            node.handle_commit_offsets(client(), &commit).await.unwrap();
+        }
+        let list = kafka::ListCommittedOffsetsRequestMsg {
+            msg_id: None,
+            keys: vec!["a".to_string(), "b".to_string()],
+            group: None,
+        };
+        match node.handle_list_committed_offsets(client(), &list).await {
+            Ok(Some(kafka::KafkaMessageBody::ListCommittedOffsetsOk(body))) => {
+                assert_eq!(body.offsets, HashMap::from([("a".to_string(), 3)]));
+            }
+            other => panic!("Unexpected reply {:?}", other),
+        }
+    }
+
+    async fn list(node: &mut Kafka, group: Option<&str>) -> HashMap<String, u64> {
+        let list = kafka::ListCommittedOffsetsRequestMsg {
+            msg_id: None,
+            keys: vec!["a".to_string()],
+            group: group.map(String::from),
+        };
+        match node.handle_list_committed_offsets(client(), &list).await {
+            Ok(Some(kafka::KafkaMessageBody::ListCommittedOffsetsOk(body))) => body.offsets,
+            other => panic!("Unexpected reply {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_groups_commit_independently() {
+        let mut node = node(KafkaOffsets::Local);
+        for (group, offset) in [(None, 2), (Some("g1"), 7), (Some("g2"), 4)] {
+            let commit = kafka::CommitOffsetsRequestMsg {
+                msg_id: None,
+                offsets: HashMap::from([("a".to_string(), offset)]),
+                group: group.map(String::from),
+            };
+            node.handle_commit_offsets(client(), &commit).await.unwrap();
+        }
+        assert_eq!(list(&mut node, None).await["a"], 2);
+        assert_eq!(list(&mut node, Some("g1")).await["a"], 7);
+        assert_eq!(list(&mut node, Some("g2")).await["a"], 4);
+        assert!(list(&mut node, Some("g3")).await.is_empty());
+    }
+
+    /// Our lin-kv requests still waiting on a reply, oldest first
+    fn kv_waiting(node: &Kafka) -> Vec<(u64, KvWaiter)> {
+        let mut waiting: Vec<(u64, KvWaiter)> = node
+            .kv_waiting
+            .iter()
+            .map(|(msg_id, waiter)| (*msg_id, waiter.clone()))
+            .collect();
+        waiting.sort_by_key(|(msg_id, _)| *msg_id);
+        waiting
+    }
+
+    async fn kv_reply(node: &mut Kafka, msg_id: u64, mut body: Value) {
+        body["in_reply_to"] = Value::from(msg_id);
+        let reply = serde_json::json!({"src": "lin-kv", "dest": "n1", "body": body});
+        let reply = serde_json::from_value(reply).unwrap();
+        node.handle_kv_reply(reply).await.unwrap();
+    }
+
+    fn missing() -> Value {
+        serde_json::json!({"type": "error", "code": 20})
+    }
+
+    fn read_ok(value: Value) -> Value {
+        serde_json::json!({"type": "read_ok", "value": value})
+    }
+
+    #[tokio::test]
+    async fn test_groups_are_registered_before_their_first_commit() {
+        let mut node = node(KafkaOffsets::LinKv);
+        let commit = kafka::CommitOffsetsRequestMsg {
+            msg_id: None,
+            offsets: HashMap::from([("a".to_string(), 5)]),
+            group: Some("g1".to_string()),
+        };
+        node.handle_commit_offsets(client(), &commit).await.unwrap();
+        let waiting = kv_waiting(&node);
+        assert!(matches!(
+            waiting[..],
+            [(_, KvWaiter::Register { from: None, .. })]
+        ));
+        kv_reply(&mut node, waiting[0].0, missing()).await;
+        let waiting = kv_waiting(&node);
+        assert!(matches!(
+            &waiting[..],
+            [(_, KvWaiter::Register { from: Some(from), .. })] if *from == serde_json::json!([])
+        ));
+        kv_reply(
+            &mut node,
+            waiting[0].0,
+            serde_json::json!({"type": "cas_ok"}),
+        )
+        .await;
+        assert!(matches!(
+            kv_waiting(&node)[..],
+            [(_, KvWaiter::Commit { offset: 5, .. })]
+        ));
+        assert!(node.registered.contains(&Some("g1".to_string())));
+        // Once registered, a group commits straight away
+        let commit = kafka::CommitOffsetsRequestMsg {
+            offsets: HashMap::from([("b".to_string(), 1)]),
+            ..commit
+        };
+        node.handle_commit_offsets(client(), &commit).await.unwrap();
+        assert!(kv_waiting(&node)
+            .iter()
+            .all(|(_, waiter)| matches!(waiter, KvWaiter::Commit { .. })));
+    }
+
+    fn error(code: u64) -> Value {
+        serde_json::json!({"type": "error", "code": code})
+    }
+
+    #[tokio::test]
+    async fn test_lin_kv_commits_only_move_forward() {
+        let mut node = node(KafkaOffsets::LinKv);
+        node.registered.insert(None);
+        let commit = |offset| kafka::CommitOffsetsRequestMsg {
+            msg_id: None,
+            offsets: HashMap::from([("a".to_string(), offset)]),
+            group: None,
+        };
+        node.handle_commit_offsets(client(), &commit(5))
+            .await
+            .unwrap();
+        let waiting = kv_waiting(&node);
+        assert!(matches!(
+            &waiting[..],
+            [(
+                _,
+                KvWaiter::Commit {
+                    from: Some(Value::Null),
+                    ..
+                }
+            )]
+        ));
+        // A slower commit got there first, and further: leave it be
+        kv_reply(&mut node, waiting[0].0, error(22)).await;
+        let waiting = kv_waiting(&node);
+        assert!(matches!(
+            waiting[..],
+            [(_, KvWaiter::Commit { from: None, .. })]
+        ));
+        kv_reply(&mut node, waiting[0].0, read_ok(Value::from(8))).await;
+        assert!(kv_waiting(&node).is_empty());
+        assert_eq!(node.committed[&(None, "a".to_string())], 8);
+        // Other failures try the same cas again
+        node.handle_commit_offsets(client(), &commit(10))
+            .await
+            .unwrap();
+        let waiting = kv_waiting(&node);
+        kv_reply(&mut node, waiting[0].0, error(11)).await;
+        let retried = kv_waiting(&node);
+        assert!(matches!(
+            &retried[..],
+            [(_, KvWaiter::Commit { offset: 10, from: Some(from), .. })] if *from == 8
+        ));
+        kv_reply(
+            &mut node,
+            retried[0].0,
+            serde_json::json!({"type": "cas_ok"}),
+        )
+        .await;
+        assert!(kv_waiting(&node).is_empty());
+        assert_eq!(node.committed[&(None, "a".to_string())], 10);
+    }
+
+    #[tokio::test]
+    async fn test_lin_kv_offsets_are_read_only_when_taken() {
+        let mut node = node(KafkaOffsets::LinKv);
+        let raw = serde_json::to_string(&send("a", 1)).unwrap();
+        node.handle_send(client(), &raw, &send("a", 1))
+            .await
+            .unwrap();
+        let waiting = kv_waiting(&node);
+        // Not an answer about the offset: claim the same one again
+        kv_reply(&mut node, waiting[0].0, error(11)).await;
+        let retried = kv_waiting(&node);
+        assert!(matches!(
+            retried[..],
+            [(_, KvWaiter::Send { offset: 0, .. })]
+        ));
+        // Taken: find out where the counter is
+        kv_reply(&mut node, retried[0].0, error(22)).await;
+        let reading = kv_waiting(&node);
+        kv_reply(&mut node, reading[0].0, read_ok(Value::from(3))).await;
+        let claiming = kv_waiting(&node);
+        assert!(matches!(
+            claiming[..],
+            [(_, KvWaiter::Send { offset: 3, .. })]
+        ));
+        kv_reply(
+            &mut node,
+            claiming[0].0,
+            serde_json::json!({"type": "cas_ok"}),
+        )
+        .await;
+        assert_eq!(poll(&mut node, "a", 3).await, vec![(3, Value::from(1))]);
+    }
+
+    #[tokio::test]
+    async fn test_retention_waits_for_every_registered_group() {
+        let dir =
+            std::env::temp_dir().join(format!("maelstrom-kafka-groups-{}", std::process::id()));
+        let storage = LogStoreConfig::Segments(crate::storage::segment::SegmentConfig {
+            segment_bytes: 64,
+            retention_bytes: Some(0),
+            ..crate::storage::segment::SegmentConfig::new(dir.clone())
+        });
+        let mut node = node(KafkaOffsets::LinKv);
+        node.storage = storage.for_node("n1").unwrap();
+        for offset in 0..20 {
+            let msg = kafka::ReplicateRequestMsg::new("a".to_string(), offset, Value::from(offset));
+            node.handle_replicate(&msg).await.unwrap();
+        }
+        // This node has only seen g1 commit
+        node.committed
+            .insert((Some("g1".to_string()), "a".to_string()), 15);
+        let mut firsts = vec![];
+        for other in [missing(), read_ok(Value::from(15))] {
+            node.start_retention().unwrap();
+            let waiting = kv_waiting(&node);
+            assert!(matches!(
+                waiting[..],
+                [(_, KvWaiter::RetentionGroups { .. })]
+            ));
+            kv_reply(
+                &mut node,
+                waiting[0].0,
+                read_ok(serde_json::json!(["g1", null])),
+            )
+            .await;
+            let waiting = kv_waiting(&node);
+            assert_eq!(waiting.len(), 2);
+            kv_reply(&mut node, waiting[0].0, read_ok(Value::from(15))).await;
+            kv_reply(&mut node, waiting[1].0, other).await;
+            firsts.push(poll(&mut node, "a", 0).await[0].0);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+        // Nothing goes while the group without a name has not committed
+        assert_eq!(firsts[0], 0);
+        assert!(firsts[1] > 0 && firsts[1] <= 15);
+    }
+
+    #[test]
+    fn test_commit_keys_do_not_collide() {
+        assert_eq!(commit_key(None, "a"), "commit-a");
+        assert_ne!(commit_key(Some("a"), "b-c"), commit_key(Some("a-b"), "c"));
+        assert_ne!(commit_key(Some("a"), "b"), commit_key(None, "1:a-b"));
+    }
+
+    #[tokio::test]
+    async fn test_poll_replies_are_capped() {
+        let mut node = node(KafkaOffsets::Local);
+        for val in 0..(MAX_POLL_MESSAGES as u64 + 10) {
+            for key in ["a", "b"] {
+                node.handle_send(client(), "", &send(key, val))
+                    .await
+                    .unwrap();
+            }
+        }
+        assert_eq!(poll(&mut node, "a", 0).await.len(), MAX_POLL_MESSAGES);
+        let mut both = kafka::PollRequestMsg {
+            msg_id: Some(2),
+            offsets: HashMap::from([("a".to_string(), 0), ("b".to_string(), 5)]),
+            max_messages: Some(7),
+            wait_ms: None,
+        };
+        let msgs = node.read_logs(&both).unwrap();
+        assert_eq!(msgs["a"].len() + msgs["b"].len(), 7);
+        assert_eq!(msgs["b"][0].0, 5);
+        both.max_messages = None;
+        let msgs = node.read_logs(&both).unwrap();
+        assert_eq!(msgs["a"].len(), MAX_POLL_MESSAGES / 2);
+        assert_eq!(msgs["b"].len(), MAX_POLL_MESSAGES / 2);
+    }
+
+    #[tokio::test]
+    async fn test_long_poll_waits_for_messages() {
+        let mut node = node(KafkaOffsets::Local);
+        let long_poll = kafka::PollRequestMsg {
+            msg_id: Some(2),
+            offsets: HashMap::from([("a".to_string(), 0)]),
+            max_messages: None,
+            wait_ms: Some(60_000),
+        };
+        assert!(node
+            .handle_poll(client(), &long_poll)
+            .await
+            .unwrap()
+            .is_none());
+        // Messages for other keys (or beyond a gap) do not answer it
+        node.handle_send(client(), "", &send("b", 1)).await.unwrap();
+        let msg = kafka::ReplicateRequestMsg::new("a".to_string(), 1, Value::from(1));
+        node.handle_replicate(&msg).await.unwrap();
+        assert_eq!(node.polls.len(), 1);
+        let msg = kafka::ReplicateRequestMsg::new("a".to_string(), 0, Value::from(0));
+        node.handle_replicate(&msg).await.unwrap();
+        assert!(node.polls.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_long_poll_expires() {
+        let mut node = node(KafkaOffsets::Local);
+        let long_poll = kafka::PollRequestMsg {
+            msg_id: Some(2),
+            offsets: HashMap::from([("a".to_string(), 0)]),
+            max_messages: None,
+            wait_ms: Some(1),
+        };
+        node.handle_poll(client(), &long_poll).await.unwrap();
+        assert_eq!(node.polls.len(), 1);
+        std::thread::sleep(Duration::from_millis(2));
+        node.handle_tick().await.unwrap();
+        assert!(node.polls.is_empty());
+    }
+}
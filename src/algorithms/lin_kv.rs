@@ -0,0 +1,404 @@
+/// Linearizable key-value store: see maelstrom lin-kv docs
+/// https://github.com/jepsen-io/maelstrom/blob/main/doc/workloads.md#workload-lin-kv
+///
+/// A single node serves every read, write and cas from its own map, one request at
+/// a time, which is trivially linearizable. The map lives in `KvState`, apart from
+/// any messaging, so that replicated versions can apply the same operations.
+///
+/// With more than one node, the map is replicated with Raft (`consensus::raft`) or,
+/// if so configured, Multi-Paxos (`consensus::paxos`) or chain replication
+/// (`consensus::chain`). Keys may also be sharded over several such groups
+/// (`consensus::sharded`).
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::mpsc::Receiver;
+
+use crate::consensus::chain::{self, ChainReplication};
+use crate::consensus::sharded::{shard_groups, Sharded};
+use crate::consensus::snapshot::FileSnapshots;
+use crate::consensus::{paxos::MultiPaxos, raft::Raft, Consensus, ConsensusConfig, StateMachine};
+use crate::errors;
+use crate::node::{send_messages, Node};
+use crate::rpc::{self, lin_kv};
+use crate::workload::{Command, ConsensusKind};
+
+/// The key-value map itself
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct KvState {
+    // Keys may be any JSON value, so we keep them by their serialized form
+    values: HashMap<String, Value>,
+}
+
+impl KvState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn key_of(key: &Value) -> String {
+        key.to_string()
+    }
+
+    pub fn get(&self, key: &Value) -> Option<&Value> {
+        self.values.get(&Self::key_of(key))
+    }
+
+    /// Carry out a client request, returning the reply body (with `msg_id`)
+    /// or the error to send back instead. Anything other than a request is refused.
+    pub fn apply(
+        &mut self,
+        request: &lin_kv::LinKvMessageBody,
+        msg_id: u64,
+    ) -> Result<lin_kv::LinKvMessageBody, errors::ErrorMsg> {
+        match request {
+            lin_kv::LinKvMessageBody::Read(body) => match self.get(&body.key) {
+                Some(value) => Ok(lin_kv::LinKvMessageBody::ReadOk(
+                    lin_kv::ReadResponseMsg::new(body.msg_id, msg_id, value.clone()),
+                )),
+                None => Err(Self::missing(body.msg_id, &body.key)),
+            },
+            lin_kv::LinKvMessageBody::Write(body) => {
+                self.values
+                    .insert(Self::key_of(&body.key), body.value.clone());
+                Ok(lin_kv::LinKvMessageBody::WriteOk(
+                    lin_kv::WriteResponseMsg::new(body.msg_id, msg_id),
+                ))
+            }
+            lin_kv::LinKvMessageBody::Cas(body) => {
+                match self.values.get_mut(&Self::key_of(&body.key)) {
+                    Some(current) if *current == body.from => *current = body.to.clone(),
+                    Some(current) => {
+                        return Err(errors::ErrorMsg::new(
+                            body.msg_id,
+                            errors::ErrorType::PreconditionFailed,
+                            format!("expected {}, but had {}", body.from, current),
+                        ))
+                    }
+                    None if body.create_if_not_exists == Some(true) => {
+                        self.values.insert(Self::key_of(&body.key), body.to.clone());
+                    }
+                    None => return Err(Self::missing(body.msg_id, &body.key)),
+                }
+                Ok(lin_kv::LinKvMessageBody::CasOk(
+                    lin_kv::CasResponseMsg::new(body.msg_id, msg_id),
+                ))
+            }
+            other => Err(errors::ErrorMsg::new(
+                other.msg_id(),
+                errors::ErrorType::NotSupported,
+                "not a lin-kv request".to_string(),
+            )),
+        }
+    }
+
+    fn missing(in_reply_to: Option<u64>, key: &Value) -> errors::ErrorMsg {
+        errors::ErrorMsg::new(
+            in_reply_to,
+            errors::ErrorType::KeyDoesNotExist,
+            format!("key {} does not exist", key),
+        )
+    }
+}
+
+impl StateMachine for KvState {
+    type Request = lin_kv::LinKvMessageBody;
+    type Snapshot = KvState;
+
+    fn parse_request(body: &Value) -> Option<Self::Request> {
+        serde_json::from_value::<lin_kv::LinKvMessageBody>(body.clone())
+            .ok()
+            .filter(lin_kv::LinKvMessageBody::is_request)
+    }
+
+    fn apply(&mut self, request: &Self::Request, msg_id: u64) -> Value {
+        let reply = match KvState::apply(self, request, msg_id) {
+            Ok(body) => serde_json::to_value(body),
+            Err(error) => serde_json::to_value(error),
+        };
+        reply.unwrap_or_else(|err| {
+            eprintln!("{:?}", err);
+            Value::Null
+        })
+    }
+
+    fn is_read(request: &Self::Request) -> bool {
+        matches!(request, lin_kv::LinKvMessageBody::Read(_))
+    }
+
+    fn key(request: &Self::Request) -> Option<String> {
+        match request {
+            lin_kv::LinKvMessageBody::Read(body) => Some(Self::key_of(&body.key)),
+            lin_kv::LinKvMessageBody::Write(body) => Some(Self::key_of(&body.key)),
+            lin_kv::LinKvMessageBody::Cas(body) => Some(Self::key_of(&body.key)),
+            _ => None,
+        }
+    }
+
+    fn snapshot(&self) -> KvState {
+        self.clone()
+    }
+
+    fn restore(&mut self, snapshot: KvState) {
+        *self = snapshot;
+    }
+}
+
+pub struct LinKv {
+    state: KvState,
+    // Set up on init when there is more than one node
+    replicated: Option<Box<dyn Consensus>>,
+    config: ConsensusConfig,
+    last_msg_id: u64,
+    rx: Receiver<Command>,
+}
+
+impl LinKv {
+    pub fn with_config(mut self, config: ConsensusConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    fn start_raft(
+        &self,
+        node_id: &str,
+        node_ids: &[String],
+    ) -> Result<Raft<KvState>, errors::ErrorMsg> {
+        let mut raft = Raft::new(node_id.to_string(), node_ids.to_vec(), KvState::new())
+            .with_snapshot_entries(self.config.snapshot_entries)
+            .with_read_mode(self.config.read_mode);
+        if let Some(dir) = &self.config.snapshot_dir {
+            // A snapshot left there by an earlier run is where we start from
+            let path = dir.join(format!("{}.json", node_id));
+            raft = raft.with_snapshot_store(Box::new(FileSnapshots::new(path)));
+        }
+        Ok(raft)
+    }
+
+    /// The consensus instance replicating the map over `node_ids`. `shard` numbers the
+    /// group when keys are sharded, to keep each group's state in the services apart.
+    fn start_group(
+        &self,
+        node_id: &str,
+        node_ids: &[String],
+        shard: Option<usize>,
+    ) -> Result<Box<dyn Consensus>, errors::ErrorMsg> {
+        let (node_id, node_ids) = (node_id.to_string(), node_ids.to_vec());
+        Ok(match self.config.kind {
+            ConsensusKind::Raft => Box::new(self.start_raft(&node_id, &node_ids)?),
+            ConsensusKind::MultiPaxos => {
+                Box::new(MultiPaxos::new(node_id, node_ids, KvState::new()))
+            }
+            ConsensusKind::Chain | ConsensusKind::Craq => {
+                let mut node = ChainReplication::new(node_id, node_ids, KvState::new())
+                    .with_craq(self.config.kind == ConsensusKind::Craq);
+                if let Some(shard) = shard {
+                    node = node.with_config_key(format!("{}-{}", chain::CONFIG_KEY, shard));
+                }
+                Box::new(node)
+            }
+        })
+    }
+
+    fn next_msg_id(&mut self) -> u64 {
+        self.last_msg_id += 1;
+        self.last_msg_id
+    }
+}
+
+#[async_trait]
+impl Node for LinKv {
+    fn new(starting_msg_id: u64, rx: Receiver<Command>) -> Self {
+        Self {
+            state: KvState::new(),
+            replicated: None,
+            config: ConsensusConfig::default(),
+            last_msg_id: starting_msg_id,
+            rx,
+        }
+    }
+
+    async fn handle(&mut self, msg: String) -> Result<(), errors::ErrorMsg> {
+        if let Some(replicated) = self.replicated.as_mut() {
+            let msg = serde_json::from_str::<Value>(msg.as_str())
+                .map_err(errors::ErrorMsg::json_parse_error)?;
+            return send_messages(replicated.on_message(msg));
+        }
+        let msg_in = serde_json::from_str::<lin_kv::LinKvMessage>(msg.as_str())
+            .map_err(errors::ErrorMsg::json_parse_error)?;
+        if !msg_in.body.is_request() {
+            return Ok(());
+        }
+        let msg_id = self.next_msg_id();
+        // Errors here belong to the client's request: they go back to the client
+        let result = match self.state.apply(&msg_in.body, msg_id) {
+            Ok(body) => msg_in.reply(body).to_json()?,
+            Err(error) => msg_in.reply_error(error).to_json()?,
+        };
+        println!("{}", result);
+        Ok(())
+    }
+
+    async fn on_init(&mut self, msg: rpc::InitMsgIn) -> Result<(), errors::ErrorMsg> {
+        if msg.body.node_ids.len() > 1 {
+            let (node_id, node_ids) = (msg.body.node_id.clone(), msg.body.node_ids.clone());
+            self.replicated = Some(match self.config.group_size {
+                Some(group_size) => {
+                    let groups = shard_groups(&node_ids, group_size);
+                    let own = groups
+                        .iter()
+                        .position(|members| members.contains(&node_id))
+                        .unwrap_or(0);
+                    let group = self.start_group(&node_id, &groups[own], Some(own))?;
+                    Box::new(Sharded::<KvState, _>::new(node_id, groups, group))
+                }
+                None => self.start_group(&node_id, &node_ids, None)?,
+            });
+        }
+        let msg_out = msg.into_response(self.last_msg_id);
+        let result = serde_json::to_string(&msg_out).map_err(errors::ErrorMsg::json_dumps_error)?;
+        println!("{}", result);
+        Ok(())
+    }
+
+    async fn start(&mut self) -> Result<(), errors::ErrorMsg> {
+        while let Some(cmd) = self.rx.recv().await {
+            match cmd {
+                Command::Init(init_msg) => self.on_init(init_msg).await?,
+                Command::Msg(msg) => self.handle(msg).await?,
+                Command::Tick => {
+                    if let Some(replicated) = self.replicated.as_mut() {
+                        send_messages(replicated.on_tick())?
+                    }
+                }
+                Command::Shutdown => self.stop().await?,
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    async fn stop(&mut self) -> Result<(), errors::ErrorMsg> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::snapshot::SnapshotStore;
+    use crate::rpc::raft::Snapshot;
+
+    fn read(key: u64) -> lin_kv::LinKvMessageBody {
+        lin_kv::LinKvMessageBody::Read(lin_kv::ReadRequestMsg {
+            msg_id: Some(1),
+            key: Value::from(key),
+        })
+    }
+
+    fn write(key: u64, value: u64) -> lin_kv::LinKvMessageBody {
+        lin_kv::LinKvMessageBody::Write(lin_kv::WriteRequestMsg {
+            msg_id: Some(1),
+            key: Value::from(key),
+            value: Value::from(value),
+        })
+    }
+
+    fn cas(key: u64, from: u64, to: u64, create: Option<bool>) -> lin_kv::LinKvMessageBody {
+        lin_kv::LinKvMessageBody::Cas(lin_kv::CasRequestMsg {
+            msg_id: Some(1),
+            key: Value::from(key),
+            from: Value::from(from),
+            to: Value::from(to),
+            create_if_not_exists: create,
+        })
+    }
+
+    fn error_code(result: Result<lin_kv::LinKvMessageBody, errors::ErrorMsg>) -> errors::ErrorType {
+        match result {
+            Err(error) => error.code,
+            Ok(body) => panic!("Unexpected reply {:?}", body),
+        }
+    }
+
+    #[test]
+    fn test_read_and_write() {
+        let mut state = KvState::new();
+        assert_eq!(
+            error_code(state.apply(&read(1), 1)),
+            errors::ErrorType::KeyDoesNotExist
+        );
+        state.apply(&write(1, 5), 2).unwrap();
+        match state.apply(&read(1), 3) {
+            Ok(lin_kv::LinKvMessageBody::ReadOk(body)) => {
+                assert_eq!(body.value, Value::from(5));
+                assert_eq!(body.in_reply_to, Some(1));
+                assert_eq!(body.msg_id, 3);
+            }
+            other => panic!("Unexpected reply {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_cas() {
+        let mut state = KvState::new();
+        assert_eq!(
+            error_code(state.apply(&cas(1, 0, 1, None), 1)),
+            errors::ErrorType::KeyDoesNotExist
+        );
+        state.apply(&cas(1, 0, 1, Some(true)), 2).unwrap();
+        assert_eq!(state.get(&Value::from(1)), Some(&Value::from(1)));
+        assert_eq!(
+            error_code(state.apply(&cas(1, 0, 2, None), 3)),
+            errors::ErrorType::PreconditionFailed
+        );
+        state.apply(&cas(1, 1, 2, None), 4).unwrap();
+        assert_eq!(state.get(&Value::from(1)), Some(&Value::from(2)));
+    }
+
+    #[test]
+    fn test_restarted_node_starts_from_its_snapshot() {
+        let dir = std::env::temp_dir().join(format!("maelstrom-lin-kv-{}", std::process::id()));
+        let mut state = KvState::new();
+        state.apply(&write(1, 5), 1).unwrap();
+        let mut store = FileSnapshots::new(dir.join("n1.json"));
+        store
+            .save(&Snapshot {
+                last_included_index: 7,
+                last_included_term: 2,
+                node_ids: vec!["n1".to_string(), "n2".to_string()],
+                state,
+            })
+            .unwrap();
+        let (_tx, rx) = tokio::sync::mpsc::channel(1);
+        let node = LinKv::new(1, rx).with_config(ConsensusConfig {
+            snapshot_dir: Some(dir.clone()),
+            ..ConsensusConfig::default()
+        });
+        let node_ids = vec!["n1".to_string(), "n2".to_string()];
+        let raft = node.start_raft("n1", &node_ids).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(raft.commit_index(), 7);
+        match raft.state().clone().apply(&read(1), 2) {
+            Ok(lin_kv::LinKvMessageBody::ReadOk(body)) => assert_eq!(body.value, Value::from(5)),
+            other => panic!("Unexpected reply {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_error_replies_go_to_the_client() {
+        let msg = lin_kv::LinKvMessage {
+            src: "c1".to_string(),
+            dest: "n1".to_string(),
+            body: read(7),
+        };
+        let error = KvState::new().apply(&msg.body, 1).unwrap_err();
+        let reply: Value =
+            serde_json::from_str(&msg.reply_error(error).to_json().unwrap()).unwrap();
+        assert_eq!(reply["dest"], "c1");
+        assert_eq!(reply["body"]["type"], "error");
+        assert_eq!(reply["body"]["code"], 20);
+        assert_eq!(reply["body"]["in_reply_to"], 1);
+    }
+}
@@ -1,4 +1,8 @@
 pub mod broadcast;
 pub mod echo;
 pub mod gcounter;
+pub mod kafka;
+pub mod lin_kv;
+pub mod txn_list_append;
+pub mod txn_rw_register;
 pub mod unique_ids;
@@ -0,0 +1,742 @@
+/// Transactional append-only lists: see maelstrom txn-list-append docs
+/// https://github.com/jepsen-io/maelstrom/blob/main/doc/workloads.md#workload-txn-list-append
+///
+/// As in Maelstrom's Datomic tutorial, the nodes keep no database of their own. The
+/// database is a tree of immutable thunks in lww-kv: a map from each key to the id
+/// of the thunk holding its list. The id of the current map lives in lin-kv, under
+/// ROOT_KEY.
+///
+/// A node runs its transactions one at a time. It reads the root pointer, fetches the
+/// map and the lists the transaction touches, and carries the transaction out
+/// against them. A transaction which appended anything writes each changed list, and
+/// then a new map, as new thunks. It commits by a compare-and-set of the root pointer
+/// from the map it read to the new one. If another transaction got there first, the
+/// client gets `TxnConflict`. A read-only transaction commits as soon as it has
+/// read. Since every commit is a linearizable step on one lin-kv key, and every
+/// transaction reads exactly the state which that step replaced, transactions are
+/// strictly serializable.
+///
+/// Thunks are never changed once written, so we cache them; after each transaction
+/// we keep only the newest map we know of and the lists it points to. lww-kv may
+/// not yet have a thunk another node has written: we ask again on the next tick.
+///
+/// With `--txn-engine`, the node instead keeps its share of the keys itself, and
+/// hands every message to one of the sharded engines in `transactions`.
+use std::collections::{HashMap, VecDeque};
+
+use async_trait::async_trait;
+use serde_json::{Map, Value};
+use tokio::sync::mpsc::Receiver;
+
+use crate::errors;
+use crate::kv;
+use crate::node::{send_messages, Node, Process};
+use crate::rpc::{self, txn};
+use crate::transactions::{self, Shard, TxnConfig};
+use crate::workload::Command;
+
+/// lin-kv key holding the id of the current map thunk
+const ROOT_KEY: &str = "root";
+
+/// Keys may be any JSON value, so maps and lists are keyed by their serialized form
+fn key_of(key: &Value) -> String {
+    key.to_string()
+}
+
+/// Carry out `txn` against `lists`, returning it with each read filled in, and the
+/// new list for each key it appended to. Reads see the transaction's own appends.
+pub fn apply(
+    txn: &[txn::MicroOp],
+    lists: &HashMap<String, Vec<Value>>,
+) -> (Vec<txn::MicroOp>, HashMap<String, Vec<Value>>) {
+    let mut changed: HashMap<String, Vec<Value>> = HashMap::new();
+    let applied = txn
+        .iter()
+        .map(|op| match op {
+            txn::MicroOp::Append { key, element } => {
+                changed
+                    .entry(key_of(key))
+                    .or_insert_with(|| lists.get(&key_of(key)).cloned().unwrap_or_default())
+                    .push(element.clone());
+                op.clone()
+            }
+            // Writes belong to txn-rw-register: we turn them away before this
+            txn::MicroOp::Read { key, .. } | txn::MicroOp::Write { key, .. } => {
+                txn::MicroOp::Read {
+                    key: key.clone(),
+                    value: changed
+                        .get(&key_of(key))
+                        .or_else(|| lists.get(&key_of(key)))
+                        .map(|list| Value::from(list.clone())),
+                }
+            }
+        })
+        .collect();
+    (applied, changed)
+}
+
+/// Lists kept by the node itself, for the sharded transaction engines
+#[derive(Clone, Debug, Default)]
+pub struct ListState {
+    lists: HashMap<String, Vec<Value>>,
+}
+
+impl ListState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, key: &Value) -> Option<&Vec<Value>> {
+        self.lists.get(&key_of(key))
+    }
+}
+
+impl Shard for ListState {
+    fn supports(&self, op: &txn::MicroOp) -> bool {
+        !matches!(op, txn::MicroOp::Write { .. })
+    }
+
+    fn execute(&self, ops: &[txn::MicroOp]) -> Vec<txn::MicroOp> {
+        apply(ops, &self.lists).0
+    }
+
+    fn install(&mut self, ops: &[txn::MicroOp]) {
+        let (_, changed) = apply(ops, &self.lists);
+        self.lists.extend(changed);
+    }
+}
+
+/// Where a transaction has got to
+#[derive(Clone, Debug)]
+enum Phase {
+    // Reading the root pointer
+    Root,
+    // Fetching the thunks of the map at `root` (none if nothing has been committed)
+    Fetch {
+        root: Option<String>,
+    },
+    // Writing the new thunks: once they are all written, we point the root at `map`
+    Write {
+        root: Option<String>,
+        map: String,
+        remaining: usize,
+        txn: Vec<txn::MicroOp>,
+    },
+    // Swapping the root pointer over from `root` to the new map
+    Commit {
+        root: Option<String>,
+        map: String,
+        txn: Vec<txn::MicroOp>,
+    },
+}
+
+/// The transaction being carried out
+#[derive(Clone, Debug)]
+struct Running {
+    msg: txn::TxnMessage,
+    request: txn::TxnRequestMsg,
+    phase: Phase,
+}
+
+pub struct TxnListAppend {
+    node_id: String,
+    root_kv: kv::KV,
+    thunk_kv: kv::KV,
+    // Thunks we have read or written, by id
+    thunks: HashMap<String, Value>,
+    // Thunks lww-kv did not have when we asked
+    refetch: Vec<String>,
+    // lww-kv reads in flight, by msg_id
+    fetching: HashMap<u64, String>,
+    running: Option<Running>,
+    queue: VecDeque<(txn::TxnMessage, txn::TxnRequestMsg)>,
+    // Answers to clients, printed once we are done with each message
+    outbox: Vec<String>,
+    last_thunk_id: u64,
+    last_msg_id: u64,
+    config: TxnConfig,
+    // Set up on init when the config asks for one of the sharded engines
+    engine: Option<Box<dyn Process + Send>>,
+    rx: Receiver<Command>,
+}
+
+impl TxnListAppend {
+    pub fn with_config(mut self, config: TxnConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    fn next_msg_id(&mut self) -> u64 {
+        self.last_msg_id += 1;
+        self.last_msg_id
+    }
+
+    fn next_thunk_id(&mut self) -> String {
+        self.last_thunk_id += 1;
+        format!("{}-{}", self.node_id, self.last_thunk_id)
+    }
+
+    fn fetch(&mut self, id: String) -> Result<(), errors::ErrorMsg> {
+        if !self.fetching.values().any(|fetching| *fetching == id) {
+            let msg_id = self.thunk_kv.read(id.clone())?;
+            self.fetching.insert(msg_id, id);
+        }
+        Ok(())
+    }
+
+    /// The map thunk at `root`, if we have it
+    fn map_at(&self, root: &Option<String>) -> Option<Map<String, Value>> {
+        match root {
+            None => Some(Map::new()),
+            Some(root) => self
+                .thunks
+                .get(root)
+                .map(|map| map.as_object().cloned().unwrap_or_default()),
+        }
+    }
+
+    /// Forget every thunk but the map at `root` and the lists it points to
+    fn keep_only(&mut self, root: &Option<String>) {
+        let map = self.map_at(root).unwrap_or_default();
+        self.thunks.retain(|id, _| {
+            root.as_ref() == Some(id) || map.values().any(|list| list.as_str() == Some(id))
+        });
+    }
+
+    /// Start on the next transaction, if we are not busy with one
+    fn start_next(&mut self) -> Result<(), errors::ErrorMsg> {
+        if self.running.is_some() {
+            return Ok(());
+        }
+        if let Some((msg, request)) = self.queue.pop_front() {
+            self.root_kv.read(ROOT_KEY.to_string())?;
+            self.running = Some(Running {
+                msg,
+                request,
+                phase: Phase::Root,
+            });
+        }
+        Ok(())
+    }
+
+    /// Answer the running transaction's client, and move on to the next
+    fn finish(
+        &mut self,
+        result: Result<Vec<txn::MicroOp>, errors::ErrorMsg>,
+    ) -> Result<(), errors::ErrorMsg> {
+        if let Some(running) = self.running.take() {
+            let json = match result {
+                Ok(applied) => {
+                    let msg_id = self.next_msg_id();
+                    let body = txn::TxnResponseMsg::new(running.request.msg_id, msg_id, applied);
+                    running
+                        .msg
+                        .reply(txn::TxnMessageBody::TxnOk(body))
+                        .to_json()?
+                }
+                Err(error) => running.msg.reply_error(error).to_json()?,
+            };
+            self.outbox.push(json);
+        }
+        self.start_next()
+    }
+
+    fn send_outbox(&mut self) {
+        for json in self.outbox.drain(..) {
+            println!("{}", json);
+        }
+    }
+
+    fn handle_tick(&mut self) -> Result<(), errors::ErrorMsg> {
+        for id in std::mem::take(&mut self.refetch) {
+            self.fetch(id)?;
+        }
+        Ok(())
+    }
+
+    /// Fetch whatever the running transaction still needs, and carry it out once it
+    /// has everything
+    fn advance(&mut self) -> Result<(), errors::ErrorMsg> {
+        let (request, root) = match &self.running {
+            Some(Running {
+                request,
+                phase: Phase::Fetch { root },
+                ..
+            }) => (request.clone(), root.clone()),
+            _ => return Ok(()),
+        };
+        let map = match self.map_at(&root) {
+            Some(map) => map,
+            None => return self.fetch(root.unwrap_or_default()),
+        };
+        // The lists the transaction touches, by key and by the thunk holding them
+        let mut lists = HashMap::new();
+        let mut missing = vec![];
+        for op in request.txn.iter() {
+            let key = op.key();
+            if let Some(id) = map.get(&key_of(key)).and_then(Value::as_str) {
+                match self.thunks.get(id) {
+                    Some(list) => {
+                        let list = list.as_array().cloned().unwrap_or_default();
+                        lists.insert(key_of(key), list);
+                    }
+                    None => missing.push(id.to_string()),
+                }
+            }
+        }
+        if !missing.is_empty() {
+            for id in missing {
+                self.fetch(id)?;
+            }
+            return Ok(());
+        }
+        let (applied, changed) = apply(&request.txn, &lists);
+        if changed.is_empty() {
+            self.keep_only(&root);
+            return self.finish(Ok(applied));
+        }
+        let mut map = map;
+        let mut writes = vec![];
+        for (key, list) in changed {
+            let id = self.next_thunk_id();
+            map.insert(key, Value::from(id.clone()));
+            writes.push((id, Value::from(list)));
+        }
+        let map_id = self.next_thunk_id();
+        writes.push((map_id.clone(), Value::Object(map)));
+        let remaining = writes.len();
+        for (id, thunk) in writes {
+            self.thunk_kv.write(id.clone(), thunk.clone())?;
+            self.thunks.insert(id, thunk);
+        }
+        if let Some(running) = self.running.as_mut() {
+            running.phase = Phase::Write {
+                root,
+                map: map_id,
+                remaining,
+                txn: applied,
+            };
+        }
+        Ok(())
+    }
+
+    /// A reply from lin-kv, about the root pointer
+    fn handle_root_reply(&mut self, body: kv::KvResponseBody) -> Result<(), errors::ErrorMsg> {
+        let phase = match &self.running {
+            Some(running) => running.phase.clone(),
+            None => return Ok(()),
+        };
+        match (phase, body) {
+            (Phase::Root, kv::KvResponseBody::ReadOk(body)) => {
+                self.set_phase(Phase::Fetch {
+                    root: body.value.as_str().map(str::to_string),
+                });
+                self.advance()
+            }
+            (Phase::Root, kv::KvResponseBody::Error(body))
+                if body.code == errors::ErrorType::KeyDoesNotExist =>
+            {
+                self.set_phase(Phase::Fetch { root: None });
+                self.advance()
+            }
+            (Phase::Root, _) => {
+                self.root_kv.read(ROOT_KEY.to_string())?;
+                Ok(())
+            }
+            (Phase::Commit { map, txn, .. }, kv::KvResponseBody::CasOk(_)) => {
+                self.keep_only(&Some(map));
+                self.finish(Ok(txn))
+            }
+            (Phase::Commit { root, .. }, kv::KvResponseBody::Error(body)) => {
+                self.keep_only(&root);
+                let in_reply_to = self
+                    .running
+                    .as_ref()
+                    .and_then(|running| running.request.msg_id);
+                let error = match body.code {
+                    errors::ErrorType::PreconditionFailed | errors::ErrorType::KeyDoesNotExist => {
+                        errors::ErrorMsg::new(
+                            in_reply_to,
+                            errors::ErrorType::TxnConflict,
+                            "another transaction committed first".to_string(),
+                        )
+                    }
+                    // Anything else means the root was not changed, but not that it moved on
+                    _ => errors::ErrorMsg::new(
+                        in_reply_to,
+                        errors::ErrorType::TemporarilyUnavailable,
+                        body.text,
+                    ),
+                };
+                self.finish(Err(error))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// A reply from lww-kv, about a thunk
+    fn handle_thunk_reply(&mut self, reply: kv::KvMsgIn) -> Result<(), errors::ErrorMsg> {
+        let fetched = reply
+            .body
+            .in_reply_to()
+            .and_then(|msg_id| self.fetching.remove(&msg_id));
+        match (fetched, reply.body) {
+            (Some(id), kv::KvResponseBody::ReadOk(body)) => {
+                self.thunks.insert(id, body.value);
+                self.advance()
+            }
+            (Some(id), _) => {
+                self.refetch.push(id);
+                Ok(())
+            }
+            (None, kv::KvResponseBody::WriteOk(_)) => {
+                let commit = match self.running.as_mut().map(|running| &mut running.phase) {
+                    Some(Phase::Write {
+                        root,
+                        map,
+                        remaining,
+                        txn,
+                    }) => {
+                        *remaining -= 1;
+                        (*remaining == 0).then(|| (root.clone(), map.clone(), txn.clone()))
+                    }
+                    _ => None,
+                };
+                if let Some((root, map, txn)) = commit {
+                    let from = root.clone().map(Value::from).unwrap_or(Value::Null);
+                    self.root_kv.cas(
+                        ROOT_KEY.to_string(),
+                        from,
+                        Value::from(map.clone()),
+                        Some(true),
+                    )?;
+                    self.set_phase(Phase::Commit { root, map, txn });
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn set_phase(&mut self, phase: Phase) {
+        if let Some(running) = self.running.as_mut() {
+            running.phase = phase;
+        }
+    }
+
+    /// A message for the node itself, rather than for a sharded engine
+    fn handle_message(&mut self, msg: String) -> Result<(), errors::ErrorMsg> {
+        if let Some(reply) = kv::KvMsgIn::parse(msg.as_str())? {
+            return if reply.src == kv::KvType::LinKV.service_name() {
+                self.handle_root_reply(reply.body)
+            } else {
+                self.handle_thunk_reply(reply)
+            };
+        }
+        let msg_in = serde_json::from_str::<txn::TxnMessage>(msg.as_str())
+            .map_err(errors::ErrorMsg::json_parse_error)?;
+        let request = match &msg_in.body {
+            txn::TxnMessageBody::Txn(body) => body.clone(),
+            // We never ask clients anything, so there is nothing to do with these
+            _ => return Ok(()),
+        };
+        if request
+            .txn
+            .iter()
+            .any(|op| matches!(op, txn::MicroOp::Write { .. }))
+        {
+            let error = errors::ErrorMsg::new(
+                request.msg_id,
+                errors::ErrorType::NotSupported,
+                "lists can be read and appended to, not written".to_string(),
+            );
+            self.outbox.push(msg_in.reply_error(error).to_json()?);
+            return Ok(());
+        }
+        self.queue.push_back((msg_in, request));
+        self.start_next()
+    }
+}
+
+#[async_trait]
+impl Node for TxnListAppend {
+    fn new(starting_msg_id: u64, rx: Receiver<Command>) -> Self {
+        Self {
+            node_id: "n0".to_string(),
+            root_kv: kv::KV::new(kv::KvType::LinKV),
+            thunk_kv: kv::KV::new(kv::KvType::LwwKV),
+            thunks: HashMap::new(),
+            refetch: vec![],
+            fetching: HashMap::new(),
+            running: None,
+            queue: VecDeque::new(),
+            outbox: vec![],
+            last_thunk_id: 0,
+            last_msg_id: starting_msg_id,
+            config: TxnConfig::default(),
+            engine: None,
+            rx,
+        }
+    }
+
+    async fn handle(&mut self, msg: String) -> Result<(), errors::ErrorMsg> {
+        if let Some(engine) = self.engine.as_mut() {
+            let msg = serde_json::from_str::<Value>(msg.as_str())
+                .map_err(errors::ErrorMsg::json_parse_error)?;
+            return send_messages(engine.on_message(msg));
+        }
+        let result = self.handle_message(msg);
+        self.send_outbox();
+        result
+    }
+
+    async fn on_init(&mut self, msg: rpc::InitMsgIn) -> Result<(), errors::ErrorMsg> {
+        self.node_id = msg.body.node_id.clone();
+        self.root_kv.set_node_id(self.node_id.clone());
+        self.thunk_kv.set_node_id(self.node_id.clone());
+        self.engine = transactions::start_engine(
+            &self.config,
+            self.node_id.clone(),
+            msg.body.node_ids.clone(),
+            ListState::new(),
+        );
+        let msg_out = msg.into_response(self.last_msg_id);
+        let result = serde_json::to_string(&msg_out).map_err(errors::ErrorMsg::json_dumps_error)?;
+        println!("{}", result);
+        Ok(())
+    }
+
+    async fn start(&mut self) -> Result<(), errors::ErrorMsg> {
+        while let Some(cmd) = self.rx.recv().await {
+            match cmd {
+                Command::Init(init_msg) => self.on_init(init_msg).await?,
+                Command::Msg(msg) => self.handle(msg).await?,
+                Command::Tick => {
+                    if let Some(engine) = self.engine.as_mut() {
+                        send_messages(engine.on_tick())?
+                    }
+                    self.handle_tick()?;
+                }
+                Command::Shutdown => self.stop().await?,
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    async fn stop(&mut self) -> Result<(), errors::ErrorMsg> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    fn parse(txn: Value) -> Vec<txn::MicroOp> {
+        serde_json::from_value(txn).unwrap()
+    }
+
+    async fn node() -> TxnListAppend {
+        let (_tx, rx) = mpsc::channel(1);
+        let mut node = TxnListAppend::new(1, rx);
+        node.on_init(rpc::InitMsgIn {
+            src: "c0".to_string(),
+            dest: "n1".to_string(),
+            body: rpc::InitRequestMsg::new(1, "n1".to_string(), vec!["n1".to_string()]),
+        })
+        .await
+        .unwrap();
+        node
+    }
+
+    fn submit(node: &mut TxnListAppend, txn: Value) {
+        let msg =
+            json!({"src": "c1", "dest": "n1", "body": {"type": "txn", "msg_id": 1, "txn": txn}});
+        node.handle_message(msg.to_string()).unwrap();
+    }
+
+    /// A reply from `service`; for reads of thunks, to the read of `thunk`
+    fn reply(node: &mut TxnListAppend, service: &str, thunk: Option<&str>, mut body: Value) {
+        if let Some(thunk) = thunk {
+            let msg_id = node
+                .fetching
+                .iter()
+                .find(|(_, id)| *id == thunk)
+                .map(|(msg_id, _)| *msg_id)
+                .expect("thunk should be being fetched");
+            body["in_reply_to"] = json!(msg_id);
+        }
+        let msg = json!({"src": service, "dest": "n1", "body": body});
+        node.handle_message(msg.to_string()).unwrap();
+    }
+
+    /// The bodies of the answers sent to clients since we last looked
+    fn answers(node: &mut TxnListAppend) -> Vec<Value> {
+        node.outbox
+            .drain(..)
+            .map(|json| serde_json::from_str::<Value>(&json).unwrap()["body"].clone())
+            .collect()
+    }
+
+    fn phase(node: &TxnListAppend) -> Option<Phase> {
+        node.running.as_ref().map(|running| running.phase.clone())
+    }
+
+    /// Carry an appending transaction through from the root read to the cas
+    fn write_and_commit(node: &mut TxnListAppend, root: Value) {
+        reply(node, "lin-kv", None, root);
+        let remaining = match phase(node) {
+            Some(Phase::Write { remaining, .. }) => remaining,
+            other => panic!("Unexpected phase {:?}", other),
+        };
+        // The root moves only once every new thunk is written
+        for _ in 0..remaining {
+            assert!(matches!(phase(node), Some(Phase::Write { .. })));
+            reply(node, "lww-kv", None, json!({"type": "write_ok"}));
+        }
+        assert!(matches!(phase(node), Some(Phase::Commit { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_commits_by_swapping_the_root() {
+        let mut node = node().await;
+        submit(&mut node, json!([["append", 1, 3], ["r", 1, null]]));
+        assert!(matches!(phase(&node), Some(Phase::Root)));
+        write_and_commit(&mut node, json!({"type": "error", "code": 20}));
+        reply(&mut node, "lin-kv", None, json!({"type": "cas_ok"}));
+        let answered = answers(&mut node);
+        assert_eq!(answered.len(), 1);
+        assert_eq!(answered[0]["type"], "txn_ok");
+        assert_eq!(answered[0]["txn"], json!([["append", 1, 3], ["r", 1, [3]]]));
+        assert!(node.running.is_none());
+        // The next transaction finds the map we wrote in our cache
+        submit(&mut node, json!([["append", 2, 4], ["r", 1, null]]));
+        let root = node
+            .thunks
+            .iter()
+            .find(|(_, thunk)| thunk.is_object())
+            .map(|(id, _)| id.clone())
+            .unwrap();
+        write_and_commit(&mut node, json!({"type": "read_ok", "value": root}));
+        assert!(node.fetching.is_empty());
+        reply(&mut node, "lin-kv", None, json!({"type": "cas_ok"}));
+        assert_eq!(
+            answers(&mut node)[0]["txn"],
+            json!([["append", 2, 4], ["r", 1, [3]]])
+        );
+        // Only the new map and its two lists are left in the cache
+        assert_eq!(node.thunks.len(), 3);
+        assert!(!node.thunks.contains_key(&root));
+    }
+
+    #[tokio::test]
+    async fn test_conflicts_when_the_root_moved() {
+        let mut node = node().await;
+        for (code, expected) in [(22, 30), (11, 11)] {
+            submit(&mut node, json!([["append", 1, 3]]));
+            write_and_commit(&mut node, json!({"type": "error", "code": 20}));
+            reply(
+                &mut node,
+                "lin-kv",
+                None,
+                json!({"type": "error", "code": code}),
+            );
+            let answered = answers(&mut node);
+            assert_eq!(answered[0]["type"], "error");
+            assert_eq!(answered[0]["code"], expected);
+            // Nothing of the failed transaction is kept
+            assert!(node.thunks.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_refetches_thunks_lww_kv_did_not_have() {
+        let mut node = node().await;
+        submit(&mut node, json!([["r", 1, null], ["r", 2, null]]));
+        reply(
+            &mut node,
+            "lin-kv",
+            None,
+            json!({"type": "read_ok", "value": "n2-3"}),
+        );
+        // Another node's map, not in lww-kv yet: we ask again on the next tick
+        reply(
+            &mut node,
+            "lww-kv",
+            Some("n2-3"),
+            json!({"type": "error", "code": 20}),
+        );
+        assert!(node.fetching.is_empty());
+        assert_eq!(node.refetch, vec!["n2-3".to_string()]);
+        node.handle_tick().unwrap();
+        let map = json!({"type": "read_ok", "value": {"1": "n2-1", "3": "n2-2"}});
+        reply(&mut node, "lww-kv", Some("n2-3"), map);
+        // Only the lists the transaction touches are fetched
+        assert_eq!(node.fetching.values().collect::<Vec<_>>(), vec!["n2-1"]);
+        reply(
+            &mut node,
+            "lww-kv",
+            Some("n2-1"),
+            json!({"type": "read_ok", "value": [5, 6]}),
+        );
+        let answered = answers(&mut node);
+        assert_eq!(answered[0]["type"], "txn_ok");
+        assert_eq!(
+            answered[0]["txn"],
+            json!([["r", 1, [5, 6]], ["r", 2, null]])
+        );
+        assert_eq!(node.thunks.len(), 2);
+    }
+
+    #[test]
+    fn test_append_round_trips() {
+        let wire = json!([["append", 1, 3], ["r", 1, [1, 2, 3]]]);
+        let ops = parse(wire.clone());
+        assert_eq!(
+            ops[0],
+            txn::MicroOp::Append {
+                key: json!(1),
+                element: json!(3)
+            }
+        );
+        assert_eq!(serde_json::to_value(&ops).unwrap(), wire);
+    }
+
+    #[test]
+    fn test_reads_see_earlier_appends() {
+        let lists = HashMap::from([("1".to_string(), vec![json!(1)])]);
+        let txn = parse(json!([
+            ["r", 1, null],
+            ["r", 2, null],
+            ["append", 1, 2],
+            ["append", 2, 5],
+            ["r", 1, null],
+            ["r", 2, null]
+        ]));
+        let (applied, changed) = apply(&txn, &lists);
+        assert_eq!(
+            serde_json::to_value(applied).unwrap(),
+            json!([
+                ["r", 1, [1]],
+                ["r", 2, null],
+                ["append", 1, 2],
+                ["append", 2, 5],
+                ["r", 1, [1, 2]],
+                ["r", 2, [5]]
+            ])
+        );
+        assert_eq!(
+            changed,
+            HashMap::from([
+                ("1".to_string(), vec![json!(1), json!(2)]),
+                ("2".to_string(), vec![json!(5)])
+            ])
+        );
+        // The lists we read from are left as they were
+        assert_eq!(lists["1"], vec![json!(1)]);
+    }
+}
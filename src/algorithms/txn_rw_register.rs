@@ -0,0 +1,765 @@
+/// Transactional read/write registers: see maelstrom txn-rw-register docs
+/// https://github.com/jepsen-io/maelstrom/blob/main/doc/workloads.md#workload-txn-rw-register
+///
+/// Each node carries out each transaction in full before it looks at the next, so
+/// every transaction is atomic, and answers from its own registers: a lone node is
+/// (strictly) serializable. The registers live in `TxnState`, apart from any
+/// messaging, so that replicated versions can apply the same transactions.
+///
+/// With more than one node, how much the nodes coordinate depends on the isolation
+/// level: the node's own (`--txn-isolation`), or one a transaction asks for.
+///
+/// At read committed we stay totally available: each node answers at once and then
+/// sends the final value of each key a transaction wrote to every peer, in one
+/// `replicate` per peer which is retried until it is acknowledged. Every
+/// transaction is stamped with a Lamport timestamp, above any its node has seen,
+/// and each register keeps the write with the latest timestamp (last writer wins).
+/// So every node orders each key's writes the same way (no dirty writes, G0); only
+/// committed, final values ever leave a node, all at once (no aborted or
+/// intermediate reads, G1a and G1b); and since a node has seen any write it reads,
+/// both reads and overwrites follow timestamp order (no G1c). Read uncommitted runs
+/// the same way, as there are never uncommitted writes for anyone to read.
+///
+/// Snapshot isolation and serializable give up availability for a certifier: the
+/// first node. A transaction runs at its own node against a snapshot, buffering its
+/// writes, and the certifier commits it only if the versions it saw are still the
+/// latest: those of the keys it wrote for snapshot isolation (the first committer
+/// wins), and of every key it touched for serializable (optimistic concurrency
+/// control, in the certifier's order). Otherwise the client gets `TxnConflict`.
+/// Certified commits are numbered, and each node keeps several versions of a key
+/// so that its snapshot can be every certified commit up to the first one it is
+/// still missing, however the replicates arrive. Levels may be mixed, but each only
+/// holds among transactions at it or stronger: writes made at read committed are in
+/// every snapshot as soon as they arrive.
+///
+/// With `--txn-engine`, the node instead keeps its share of the keys itself, and
+/// hands every message to one of the sharded engines in `transactions`.
+use std::collections::{BTreeSet, HashMap};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::mpsc::Receiver;
+
+use crate::errors;
+use crate::node::{send_messages, Node, Process, Retrier};
+use crate::rpc::{self, txn};
+use crate::transactions::{self, Shard, TxnConfig};
+use crate::workload::{Command, Isolation};
+
+/// Re-send replicated writes which are not acknowledged within this many ticks
+const REPLICATE_RESEND_TICKS: u64 = 3;
+
+/// One write to a register: its value, the timestamp of the transaction which wrote
+/// it, and the transaction's place in the certifier's order if it was certified
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Version {
+    value: Value,
+    timestamp: txn::Timestamp,
+    seq: Option<u64>,
+}
+
+/// The registers themselves
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TxnState {
+    // Keys may be any JSON value, so we keep them by their serialized form. Each
+    // keeps its versions oldest first, back to the newest in the current snapshot.
+    versions: HashMap<String, Vec<Version>>,
+    // The highest timestamp counter seen
+    clock: u64,
+    // Every certified commit up to this one is here
+    stable: u64,
+    // Certified commits here which came ahead of one still missing
+    early: BTreeSet<u64>,
+}
+
+/// A transaction carried out against a snapshot, with its writes not yet installed
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Execution {
+    /// The transaction with every read's value filled in
+    pub txn: Vec<txn::MicroOp>,
+    /// The final value written to each key
+    pub writes: Vec<(Value, Value)>,
+    /// The version of each key the transaction touched, as it first found it
+    pub seen: Vec<(Value, Option<txn::Timestamp>)>,
+}
+
+impl Execution {
+    /// The versions which must still be the latest for the transaction to commit
+    pub fn checks(&self, isolation: Isolation) -> Vec<(Value, Option<txn::Timestamp>)> {
+        self.seen
+            .iter()
+            .filter(|(key, _)| match isolation {
+                Isolation::Serializable => true,
+                Isolation::SnapshotIsolation => {
+                    self.writes.iter().any(|(written, _)| written == key)
+                }
+                Isolation::ReadUncommitted | Isolation::ReadCommitted => false,
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+impl TxnState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn key_of(key: &Value) -> String {
+        key.to_string()
+    }
+
+    /// The newest version of `key` in `snapshot`, or the newest of all with no snapshot
+    fn version(&self, key: &Value, snapshot: Option<u64>) -> Option<&Version> {
+        self.versions
+            .get(&Self::key_of(key))?
+            .iter()
+            .rev()
+            .find(|version| match (version.seq, snapshot) {
+                (Some(seq), Some(snapshot)) => seq <= snapshot,
+                _ => true,
+            })
+    }
+
+    pub fn get(&self, key: &Value) -> Option<&Value> {
+        self.version(key, None).map(|version| &version.value)
+    }
+
+    /// The latest consistent snapshot: every certified commit before the first missing
+    pub fn snapshot(&self) -> u64 {
+        self.stable
+    }
+
+    /// A timestamp later than any seen here, for a new transaction at `node_id`
+    pub fn next_timestamp(&mut self, node_id: &str) -> txn::Timestamp {
+        self.clock += 1;
+        txn::Timestamp::new(self.clock, node_id.to_string())
+    }
+
+    /// Add a version of `key`, replacing any other write by the same transaction
+    fn set(&mut self, key: &Value, value: Value, timestamp: &txn::Timestamp, seq: Option<u64>) {
+        self.clock = self.clock.max(timestamp.counter);
+        let versions = self.versions.entry(Self::key_of(key)).or_default();
+        match versions.binary_search_by(|version| version.timestamp.cmp(timestamp)) {
+            Ok(index) => versions[index].value = value,
+            Err(index) => versions.insert(
+                index,
+                Version {
+                    value,
+                    timestamp: timestamp.clone(),
+                    seq,
+                },
+            ),
+        }
+        // No snapshot from now on can read past the newest version in this one
+        let stable = self.stable;
+        if let Some(oldest) = versions
+            .iter()
+            .rposition(|version| version.seq.is_none_or(|seq| seq <= stable))
+        {
+            versions.drain(..oldest);
+        }
+    }
+
+    /// Carry out `txn` against `snapshot` (or the newest versions, with none) without
+    /// installing its writes. Reads see the transaction's own writes.
+    pub fn execute(&self, txn: &[txn::MicroOp], snapshot: Option<u64>) -> Execution {
+        let mut execution = Execution::default();
+        for (index, op) in txn.iter().enumerate() {
+            let key = op.key();
+            if !execution.seen.iter().any(|(seen, _)| seen == key) {
+                let version = self.version(key, snapshot);
+                execution.seen.push((
+                    key.clone(),
+                    version.map(|version| version.timestamp.clone()),
+                ));
+            }
+            execution.txn.push(match op {
+                txn::MicroOp::Read { .. } => txn::MicroOp::Read {
+                    key: key.clone(),
+                    value: match final_writes(&txn[..index])
+                        .into_iter()
+                        .find(|(written, _)| written == key)
+                    {
+                        Some((_, value)) => Some(value),
+                        None => self
+                            .version(key, snapshot)
+                            .map(|version| version.value.clone()),
+                    },
+                },
+                // Appends belong to txn-list-append: we turn them away before this
+                txn::MicroOp::Write { .. } | txn::MicroOp::Append { .. } => op.clone(),
+            });
+        }
+        execution.writes = final_writes(txn);
+        execution
+    }
+
+    /// Carry out every micro-op of `txn` in order, writing at `timestamp`, and return
+    /// it with each read's value filled in. Reads see the transaction's own writes.
+    pub fn apply(&mut self, txn: &[txn::MicroOp], timestamp: &txn::Timestamp) -> Vec<txn::MicroOp> {
+        let execution = self.execute(txn, None);
+        self.apply_writes(&execution.writes, timestamp);
+        execution.txn
+    }
+
+    /// Install another node's writes, all from one transaction at `timestamp`
+    pub fn apply_writes(&mut self, writes: &[(Value, Value)], timestamp: &txn::Timestamp) {
+        for (key, value) in writes.iter() {
+            self.set(key, value.clone(), timestamp, None);
+        }
+    }
+
+    /// Install the writes of certified commit `seq`, unless they are here already
+    pub fn apply_certified(
+        &mut self,
+        writes: &[(Value, Value)],
+        timestamp: &txn::Timestamp,
+        seq: u64,
+    ) {
+        if seq <= self.stable || self.early.contains(&seq) {
+            return;
+        }
+        self.early.insert(seq);
+        while self.early.remove(&(self.stable + 1)) {
+            self.stable += 1;
+        }
+        for (key, value) in writes.iter() {
+            self.set(key, value.clone(), timestamp, Some(seq));
+        }
+    }
+
+    /// True if each key in `checks` still has the version it names as its newest
+    pub fn validate(&self, checks: &[(Value, Option<txn::Timestamp>)]) -> bool {
+        checks.iter().all(|(key, seen)| {
+            self.version(key, None).map(|version| &version.timestamp) == seen.as_ref()
+        })
+    }
+}
+
+impl Shard for TxnState {
+    fn supports(&self, op: &txn::MicroOp) -> bool {
+        !matches!(op, txn::MicroOp::Append { .. })
+    }
+
+    fn execute(&self, ops: &[txn::MicroOp]) -> Vec<txn::MicroOp> {
+        self.execute(ops, None).txn
+    }
+
+    fn install(&mut self, ops: &[txn::MicroOp]) {
+        // Only the key's owner writes it, so its own clock orders the writes
+        let timestamp = self.next_timestamp("");
+        self.apply_writes(&final_writes(ops), &timestamp);
+    }
+}
+
+/// The last value `txn` wrote to each key it wrote, in the order the keys were first
+/// written
+pub fn final_writes(txn: &[txn::MicroOp]) -> Vec<(Value, Value)> {
+    let mut writes: Vec<(Value, Value)> = vec![];
+    for op in txn.iter() {
+        if let txn::MicroOp::Write { key, value } = op {
+            match writes.iter_mut().find(|(written, _)| written == key) {
+                Some(write) => write.1 = value.clone(),
+                None => writes.push((key.clone(), value.clone())),
+            }
+        }
+    }
+    writes
+}
+
+pub struct TxnRwRegister {
+    node_id: String,
+    node_ids: Vec<String>,
+    isolation: Isolation,
+    state: TxnState,
+    peers: Retrier,
+    // Transactions waiting on the certifier, by the msg_id of their certify request
+    certifying: HashMap<u64, (txn::TxnMessage, Vec<txn::MicroOp>)>,
+    // The certifier's decisions, by node and msg_id, so that resent requests get the same
+    decided: HashMap<(String, u64), bool>,
+    // By node, the msg_id below which it has the answer to every certify it sent:
+    // those decisions are dropped, and requests resent from before then ignored
+    answered_below: HashMap<String, u64>,
+    last_msg_id: u64,
+    config: TxnConfig,
+    // Set up on init when the config asks for one of the sharded engines
+    engine: Option<Box<dyn Process + Send>>,
+    rx: Receiver<Command>,
+}
+
+impl TxnRwRegister {
+    pub fn with_isolation(mut self, isolation: Isolation) -> Self {
+        self.isolation = isolation;
+        self
+    }
+
+    pub fn with_config(mut self, config: TxnConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    fn next_msg_id(&mut self) -> u64 {
+        self.last_msg_id += 1;
+        self.last_msg_id
+    }
+
+    fn certifier(&self) -> &str {
+        self.node_ids.first().unwrap_or(&self.node_id)
+    }
+
+    /// Send a committed transaction's writes to every other node
+    fn replicate(
+        &mut self,
+        timestamp: &txn::Timestamp,
+        writes: &[(Value, Value)],
+        seq: Option<u64>,
+    ) -> Result<(), errors::ErrorMsg> {
+        if writes.is_empty() {
+            return Ok(());
+        }
+        for peer in self.node_ids.clone().iter() {
+            if *peer != self.node_id {
+                let body = txn::TxnMessageBody::Replicate(txn::ReplicateRequestMsg::new(
+                    timestamp.clone(),
+                    writes.to_vec(),
+                    seq,
+                ));
+                self.peers.send(peer, &body)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Commit at once, and tell the other nodes afterwards
+    fn commit_available(
+        &mut self,
+        msg: &txn::TxnRequestMsg,
+    ) -> Result<Vec<txn::MicroOp>, errors::ErrorMsg> {
+        let timestamp = self.state.next_timestamp(&self.node_id);
+        let applied = self.state.apply(&msg.txn, &timestamp);
+        self.replicate(&timestamp, &final_writes(&msg.txn), None)?;
+        Ok(applied)
+    }
+
+    /// As the certifier: commit `writes` if every version in `checks` is still the latest
+    fn decide(
+        &mut self,
+        checks: &[(Value, Option<txn::Timestamp>)],
+        writes: &[(Value, Value)],
+    ) -> Result<bool, errors::ErrorMsg> {
+        if !self.state.validate(checks) {
+            return Ok(false);
+        }
+        if !writes.is_empty() {
+            // Only the certifier numbers commits, so it is never missing one
+            let seq = self.state.snapshot() + 1;
+            let timestamp = self.state.next_timestamp(&self.node_id);
+            self.state.apply_certified(writes, &timestamp, seq);
+            self.replicate(&timestamp, writes, Some(seq))?;
+        }
+        Ok(true)
+    }
+
+    /// Run a transaction against this node's snapshot and have the certifier commit it.
+    /// Returns the transaction if it is already decided, and otherwise waits.
+    fn certify(
+        &mut self,
+        msg_in: &txn::TxnMessage,
+        msg: &txn::TxnRequestMsg,
+        isolation: Isolation,
+    ) -> Result<Option<(Vec<txn::MicroOp>, bool)>, errors::ErrorMsg> {
+        let execution = self.state.execute(&msg.txn, Some(self.state.snapshot()));
+        let checks = execution.checks(isolation);
+        if checks.is_empty() && execution.writes.is_empty() {
+            return Ok(Some((execution.txn, true)));
+        }
+        if self.certifier() == self.node_id {
+            let committed = self.decide(&checks, &execution.writes)?;
+            return Ok(Some((execution.txn, committed)));
+        }
+        let certifier = self.certifier().to_string();
+        let waiting_since = self.certifying.keys().min().copied();
+        let body = txn::TxnMessageBody::Certify(txn::CertifyRequestMsg::new(
+            checks,
+            execution.writes,
+            waiting_since,
+        ));
+        let msg_id = self.peers.send(&certifier, &body)?;
+        self.certifying
+            .insert(msg_id, (msg_in.clone(), execution.txn));
+        Ok(None)
+    }
+
+    /// As the certifier: decide a certify request from `src`, giving a resent request
+    /// the same answer. Returns None for one resent after `src` had its answer.
+    fn handle_certify(
+        &mut self,
+        src: &str,
+        body: &txn::CertifyRequestMsg,
+    ) -> Result<Option<bool>, errors::ErrorMsg> {
+        let msg_id = body.msg_id.unwrap_or_default();
+        let answered_below = self.answered_below.entry(src.to_string()).or_insert(0);
+        let waiting_since = body.waiting_since.unwrap_or(msg_id);
+        if waiting_since > *answered_below {
+            *answered_below = waiting_since;
+            self.decided
+                .retain(|(node, id), _| node != src || *id >= waiting_since);
+        } else if msg_id < *answered_below {
+            return Ok(None);
+        }
+        let request = (src.to_string(), msg_id);
+        let committed = match self.decided.get(&request) {
+            Some(committed) => *committed,
+            None => {
+                let committed = self.decide(&body.checks, &body.writes)?;
+                self.decided.insert(request, committed);
+                committed
+            }
+        };
+        Ok(Some(committed))
+    }
+
+    /// Answer the client: with the transaction if it committed, or a conflict if not
+    fn finish(
+        &mut self,
+        msg_in: &txn::TxnMessage,
+        txn: Vec<txn::MicroOp>,
+        committed: bool,
+    ) -> Result<(), errors::ErrorMsg> {
+        let in_reply_to = msg_in.body.msg_id();
+        let result = if committed {
+            let msg_id = self.next_msg_id();
+            let body = txn::TxnResponseMsg::new(in_reply_to, msg_id, txn);
+            msg_in.reply(txn::TxnMessageBody::TxnOk(body)).to_json()?
+        } else {
+            let error = errors::ErrorMsg::new(
+                in_reply_to,
+                errors::ErrorType::TxnConflict,
+                "transaction conflicts with one committed since its snapshot".to_string(),
+            );
+            msg_in.reply_error(error).to_json()?
+        };
+        println!("{}", result);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Node for TxnRwRegister {
+    fn new(starting_msg_id: u64, rx: Receiver<Command>) -> Self {
+        Self {
+            node_id: "n0".to_string(),
+            node_ids: vec![],
+            isolation: Isolation::default(),
+            state: TxnState::new(),
+            peers: Retrier::new(REPLICATE_RESEND_TICKS),
+            certifying: HashMap::new(),
+            decided: HashMap::new(),
+            answered_below: HashMap::new(),
+            last_msg_id: starting_msg_id,
+            config: TxnConfig::default(),
+            engine: None,
+            rx,
+        }
+    }
+
+    async fn handle(&mut self, msg: String) -> Result<(), errors::ErrorMsg> {
+        if let Some(engine) = self.engine.as_mut() {
+            let msg = serde_json::from_str::<Value>(msg.as_str())
+                .map_err(errors::ErrorMsg::json_parse_error)?;
+            return send_messages(engine.on_message(msg));
+        }
+        let msg_in = serde_json::from_str::<txn::TxnMessage>(msg.as_str())
+            .map_err(errors::ErrorMsg::json_parse_error)?;
+        let reply = match &msg_in.body {
+            txn::TxnMessageBody::Txn(body)
+                if body
+                    .txn
+                    .iter()
+                    .any(|op| matches!(op, txn::MicroOp::Append { .. })) =>
+            {
+                let error = errors::ErrorMsg::new(
+                    body.msg_id,
+                    errors::ErrorType::NotSupported,
+                    "registers can be read and written, not appended to".to_string(),
+                );
+                println!("{}", msg_in.reply_error(error).to_json()?);
+                None
+            }
+            txn::TxnMessageBody::Txn(body) => {
+                let decided = match body.isolation.unwrap_or(self.isolation) {
+                    Isolation::ReadUncommitted | Isolation::ReadCommitted => {
+                        Some((self.commit_available(body)?, true))
+                    }
+                    isolation => self.certify(&msg_in, body, isolation)?,
+                };
+                if let Some((txn, committed)) = decided {
+                    self.finish(&msg_in, txn, committed)?;
+                }
+                None
+            }
+            txn::TxnMessageBody::Replicate(body) => {
+                // Duplicates are harmless: the same timestamp writes the same values
+                match body.seq {
+                    Some(seq) => self
+                        .state
+                        .apply_certified(&body.writes, &body.timestamp, seq),
+                    None => self.state.apply_writes(&body.writes, &body.timestamp),
+                }
+                Some(txn::TxnMessageBody::ReplicateOk(
+                    txn::ReplicateResponseMsg::new(body.msg_id),
+                ))
+            }
+            txn::TxnMessageBody::ReplicateOk(body) => {
+                if let Some(in_reply_to) = body.in_reply_to {
+                    self.peers.ack(in_reply_to);
+                }
+                None
+            }
+            txn::TxnMessageBody::Certify(body) => {
+                self.handle_certify(&msg_in.src, body)?.map(|committed| {
+                    txn::TxnMessageBody::CertifyOk(txn::CertifyResponseMsg::new(
+                        body.msg_id,
+                        committed,
+                    ))
+                })
+            }
+            txn::TxnMessageBody::CertifyOk(body) => {
+                let in_reply_to = body.in_reply_to.unwrap_or_default();
+                self.peers.ack(in_reply_to);
+                if let Some((client_msg, txn)) = self.certifying.remove(&in_reply_to) {
+                    self.finish(&client_msg, txn, body.committed)?;
+                }
+                None
+            }
+            // We never ask clients anything, so there is nothing to do with these
+            _ => None,
+        };
+        if let Some(body) = reply {
+            println!("{}", msg_in.reply(body).to_json()?);
+        }
+        Ok(())
+    }
+
+    async fn on_init(&mut self, msg: rpc::InitMsgIn) -> Result<(), errors::ErrorMsg> {
+        self.node_id = msg.body.node_id.clone();
+        self.node_ids = msg.body.node_ids.clone();
+        self.peers.set_node_id(self.node_id.clone());
+        self.engine = transactions::start_engine(
+            &self.config,
+            self.node_id.clone(),
+            self.node_ids.clone(),
+            TxnState::new(),
+        );
+        let msg_out = msg.into_response(self.last_msg_id);
+        let result = serde_json::to_string(&msg_out).map_err(errors::ErrorMsg::json_dumps_error)?;
+        println!("{}", result);
+        Ok(())
+    }
+
+    async fn start(&mut self) -> Result<(), errors::ErrorMsg> {
+        while let Some(cmd) = self.rx.recv().await {
+            match cmd {
+                Command::Init(init_msg) => self.on_init(init_msg).await?,
+                Command::Msg(msg) => self.handle(msg).await?,
+                Command::Tick => match self.engine.as_mut() {
+                    Some(engine) => send_messages(engine.on_tick())?,
+                    None => self.peers.tick()?,
+                },
+                Command::Shutdown => self.stop().await?,
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    async fn stop(&mut self) -> Result<(), errors::ErrorMsg> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn parse(txn: Value) -> Vec<txn::MicroOp> {
+        serde_json::from_value(txn).unwrap()
+    }
+
+    #[test]
+    fn test_micro_ops_round_trip() {
+        let wire = json!([["r", 1, null], ["w", 1, 6], ["r", 2, 3]]);
+        let ops = parse(wire.clone());
+        assert_eq!(
+            ops[0],
+            txn::MicroOp::Read {
+                key: json!(1),
+                value: None
+            }
+        );
+        assert_eq!(serde_json::to_value(&ops).unwrap(), wire);
+        assert!(serde_json::from_value::<Vec<txn::MicroOp>>(json!([["x", 1, 2]])).is_err());
+    }
+
+    #[test]
+    fn test_reads_see_earlier_writes() {
+        let mut state = TxnState::new();
+        let txn = parse(json!([
+            ["r", 1, null],
+            ["w", 1, 5],
+            ["w", 1, 6],
+            ["r", 1, null]
+        ]));
+        let timestamp = state.next_timestamp("n1");
+        assert_eq!(
+            serde_json::to_value(state.apply(&txn, &timestamp)).unwrap(),
+            json!([["r", 1, null], ["w", 1, 5], ["w", 1, 6], ["r", 1, 6]])
+        );
+        let txn = parse(json!([["w", 2, 7], ["r", 1, null], ["r", 2, null]]));
+        let timestamp = state.next_timestamp("n1");
+        let applied = state.apply(&txn, &timestamp);
+        assert_eq!(
+            serde_json::to_value(applied).unwrap(),
+            json!([["w", 2, 7], ["r", 1, 6], ["r", 2, 7]])
+        );
+    }
+
+    #[test]
+    fn test_only_final_writes_are_replicated() {
+        let txn = parse(json!([
+            ["w", 1, 5],
+            ["w", 2, 1],
+            ["r", 1, null],
+            ["w", 1, 6]
+        ]));
+        assert_eq!(
+            final_writes(&txn),
+            vec![(json!(1), json!(6)), (json!(2), json!(1))]
+        );
+        assert!(final_writes(&parse(json!([["r", 1, null]]))).is_empty());
+    }
+
+    #[test]
+    fn test_last_writer_wins_on_every_node() {
+        let early = txn::Timestamp::new(1, "n2".to_string());
+        let late = txn::Timestamp::new(1, "n3".to_string());
+        let (mut a, mut b) = (TxnState::new(), TxnState::new());
+        a.apply_writes(&[(json!(1), json!(2))], &early);
+        a.apply_writes(&[(json!(1), json!(3))], &late);
+        // The same writes, the other way round
+        b.apply_writes(&[(json!(1), json!(3))], &late);
+        b.apply_writes(&[(json!(1), json!(2))], &early);
+        assert_eq!(a, b);
+        assert_eq!(a.get(&json!(1)), Some(&json!(3)));
+        // A new transaction here is stamped after everything it has seen
+        assert!(a.next_timestamp("n1") > late);
+    }
+
+    #[test]
+    fn test_snapshot_waits_for_missing_commits() {
+        let mut state = TxnState::new();
+        let stamp = |counter| txn::Timestamp::new(counter, "n0".to_string());
+        state.apply_certified(&[(json!(1), json!(1))], &stamp(1), 1);
+        // Commit 3 arrives before commit 2, which it may depend on
+        state.apply_certified(&[(json!(1), json!(3)), (json!(2), json!(3))], &stamp(3), 3);
+        let txn = parse(json!([["r", 1, null], ["r", 2, null]]));
+        assert_eq!(state.snapshot(), 1);
+        assert_eq!(
+            serde_json::to_value(state.execute(&txn, Some(state.snapshot())).txn).unwrap(),
+            json!([["r", 1, 1], ["r", 2, null]])
+        );
+        state.apply_certified(&[(json!(2), json!(2))], &stamp(2), 2);
+        // Resent commits change nothing
+        state.apply_certified(&[(json!(2), json!(2))], &stamp(2), 2);
+        assert_eq!(state.snapshot(), 3);
+        assert_eq!(
+            serde_json::to_value(state.execute(&txn, Some(state.snapshot())).txn).unwrap(),
+            json!([["r", 1, 3], ["r", 2, 3]])
+        );
+    }
+
+    #[test]
+    fn test_first_committer_wins() {
+        let mut state = TxnState::new();
+        let txn = parse(json!([["r", 1, null], ["w", 1, 2]]));
+        let first = state.execute(&txn, Some(state.snapshot()));
+        let second = state.execute(&txn, Some(state.snapshot()));
+        let isolation = Isolation::SnapshotIsolation;
+        assert!(state.validate(&first.checks(isolation)));
+        let timestamp = state.next_timestamp("n0");
+        state.apply_certified(&first.writes, &timestamp, 1);
+        assert!(!state.validate(&second.checks(isolation)));
+        // Read only transactions have nothing to check at snapshot isolation
+        let read = state.execute(&parse(json!([["r", 1, null]])), Some(0));
+        assert!(read.checks(isolation).is_empty());
+        assert!(!state.validate(&read.checks(Isolation::Serializable)));
+    }
+
+    #[test]
+    fn test_certifier_forgets_answered_requests() {
+        let (_tx, rx) = tokio::sync::mpsc::channel(1);
+        let mut certifier = TxnRwRegister::new(1, rx);
+        certifier.node_id = "n1".to_string();
+        certifier.node_ids = vec!["n1".to_string(), "n2".to_string()];
+        let certify = |msg_id, waiting_since| txn::CertifyRequestMsg {
+            msg_id: Some(msg_id),
+            ..txn::CertifyRequestMsg::new(vec![], vec![(json!(msg_id), json!(1))], waiting_since)
+        };
+        let decided = |certifier: &TxnRwRegister| {
+            let mut decided: Vec<u64> = certifier.decided.keys().map(|(_, id)| *id).collect();
+            decided.sort();
+            decided
+        };
+        assert_eq!(
+            certifier.handle_certify("n2", &certify(1, None)).unwrap(),
+            Some(true)
+        );
+        assert_eq!(
+            certifier.handle_certify("n2", &certify(1, None)).unwrap(),
+            Some(true)
+        );
+        // n2 is still waiting on 1 when it sends 2
+        assert_eq!(
+            certifier
+                .handle_certify("n2", &certify(2, Some(1)))
+                .unwrap(),
+            Some(true)
+        );
+        assert_eq!(decided(&certifier), vec![1, 2]);
+        // ...but not by the time it sends 3
+        assert_eq!(
+            certifier.handle_certify("n2", &certify(3, None)).unwrap(),
+            Some(true)
+        );
+        assert_eq!(decided(&certifier), vec![3]);
+        // A late copy of an answered request is not decided again
+        assert_eq!(
+            certifier.handle_certify("n2", &certify(1, None)).unwrap(),
+            None
+        );
+        assert_eq!(certifier.state.snapshot(), 3);
+    }
+
+    #[test]
+    fn test_only_serializable_prevents_write_skew() {
+        for (isolation, skewed) in [
+            (Isolation::SnapshotIsolation, true),
+            (Isolation::Serializable, false),
+        ] {
+            let mut state = TxnState::new();
+            // Each reads both keys, and writes the one the other does not
+            let a = parse(json!([["r", 1, null], ["r", 2, null], ["w", 1, 1]]));
+            let b = parse(json!([["r", 1, null], ["r", 2, null], ["w", 2, 1]]));
+            let a = state.execute(&a, Some(state.snapshot()));
+            let b = state.execute(&b, Some(state.snapshot()));
+            assert!(state.validate(&a.checks(isolation)));
+            let timestamp = state.next_timestamp("n0");
+            state.apply_certified(&a.writes, &timestamp, 1);
+            assert_eq!(state.validate(&b.checks(isolation)), skewed);
+        }
+    }
+}
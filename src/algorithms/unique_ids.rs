@@ -1,18 +1,24 @@
+use std::collections::VecDeque;
+use std::ops::Range;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use async_trait::async_trait;
 use tokio::sync::mpsc::Receiver;
 
+use serde_json::Value;
+
 use crate::errors;
+use crate::kv;
 use crate::node::Node;
-use crate::rpc::{self, unique_ids};
-use crate::workload::Command;
+use crate::rpc::{self, unique_ids, unique_ids::GeneratedId};
+use crate::workload::{Command, IdStrategyKind, Options};
+
+/// Our epoch begins Sunday, January 1, 2023 1:01:01 AM
+pub const DEFAULT_EPOCH_MS: u64 = 1672534861000;
 
-/// This is inspired by twitter snowflake:
-/// 64 bits in three chunks:
-///   timestamp (first 23 bits)
-///   node id (middle 10 bits)
-///   (counter % 1024) remaining bits
+/// Maelstrom (and most JSON consumers) read IDs as signed 64 bit integers,
+/// so an ID may use at most 63 bits.
+const MAX_ID_BITS: u8 = 63;
 
 /// We're going to use the integer identifier for this node as part of our unique id
 fn node_string_id_to_u64(node_id: &str) -> u64 {
@@ -25,38 +31,602 @@ fn node_string_id_to_u64(node_id: &str) -> u64 {
     }
 }
 
-// Milliseconds since epoch are the leftmost bits in our unique id
-pub fn get_milliseconds() -> String {
-    // our epoch begins Sunday, January 1, 2023 1:01:01 AM
-    let our_epoch_start: SystemTime = UNIX_EPOCH + Duration::from_secs(1672534861);
-    let start = SystemTime::now();
-    let since_the_epoch = start
+/// Milliseconds elapsed since `epoch_ms` (itself milliseconds since the UNIX epoch).
+/// A clock reading before the epoch counts as zero rather than a failure.
+fn millis_since(epoch_ms: u64) -> u64 {
+    let our_epoch_start: SystemTime = UNIX_EPOCH + Duration::from_millis(epoch_ms);
+    SystemTime::now()
         .duration_since(our_epoch_start)
-        .expect("Time went backwards");
-    let epoch_milli_string = since_the_epoch.as_millis().to_string();
-    let (head, _) = epoch_milli_string.split_at(10);
-    head.to_owned()
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Strategies for producing unique IDs. Each node runs exactly one of these.
+pub trait IdStrategy: Send {
+    /// Called with our node id (e.g. "n2") once the init message arrives
+    fn on_init(&mut self, node_id: &str) -> Result<(), errors::ErrorMsg>;
+    fn generate(&mut self) -> Result<GeneratedId, errors::ErrorMsg>;
+
+    // Strategies which coordinate through a KV service may not always have an ID
+    // to hand out: requests wait until `is_ready` returns true.
+    fn is_ready(&self) -> bool {
+        true
+    }
+    // How long until the strategy can give out another ID, if it cannot right now
+    fn wait(&self) -> Option<Duration> {
+        None
+    }
+    fn on_tick(&mut self) -> Result<(), errors::ErrorMsg> {
+        Ok(())
+    }
+    fn on_kv_reply(&mut self, _reply: kv::KvMsgIn) -> Result<(), errors::ErrorMsg> {
+        Ok(())
+    }
+}
+
+/// Build the strategy selected on the command line
+pub fn id_strategy(options: &Options) -> Result<Box<dyn IdStrategy>, errors::ErrorMsg> {
+    Ok(match options.id_strategy {
+        IdStrategyKind::Snowflake => Box::new(Snowflake::new(options.snowflake_layout()?)),
+        IdStrategyKind::UuidV7 => Box::new(UuidV7::new()),
+        IdStrategyKind::Ulid => Box::new(Ulid::new()),
+        IdStrategyKind::NodeCounter => Box::new(NodeCounter::new()),
+        IdStrategyKind::RangeLease => Box::new(RangeLease::new(options.id_lease_size)),
+    })
+}
+
+// Milliseconds since our epoch are the leftmost bits in our unique id
+pub fn get_milliseconds() -> u64 {
+    millis_since(DEFAULT_EPOCH_MS)
+}
+
+/// Bit widths of the three parts of an ID, plus the epoch its timestamps count from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SnowflakeLayout {
+    pub timestamp_bits: u8,
+    pub node_bits: u8,
+    pub sequence_bits: u8,
+    pub epoch_ms: u64,
+}
+
+impl Default for SnowflakeLayout {
+    /// 41 bits of milliseconds last roughly 69 years from the epoch,
+    /// 10 bits allow 1024 nodes, and 12 bits allow 4096 IDs per node per millisecond.
+    fn default() -> Self {
+        Self {
+            timestamp_bits: 41,
+            node_bits: 10,
+            sequence_bits: 12,
+            epoch_ms: DEFAULT_EPOCH_MS,
+        }
+    }
+}
+
+impl SnowflakeLayout {
+    pub fn new(
+        timestamp_bits: u8,
+        node_bits: u8,
+        sequence_bits: u8,
+        epoch_ms: u64,
+    ) -> Result<Self, errors::ErrorMsg> {
+        let total = timestamp_bits as u16 + node_bits as u16 + sequence_bits as u16;
+        if total > MAX_ID_BITS as u16 || timestamp_bits == 0 || sequence_bits == 0 {
+            let text = format!(
+                "Invalid ID layout: {}+{}+{} bits (timestamp and sequence need at least one bit, total at most {})",
+                timestamp_bits, node_bits, sequence_bits, MAX_ID_BITS
+            );
+            eprintln!("{}", text);
+            return Err(errors::ErrorMsg::new(
+                None,
+                errors::ErrorType::MalformedRequest,
+                text,
+            ));
+        }
+        Ok(Self {
+            timestamp_bits,
+            node_bits,
+            sequence_bits,
+            epoch_ms,
+        })
+    }
+
+    pub fn max_timestamp(&self) -> u64 {
+        (1 << self.timestamp_bits) - 1
+    }
+
+    pub fn max_node(&self) -> u64 {
+        (1 << self.node_bits) - 1
+    }
+
+    pub fn max_sequence(&self) -> u64 {
+        (1 << self.sequence_bits) - 1
+    }
+
+    /// Milliseconds since this layout's epoch
+    pub fn now(&self) -> u64 {
+        millis_since(self.epoch_ms)
+    }
+
+    /// Time left until the clock reaches `timestamp` (milliseconds since our epoch)
+    pub fn until(&self, timestamp: u64) -> Duration {
+        let at = UNIX_EPOCH + Duration::from_millis(self.epoch_ms + timestamp);
+        at.duration_since(SystemTime::now()).unwrap_or_default()
+    }
+
+    pub fn compose(&self, timestamp: u64, node_id: u64, sequence: u64) -> u64 {
+        (timestamp << (self.node_bits + self.sequence_bits))
+            | (node_id << self.sequence_bits)
+            | sequence
+    }
+
+    /// Split an ID back into (timestamp, node id, sequence)
+    pub fn decompose(&self, id: u64) -> (u64, u64, u64) {
+        (
+            (id >> (self.node_bits + self.sequence_bits)) & self.max_timestamp(),
+            (id >> self.sequence_bits) & self.max_node(),
+            id & self.max_sequence(),
+        )
+    }
+}
+
+/// Snowflake ID generator for a single node.
+/// This is inspired by twitter snowflake: an ID is a 64 bit integer in three chunks,
+/// from most to least significant:
+///   milliseconds since our epoch
+///   node id
+///   sequence number within the millisecond
+/// The width of each chunk is set by a `SnowflakeLayout`.
+/// IDs from one generator strictly increase, even if the wall clock goes backwards:
+/// in that case we hold on to the last timestamp we used until the clock catches up.
+#[derive(Clone, Debug)]
+pub struct Snowflake {
+    layout: SnowflakeLayout,
+    node_id: u64,
+    last_timestamp: u64,
+    sequence: u64,
+}
+
+impl Snowflake {
+    pub fn new(layout: SnowflakeLayout) -> Self {
+        Self {
+            layout,
+            node_id: 0,
+            last_timestamp: 0,
+            sequence: 0,
+        }
+    }
+
+    pub fn layout(&self) -> &SnowflakeLayout {
+        &self.layout
+    }
+
+    pub fn set_node_id(&mut self, node_id: u64) -> Result<(), errors::ErrorMsg> {
+        if node_id > self.layout.max_node() {
+            let text = format!(
+                "Node id {} does not fit in {} bits",
+                node_id, self.layout.node_bits
+            );
+            eprintln!("{}", text);
+            return Err(errors::ErrorMsg::new(
+                None,
+                errors::ErrorType::NotSupported,
+                text,
+            ));
+        }
+        self.node_id = node_id;
+        Ok(())
+    }
+
+    /// Generate an ID. Fails if this millisecond's sequence is used up: `wait` says
+    /// how long until the next one.
+    pub fn generate(&mut self) -> Result<u64, errors::ErrorMsg> {
+        self.generate_at(self.layout.now())
+    }
+
+    fn generate_at(&mut self, now: u64) -> Result<u64, errors::ErrorMsg> {
+        self.next_id(now)?.ok_or_else(|| {
+            errors::ErrorMsg::new(
+                None,
+                errors::ErrorType::TemporarilyUnavailable,
+                "Sequence used up for this millisecond".to_string(),
+            )
+        })
+    }
+
+    /// How long until we can generate another ID, if the sequence is used up
+    pub fn wait(&self) -> Option<Duration> {
+        if self.sequence < self.layout.max_sequence() {
+            return None;
+        }
+        let wait = self.layout.until(self.last_timestamp + 1);
+        (!wait.is_zero()).then_some(wait)
+    }
+
+    /// Generate an ID as of `now` (milliseconds since the layout's epoch).
+    /// Returns `None` if the sequence for the current millisecond is exhausted.
+    fn next_id(&mut self, now: u64) -> Result<Option<u64>, errors::ErrorMsg> {
+        if now > self.last_timestamp {
+            self.last_timestamp = now;
+            self.sequence = 0;
+        } else if self.sequence < self.layout.max_sequence() {
+            // Same millisecond, or the clock went backwards: keep the last timestamp
+            self.sequence += 1;
+        } else {
+            return Ok(None);
+        }
+        if self.last_timestamp > self.layout.max_timestamp() {
+            return Err(errors::ErrorMsg::new(
+                None,
+                errors::ErrorType::Crash,
+                "Timestamp no longer fits in the ID layout".to_string(),
+            ));
+        }
+        Ok(Some(self.layout.compose(
+            self.last_timestamp,
+            self.node_id,
+            self.sequence,
+        )))
+    }
+}
+
+impl IdStrategy for Snowflake {
+    fn on_init(&mut self, node_id: &str) -> Result<(), errors::ErrorMsg> {
+        self.set_node_id(node_string_id_to_u64(node_id))
+    }
+
+    fn generate(&mut self) -> Result<GeneratedId, errors::ErrorMsg> {
+        Snowflake::generate(self).map(GeneratedId::Numeric)
+    }
+
+    fn wait(&self) -> Option<Duration> {
+        Snowflake::wait(self)
+    }
+}
+
+/// Time-ordered UUIDs as described in RFC 9562:
+///   48 bits of UNIX milliseconds, 4 version bits (0111),
+///   12 bits `rand_a`, 2 variant bits (10) and 62 bits `rand_b`.
+/// We use `rand_a` as a counter within the millisecond (seeded randomly),
+/// which keeps the UUIDs from one node strictly increasing.
+#[derive(Clone, Debug, Default)]
+pub struct UuidV7 {
+    last_millis: u64,
+    counter: u64,
+}
+
+impl UuidV7 {
+    const MAX_COUNTER: u64 = 0xfff;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn next_uuid(&mut self, now: u64) -> u128 {
+        if now > self.last_millis {
+            self.last_millis = now;
+            // leave headroom in the counter for IDs later in this millisecond
+            self.counter = rand::random::<u64>() & (Self::MAX_COUNTER >> 1);
+        } else if self.counter < Self::MAX_COUNTER {
+            self.counter += 1;
+        } else {
+            // counter exhausted (or clock went backwards): borrow the next millisecond
+            self.last_millis += 1;
+            self.counter = 0;
+        }
+        let rand_b = rand::random::<u64>() & ((1 << 62) - 1);
+        ((self.last_millis as u128 & 0xffff_ffff_ffff) << 80)
+            | (0x7 << 76)
+            | ((self.counter as u128) << 64)
+            | (0b10 << 62)
+            | rand_b as u128
+    }
+}
+
+impl IdStrategy for UuidV7 {
+    fn on_init(&mut self, _node_id: &str) -> Result<(), errors::ErrorMsg> {
+        Ok(())
+    }
+
+    fn generate(&mut self) -> Result<GeneratedId, errors::ErrorMsg> {
+        let uuid = format!("{:032x}", self.next_uuid(millis_since(0)));
+        Ok(GeneratedId::Text(format!(
+            "{}-{}-{}-{}-{}",
+            &uuid[0..8],
+            &uuid[8..12],
+            &uuid[12..16],
+            &uuid[16..20],
+            &uuid[20..32]
+        )))
+    }
+}
+
+/// Universally Unique Lexicographically Sortable Identifiers: https://github.com/ulid/spec
+///   48 bits of UNIX milliseconds and 80 random bits, in Crockford's base32.
+/// Within one millisecond the random part is incremented, per the spec's monotonic mode.
+#[derive(Clone, Debug, Default)]
+pub struct Ulid {
+    last_millis: u64,
+    random: u128,
+}
+
+impl Ulid {
+    const ALPHABET: &'static [u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+    const MAX_RANDOM: u128 = (1 << 80) - 1;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn next_ulid(&mut self, now: u64) -> u128 {
+        if now > self.last_millis {
+            self.last_millis = now;
+            self.random = rand::random::<u128>() & (Self::MAX_RANDOM >> 1);
+        } else if self.random < Self::MAX_RANDOM {
+            self.random += 1;
+        } else {
+            self.last_millis += 1;
+            self.random = 0;
+        }
+        ((self.last_millis as u128 & 0xffff_ffff_ffff) << 80) | self.random
+    }
+
+    fn encode(mut value: u128) -> String {
+        let mut chars = [0u8; 26];
+        for c in chars.iter_mut().rev() {
+            *c = Self::ALPHABET[(value & 0x1f) as usize];
+            value >>= 5;
+        }
+        chars.iter().map(|c| *c as char).collect()
+    }
+}
+
+impl IdStrategy for Ulid {
+    fn on_init(&mut self, _node_id: &str) -> Result<(), errors::ErrorMsg> {
+        Ok(())
+    }
+
+    fn generate(&mut self) -> Result<GeneratedId, errors::ErrorMsg> {
+        Ok(GeneratedId::Text(Self::encode(
+            self.next_ulid(millis_since(0)),
+        )))
+    }
+}
+
+/// IDs like "n2-17": our node id and a local counter.
+/// Unique because node ids are, but they carry no ordering between nodes.
+#[derive(Clone, Debug, Default)]
+pub struct NodeCounter {
+    node_id: String,
+    counter: u64,
+}
+
+impl NodeCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl IdStrategy for NodeCounter {
+    fn on_init(&mut self, node_id: &str) -> Result<(), errors::ErrorMsg> {
+        self.node_id = node_id.to_string();
+        Ok(())
+    }
+
+    fn generate(&mut self) -> Result<GeneratedId, errors::ErrorMsg> {
+        self.counter += 1;
+        Ok(GeneratedId::Text(format!(
+            "{}-{}",
+            self.node_id, self.counter
+        )))
+    }
+}
+
+/// Key in lin-kv holding the start of the next unleased block of IDs
+const LEASE_KEY: &str = "unique-ids-next-block";
+
+/// Ticks to wait for a KV reply before starting a lease renewal over
+const LEASE_TIMEOUT_TICKS: u64 = 5;
+
+/// Where a lease renewal has got to
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum LeaseRenewal {
+    Reading { msg_id: u64 },
+    Claiming { msg_id: u64, from: u64 },
+}
+
+/// Dense IDs leased in blocks from a counter in lin-kv.
+/// A block `[n, n + size)` belongs to us once our CAS of the counter from `n` to
+/// `n + size` succeeds, so blocks never overlap and IDs are roughly ordered across
+/// the cluster. We renew ahead of time, once a quarter of the block remains.
+/// IDs are only lost (leaving a gap) if a CAS succeeds but its reply goes missing.
+#[derive(Clone, Debug)]
+pub struct RangeLease {
+    kvstore: kv::KV,
+    block_size: u64,
+    current: Range<u64>,
+    next_block: Option<Range<u64>>,
+    renewal: Option<LeaseRenewal>,
+    renewal_ticks: u64,
+}
+
+impl RangeLease {
+    pub fn new(block_size: u64) -> Self {
+        Self {
+            kvstore: kv::KV::new(kv::KvType::LinKV),
+            block_size: block_size.max(1),
+            current: 0..0,
+            next_block: None,
+            renewal: None,
+            renewal_ticks: 0,
+        }
+    }
+
+    /// Start a renewal if we are running low and none is in flight
+    fn maybe_renew(&mut self) -> Result<(), errors::ErrorMsg> {
+        let low_water = self.block_size / 4;
+        if self.renewal.is_none()
+            && self.next_block.is_none()
+            && (self.current.end - self.current.start) <= low_water
+        {
+            self.read_counter()?;
+        }
+        Ok(())
+    }
+
+    fn read_counter(&mut self) -> Result<(), errors::ErrorMsg> {
+        let msg_id = self.kvstore.read(LEASE_KEY.to_string())?;
+        self.renewal = Some(LeaseRenewal::Reading { msg_id });
+        self.renewal_ticks = 0;
+        Ok(())
+    }
+
+    fn claim(&mut self, from: u64) -> Result<(), errors::ErrorMsg> {
+        let msg_id = self.kvstore.cas(
+            LEASE_KEY.to_string(),
+            Value::from(from),
+            Value::from(from + self.block_size),
+            (from == 0).then_some(true),
+        )?;
+        self.renewal = Some(LeaseRenewal::Claiming { msg_id, from });
+        self.renewal_ticks = 0;
+        Ok(())
+    }
+
+    fn leased(&mut self, from: u64) {
+        let block = from..from + self.block_size;
+        if self.current.is_empty() {
+            self.current = block;
+        } else {
+            self.next_block = Some(block);
+        }
+    }
+}
+
+impl IdStrategy for RangeLease {
+    fn on_init(&mut self, node_id: &str) -> Result<(), errors::ErrorMsg> {
+        self.kvstore.set_node_id(node_id.to_string());
+        self.maybe_renew()
+    }
+
+    fn is_ready(&self) -> bool {
+        !self.current.is_empty() || self.next_block.is_some()
+    }
+
+    fn generate(&mut self) -> Result<GeneratedId, errors::ErrorMsg> {
+        if self.current.is_empty() {
+            self.current = self.next_block.take().unwrap_or(0..0);
+        }
+        let id = self.current.next().ok_or_else(|| {
+            errors::ErrorMsg::new(
+                None,
+                errors::ErrorType::TemporarilyUnavailable,
+                "Waiting on an ID lease".to_string(),
+            )
+        })?;
+        self.maybe_renew()?;
+        Ok(GeneratedId::Numeric(id))
+    }
+
+    fn on_tick(&mut self) -> Result<(), errors::ErrorMsg> {
+        if self.renewal.is_some() {
+            self.renewal_ticks += 1;
+            if self.renewal_ticks > LEASE_TIMEOUT_TICKS {
+                self.read_counter()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn on_kv_reply(&mut self, reply: kv::KvMsgIn) -> Result<(), errors::ErrorMsg> {
+        let renewal = match &self.renewal {
+            Some(renewal) => renewal.clone(),
+            None => return Ok(()),
+        };
+        // Ignore replies to requests we have since given up on
+        let expected = match renewal {
+            LeaseRenewal::Reading { msg_id } => msg_id,
+            LeaseRenewal::Claiming { msg_id, .. } => msg_id,
+        };
+        if reply.body.in_reply_to() != Some(expected) {
+            return Ok(());
+        }
+        match (renewal, reply.body) {
+            (LeaseRenewal::Reading { .. }, kv::KvResponseBody::ReadOk(body)) => {
+                self.claim(body.value.as_u64().unwrap_or(0))
+            }
+            (LeaseRenewal::Reading { .. }, kv::KvResponseBody::Error(body))
+                if body.code == errors::ErrorType::KeyDoesNotExist =>
+            {
+                self.claim(0)
+            }
+            (LeaseRenewal::Claiming { from, .. }, kv::KvResponseBody::CasOk(_)) => {
+                self.renewal = None;
+                self.leased(from);
+                self.maybe_renew()
+            }
+            // Someone else got there first (or something went wrong): start over
+            _ => self.read_counter(),
+        }
+    }
 }
 
 /// This is our Node implementation
 pub struct UniqueIdGenerator {
-    counter: u64,
-    node_id: u64,
+    strategy: Box<dyn IdStrategy>,
+    // generate requests which arrived while the strategy had no ID to give
+    waiting: VecDeque<String>,
     last_msg_id: u64,
     rx: Receiver<Command>,
 }
 
 impl UniqueIdGenerator {
-    pub fn generate(&mut self) -> Result<u64, errors::ErrorMsg> {
-        self.counter += 1;
-        let epoch_millis = get_milliseconds();
-        let epoch = epoch_millis
-            .parse::<u64>()
-            .map_err(errors::ErrorMsg::crash_error)?;
-        let mut result = epoch << 23;
-        result += self.node_id << 10;
-        result += self.counter % 1024;
-        Ok(result)
+    pub fn with_strategy(mut self, strategy: Box<dyn IdStrategy>) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    pub fn generate(&mut self) -> Result<GeneratedId, errors::ErrorMsg> {
+        self.strategy.generate()
+    }
+
+    /// Answer waiting requests for as long as the strategy can. If it still has no
+    /// ID once we have waited (the clock went backwards again, say), the request
+    /// goes back to the front of the queue for the next tick.
+    async fn serve_waiting(&mut self) -> Result<(), errors::ErrorMsg> {
+        while self.strategy.is_ready() {
+            let msg = match self.waiting.pop_front() {
+                Some(msg) => msg,
+                None => break,
+            };
+            if let Some(wait) = self.strategy.wait() {
+                tokio::time::sleep(wait).await;
+            }
+            let generated_id = match self.generate() {
+                Ok(generated_id) => generated_id,
+                Err(e) if e.code == errors::ErrorType::TemporarilyUnavailable => {
+                    self.waiting.push_front(msg);
+                    break;
+                }
+                Err(e) => return Err(e),
+            };
+            self.last_msg_id += 1;
+            let result = unique_ids::GenerateMsgIn::parse_msg_to_str_response(
+                msg.as_str(),
+                generated_id,
+                self.last_msg_id,
+            )
+            .map_err(|_e| {
+                eprintln!("{:?}", _e);
+                _e
+            })?;
+            println!("{}", result);
+        }
+        Ok(())
+    }
+
+    async fn handle_tick(&mut self) -> Result<(), errors::ErrorMsg> {
+        self.strategy.on_tick()?;
+        self.serve_waiting().await
     }
 }
 
@@ -66,8 +636,8 @@ impl Node for UniqueIdGenerator {
         Self {
             rx,
             last_msg_id: starting_msg_id,
-            counter: 1,
-            node_id: 0,
+            strategy: Box::new(Snowflake::new(SnowflakeLayout::default())),
+            waiting: VecDeque::new(),
         }
     }
 
@@ -77,6 +647,7 @@ impl Node for UniqueIdGenerator {
             match cmd {
                 Command::Init(init_msg) => self.on_init(init_msg).await?,
                 Command::Msg(msg) => self.handle(msg).await?,
+                Command::Tick => self.handle_tick().await?,
                 Command::Shutdown => self.stop().await?,
                 _ => (),
             }
@@ -85,23 +656,16 @@ impl Node for UniqueIdGenerator {
     }
 
     async fn handle(&mut self, msg: String) -> Result<(), errors::ErrorMsg> {
-        self.last_msg_id += 1;
-        let generated_id = self.generate()?;
-        let result = unique_ids::GenerateMsgIn::parse_msg_to_str_response(
-            msg.as_str(),
-            generated_id.to_string(),
-            self.last_msg_id,
-        )
-        .map_err(|_e| {
-            eprintln!("{:?}", _e);
-            _e
-        })?;
-        println!("{}", result);
-        Ok(())
+        if let Some(reply) = kv::KvMsgIn::parse(msg.as_str())? {
+            self.strategy.on_kv_reply(reply)?;
+        } else {
+            self.waiting.push_back(msg);
+        }
+        self.serve_waiting().await
     }
 
     async fn on_init(&mut self, msg: rpc::InitMsgIn) -> Result<(), errors::ErrorMsg> {
-        self.node_id = node_string_id_to_u64(msg.body.node_id.as_str());
+        self.strategy.on_init(msg.body.node_id.as_str())?;
         let msg_out = msg.into_response(self.last_msg_id);
         let result = serde_json::to_string(&msg_out).map_err(errors::ErrorMsg::json_dumps_error)?;
         println!("{}", result);
@@ -114,22 +678,268 @@ impl Node for UniqueIdGenerator {
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashSet;
+
     use super::*;
 
-    // #[test]
-    // fn test_generate() {
-    //     let mut generator = UniqueIdGenerator::new();
-    //     assert!(generator.generate().expect("Could not generate") > 50463625189535744);
-    // }
-
-    // #[test]
-    // fn test_generate_orderable() {
-    //     let mut generator = UniqueIdGenerator::new();
-    //     let one = generator.generate().expect("Could not generate");
-    //     let two = generator.generate().expect("Could not generate");
-    //     let three = generator.generate().expect("Could not generate");
-
-    //     assert!(one < two);
-    //     assert!(two < three);
-    // }
+    fn generator(layout: SnowflakeLayout, node_id: u64) -> Snowflake {
+        let mut generator = Snowflake::new(layout);
+        generator.set_node_id(node_id).expect("Node id should fit");
+        generator
+    }
+
+    /// Generate an ID, waiting for the next millisecond as the node would
+    fn generate(generator: &mut Snowflake) -> u64 {
+        if let Some(wait) = generator.wait() {
+            std::thread::sleep(wait);
+        }
+        generator.generate().expect("Could not generate")
+    }
+
+    /// A snowflake whose clock reads `clock`, one reading per ID it is asked for
+    struct SteppedClock {
+        snowflake: Snowflake,
+        clock: VecDeque<u64>,
+    }
+
+    impl IdStrategy for SteppedClock {
+        fn on_init(&mut self, node_id: &str) -> Result<(), errors::ErrorMsg> {
+            self.snowflake.on_init(node_id)
+        }
+
+        fn generate(&mut self) -> Result<GeneratedId, errors::ErrorMsg> {
+            let now = self.clock.pop_front().expect("Clock should have a reading");
+            self.snowflake.generate_at(now).map(GeneratedId::Numeric)
+        }
+
+        fn wait(&self) -> Option<Duration> {
+            (self.snowflake.sequence == self.snowflake.layout.max_sequence())
+                .then_some(Duration::from_millis(1))
+        }
+    }
+
+    fn text_ids(strategy: &mut dyn IdStrategy, count: usize) -> Vec<String> {
+        (0..count)
+            .map(|_| match strategy.generate().expect("Could not generate") {
+                GeneratedId::Text(id) => id,
+                GeneratedId::Numeric(_) => panic!("Expected string IDs"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_generate_unique() {
+        let mut generator = generator(SnowflakeLayout::default(), 3);
+        let ids: HashSet<u64> = (0..20_000).map(|_| generate(&mut generator)).collect();
+        assert_eq!(ids.len(), 20_000);
+    }
+
+    #[test]
+    fn test_generate_orderable() {
+        let mut generator = generator(SnowflakeLayout::default(), 3);
+        let ids: Vec<u64> = (0..20_000).map(|_| generate(&mut generator)).collect();
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(ids.iter().all(|id| *id <= i64::MAX as u64));
+    }
+
+    #[test]
+    fn test_generate_waits_when_sequence_exhausted() {
+        // Only four IDs per millisecond
+        let layout = SnowflakeLayout::new(41, 10, 2, DEFAULT_EPOCH_MS).unwrap();
+        let mut generator = generator(layout, 1);
+        let ids: Vec<u64> = (0..50).map(|_| generate(&mut generator)).collect();
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+        let timestamps: HashSet<u64> = ids.iter().map(|id| layout.decompose(*id).0).collect();
+        assert!(timestamps.len() >= 50 / 4);
+    }
+
+    #[test]
+    fn test_used_up_sequence_says_how_long_to_wait() {
+        let layout = SnowflakeLayout::new(41, 10, 2, DEFAULT_EPOCH_MS).unwrap();
+        let mut generator = generator(layout, 1);
+        // The clock went back a second, and we used up the last millisecond we saw
+        generator.last_timestamp = layout.now() + 1000;
+        generator.sequence = layout.max_sequence();
+        let wait = generator.wait().expect("Sequence should be used up");
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_millis(1001));
+        let error = generator.generate().unwrap_err();
+        assert_eq!(error.code, errors::ErrorType::TemporarilyUnavailable);
+        // Once we have waited, the next millisecond's sequence is ours
+        generator.last_timestamp = layout.now() - 1;
+        assert!(generator.wait().is_none());
+        generator.generate().expect("Could not generate");
+    }
+
+    #[tokio::test]
+    async fn test_clock_going_back_during_the_wait_keeps_the_request() {
+        let layout = SnowflakeLayout::new(41, 10, 2, DEFAULT_EPOCH_MS).unwrap();
+        let (_tx, rx) = tokio::sync::mpsc::channel(1);
+        let mut node = UniqueIdGenerator::new(1, rx).with_strategy(Box::new(SteppedClock {
+            snowflake: generator(layout, 1),
+            // Four IDs use up millisecond 1000; after waiting the clock reads 900
+            clock: VecDeque::from([1000, 1000, 1000, 1000, 900, 1001]),
+        }));
+        for msg_id in 0..5 {
+            let msg = format!(
+                r#"{{"src": "c1", "dest": "n1", "body": {{"type": "generate", "msg_id": {}}}}}"#,
+                msg_id
+            );
+            node.handle(msg).await.expect("Node should carry on");
+        }
+        assert_eq!(node.waiting.len(), 1);
+        assert_eq!(node.last_msg_id, 5);
+        // The next tick finds the clock has caught up
+        node.handle_tick().await.unwrap();
+        assert!(node.waiting.is_empty());
+        assert_eq!(node.last_msg_id, 6);
+    }
+
+    #[test]
+    fn test_clock_regression_holds_last_timestamp() {
+        let layout = SnowflakeLayout::new(41, 10, 2, DEFAULT_EPOCH_MS).unwrap();
+        let mut generator = generator(layout, 7);
+        let first = generator.next_id(1000).unwrap().unwrap();
+        // The clock jumps backwards: IDs keep increasing on the old timestamp
+        let second = generator.next_id(400).unwrap().unwrap();
+        let third = generator.next_id(999).unwrap().unwrap();
+        assert!(first < second && second < third);
+        assert_eq!(layout.decompose(third), (1000, 7, 2));
+        generator.next_id(500).unwrap().unwrap();
+        // ...until the sequence is used up, when we must wait for the clock
+        assert!(generator.next_id(600).unwrap().is_none());
+        let fifth = generator.next_id(1001).unwrap().unwrap();
+        assert_eq!(layout.decompose(fifth), (1001, 7, 0));
+    }
+
+    #[test]
+    fn test_layout_round_trip() {
+        let layout = SnowflakeLayout::default();
+        let id = layout.compose(123_456_789, 1023, 4095);
+        assert_eq!(layout.decompose(id), (123_456_789, 1023, 4095));
+    }
+
+    #[test]
+    fn test_layout_validation() {
+        assert!(SnowflakeLayout::new(42, 10, 12, DEFAULT_EPOCH_MS).is_err());
+        assert!(SnowflakeLayout::new(0, 10, 12, DEFAULT_EPOCH_MS).is_err());
+        assert!(Snowflake::new(SnowflakeLayout::default())
+            .set_node_id(1024)
+            .is_err());
+    }
+
+    #[test]
+    fn test_uuid_v7_format() {
+        let mut generator = UuidV7::new();
+        let ids = text_ids(&mut generator, 5_000);
+        for id in ids.iter() {
+            let parts: Vec<&str> = id.split('-').collect();
+            assert_eq!(
+                parts.iter().map(|p| p.len()).collect::<Vec<_>>(),
+                vec![8, 4, 4, 4, 12]
+            );
+            assert!(parts[2].starts_with('7'));
+            assert!("89ab".contains(&parts[3][0..1]));
+        }
+        // lowercase hex of equal length sorts in generation order
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn test_ulid_format() {
+        let mut generator = Ulid::new();
+        assert_eq!(Ulid::encode(0), "00000000000000000000000000");
+        assert_eq!(Ulid::encode(u128::MAX), "7ZZZZZZZZZZZZZZZZZZZZZZZZZ");
+        let ids = text_ids(&mut generator, 5_000);
+        assert!(ids.iter().all(|id| id.len() == 26));
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn test_node_counter() {
+        let mut generator = NodeCounter::new();
+        generator.on_init("n4").unwrap();
+        assert_eq!(
+            generator.generate().unwrap(),
+            GeneratedId::Text("n4-1".to_string())
+        );
+        assert_eq!(
+            generator.generate().unwrap(),
+            GeneratedId::Text("n4-2".to_string())
+        );
+    }
+
+    fn kv_reply(body: &str) -> kv::KvMsgIn {
+        let msg = format!(r#"{{"src": "lin-kv", "dest": "n1", "body": {}}}"#, body);
+        kv::KvMsgIn::parse(&msg).unwrap().unwrap()
+    }
+
+    fn renewal_msg_id(lease: &RangeLease) -> u64 {
+        match lease.renewal {
+            Some(LeaseRenewal::Reading { msg_id }) => msg_id,
+            Some(LeaseRenewal::Claiming { msg_id, .. }) => msg_id,
+            None => panic!("Expected a renewal in flight"),
+        }
+    }
+
+    #[test]
+    fn test_range_lease() {
+        let mut lease = RangeLease::new(8);
+        lease.on_init("n1").unwrap();
+        assert!(!lease.is_ready());
+
+        // No counter yet: we claim the first block
+        let id = renewal_msg_id(&lease);
+        lease
+            .on_kv_reply(kv_reply(&format!(
+                r#"{{"type": "error", "code": 20, "in_reply_to": {}}}"#,
+                id
+            )))
+            .unwrap();
+        assert_eq!(
+            lease.renewal,
+            Some(LeaseRenewal::Claiming {
+                msg_id: id + 1,
+                from: 0
+            })
+        );
+        // Lost the race: read again, then claim from wherever the counter is
+        lease
+            .on_kv_reply(kv_reply(&format!(
+                r#"{{"type": "error", "code": 22, "in_reply_to": {}}}"#,
+                id + 1
+            )))
+            .unwrap();
+        lease
+            .on_kv_reply(kv_reply(&format!(
+                r#"{{"type": "read_ok", "value": 16, "in_reply_to": {}}}"#,
+                id + 2
+            )))
+            .unwrap();
+        lease
+            .on_kv_reply(kv_reply(&format!(
+                r#"{{"type": "cas_ok", "in_reply_to": {}}}"#,
+                id + 3
+            )))
+            .unwrap();
+        assert!(lease.is_ready());
+        assert!(lease.renewal.is_none());
+
+        let ids: Vec<GeneratedId> = (0..6).map(|_| lease.generate().unwrap()).collect();
+        assert_eq!(ids, (16..22).map(GeneratedId::Numeric).collect::<Vec<_>>());
+        // Down to two IDs left in the block: renewal has begun ahead of time
+        assert!(lease.renewal.is_some());
+        // A stale reply is ignored
+        lease
+            .on_kv_reply(kv_reply(r#"{"type": "cas_ok", "in_reply_to": 1}"#))
+            .unwrap();
+        assert!(lease.renewal.is_some());
+    }
+
+    #[test]
+    fn test_timestamp_overflow_is_an_error() {
+        let layout = SnowflakeLayout::new(4, 4, 4, DEFAULT_EPOCH_MS).unwrap();
+        let mut generator = generator(layout, 1);
+        assert!(generator.next_id(15).unwrap().is_some());
+        assert!(generator.next_id(16).is_err());
+    }
 }
@@ -0,0 +1,790 @@
+/// Chain replication: see van Renesse & Schneider, "Chain Replication for Supporting
+/// High Throughput and Availability", and for reads at any node, Terrace & Freedman,
+/// "Object Storage on CRAQ". https://www.usenix.org/legacy/event/usenix09/tech/full_papers/terrace/terrace.pdf
+///
+/// The nodes form a chain, at first in `init` order. Updates go to the head, which
+/// numbers them and passes them down the chain; each node holds an update as pending
+/// (dirty) until the tail has it, when it is committed. The tail's acks go back up
+/// the chain, each node applying what they cover, and the node which took a request
+/// answers it once it applies it. Reads are served by the tail or, with CRAQ, by any
+/// node for a key with no pending update; reads of dirty keys go to the tail.
+///
+/// The chain itself lives in maelstrom's lin-kv service under CONFIG_KEY, and every
+/// change to it is a cas from the configuration it replaces, with the next epoch. A
+/// node which hears nothing from a neighbour for FAILURE_TICKS drops it from the
+/// chain, and a node outside the chain appends itself as the new tail, getting the
+/// state from its predecessor before any entries. Nodes poll the configuration every
+/// POLL_TICKS and serve clients for LEASE_TICKS after a poll which found them in it.
+/// A node waits ACTIVATE_TICKS after taking up a new configuration before it serves,
+/// by which time no node dropped from the chain can still be serving stale state.
+/// Like Raft's leases, this depends on the nodes' clocks running at about the same
+/// rate.
+use std::collections::BTreeMap;
+
+use serde_json::{json, Value};
+
+use crate::consensus::{Consensus, StateMachine};
+use crate::errors;
+use crate::node::{Forwarder, Process, FORWARD_EXPIRE_TICKS};
+use crate::rpc::chain::{self, ChainConfig, ChainMessageBody};
+use crate::rpc::raft::ClientRequest;
+
+/// Where the chain's configuration is kept, and under which key by default
+pub const CONFIG_SERVICE: &str = "lin-kv";
+pub const CONFIG_KEY: &str = "chain";
+
+/// Read the configuration this often
+const POLL_TICKS: u64 = 2;
+
+/// Serve clients for this long after a read of the configuration was sent
+const LEASE_TICKS: u64 = 6;
+
+/// Wait this long after taking up a new configuration, for old leases to run out
+const ACTIVATE_TICKS: u64 = LEASE_TICKS + 2;
+
+/// Drop a neighbour from the chain after hearing nothing from it for this long
+const FAILURE_TICKS: u64 = 10;
+
+/// Give up on a read or cas of the configuration after this many ticks
+const CONFIG_TIMEOUT_TICKS: u64 = 10;
+
+/// Most entries sent down the chain in one message
+const MAX_FORWARD_ENTRIES: usize = 64;
+
+/// A read or cas of the configuration which has not been answered
+struct ConfigRequest {
+    msg_id: u64,
+    sent: u64,
+    // The configuration a cas puts in place; None for a read
+    proposed: Option<ChainConfig>,
+}
+
+pub struct ChainReplication<S: StateMachine> {
+    node_id: String,
+    node_ids: Vec<String>,
+    state: S,
+    craq: bool,
+    config_key: String,
+    config: ChainConfig,
+    // Every entry up to here is applied
+    committed: u64,
+    // Entries after `committed`, in order with no gaps
+    pending: BTreeMap<u64, ClientRequest<S::Request>>,
+    // Every entry up to here has reached our successor, as far as we know
+    successor_seq: u64,
+    heard_from_successor: u64,
+    heard_from_predecessor: u64,
+    // We have joined the chain and not yet had the state from our predecessor
+    needs_sync: bool,
+    ticks: u64,
+    active_at: u64,
+    lease_until: u64,
+    config_request: Option<ConfigRequest>,
+    next_poll: u64,
+    rejoin_at: u64,
+    forwarder: Forwarder,
+    last_msg_id: u64,
+    outbox: Vec<Value>,
+}
+
+impl<S: StateMachine> ChainReplication<S> {
+    pub fn new(node_id: String, node_ids: Vec<String>, state: S) -> Self {
+        let mut forwarder = Forwarder::new(FORWARD_EXPIRE_TICKS);
+        forwarder.set_node_id(node_id.clone());
+        let config = ChainConfig {
+            epoch: 0,
+            chain: node_ids.clone(),
+        };
+        Self {
+            node_id,
+            node_ids,
+            state,
+            craq: false,
+            config_key: CONFIG_KEY.to_string(),
+            config,
+            committed: 0,
+            pending: BTreeMap::new(),
+            successor_seq: 0,
+            heard_from_successor: 0,
+            heard_from_predecessor: 0,
+            needs_sync: false,
+            ticks: 0,
+            active_at: 0,
+            lease_until: 0,
+            config_request: None,
+            next_poll: 0,
+            rejoin_at: 0,
+            forwarder,
+            last_msg_id: 0,
+            outbox: vec![],
+        }
+    }
+
+    /// Serve reads of clean keys at every node, rather than only at the tail
+    pub fn with_craq(mut self, craq: bool) -> Self {
+        self.craq = craq;
+        self
+    }
+
+    /// Keep the configuration under `key` instead, so several chains can share the service
+    pub fn with_config_key(mut self, key: String) -> Self {
+        self.config_key = key;
+        self
+    }
+
+    pub fn config(&self) -> &ChainConfig {
+        &self.config
+    }
+
+    /// Every entry up to here is applied
+    pub fn committed(&self) -> u64 {
+        self.committed
+    }
+
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    /// Whether we may answer clients: in the chain, with a lease, and settled in
+    pub fn is_serving(&self) -> bool {
+        self.position().is_some()
+            && !self.needs_sync
+            && self.ticks >= self.active_at
+            && self.ticks < self.lease_until
+    }
+
+    fn next_msg_id(&mut self) -> u64 {
+        self.last_msg_id += 1;
+        self.last_msg_id
+    }
+
+    fn position(&self) -> Option<usize> {
+        self.config.chain.iter().position(|id| *id == self.node_id)
+    }
+
+    fn predecessor(&self) -> Option<String> {
+        let position = self.position().filter(|position| *position > 0)?;
+        Some(self.config.chain[position - 1].clone())
+    }
+
+    fn successor(&self) -> Option<String> {
+        let position = self.position()?;
+        self.config.chain.get(position + 1).cloned()
+    }
+
+    fn is_head(&self) -> bool {
+        self.position() == Some(0)
+    }
+
+    fn is_tail(&self) -> bool {
+        self.position().is_some() && self.successor().is_none()
+    }
+
+    /// The last entry we hold, committed or not
+    fn last_seq(&self) -> u64 {
+        self.committed + self.pending.len() as u64
+    }
+
+    fn send(&mut self, dest: &str, body: ChainMessageBody<S::Request, S::Snapshot>) {
+        let msg = chain::ChainMessage {
+            src: self.node_id.clone(),
+            dest: dest.to_string(),
+            body,
+        };
+        match serde_json::to_value(&msg) {
+            Ok(msg) => self.outbox.push(msg),
+            Err(err) => eprintln!("{:?}", err),
+        }
+    }
+
+    fn reply(&mut self, dest: &str, body: Value) {
+        self.outbox.push(json!({
+            "src": self.node_id,
+            "dest": dest,
+            "body": body,
+        }));
+    }
+
+    fn refuse(&mut self, msg: &Value, reason: &str) {
+        let error = errors::ErrorMsg::new(
+            msg["body"]["msg_id"].as_u64(),
+            errors::ErrorType::TemporarilyUnavailable,
+            reason.to_string(),
+        );
+        match serde_json::to_value(error) {
+            Ok(body) => self.reply(msg["src"].as_str().unwrap_or_default(), body),
+            Err(err) => eprintln!("{:?}", err),
+        }
+    }
+
+    /// Whether no pending entry could touch what `request` reads
+    fn is_clean(&self, request: &S::Request) -> bool {
+        match S::key(request) {
+            Some(key) => self
+                .pending
+                .values()
+                .all(|pending| S::key(&pending.body).is_some_and(|other| other != key)),
+            None => self.pending.is_empty(),
+        }
+    }
+
+    /// Apply entries up to `seq`, answering those we took
+    fn commit_through(&mut self, seq: u64) {
+        while self.committed < seq {
+            let request = match self.pending.remove(&(self.committed + 1)) {
+                Some(request) => request,
+                None => break,
+            };
+            self.committed += 1;
+            let msg_id = self.next_msg_id();
+            let body = self.state.apply(&request.body, msg_id);
+            if request.origin == self.node_id {
+                self.reply(&request.client, body);
+            }
+        }
+    }
+
+    /// The tail commits whatever reaches it, while it may serve
+    fn commit_at_tail(&mut self) {
+        if self.is_tail() && self.is_serving() {
+            self.commit_through(self.last_seq());
+        }
+    }
+
+    /// At the head: number an update and pass it down the chain
+    fn sequence(&mut self, request: ClientRequest<S::Request>) {
+        let seq = self.last_seq() + 1;
+        self.pending.insert(seq, request.clone());
+        match self.successor() {
+            Some(successor) => {
+                let body = chain::ForwardMsg {
+                    epoch: self.config.epoch,
+                    entries: vec![chain::ChainEntry { seq, request }],
+                };
+                self.send(&successor, ChainMessageBody::ChainForward(body));
+            }
+            None => self.commit_at_tail(),
+        }
+    }
+
+    /// Resend whatever our successor has not acked; an empty forward is a heartbeat
+    fn send_forward(&mut self) {
+        let successor = match self.successor() {
+            Some(successor) => successor,
+            None => return,
+        };
+        let entries = self
+            .pending
+            .range(self.successor_seq + 1..)
+            .take(MAX_FORWARD_ENTRIES)
+            .map(|(seq, request)| chain::ChainEntry {
+                seq: *seq,
+                request: request.clone(),
+            })
+            .collect();
+        let body = chain::ForwardMsg {
+            epoch: self.config.epoch,
+            entries,
+        };
+        self.send(&successor, ChainMessageBody::ChainForward(body));
+    }
+
+    fn send_ack(&mut self, dest: &str) {
+        let body = chain::AckMsg {
+            epoch: self.config.epoch,
+            seq: self.last_seq(),
+            committed: self.committed,
+            needs_sync: self.needs_sync,
+        };
+        self.send(dest, ChainMessageBody::ChainAck(body));
+    }
+
+    fn handle_submit(&mut self, msg: chain::SubmitMsg<S::Request>) {
+        // Anything else is dropped: its client will time out
+        if self.is_head() && self.is_serving() {
+            self.sequence(msg.request);
+        }
+    }
+
+    fn handle_forward(&mut self, src: &str, msg: chain::ForwardMsg<S::Request>) {
+        if msg.epoch != self.config.epoch || self.predecessor().as_deref() != Some(src) {
+            return;
+        }
+        self.heard_from_predecessor = self.ticks;
+        let mut new = vec![];
+        if !self.needs_sync {
+            for entry in msg.entries {
+                if entry.seq == self.last_seq() + 1 {
+                    self.pending.insert(entry.seq, entry.request.clone());
+                    new.push(entry);
+                }
+            }
+        }
+        match self.successor() {
+            Some(successor) if !new.is_empty() => {
+                let body = chain::ForwardMsg {
+                    epoch: self.config.epoch,
+                    entries: new,
+                };
+                self.send(&successor, ChainMessageBody::ChainForward(body));
+            }
+            _ => self.commit_at_tail(),
+        }
+        self.send_ack(src);
+    }
+
+    fn handle_ack(&mut self, src: &str, msg: chain::AckMsg) {
+        if msg.epoch != self.config.epoch || self.successor().as_deref() != Some(src) {
+            return;
+        }
+        self.heard_from_successor = self.ticks;
+        self.successor_seq = msg.seq;
+        if msg.needs_sync || msg.seq < self.committed {
+            let body = chain::SyncMsg {
+                epoch: self.config.epoch,
+                seq: self.committed,
+                state: self.state.snapshot(),
+            };
+            self.send(src, ChainMessageBody::ChainSync(body));
+            self.successor_seq = self.committed;
+        }
+        // A node which has just joined has nothing committed of ours to tell
+        if !msg.needs_sync {
+            self.commit_through(msg.committed.min(self.last_seq()));
+        }
+    }
+
+    fn handle_sync(&mut self, src: &str, msg: chain::SyncMsg<S::Snapshot>) {
+        if msg.epoch != self.config.epoch || self.predecessor().as_deref() != Some(src) {
+            return;
+        }
+        if !self.needs_sync && msg.seq <= self.last_seq() {
+            return;
+        }
+        self.heard_from_predecessor = self.ticks;
+        self.state.restore(msg.state);
+        self.committed = msg.seq;
+        self.pending.clear();
+        self.needs_sync = false;
+        self.send_ack(src);
+    }
+
+    fn handle_client_request(&mut self, msg: &Value, request: S::Request) {
+        if !self.is_serving() {
+            return self.refuse(msg, "not serving");
+        }
+        let src = msg["src"].as_str().unwrap_or_default().to_string();
+        if !S::is_read(&request) {
+            let request = ClientRequest {
+                origin: self.node_id.clone(),
+                client: src,
+                body: request,
+            };
+            if self.is_head() {
+                return self.sequence(request);
+            }
+            let head = self.config.chain[0].clone();
+            let body = chain::SubmitMsg { request };
+            return self.send(&head, ChainMessageBody::ChainSubmit(body));
+        }
+        self.commit_at_tail();
+        if self.is_tail() || (self.craq && self.is_clean(&request)) {
+            let msg_id = self.next_msg_id();
+            let body = self.state.apply(&request, msg_id);
+            return self.reply(&src, body);
+        }
+        if self.node_ids.contains(&src) {
+            // Forwarded by a node which took us for the tail: one hop only
+            return self.refuse(msg, "not the tail");
+        }
+        if let Some(tail) = self.config.chain.last().cloned() {
+            let forwarded = self.forwarder.forward_msg(msg, &tail);
+            self.outbox.push(forwarded);
+        }
+    }
+
+    fn handle_chain_message(&mut self, msg: chain::ChainMessage<S::Request, S::Snapshot>) {
+        match msg.body {
+            ChainMessageBody::ChainSubmit(body) => self.handle_submit(body),
+            ChainMessageBody::ChainForward(body) => self.handle_forward(&msg.src, body),
+            ChainMessageBody::ChainAck(body) => self.handle_ack(&msg.src, body),
+            ChainMessageBody::ChainSync(body) => self.handle_sync(&msg.src, body),
+        }
+    }
+
+    fn request_config(&mut self, mut body: Value, proposed: Option<ChainConfig>) {
+        let msg_id = self.next_msg_id();
+        body["msg_id"] = Value::from(msg_id);
+        self.config_request = Some(ConfigRequest {
+            msg_id,
+            sent: self.ticks,
+            proposed,
+        });
+        self.reply(CONFIG_SERVICE, body);
+    }
+
+    fn poll_config(&mut self) {
+        self.next_poll = self.ticks + POLL_TICKS;
+        self.request_config(json!({"type": "read", "key": self.config_key}), None);
+    }
+
+    fn propose_config(&mut self, chain: Vec<String>) {
+        let proposed = ChainConfig {
+            epoch: self.config.epoch + 1,
+            chain,
+        };
+        let body = json!({
+            "type": "cas",
+            "key": self.config_key,
+            "from": self.config,
+            "to": proposed,
+            // The first configuration, from init, is never stored
+            "create_if_not_exists": self.config.epoch == 0,
+        });
+        self.request_config(body, Some(proposed));
+    }
+
+    /// The chain we would like instead of the current one, if any
+    fn wanted_chain(&mut self) -> Option<Vec<String>> {
+        let mut chain = self.config.chain.clone();
+        if self.position().is_none() {
+            if self.ticks < self.rejoin_at {
+                return None;
+            }
+            self.rejoin_at = self.ticks + FAILURE_TICKS;
+            chain.push(self.node_id.clone());
+            return Some(chain);
+        }
+        let silent = |heard: u64| self.ticks >= heard + FAILURE_TICKS;
+        let failed = match (self.successor(), self.predecessor()) {
+            (Some(successor), _) if silent(self.heard_from_successor) => successor,
+            (_, Some(predecessor)) if silent(self.heard_from_predecessor) => predecessor,
+            _ => return None,
+        };
+        chain.retain(|id| *id != failed);
+        Some(chain)
+    }
+
+    fn handle_config_reply(&mut self, msg: &Value) {
+        let request = match self.config_request.take() {
+            Some(request) if msg["body"]["in_reply_to"].as_u64() == Some(request.msg_id) => request,
+            other => {
+                self.config_request = other;
+                return;
+            }
+        };
+        let code = msg["body"]["code"]
+            .as_u64()
+            .and_then(errors::ErrorType::from_code);
+        match (msg["body"]["type"].as_str(), code) {
+            (Some("read_ok"), _) => {
+                match serde_json::from_value::<ChainConfig>(msg["body"]["value"].clone()) {
+                    Ok(config) => self.learn_config(config, request.sent),
+                    Err(err) => eprintln!("{:?}", err),
+                }
+            }
+            (Some("cas_ok"), _) => {
+                if let Some(config) = request.proposed {
+                    self.learn_config(config, request.sent);
+                }
+            }
+            // Nothing has been stored: the chain is still the one from init
+            (Some("error"), Some(errors::ErrorType::KeyDoesNotExist)) => {
+                let config = ChainConfig {
+                    epoch: 0,
+                    chain: self.node_ids.clone(),
+                };
+                self.learn_config(config, request.sent);
+            }
+            // Somebody changed it first: find out what it is on the next tick
+            _ => self.next_poll = 0,
+        }
+    }
+
+    /// `config` was current at some point after tick `sent`
+    fn learn_config(&mut self, config: ChainConfig, sent: u64) {
+        let epoch = config.epoch;
+        if epoch > self.config.epoch {
+            self.adopt_config(config);
+        }
+        if epoch == self.config.epoch && self.position().is_some() {
+            self.lease_until = sent + LEASE_TICKS;
+        }
+    }
+
+    fn adopt_config(&mut self, config: ChainConfig) {
+        let was_member = self.position().is_some();
+        self.config = config;
+        self.active_at = self.ticks + ACTIVATE_TICKS;
+        self.heard_from_successor = self.ticks;
+        self.heard_from_predecessor = self.ticks;
+        self.successor_seq = self.committed;
+        match self.position() {
+            Some(_) if !was_member => self.needs_sync = !self.is_head(),
+            Some(_) => (),
+            None => self.rejoin_at = self.ticks + FAILURE_TICKS,
+        }
+    }
+
+    fn tick_config(&mut self) {
+        let expired = self
+            .config_request
+            .as_ref()
+            .is_some_and(|request| self.ticks >= request.sent + CONFIG_TIMEOUT_TICKS);
+        if expired {
+            self.config_request = None;
+        }
+        if self.config_request.is_some() {
+            return;
+        }
+        if self.ticks >= self.next_poll {
+            self.poll_config();
+        } else if let Some(chain) = self.wanted_chain() {
+            self.propose_config(chain);
+        }
+    }
+}
+
+impl<S: StateMachine> Process for ChainReplication<S> {
+    fn on_message(&mut self, msg: Value) -> Vec<Value> {
+        let msg_type = msg["body"]["type"].as_str().unwrap_or_default();
+        if msg["src"] == CONFIG_SERVICE {
+            self.handle_config_reply(&msg);
+        } else if chain::MESSAGE_TYPES.contains(&msg_type) {
+            match serde_json::from_value::<chain::ChainMessage<S::Request, S::Snapshot>>(msg) {
+                Ok(msg) => self.handle_chain_message(msg),
+                Err(err) => eprintln!("{:?}", err),
+            }
+        } else if let Some(reply) = self.forwarder.complete_msg(&msg) {
+            self.outbox.push(reply);
+        } else if let Some(request) = S::parse_request(&msg["body"]) {
+            self.handle_client_request(&msg, request);
+        }
+        std::mem::take(&mut self.outbox)
+    }
+
+    fn on_tick(&mut self) -> Vec<Value> {
+        self.ticks += 1;
+        self.forwarder.tick();
+        self.tick_config();
+        self.commit_at_tail();
+        self.send_forward();
+        std::mem::take(&mut self.outbox)
+    }
+}
+
+impl<S: StateMachine + Send> Consensus for ChainReplication<S> {
+    /// The head, which orders every update
+    fn leader(&self) -> Option<&str> {
+        self.config.chain.first().map(String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::algorithms::lin_kv::KvState;
+    use crate::sim::linearizability::{self, KvClients, Outcome};
+    use crate::sim::{node_ids, random_partitions, LinKvService, Simulation};
+
+    fn cluster(size: usize, seed: u64, craq: bool) -> Simulation<ChainReplication<KvState>> {
+        let node_ids = node_ids(size);
+        let processes = node_ids
+            .iter()
+            .map(|id| {
+                let node = ChainReplication::new(id.clone(), node_ids.clone(), KvState::new())
+                    .with_craq(craq);
+                (id.clone(), node)
+            })
+            .collect();
+        Simulation::new(seed, processes)
+            .with_service(CONFIG_SERVICE, Box::new(LinKvService::new(CONFIG_SERVICE)))
+    }
+
+    /// Every node in the chain has applied the same entries
+    fn assert_chain_agrees(sim: &Simulation<ChainReplication<KvState>>) {
+        let (_, first) = sim.processes().next().unwrap();
+        let chain = first.config().chain.clone();
+        let tail = sim.process(chain.last().unwrap()).unwrap();
+        for id in chain.iter() {
+            let node = sim.process(id).unwrap();
+            assert_eq!(node.config(), first.config());
+            assert_eq!(node.committed(), tail.committed());
+            assert_eq!(node.state(), tail.state());
+        }
+    }
+
+    /// Tick, answering any poll of the configuration with `config` (None: not stored)
+    fn tick(node: &mut ChainReplication<KvState>, config: Option<&ChainConfig>) {
+        let out = node.on_tick();
+        if let Some(poll) = out.iter().find(|msg| msg["dest"] == CONFIG_SERVICE) {
+            let in_reply_to = poll["body"]["msg_id"].clone();
+            let body = match config {
+                Some(config) => {
+                    json!({"type": "read_ok", "value": config, "in_reply_to": in_reply_to})
+                }
+                None => json!({"type": "error", "code": 20, "in_reply_to": in_reply_to}),
+            };
+            node.on_message(json!({"src": CONFIG_SERVICE, "dest": "n2", "body": body}));
+        }
+    }
+
+    /// n2 of n1 -> n2 -> n3, with a lease from the configuration service
+    fn middle_node(craq: bool) -> ChainReplication<KvState> {
+        let node_ids: Vec<String> = ["n1", "n2", "n3"].map(String::from).to_vec();
+        let mut node =
+            ChainReplication::new("n2".to_string(), node_ids, KvState::new()).with_craq(craq);
+        tick(&mut node, None);
+        assert!(node.is_serving());
+        node
+    }
+
+    fn read(node: &mut ChainReplication<KvState>, key: u64) -> Value {
+        let out = node.on_message(json!({
+            "src": "c1",
+            "dest": "n2",
+            "body": {"type": "read", "key": key, "msg_id": 1}
+        }));
+        assert_eq!(out.len(), 1);
+        out[0].clone()
+    }
+
+    #[test]
+    fn test_craq_serves_clean_keys_anywhere() {
+        let mut node = middle_node(true);
+        assert_eq!(read(&mut node, 1)["dest"], "c1");
+        node.on_message(json!({
+            "src": "n1",
+            "dest": "n2",
+            "body": {
+                "type": "chain_forward",
+                "epoch": 0,
+                "entries": [{
+                    "seq": 1,
+                    "request": {
+                        "origin": "n1",
+                        "client": "c2",
+                        "body": {"type": "write", "key": 1, "value": 5, "msg_id": 1}
+                    }
+                }]
+            }
+        }));
+        // Key 1 is dirty until the tail's ack comes back
+        assert_eq!(read(&mut node, 1)["dest"], "n3");
+        assert_eq!(read(&mut node, 2)["dest"], "c1");
+        node.on_message(json!({
+            "src": "n3",
+            "dest": "n2",
+            "body": {"type": "chain_ack", "epoch": 0, "seq": 1, "committed": 1}
+        }));
+        let reply = read(&mut node, 1);
+        assert_eq!(reply["dest"], "c1");
+        assert_eq!(reply["body"]["value"], 5);
+        // Without CRAQ, only the tail serves reads
+        assert_eq!(read(&mut middle_node(false), 2)["dest"], "n3");
+    }
+
+    #[test]
+    fn test_serves_only_while_leased_and_settled() {
+        let mut node = middle_node(true);
+        // The service stops answering: the lease runs out
+        for _ in 0..LEASE_TICKS {
+            node.on_tick();
+        }
+        assert!(!node.is_serving());
+        assert_eq!(read(&mut node, 1)["body"]["code"], 11);
+        // n3 is dropped, and n2 (now the tail) waits out any lease n3 may hold
+        let config = ChainConfig {
+            epoch: 1,
+            chain: vec!["n1".to_string(), "n2".to_string()],
+        };
+        while node.config().epoch == 0 {
+            tick(&mut node, Some(&config));
+        }
+        let adopted = node.ticks;
+        while node.ticks < adopted + ACTIVATE_TICKS {
+            assert!(!node.is_serving());
+            tick(&mut node, Some(&config));
+        }
+        assert!(node.ticks > adopted + LEASE_TICKS);
+        assert!(node.is_serving());
+        assert_eq!(read(&mut node, 1)["dest"], "c1");
+    }
+
+    #[test]
+    fn test_replicates_down_the_chain() {
+        for craq in [false, true] {
+            let mut sim = cluster(3, 1, craq);
+            sim.run_for(1000);
+            let settings = KvClients {
+                duration_ms: 3000,
+                ..KvClients::default()
+            };
+            let history = linearizability::run_kv_clients(&mut sim, &settings, |_| ());
+            sim.run_for(1000);
+            linearizability::check(&history).unwrap();
+            let ok = history
+                .iter()
+                .filter(|op| matches!(op.outcome, Outcome::Ok(_)))
+                .count();
+            assert!(ok > 50, "only {} requests succeeded", ok);
+            assert_eq!(sim.process("n1").unwrap().config().epoch, 0);
+            assert_chain_agrees(&sim);
+        }
+    }
+
+    #[test]
+    fn test_partitioned_node_leaves_and_rejoins() {
+        let mut sim = cluster(3, 2, true);
+        sim.run_for(1000);
+        let n3 = ["n3".to_string()];
+        sim.partition(&n3, &["n1".to_string(), "n2".to_string()]);
+        sim.run_for(5000);
+        // The chain cannot go on through a link which is down
+        let chain = &sim.process("n1").unwrap().config().chain;
+        assert!(!(chain.contains(&"n2".to_string()) && chain.contains(&"n3".to_string())));
+        sim.heal();
+        sim.run_for(10_000);
+        let settings = KvClients {
+            duration_ms: 2000,
+            ..KvClients::default()
+        };
+        let history = linearizability::run_kv_clients(&mut sim, &settings, |_| ());
+        sim.run_for(1000);
+        linearizability::check(&history).unwrap();
+        assert!(history
+            .iter()
+            .any(|op| matches!(op.outcome, Outcome::Ok(_))));
+        assert_eq!(sim.process("n1").unwrap().config().chain.len(), 3);
+        assert_chain_agrees(&sim);
+    }
+
+    #[test]
+    fn test_linearizable_under_partitions_and_loss() {
+        for seed in 0..4 {
+            let mut sim = cluster(5, seed, seed % 2 == 1)
+                .with_loss(0.05)
+                .with_latency(10);
+            let settings = KvClients {
+                duration_ms: 10_000,
+                seed,
+                ..KvClients::default()
+            };
+            let mut history =
+                linearizability::run_kv_clients(&mut sim, &settings, random_partitions(seed));
+            sim.heal();
+            sim.run_for(5000);
+            let settings = KvClients {
+                duration_ms: 2000,
+                seed: seed + 100,
+                ..KvClients::default()
+            };
+            let after = linearizability::run_kv_clients(&mut sim, &settings, |_| ());
+            assert!(after.iter().any(|op| matches!(op.outcome, Outcome::Ok(_))));
+            history.extend(after);
+            linearizability::check(&history).unwrap();
+            sim.run_for(2000);
+            assert_chain_agrees(&sim);
+        }
+    }
+}
@@ -0,0 +1,95 @@
+/// Consensus protocols which replicate a state machine over the nodes from `init`.
+///
+/// Protocols are written as `node::Process`es: they never print or read the clock
+/// themselves, so a node can drive one for real while `sim` drives it in tests.
+pub mod chain;
+pub mod paxos;
+pub mod raft;
+pub mod sharded;
+pub mod snapshot;
+
+use std::fmt::Debug;
+use std::path::PathBuf;
+
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use crate::node::Process;
+use crate::workload::{ConsensusKind, ReadMode};
+
+/// Something to replicate. It must be deterministic: every node applies the same
+/// requests in the same order and must end up in the same state.
+pub trait StateMachine {
+    type Request: Clone + Debug + Send + Serialize + DeserializeOwned + 'static;
+    type Snapshot: Clone + Debug + Send + Serialize + DeserializeOwned + 'static;
+
+    /// The request in a client's message body, if it is one we serve
+    fn parse_request(body: &Value) -> Option<Self::Request>;
+
+    /// Carry out a committed request, returning the body of the reply to its client
+    fn apply(&mut self, request: &Self::Request, msg_id: u64) -> Value;
+
+    /// Whether `request` leaves the state as it is, so may be served without the log
+    fn is_read(_request: &Self::Request) -> bool {
+        false
+    }
+
+    /// The key `request` touches, for protocols which track keys apart, or None if
+    /// it may touch any of them
+    fn key(_request: &Self::Request) -> Option<String> {
+        None
+    }
+
+    /// Everything needed to rebuild the current state
+    fn snapshot(&self) -> Self::Snapshot;
+
+    /// Replace the current state with one from `snapshot`
+    fn restore(&mut self, snapshot: Self::Snapshot);
+}
+
+/// A consensus protocol: messages and ticks in, messages out
+pub trait Consensus: Process + Send {
+    /// The node we believe is leading, if any
+    fn leader(&self) -> Option<&str>;
+}
+
+impl Process for Box<dyn Consensus> {
+    fn on_message(&mut self, msg: Value) -> Vec<Value> {
+        self.as_mut().on_message(msg)
+    }
+
+    fn on_tick(&mut self) -> Vec<Value> {
+        self.as_mut().on_tick()
+    }
+}
+
+impl Consensus for Box<dyn Consensus> {
+    fn leader(&self) -> Option<&str> {
+        self.as_ref().leader()
+    }
+}
+
+/// Settings for the consensus protocols, from the command line
+#[derive(Clone, Debug)]
+pub struct ConsensusConfig {
+    // Snapshot the state machine (and drop the log before it) every this many entries
+    pub snapshot_entries: u64,
+    // Keep each node's latest snapshot in a file here
+    pub snapshot_dir: Option<PathBuf>,
+    pub read_mode: ReadMode,
+    pub kind: ConsensusKind,
+    // Shard keys over groups of about this many nodes, each replicated on its own
+    pub group_size: Option<usize>,
+}
+
+impl Default for ConsensusConfig {
+    fn default() -> Self {
+        Self {
+            snapshot_entries: 1000,
+            snapshot_dir: None,
+            read_mode: ReadMode::Log,
+            kind: ConsensusKind::Raft,
+            group_size: None,
+        }
+    }
+}
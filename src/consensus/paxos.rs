@@ -0,0 +1,617 @@
+/// Multi-Paxos: see Lamport, "Paxos Made Simple", and van Renesse & Altinbuken,
+/// "Paxos Made Moderately Complex". https://lamport.azurewebsites.net/pubs/paxos-simple.pdf
+///
+/// Every node is an acceptor and a learner; one at a time acts as the stable leader
+/// (the distinguished proposer). A node which hears nothing from a leader for its
+/// (randomised) election timeout runs phase 1 once, with a ballot above any it has
+/// seen, for every slot after the ones it knows are chosen. With a majority of
+/// promises it re-proposes, in its own ballot, the value accepted in the highest
+/// ballot for each of those slots (or a no-op where there is none), and from then on
+/// only runs phase 2. Client requests which arrive between ticks are batched into one
+/// `accept` per peer on the next tick, which doubles as the heartbeat. It carries
+/// the leader's executed index, below which every slot is chosen, so followers learn
+/// a slot's value once it has been chosen in the ballot they accepted it in. An
+/// acceptor which has heard from a leader recently ignores other nodes' prepares,
+/// which keeps the leader in place while it is up.
+///
+/// Requests are forwarded to the leader and answered by the node which took them, as
+/// with `raft`. Slots are never compacted and acceptor state is not persisted, so
+/// this suits tests and comparisons rather than long runs.
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde_json::{json, Value};
+
+use crate::consensus::raft::Role;
+use crate::consensus::{Consensus, StateMachine};
+use crate::errors;
+use crate::node::{Forwarder, Process, FORWARD_EXPIRE_TICKS};
+use crate::rpc::paxos::{self, Ballot, PaxosMessageBody};
+use crate::rpc::raft::ClientRequest;
+
+/// Shortest election timeout; each timeout is drawn from [this, twice this)
+const ELECTION_TIMEOUT_TICKS: u64 = 6;
+
+/// Most proposals sent in one accept
+const MAX_ACCEPT_PROPOSALS: usize = 64;
+
+pub struct MultiPaxos<S: StateMachine> {
+    node_id: String,
+    node_ids: Vec<String>,
+    state: S,
+    role: Role,
+    leader: Option<String>,
+    // Acceptor: the highest ballot promised, and the latest proposal accepted per slot
+    promised: Ballot,
+    accepted: BTreeMap<u64, paxos::AcceptedProposal<S::Request>>,
+    // Learner: every slot up to here is chosen and applied
+    executed: u64,
+    // Proposer: our ballot, as candidate or leader
+    ballot: Ballot,
+    prepare_from: u64,
+    promises: HashMap<String, Vec<paxos::AcceptedProposal<S::Request>>>,
+    next_slot: u64,
+    // Who has accepted each of our proposals which is not yet executed
+    acks: HashMap<u64, HashSet<String>>,
+    peer_executed: HashMap<String, u64>,
+    // Requests to propose on the next tick, with the messages they came in
+    batch: Vec<(Value, S::Request)>,
+    ticks_since_heard: u64,
+    election_timeout: u64,
+    rng: StdRng,
+    forwarder: Forwarder,
+    last_msg_id: u64,
+    outbox: Vec<Value>,
+}
+
+impl<S: StateMachine> MultiPaxos<S> {
+    pub fn new(node_id: String, node_ids: Vec<String>, state: S) -> Self {
+        let mut forwarder = Forwarder::new(FORWARD_EXPIRE_TICKS);
+        forwarder.set_node_id(node_id.clone());
+        let position = node_ids.iter().position(|id| *id == node_id).unwrap_or(0);
+        let mut paxos = Self {
+            node_id,
+            node_ids,
+            state,
+            role: Role::Follower,
+            leader: None,
+            promised: Ballot::default(),
+            accepted: BTreeMap::new(),
+            executed: 0,
+            ballot: Ballot::default(),
+            prepare_from: 1,
+            promises: HashMap::new(),
+            next_slot: 1,
+            acks: HashMap::new(),
+            peer_executed: HashMap::new(),
+            batch: vec![],
+            ticks_since_heard: 0,
+            election_timeout: ELECTION_TIMEOUT_TICKS,
+            rng: StdRng::seed_from_u64(position as u64),
+            forwarder,
+            last_msg_id: 0,
+            outbox: vec![],
+        };
+        paxos.reset_election_timer();
+        paxos
+    }
+
+    /// Vary the election timeouts (e.g. from one simulation to the next)
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        let position = self.node_ids.iter().position(|id| *id == self.node_id);
+        self.rng = StdRng::seed_from_u64(seed ^ position.unwrap_or(0) as u64);
+        self.reset_election_timer();
+        self
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn ballot(&self) -> &Ballot {
+        &self.ballot
+    }
+
+    /// Every slot up to here is chosen and applied
+    pub fn executed(&self) -> u64 {
+        self.executed
+    }
+
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    fn next_msg_id(&mut self) -> u64 {
+        self.last_msg_id += 1;
+        self.last_msg_id
+    }
+
+    fn quorum(&self) -> usize {
+        self.node_ids.len() / 2 + 1
+    }
+
+    fn is_quorum<'a>(&self, nodes: impl Iterator<Item = &'a String>) -> bool {
+        nodes.filter(|id| self.node_ids.contains(id)).count() >= self.quorum()
+    }
+
+    fn peers(&self) -> Vec<String> {
+        self.node_ids
+            .iter()
+            .filter(|id| **id != self.node_id)
+            .cloned()
+            .collect()
+    }
+
+    fn send(&mut self, dest: &str, body: PaxosMessageBody<S::Request>) {
+        let msg = paxos::PaxosMessage {
+            src: self.node_id.clone(),
+            dest: dest.to_string(),
+            body,
+        };
+        match serde_json::to_value(&msg) {
+            Ok(msg) => self.outbox.push(msg),
+            Err(err) => eprintln!("{:?}", err),
+        }
+    }
+
+    fn reply(&mut self, dest: &str, body: Value) {
+        self.outbox.push(json!({
+            "src": self.node_id,
+            "dest": dest,
+            "body": body,
+        }));
+    }
+
+    fn refuse(&mut self, msg: &Value, reason: &str) {
+        let error = errors::ErrorMsg::new(
+            msg["body"]["msg_id"].as_u64(),
+            errors::ErrorType::TemporarilyUnavailable,
+            reason.to_string(),
+        );
+        match serde_json::to_value(error) {
+            Ok(body) => self.reply(msg["src"].as_str().unwrap_or_default(), body),
+            Err(err) => eprintln!("{:?}", err),
+        }
+    }
+
+    fn reset_election_timer(&mut self) {
+        self.ticks_since_heard = 0;
+        self.election_timeout = self
+            .rng
+            .gen_range(ELECTION_TIMEOUT_TICKS..2 * ELECTION_TIMEOUT_TICKS);
+    }
+
+    /// Stop leading (or standing), refusing the requests we had not yet proposed
+    fn step_down(&mut self) {
+        self.role = Role::Follower;
+        self.acks.clear();
+        for (msg, _) in std::mem::take(&mut self.batch) {
+            self.refuse(&msg, "no longer leader");
+        }
+    }
+
+    fn accepted_from(&self, from: u64) -> Vec<paxos::AcceptedProposal<S::Request>> {
+        self.accepted
+            .range(from..)
+            .map(|(_, accepted)| accepted.clone())
+            .collect()
+    }
+
+    /// Phase 1a, for every slot we do not know to be chosen
+    fn start_prepare(&mut self) {
+        self.step_down();
+        self.role = Role::Candidate;
+        self.leader = None;
+        self.reset_election_timer();
+        let round = self.promised.round.max(self.ballot.round) + 1;
+        self.ballot = Ballot::new(round, self.node_id.clone());
+        self.promised = self.ballot.clone();
+        self.prepare_from = self.executed + 1;
+        let ours = self.accepted_from(self.prepare_from);
+        self.promises = HashMap::from([(self.node_id.clone(), ours)]);
+        self.peer_executed.clear();
+        if self.is_quorum(self.promises.keys()) {
+            return self.become_leader();
+        }
+        let body = paxos::PrepareMsg {
+            ballot: self.ballot.clone(),
+            from: self.prepare_from,
+        };
+        for peer in self.peers() {
+            self.send(&peer, PaxosMessageBody::Prepare(body.clone()));
+        }
+    }
+
+    fn handle_prepare(&mut self, src: &str, msg: paxos::PrepareMsg) {
+        // A stable leader: ignore anyone else while the leader is being heard from
+        let heard = self.leader.as_deref().is_some_and(|leader| leader != src)
+            && self.ticks_since_heard < ELECTION_TIMEOUT_TICKS;
+        if self.role == Role::Leader || heard {
+            return;
+        }
+        let ok = msg.ballot >= self.promised;
+        if ok && msg.ballot > self.promised {
+            self.promised = msg.ballot.clone();
+            self.step_down();
+            self.leader = None;
+            self.reset_election_timer();
+        }
+        let body = paxos::PromiseMsg {
+            ballot: msg.ballot,
+            ok,
+            promised: self.promised.clone(),
+            accepted: match ok {
+                true => self.accepted_from(msg.from),
+                false => vec![],
+            },
+            executed: self.executed,
+        };
+        self.send(src, PaxosMessageBody::Promise(body));
+    }
+
+    fn handle_promise(&mut self, src: &str, msg: paxos::PromiseMsg<S::Request>) {
+        if self.role != Role::Candidate || msg.ballot != self.ballot {
+            return;
+        }
+        if !msg.ok {
+            self.promised = self.promised.clone().max(msg.promised);
+            return self.step_down();
+        }
+        self.peer_executed.insert(src.to_string(), msg.executed);
+        self.promises.insert(src.to_string(), msg.accepted);
+        if self.is_quorum(self.promises.keys()) {
+            self.become_leader();
+        }
+    }
+
+    /// Phase 1 is done: re-propose whatever may have been chosen, and fill the gaps
+    fn become_leader(&mut self) {
+        self.role = Role::Leader;
+        self.leader = Some(self.node_id.clone());
+        self.acks.clear();
+        let mut recovered: BTreeMap<u64, paxos::AcceptedProposal<S::Request>> = BTreeMap::new();
+        for accepted in std::mem::take(&mut self.promises).into_values().flatten() {
+            match recovered.get(&accepted.proposal.slot) {
+                Some(highest) if highest.ballot >= accepted.ballot => (),
+                _ => {
+                    recovered.insert(accepted.proposal.slot, accepted);
+                }
+            }
+        }
+        let last = recovered.keys().last().copied().unwrap_or(0);
+        self.next_slot = self.prepare_from.max(last + 1);
+        for slot in self.prepare_from..self.next_slot {
+            let request = recovered
+                .remove(&slot)
+                .and_then(|accepted| accepted.proposal.request);
+            self.propose(slot, request);
+        }
+        self.advance_executed();
+        self.broadcast_accept();
+    }
+
+    /// Accept our own proposal for `slot`
+    fn propose(&mut self, slot: u64, request: Option<ClientRequest<S::Request>>) {
+        let accepted = paxos::AcceptedProposal {
+            ballot: self.ballot.clone(),
+            proposal: paxos::Proposal { slot, request },
+        };
+        self.accepted.insert(slot, accepted);
+        self.acks
+            .insert(slot, HashSet::from([self.node_id.clone()]));
+    }
+
+    /// Give each request batched since the last tick a slot of its own
+    fn propose_batch(&mut self) {
+        for (msg, body) in std::mem::take(&mut self.batch) {
+            let request = ClientRequest {
+                origin: self.node_id.clone(),
+                client: msg["src"].as_str().unwrap_or_default().to_string(),
+                body,
+            };
+            self.propose(self.next_slot, Some(request));
+            self.next_slot += 1;
+        }
+        // A lone node needs nobody else's acceptance
+        self.advance_executed();
+    }
+
+    fn send_accept(&mut self, peer: &str) {
+        let from = self.peer_executed.get(peer).unwrap_or(&0) + 1;
+        let proposals = self
+            .accepted
+            .range(from..self.next_slot)
+            .take(MAX_ACCEPT_PROPOSALS)
+            .map(|(_, accepted)| accepted.proposal.clone())
+            .collect();
+        let body = paxos::AcceptMsg {
+            ballot: self.ballot.clone(),
+            proposals,
+            executed: self.executed,
+        };
+        self.send(peer, PaxosMessageBody::Accept(body));
+    }
+
+    fn broadcast_accept(&mut self) {
+        for peer in self.peers() {
+            self.send_accept(&peer);
+        }
+    }
+
+    fn handle_accept(&mut self, src: &str, msg: paxos::AcceptMsg<S::Request>) {
+        if msg.ballot < self.promised {
+            let body = paxos::AcceptedMsg {
+                ballot: msg.ballot,
+                ok: false,
+                promised: self.promised.clone(),
+                slots: vec![],
+                executed: self.executed,
+            };
+            return self.send(src, PaxosMessageBody::Accepted(body));
+        }
+        if self.role != Role::Follower {
+            self.step_down();
+        }
+        self.promised = msg.ballot.clone();
+        self.leader = Some(src.to_string());
+        self.reset_election_timer();
+        let mut slots = vec![];
+        for proposal in msg.proposals {
+            slots.push(proposal.slot);
+            let accepted = paxos::AcceptedProposal {
+                ballot: msg.ballot.clone(),
+                proposal,
+            };
+            self.accepted.insert(accepted.proposal.slot, accepted);
+        }
+        // What we accepted in the leader's ballot, it has chosen up to its executed index
+        while self.executed < msg.executed {
+            match self.accepted.get(&(self.executed + 1)) {
+                Some(accepted) if accepted.ballot == msg.ballot => self.execute_next(),
+                _ => break,
+            }
+        }
+        let body = paxos::AcceptedMsg {
+            ballot: msg.ballot,
+            ok: true,
+            promised: self.promised.clone(),
+            slots,
+            executed: self.executed,
+        };
+        self.send(src, PaxosMessageBody::Accepted(body));
+    }
+
+    fn handle_accepted(&mut self, src: &str, msg: paxos::AcceptedMsg) {
+        if self.role != Role::Leader || msg.ballot != self.ballot {
+            return;
+        }
+        if !msg.ok {
+            self.promised = self.promised.clone().max(msg.promised);
+            self.leader = None;
+            return self.step_down();
+        }
+        let executed = self.peer_executed.entry(src.to_string()).or_insert(0);
+        *executed = msg.executed.max(*executed);
+        for slot in msg.slots {
+            if let Some(acks) = self.acks.get_mut(&slot) {
+                acks.insert(src.to_string());
+            }
+        }
+        self.advance_executed();
+    }
+
+    /// Execute our proposals in order, for as long as a majority has accepted them
+    fn advance_executed(&mut self) {
+        while let Some(acks) = self.acks.get(&(self.executed + 1)) {
+            if !self.is_quorum(acks.iter()) {
+                break;
+            }
+            self.acks.remove(&(self.executed + 1));
+            self.execute_next();
+        }
+    }
+
+    /// Apply the next slot, which is chosen, answering the request if we took it
+    fn execute_next(&mut self) {
+        self.executed += 1;
+        let request = self
+            .accepted
+            .get(&self.executed)
+            .and_then(|accepted| accepted.proposal.request.clone());
+        if let Some(request) = request {
+            let msg_id = self.next_msg_id();
+            let body = self.state.apply(&request.body, msg_id);
+            if request.origin == self.node_id {
+                self.reply(&request.client, body);
+            }
+        }
+    }
+
+    fn handle_client_request(&mut self, msg: &Value, request: S::Request) {
+        if self.role == Role::Leader {
+            return self.batch.push((msg.clone(), request));
+        }
+        let src = msg["src"].as_str().unwrap_or_default().to_string();
+        match self.leader.clone() {
+            Some(leader) if !self.node_ids.contains(&src) => {
+                let forwarded = self.forwarder.forward_msg(msg, &leader);
+                self.outbox.push(forwarded);
+            }
+            _ => self.refuse(msg, "no leader"),
+        }
+    }
+
+    fn handle_paxos_message(&mut self, msg: paxos::PaxosMessage<S::Request>) {
+        match msg.body {
+            PaxosMessageBody::Prepare(body) => self.handle_prepare(&msg.src, body),
+            PaxosMessageBody::Promise(body) => self.handle_promise(&msg.src, body),
+            PaxosMessageBody::Accept(body) => self.handle_accept(&msg.src, body),
+            PaxosMessageBody::Accepted(body) => self.handle_accepted(&msg.src, body),
+        }
+    }
+}
+
+impl<S: StateMachine> Process for MultiPaxos<S> {
+    fn on_message(&mut self, msg: Value) -> Vec<Value> {
+        let msg_type = msg["body"]["type"].as_str().unwrap_or_default();
+        if paxos::MESSAGE_TYPES.contains(&msg_type) {
+            match serde_json::from_value::<paxos::PaxosMessage<S::Request>>(msg) {
+                Ok(msg) => self.handle_paxos_message(msg),
+                Err(err) => eprintln!("{:?}", err),
+            }
+        } else if let Some(reply) = self.forwarder.complete_msg(&msg) {
+            self.outbox.push(reply);
+        } else if let Some(request) = S::parse_request(&msg["body"]) {
+            self.handle_client_request(&msg, request);
+        }
+        std::mem::take(&mut self.outbox)
+    }
+
+    fn on_tick(&mut self) -> Vec<Value> {
+        self.forwarder.tick();
+        if self.role == Role::Leader {
+            self.propose_batch();
+            self.broadcast_accept();
+        } else {
+            self.ticks_since_heard += 1;
+            if self.ticks_since_heard >= self.election_timeout {
+                self.start_prepare();
+            }
+        }
+        std::mem::take(&mut self.outbox)
+    }
+}
+
+impl<S: StateMachine + Send> Consensus for MultiPaxos<S> {
+    fn leader(&self) -> Option<&str> {
+        self.leader.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::algorithms::lin_kv::KvState;
+    use crate::sim::linearizability::{self, KvClients, Outcome};
+    use crate::sim::{node_ids, random_partitions, Simulation};
+
+    fn cluster(size: usize, seed: u64) -> Simulation<MultiPaxos<KvState>> {
+        let node_ids = node_ids(size);
+        let processes = node_ids
+            .iter()
+            .map(|id| {
+                let paxos =
+                    MultiPaxos::new(id.clone(), node_ids.clone(), KvState::new()).with_seed(seed);
+                (id.clone(), paxos)
+            })
+            .collect();
+        Simulation::new(seed, processes)
+    }
+
+    fn leaders(sim: &Simulation<MultiPaxos<KvState>>) -> Vec<String> {
+        sim.processes()
+            .filter(|(_, paxos)| paxos.role() == Role::Leader)
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+
+    fn assert_all_agree(sim: &Simulation<MultiPaxos<KvState>>) {
+        let (_, first) = sim.processes().next().unwrap();
+        for (_, paxos) in sim.processes() {
+            assert_eq!(paxos.executed(), first.executed());
+            assert_eq!(paxos.state(), first.state());
+        }
+    }
+
+    #[test]
+    fn test_elects_one_stable_leader() {
+        let mut sim = cluster(3, 1);
+        sim.run_for(3000);
+        let leader = leaders(&sim);
+        assert_eq!(leader.len(), 1);
+        let ballot = sim.process(&leader[0]).unwrap().ballot().clone();
+        sim.run_for(10_000);
+        assert_eq!(leaders(&sim), leader);
+        assert_eq!(sim.process(&leader[0]).unwrap().ballot(), &ballot);
+        for (_, paxos) in sim.processes() {
+            assert_eq!(paxos.leader(), Some(leader[0].as_str()));
+        }
+    }
+
+    #[test]
+    fn test_requests_between_ticks_are_batched() {
+        let node_ids: Vec<String> = ["n1", "n2", "n3"].map(String::from).to_vec();
+        let mut paxos = MultiPaxos::new("n1".to_string(), node_ids, KvState::new());
+        while paxos.role() != Role::Candidate {
+            paxos.on_tick();
+        }
+        let ballot = paxos.ballot().clone();
+        paxos.on_message(json!({
+            "src": "n2",
+            "dest": "n1",
+            "body": {"type": "promise", "ballot": ballot, "ok": true, "promised": ballot, "accepted": [], "executed": 0}
+        }));
+        assert_eq!(paxos.role(), Role::Leader);
+        for key in 0..3 {
+            let out = paxos.on_message(json!({
+                "src": "c1",
+                "dest": "n1",
+                "body": {"type": "write", "key": key, "value": key, "msg_id": key}
+            }));
+            assert!(out.is_empty());
+        }
+        let out = paxos.on_tick();
+        assert_eq!(out.len(), 2);
+        for msg in out {
+            assert_eq!(msg["body"]["type"], "accept");
+            assert_eq!(msg["body"]["proposals"].as_array().unwrap().len(), 3);
+        }
+    }
+
+    #[test]
+    fn test_replicates_to_every_node() {
+        let mut sim = cluster(3, 2);
+        sim.run_for(2000);
+        let settings = KvClients {
+            duration_ms: 3000,
+            ..KvClients::default()
+        };
+        let history = linearizability::run_kv_clients(&mut sim, &settings, |_| ());
+        sim.run_for(1000);
+        linearizability::check(&history).unwrap();
+        let ok = history
+            .iter()
+            .filter(|op| matches!(op.outcome, Outcome::Ok(_)))
+            .count();
+        assert!(ok > 50, "only {} requests succeeded", ok);
+        assert_all_agree(&sim);
+    }
+
+    #[test]
+    fn test_linearizable_under_partitions_and_loss() {
+        for seed in 0..3 {
+            let mut sim = cluster(5, seed).with_loss(0.05).with_latency(10);
+            let settings = KvClients {
+                duration_ms: 10_000,
+                seed,
+                ..KvClients::default()
+            };
+            let mut history =
+                linearizability::run_kv_clients(&mut sim, &settings, random_partitions(seed));
+            sim.heal();
+            sim.run_for(3000);
+            let settings = KvClients {
+                duration_ms: 2000,
+                seed: seed + 100,
+                ..KvClients::default()
+            };
+            let after = linearizability::run_kv_clients(&mut sim, &settings, |_| ());
+            assert!(after.iter().any(|op| matches!(op.outcome, Outcome::Ok(_))));
+            history.extend(after);
+            linearizability::check(&history).unwrap();
+            sim.run_for(2000);
+            assert_all_agree(&sim);
+        }
+    }
+}
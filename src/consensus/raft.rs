@@ -0,0 +1,1380 @@
+/// Raft: see the paper, "In Search of an Understandable Consensus Algorithm"
+/// https://raft.github.io/raft.pdf
+///
+/// Time is counted in `Command::Tick`s. A follower which hears nothing from a leader
+/// for its (randomised) election timeout stands as a candidate; the leader sends
+/// `append_entries` to every peer on each tick, carrying whatever part of its log
+/// the peer is missing, or nothing as a heartbeat. A new leader starts its term by
+/// appending a no-op, so that entries from earlier terms get committed promptly.
+///
+/// Client requests are appended to the leader's log and answered once committed and
+/// applied, so reads go through the log as well. A node which is not the leader
+/// forwards requests to the leader it knows of, or answers TemporarilyUnavailable
+/// if it knows of none. Requests are forwarded one hop at most: a node which is
+/// sent a request by another node and is not leader itself refuses it.
+///
+/// Every `snapshot_entries` applied entries we snapshot the state machine and drop
+/// the log up to that point. A follower which needs entries the leader no longer
+/// has is sent the leader's snapshot with `install_snapshot` instead. Snapshots are
+/// also handed to a `SnapshotStore`, which may keep them on disk. Terms and votes
+/// are not persisted, so this is not enough to restart a node safely mid-run.
+///
+/// Membership changes one server at a time, as in chapter 4 of Ongaro's thesis:
+/// any two majorities of configurations which differ by one server overlap, so a
+/// node can use each new configuration as soon as it is in its log. An `add_server`
+/// first replicates to the new node as a non-voting learner until it has caught up,
+/// so that the cluster does not wait on it once it counts towards a majority. Only
+/// one change may be under way at a time, and not until the leader has committed
+/// an entry in its term. A leader which removes itself steps down once the change
+/// is committed. A node joining a running cluster starts with an empty
+/// configuration and never stands for election until it is added.
+///
+/// Reads can skip the log (section 6.4 of the thesis). With `ReadMode::ReadIndex`
+/// the leader notes its commit index, then waits for a majority to answer a round
+/// of append_entries sent after the read arrived: if they do, it was still leader
+/// when the read arrived, and it serves the read once it has applied up to the
+/// index it noted. With `ReadMode::Lease` a majority answering a round also gives
+/// the leader a lease of `LEASE_TICKS` from when the round was sent, during which
+/// it serves reads straight away. This relies on each node refusing to vote for
+/// `ELECTION_TIMEOUT_TICKS` after hearing from a leader, and on the nodes' clocks
+/// (that is, their ticks) not drifting apart by more than the difference. Either
+/// way a leader serves no reads until it has committed an entry in its term.
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde_json::{json, Value};
+
+use crate::consensus::snapshot::{MemorySnapshots, SnapshotStore};
+use crate::consensus::{Consensus, StateMachine};
+use crate::errors;
+use crate::node::{Forwarder, Process, FORWARD_EXPIRE_TICKS};
+use crate::rpc::raft::{self, RaftMessageBody};
+use crate::workload::ReadMode;
+
+/// Shortest election timeout; each timeout is drawn from [this, twice this)
+const ELECTION_TIMEOUT_TICKS: u64 = 6;
+
+/// Most entries sent in one append_entries
+const MAX_APPEND_ENTRIES: usize = 64;
+
+/// Default number of applied entries between snapshots
+const SNAPSHOT_ENTRIES: u64 = 1000;
+
+/// An added node which has not caught up after this many ticks is not added
+const CATCH_UP_TICKS: u64 = 40;
+
+/// How long a lease lasts from when the round which confirmed it was sent. This is
+/// less than ELECTION_TIMEOUT_TICKS to allow for ticks landing at different moments
+/// on different nodes, and for some drift between their clocks.
+const LEASE_TICKS: u64 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// An `add_server` waiting for its node to catch up before the configuration changes
+struct CatchUp {
+    request: Value,
+    node_id: String,
+    ticks: u64,
+}
+
+/// A read waiting for the leader to confirm it is still leader, or to catch up
+struct PendingRead<R> {
+    msg: Value,
+    request: R,
+    read_index: u64,
+    // Answers to this round of append_entries (or a later one) confirm the leader
+    read_round: u64,
+}
+
+pub struct Raft<S: StateMachine> {
+    node_id: String,
+    // The current configuration: from the latest change in the log, or base_config
+    node_ids: Vec<String>,
+    // The configuration as of snapshot_index
+    base_config: Vec<String>,
+    // Every node we have seen in a configuration; requests from these are not forwarded
+    known_nodes: HashSet<String>,
+    catch_up: Option<CatchUp>,
+    state: S,
+    role: Role,
+    term: u64,
+    voted_for: Option<String>,
+    leader: Option<String>,
+    // log[i] holds the entry at index snapshot_index + i + 1
+    log: Vec<raft::Entry<S::Request>>,
+    // The log up to snapshot_index has been compacted into `snapshot`
+    snapshot: Option<raft::Snapshot<S::Snapshot>>,
+    snapshot_index: u64,
+    snapshot_term: u64,
+    snapshot_entries: u64,
+    snapshots: Box<dyn SnapshotStore<S::Snapshot>>,
+    commit_index: u64,
+    last_applied: u64,
+    votes: HashSet<String>,
+    next_index: HashMap<String, u64>,
+    match_index: HashMap<String, u64>,
+    ticks_since_heard: u64,
+    election_timeout: u64,
+    rng: StdRng,
+    forwarder: Forwarder,
+    last_msg_id: u64,
+    outbox: Vec<Value>,
+    read_mode: ReadMode,
+    reads: Vec<PendingRead<S::Request>>,
+    // The latest round of append_entries we have sent, and the tick each was sent on
+    read_round: u64,
+    round_ticks: BTreeMap<u64, u64>,
+    // The latest round each peer has answered in this term
+    acked_rounds: HashMap<String, u64>,
+    // Index of the no-op which started our term as leader
+    term_start: u64,
+    ticks: u64,
+    lease_until: u64,
+}
+
+impl<S: StateMachine> Raft<S> {
+    pub fn new(node_id: String, node_ids: Vec<String>, state: S) -> Self {
+        let mut forwarder = Forwarder::new(FORWARD_EXPIRE_TICKS);
+        forwarder.set_node_id(node_id.clone());
+        let position = node_ids.iter().position(|id| *id == node_id).unwrap_or(0);
+        let mut raft = Self {
+            node_id,
+            base_config: node_ids.clone(),
+            known_nodes: node_ids.iter().cloned().collect(),
+            catch_up: None,
+            node_ids,
+            state,
+            role: Role::Follower,
+            term: 0,
+            voted_for: None,
+            leader: None,
+            log: vec![],
+            snapshot: None,
+            snapshot_index: 0,
+            snapshot_term: 0,
+            snapshot_entries: SNAPSHOT_ENTRIES,
+            snapshots: Box::new(MemorySnapshots::new()),
+            commit_index: 0,
+            last_applied: 0,
+            votes: HashSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            ticks_since_heard: 0,
+            election_timeout: ELECTION_TIMEOUT_TICKS,
+            rng: StdRng::seed_from_u64(position as u64),
+            forwarder,
+            last_msg_id: 0,
+            outbox: vec![],
+            read_mode: ReadMode::Log,
+            reads: vec![],
+            read_round: 0,
+            round_ticks: BTreeMap::new(),
+            acked_rounds: HashMap::new(),
+            term_start: 0,
+            ticks: 0,
+            lease_until: 0,
+        };
+        raft.reset_election_timer();
+        raft
+    }
+
+    /// Vary the election timeouts (e.g. from one simulation to the next)
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        let position = self.node_ids.iter().position(|id| *id == self.node_id);
+        self.rng = StdRng::seed_from_u64(seed ^ position.unwrap_or(0) as u64);
+        self.reset_election_timer();
+        self
+    }
+
+    pub fn with_read_mode(mut self, read_mode: ReadMode) -> Self {
+        self.read_mode = read_mode;
+        self
+    }
+
+    pub fn with_snapshot_entries(mut self, snapshot_entries: u64) -> Self {
+        self.snapshot_entries = snapshot_entries.max(1);
+        self
+    }
+
+    /// Keep snapshots in `snapshots`, starting from the one already there (if any)
+    pub fn with_snapshot_store(mut self, snapshots: Box<dyn SnapshotStore<S::Snapshot>>) -> Self {
+        self.snapshots = snapshots;
+        match self.snapshots.load() {
+            Ok(Some(snapshot)) => self.install(snapshot),
+            Ok(None) => (),
+            Err(err) => eprintln!("{:?}", err),
+        }
+        self
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    pub fn commit_index(&self) -> u64 {
+        self.commit_index
+    }
+
+    /// The nodes in the current configuration
+    pub fn config(&self) -> &[String] {
+        &self.node_ids
+    }
+
+    /// Whether a configuration change is still to be committed
+    pub fn config_pending(&self) -> bool {
+        self.log
+            .iter()
+            .skip(self.position(self.commit_index + 1))
+            .any(|entry| entry.config.is_some())
+    }
+
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    /// Entries held in the log, i.e. not yet compacted into a snapshot
+    pub fn log_len(&self) -> usize {
+        self.log.len()
+    }
+
+    fn next_msg_id(&mut self) -> u64 {
+        self.last_msg_id += 1;
+        self.last_msg_id
+    }
+
+    fn last_index(&self) -> u64 {
+        self.snapshot_index + self.log.len() as u64
+    }
+
+    /// Position in `log` of the entry at `index`, which must be after the snapshot
+    fn position(&self, index: u64) -> usize {
+        (index - self.snapshot_index - 1) as usize
+    }
+
+    /// Term of the entry at `index`, which must be no earlier than the snapshot
+    fn term_at(&self, index: u64) -> u64 {
+        if index <= self.snapshot_index {
+            return self.snapshot_term;
+        }
+        self.log[self.position(index)].term
+    }
+
+    fn quorum(&self) -> usize {
+        self.node_ids.len() / 2 + 1
+    }
+
+    /// Whether `nodes` include a majority of the current configuration
+    fn is_quorum<'a>(&self, nodes: impl Iterator<Item = &'a String>) -> bool {
+        nodes.filter(|id| self.node_ids.contains(id)).count() >= self.quorum()
+    }
+
+    /// Everyone the leader replicates to: the configuration, and any node catching up
+    fn peers(&self) -> Vec<String> {
+        let learner = self.catch_up.as_ref().map(|catch_up| &catch_up.node_id);
+        self.node_ids
+            .iter()
+            .chain(learner)
+            .filter(|id| **id != self.node_id)
+            .cloned()
+            .collect()
+    }
+
+    /// The configuration as of `index`, which must be no earlier than the snapshot
+    fn config_at(&self, index: u64) -> Vec<String> {
+        self.log[..self.position(index + 1)]
+            .iter()
+            .rev()
+            .find_map(|entry| entry.config.as_ref())
+            .map(|change| change.node_ids.clone())
+            .unwrap_or_else(|| self.base_config.clone())
+    }
+
+    /// Take up the latest configuration in the log, after it has changed
+    fn refresh_config(&mut self) {
+        self.node_ids = self.config_at(self.last_index());
+        self.known_nodes.extend(self.node_ids.iter().cloned());
+    }
+
+    fn send(&mut self, dest: &str, body: RaftMessageBody<S::Request, S::Snapshot>) {
+        let msg = raft::RaftMessage {
+            src: self.node_id.clone(),
+            dest: dest.to_string(),
+            body,
+        };
+        match serde_json::to_value(&msg) {
+            Ok(msg) => self.outbox.push(msg),
+            Err(err) => eprintln!("{:?}", err),
+        }
+    }
+
+    fn reply(&mut self, dest: &str, body: Value) {
+        self.outbox.push(json!({
+            "src": self.node_id,
+            "dest": dest,
+            "body": body,
+        }));
+    }
+
+    fn reset_election_timer(&mut self) {
+        self.ticks_since_heard = 0;
+        self.election_timeout = self
+            .rng
+            .gen_range(ELECTION_TIMEOUT_TICKS..2 * ELECTION_TIMEOUT_TICKS);
+    }
+
+    /// Anyone with a later term than ours makes us a follower in that term
+    fn observe_term(&mut self, term: u64) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.role = Role::Follower;
+            self.leader = None;
+        }
+    }
+
+    fn start_election(&mut self) {
+        self.term += 1;
+        self.role = Role::Candidate;
+        self.voted_for = Some(self.node_id.clone());
+        self.votes = HashSet::from([self.node_id.clone()]);
+        self.leader = None;
+        self.reset_election_timer();
+        if self.is_quorum(self.votes.iter()) {
+            return self.become_leader();
+        }
+        let body = raft::RequestVoteMsg {
+            term: self.term,
+            last_log_index: self.last_index(),
+            last_log_term: self.term_at(self.last_index()),
+        };
+        for peer in self.peers() {
+            self.send(&peer, RaftMessageBody::RequestVote(body.clone()));
+        }
+    }
+
+    fn become_leader(&mut self) {
+        self.role = Role::Leader;
+        self.leader = Some(self.node_id.clone());
+        self.next_index.clear();
+        self.match_index.clear();
+        self.acked_rounds.clear();
+        self.lease_until = 0;
+        for peer in self.peers() {
+            self.next_index.insert(peer.clone(), self.last_index() + 1);
+            self.match_index.insert(peer, 0);
+        }
+        self.log.push(raft::Entry {
+            term: self.term,
+            request: None,
+            config: None,
+        });
+        self.term_start = self.last_index();
+        self.advance_commit();
+        self.broadcast_append();
+    }
+
+    fn send_append(&mut self, peer: &str) {
+        let next = *self.next_index.get(peer).unwrap_or(&1);
+        if next <= self.snapshot_index {
+            if let Some(snapshot) = self.snapshot.clone() {
+                let body = raft::InstallSnapshotMsg {
+                    term: self.term,
+                    snapshot,
+                };
+                return self.send(peer, RaftMessageBody::InstallSnapshot(body));
+            }
+        }
+        let prev_log_index = next - 1;
+        let entries = self
+            .log
+            .iter()
+            .skip(self.position(next))
+            .take(MAX_APPEND_ENTRIES)
+            .cloned()
+            .collect();
+        let body = raft::AppendEntriesMsg {
+            term: self.term,
+            prev_log_index,
+            prev_log_term: self.term_at(prev_log_index),
+            entries,
+            leader_commit: self.commit_index,
+            read_round: self.read_round,
+        };
+        self.send(peer, RaftMessageBody::AppendEntries(body));
+    }
+
+    fn broadcast_append(&mut self) {
+        self.read_round += 1;
+        self.round_ticks.insert(self.read_round, self.ticks);
+        for peer in self.peers() {
+            self.send_append(&peer);
+        }
+    }
+
+    fn handle_request_vote(&mut self, src: &str, msg: &raft::RequestVoteMsg) {
+        let last_term = self.term_at(self.last_index());
+        let up_to_date = msg.last_log_term > last_term
+            || (msg.last_log_term == last_term && msg.last_log_index >= self.last_index());
+        let free = match &self.voted_for {
+            None => true,
+            Some(candidate) => candidate == src,
+        };
+        let vote_granted = msg.term == self.term && free && up_to_date;
+        if vote_granted {
+            self.voted_for = Some(src.to_string());
+            self.reset_election_timer();
+        }
+        let body = raft::RequestVoteResponseMsg {
+            term: self.term,
+            vote_granted,
+        };
+        self.send(src, RaftMessageBody::RequestVoteOk(body));
+    }
+
+    fn handle_request_vote_ok(&mut self, src: &str, msg: &raft::RequestVoteResponseMsg) {
+        if self.role != Role::Candidate || msg.term != self.term || !msg.vote_granted {
+            return;
+        }
+        self.votes.insert(src.to_string());
+        if self.is_quorum(self.votes.iter()) {
+            self.become_leader();
+        }
+    }
+
+    fn handle_append_entries(&mut self, src: &str, mut msg: raft::AppendEntriesMsg<S::Request>) {
+        // Only answer the round for a leader of our own term, which we now follow
+        let read_round = match msg.term < self.term {
+            true => 0,
+            false => msg.read_round,
+        };
+        let refuse = |raft: &mut Self, next_index: u64| {
+            let body = raft::AppendEntriesResponseMsg {
+                term: raft.term,
+                success: false,
+                match_index: 0,
+                next_index,
+                read_round,
+            };
+            raft.send(src, RaftMessageBody::AppendEntriesOk(body));
+        };
+        if msg.term < self.term {
+            return refuse(self, 0);
+        }
+        self.role = Role::Follower;
+        self.leader = Some(src.to_string());
+        self.reset_election_timer();
+        if msg.prev_log_index < self.snapshot_index {
+            // Everything in our snapshot is committed, so it matches the leader's log
+            let skip = (self.snapshot_index - msg.prev_log_index) as usize;
+            msg.entries.drain(..skip.min(msg.entries.len()));
+            msg.prev_log_index = self.snapshot_index;
+            msg.prev_log_term = self.snapshot_term;
+        }
+        if msg.prev_log_index > self.last_index() {
+            return refuse(self, self.last_index() + 1);
+        }
+        let conflict_term = self.term_at(msg.prev_log_index);
+        if conflict_term != msg.prev_log_term {
+            // Skip back over the whole conflicting term at once
+            let mut next = msg.prev_log_index;
+            while next > self.snapshot_index + 1 && self.term_at(next - 1) == conflict_term {
+                next -= 1;
+            }
+            return refuse(self, next);
+        }
+        let match_index = msg.prev_log_index + msg.entries.len() as u64;
+        let mut config_changed = false;
+        for (index, entry) in (msg.prev_log_index + 1..).zip(msg.entries) {
+            if index <= self.last_index() {
+                if self.term_at(index) == entry.term {
+                    continue;
+                }
+                // Only uncommitted entries can conflict with the leader's log
+                self.log.truncate(self.position(index));
+                config_changed = true;
+            }
+            config_changed |= entry.config.is_some();
+            self.log.push(entry);
+        }
+        if config_changed {
+            self.refresh_config();
+        }
+        if msg.leader_commit > self.commit_index {
+            // A delayed, shorter append may match less than we have already committed
+            self.commit_index = self.commit_index.max(msg.leader_commit.min(match_index));
+            self.apply_committed();
+        }
+        let body = raft::AppendEntriesResponseMsg {
+            term: self.term,
+            success: true,
+            match_index,
+            next_index: match_index + 1,
+            read_round,
+        };
+        self.send(src, RaftMessageBody::AppendEntriesOk(body));
+    }
+
+    fn handle_append_entries_ok(&mut self, src: &str, msg: &raft::AppendEntriesResponseMsg) {
+        if self.role != Role::Leader || msg.term != self.term {
+            return;
+        }
+        // Even a refusal shows that src took us for leader when it answered
+        let acked = self.acked_rounds.entry(src.to_string()).or_insert(0);
+        *acked = msg.read_round.max(*acked);
+        let matched = *self.match_index.get(src).unwrap_or(&0);
+        let next = *self.next_index.get(src).unwrap_or(&1);
+        if msg.success {
+            self.match_index
+                .insert(src.to_string(), matched.max(msg.match_index));
+            self.next_index
+                .insert(src.to_string(), next.max(msg.match_index + 1));
+            self.advance_commit();
+        } else {
+            let next = msg.next_index.min(next).max(matched + 1);
+            self.next_index.insert(src.to_string(), next);
+            self.send_append(src);
+        }
+        self.confirm_rounds();
+    }
+
+    fn handle_install_snapshot(&mut self, src: &str, msg: raft::InstallSnapshotMsg<S::Snapshot>) {
+        if msg.term >= self.term {
+            self.role = Role::Follower;
+            self.leader = Some(src.to_string());
+            self.reset_election_timer();
+            if msg.snapshot.last_included_index > self.commit_index {
+                self.install(msg.snapshot.clone());
+                if let Err(err) = self.snapshots.save(&msg.snapshot) {
+                    eprintln!("{:?}", err);
+                }
+            }
+        }
+        let body = raft::InstallSnapshotResponseMsg {
+            term: self.term,
+            match_index: match msg.term < self.term {
+                true => 0,
+                false => msg.snapshot.last_included_index,
+            },
+        };
+        self.send(src, RaftMessageBody::InstallSnapshotOk(body));
+    }
+
+    /// Take on `snapshot`, keeping any of our log which follows on from it
+    fn install(&mut self, snapshot: raft::Snapshot<S::Snapshot>) {
+        let index = snapshot.last_included_index;
+        let follows_on = index > self.snapshot_index
+            && index < self.last_index()
+            && self.term_at(index) == snapshot.last_included_term;
+        if follows_on {
+            let drop = self.position(index) + 1;
+            self.log.drain(..drop);
+        } else {
+            self.log.clear();
+        }
+        self.snapshot_index = index;
+        self.snapshot_term = snapshot.last_included_term;
+        self.base_config = snapshot.node_ids.clone();
+        self.state.restore(snapshot.state.clone());
+        self.commit_index = self.commit_index.max(index);
+        self.last_applied = index;
+        self.snapshot = Some(snapshot);
+        self.refresh_config();
+    }
+
+    fn handle_install_snapshot_ok(&mut self, src: &str, msg: &raft::InstallSnapshotResponseMsg) {
+        if self.role != Role::Leader || msg.term != self.term {
+            return;
+        }
+        let matched = *self.match_index.get(src).unwrap_or(&0);
+        let next = *self.next_index.get(src).unwrap_or(&1);
+        self.match_index
+            .insert(src.to_string(), matched.max(msg.match_index));
+        self.next_index
+            .insert(src.to_string(), next.max(msg.match_index + 1));
+        self.advance_commit();
+    }
+
+    /// Snapshot the state machine if enough has been applied since the last time
+    fn maybe_compact(&mut self) {
+        if self.last_applied < self.snapshot_index + self.snapshot_entries {
+            return;
+        }
+        let snapshot = raft::Snapshot {
+            last_included_index: self.last_applied,
+            last_included_term: self.term_at(self.last_applied),
+            node_ids: self.config_at(self.last_applied),
+            state: self.state.snapshot(),
+        };
+        if let Err(err) = self.snapshots.save(&snapshot) {
+            eprintln!("{:?}", err);
+        }
+        let drop = self.position(self.last_applied) + 1;
+        self.log.drain(..drop);
+        self.snapshot_index = snapshot.last_included_index;
+        self.snapshot_term = snapshot.last_included_term;
+        self.base_config = snapshot.node_ids.clone();
+        self.snapshot = Some(snapshot);
+    }
+
+    /// Commit the latest entry from our own term which a majority holds
+    fn advance_commit(&mut self) {
+        for index in (self.commit_index + 1..=self.last_index()).rev() {
+            if self.term_at(index) != self.term {
+                break;
+            }
+            let holders = self
+                .match_index
+                .iter()
+                .filter(|(_, matched)| **matched >= index)
+                .map(|(peer, _)| peer);
+            if self.is_quorum(holders.chain([&self.node_id])) {
+                self.commit_index = index;
+                self.apply_committed();
+                break;
+            }
+        }
+    }
+
+    /// Apply committed entries in order, answering the requests we took from clients
+    fn apply_committed(&mut self) {
+        while self.last_applied < self.commit_index {
+            self.last_applied += 1;
+            let entry = &self.log[self.position(self.last_applied)];
+            if let Some(request) = entry.request.clone() {
+                let msg_id = self.next_msg_id();
+                let body = self.state.apply(&request.body, msg_id);
+                if request.origin == self.node_id {
+                    self.reply(&request.client, body);
+                }
+            } else if let Some(change) = entry.config.clone() {
+                if change.origin == self.node_id {
+                    let msg_id = self.next_msg_id();
+                    self.reply_with(&change.client, &change.request.reply(msg_id));
+                }
+                if self.role == Role::Leader && !change.node_ids.contains(&self.node_id) {
+                    self.role = Role::Follower;
+                    self.leader = None;
+                }
+            }
+        }
+        self.serve_reads();
+        self.maybe_compact();
+    }
+
+    fn handle_client_request(&mut self, msg: &Value, request: S::Request) {
+        let src = msg["src"].as_str().unwrap_or_default().to_string();
+        if self.role != Role::Leader {
+            return self.redirect(msg);
+        }
+        if self.read_mode != ReadMode::Log && S::is_read(&request) {
+            return self.handle_read(msg, request);
+        }
+        self.log.push(raft::Entry {
+            term: self.term,
+            request: Some(raft::ClientRequest {
+                origin: self.node_id.clone(),
+                client: src,
+                body: request,
+            }),
+            config: None,
+        });
+        // Peers hear about it on the next tick; a lone node commits straight away
+        self.advance_commit();
+    }
+
+    fn handle_read(&mut self, msg: &Value, request: S::Request) {
+        let started = self.commit_index >= self.term_start;
+        if self.read_mode == ReadMode::Lease && started && self.ticks < self.lease_until {
+            // Everything committed is applied as soon as it is committed
+            let msg_id = self.next_msg_id();
+            let body = self.state.apply(&request, msg_id);
+            let src = msg["src"].as_str().unwrap_or_default().to_string();
+            return self.reply(&src, body);
+        }
+        let read = PendingRead {
+            msg: msg.clone(),
+            request,
+            read_index: self.commit_index.max(self.term_start),
+            read_round: self.read_round + 1,
+        };
+        self.reads.push(read);
+        // Start the round now, unless one is still out: then it starts once that is in
+        if self.confirmed_round() >= self.read_round {
+            self.broadcast_append();
+            self.confirm_rounds();
+        }
+    }
+
+    /// The latest round of append_entries which a majority has answered
+    fn confirmed_round(&self) -> u64 {
+        let mut acked: Vec<u64> = self
+            .node_ids
+            .iter()
+            .map(|id| match *id == self.node_id {
+                true => self.read_round,
+                false => *self.acked_rounds.get(id).unwrap_or(&0),
+            })
+            .collect();
+        acked.sort_unstable_by(|a, b| b.cmp(a));
+        *acked.get(self.quorum() - 1).unwrap_or(&0)
+    }
+
+    /// Take the answers to our rounds so far: extend the lease, and serve any reads
+    /// which were waiting on them
+    fn confirm_rounds(&mut self) {
+        let confirmed = self.confirmed_round();
+        if let Some(sent) = self.round_ticks.get(&confirmed) {
+            self.lease_until = self.lease_until.max(sent + LEASE_TICKS);
+        }
+        self.round_ticks = self.round_ticks.split_off(&confirmed);
+        self.serve_reads();
+        let waiting = self
+            .reads
+            .iter()
+            .any(|read| read.read_round > self.read_round);
+        if waiting && confirmed >= self.read_round {
+            self.broadcast_append();
+        }
+    }
+
+    /// Answer reads which are confirmed and whose read index we have applied
+    fn serve_reads(&mut self) {
+        if self.reads.is_empty() {
+            return;
+        }
+        let confirmed = self.confirmed_round();
+        let (ready, waiting) = std::mem::take(&mut self.reads)
+            .into_iter()
+            .partition(|read| read.read_round <= confirmed && read.read_index <= self.last_applied);
+        self.reads = waiting;
+        for read in ready {
+            let msg_id = self.next_msg_id();
+            let body = self.state.apply(&read.request, msg_id);
+            let src = read.msg["src"].as_str().unwrap_or_default().to_string();
+            self.reply(&src, body);
+        }
+    }
+
+    /// Pass a request on to the leader, or refuse it if we cannot
+    fn redirect(&mut self, msg: &Value) {
+        let src = msg["src"].as_str().unwrap_or_default().to_string();
+        match self.leader.clone() {
+            Some(leader) if !self.known_nodes.contains(&src) => {
+                let forwarded = self.forwarder.forward_msg(msg, &leader);
+                self.outbox.push(forwarded);
+            }
+            _ => self.refuse(msg, "no leader"),
+        }
+    }
+
+    fn refuse(&mut self, msg: &Value, reason: &str) {
+        let error = errors::ErrorMsg::new(
+            msg["body"]["msg_id"].as_u64(),
+            errors::ErrorType::TemporarilyUnavailable,
+            reason.to_string(),
+        );
+        let src = msg["src"].as_str().unwrap_or_default().to_string();
+        self.reply_with(&src, &error);
+    }
+
+    fn reply_with<T: serde::Serialize>(&mut self, dest: &str, body: &T) {
+        match serde_json::to_value(body) {
+            Ok(body) => self.reply(dest, body),
+            Err(err) => eprintln!("{:?}", err),
+        }
+    }
+
+    /// A node which is no longer leader cannot serve the reads it was holding
+    fn drop_reads(&mut self) {
+        if self.role == Role::Leader {
+            return;
+        }
+        for read in std::mem::take(&mut self.reads) {
+            self.refuse(&read.msg, "no longer leader");
+        }
+    }
+
+    fn handle_membership_request(&mut self, msg: &Value, request: raft::MembershipRequest) {
+        if self.role != Role::Leader {
+            return self.redirect(msg);
+        }
+        let node_id = request.node_id().to_string();
+        let mut node_ids = self.node_ids.clone();
+        let member = node_ids.contains(&node_id);
+        match &request {
+            raft::MembershipRequest::AddServer(_) if !member => node_ids.push(node_id.clone()),
+            raft::MembershipRequest::RemoveServer(_) if member => {
+                node_ids.retain(|id| *id != node_id)
+            }
+            _ => {
+                // Already done
+                let msg_id = self.next_msg_id();
+                let src = msg["src"].as_str().unwrap_or_default().to_string();
+                return self.reply_with(&src, &request.reply(msg_id));
+            }
+        }
+        if self.catch_up.is_some() || self.config_pending() {
+            return self.refuse(msg, "membership change in progress");
+        }
+        if self.term_at(self.commit_index) != self.term {
+            return self.refuse(msg, "leader has not committed in its term");
+        }
+        if let raft::MembershipRequest::AddServer(_) = request {
+            self.known_nodes.insert(node_id.clone());
+            self.next_index
+                .insert(node_id.clone(), self.last_index() + 1);
+            self.match_index.insert(node_id.clone(), 0);
+            self.catch_up = Some(CatchUp {
+                request: msg.clone(),
+                node_id,
+                ticks: 0,
+            });
+            return;
+        }
+        self.change_config(msg, request, node_ids);
+    }
+
+    /// Append the configuration `node_ids`, which takes effect straight away
+    fn change_config(
+        &mut self,
+        msg: &Value,
+        request: raft::MembershipRequest,
+        node_ids: Vec<String>,
+    ) {
+        self.log.push(raft::Entry {
+            term: self.term,
+            request: None,
+            config: Some(raft::ConfigChange {
+                node_ids,
+                origin: self.node_id.clone(),
+                client: msg["src"].as_str().unwrap_or_default().to_string(),
+                request,
+            }),
+        });
+        self.refresh_config();
+        self.advance_commit();
+    }
+
+    /// Add a node which has caught up (to within one append_entries) to the configuration
+    fn check_catch_up(&mut self) {
+        let Some(catch_up) = self.catch_up.as_mut() else {
+            return;
+        };
+        catch_up.ticks += 1;
+        let matched = *self.match_index.get(&catch_up.node_id).unwrap_or(&0);
+        let timed_out = catch_up.ticks >= CATCH_UP_TICKS;
+        if matched + (MAX_APPEND_ENTRIES as u64) < self.last_index() {
+            if timed_out {
+                let catch_up = self.catch_up.take().unwrap();
+                self.refuse(&catch_up.request, "new node did not catch up");
+            }
+            return;
+        }
+        let catch_up = self.catch_up.take().unwrap();
+        let request = serde_json::from_value(catch_up.request["body"].clone());
+        match request {
+            Ok(request) => {
+                let mut node_ids = self.node_ids.clone();
+                node_ids.push(catch_up.node_id);
+                self.change_config(&catch_up.request, request, node_ids);
+            }
+            Err(err) => eprintln!("{:?}", err),
+        }
+    }
+
+    fn handle_raft_message(&mut self, msg: raft::RaftMessage<S::Request, S::Snapshot>) {
+        if let RaftMessageBody::RequestVote(_) = msg.body {
+            // Ignore candidates while a leader is about, so its lease holds
+            let heard = self.leader.is_some() && self.ticks_since_heard < ELECTION_TIMEOUT_TICKS;
+            if self.role == Role::Leader || heard {
+                return;
+            }
+        }
+        self.observe_term(msg.body.term());
+        match msg.body {
+            RaftMessageBody::RequestVote(body) => self.handle_request_vote(&msg.src, &body),
+            RaftMessageBody::RequestVoteOk(body) => self.handle_request_vote_ok(&msg.src, &body),
+            RaftMessageBody::AppendEntries(body) => self.handle_append_entries(&msg.src, body),
+            RaftMessageBody::AppendEntriesOk(body) => {
+                self.handle_append_entries_ok(&msg.src, &body)
+            }
+            RaftMessageBody::InstallSnapshot(body) => self.handle_install_snapshot(&msg.src, body),
+            RaftMessageBody::InstallSnapshotOk(body) => {
+                self.handle_install_snapshot_ok(&msg.src, &body)
+            }
+        }
+    }
+}
+
+impl<S: StateMachine> Process for Raft<S> {
+    fn on_message(&mut self, msg: Value) -> Vec<Value> {
+        let msg_type = msg["body"]["type"].as_str().unwrap_or_default();
+        if raft::MESSAGE_TYPES.contains(&msg_type) {
+            match serde_json::from_value::<raft::RaftMessage<S::Request, S::Snapshot>>(msg) {
+                Ok(msg) => self.handle_raft_message(msg),
+                Err(err) => eprintln!("{:?}", err),
+            }
+        } else if let Some(reply) = self.forwarder.complete_msg(&msg) {
+            self.outbox.push(reply);
+        } else if raft::MEMBERSHIP_TYPES.contains(&msg_type) {
+            match serde_json::from_value::<raft::MembershipRequest>(msg["body"].clone()) {
+                Ok(request) => self.handle_membership_request(&msg, request),
+                Err(err) => eprintln!("{:?}", err),
+            }
+        } else if let Some(request) = S::parse_request(&msg["body"]) {
+            self.handle_client_request(&msg, request);
+        }
+        self.drop_reads();
+        std::mem::take(&mut self.outbox)
+    }
+
+    fn on_tick(&mut self) -> Vec<Value> {
+        self.forwarder.tick();
+        self.ticks += 1;
+        if self.role == Role::Leader {
+            self.check_catch_up();
+            self.broadcast_append();
+        } else {
+            self.catch_up = None;
+            self.ticks_since_heard += 1;
+            // Nodes outside the configuration (e.g. not yet added) never stand
+            let member = self.node_ids.contains(&self.node_id);
+            if self.ticks_since_heard >= self.election_timeout && member {
+                self.start_election();
+            }
+        }
+        self.drop_reads();
+        std::mem::take(&mut self.outbox)
+    }
+}
+
+impl<S: StateMachine + Send> Consensus for Raft<S> {
+    fn leader(&self) -> Option<&str> {
+        self.leader.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::algorithms::lin_kv::KvState;
+    use crate::sim::linearizability::{self, KvClients, KvOp, Outcome};
+    use crate::sim::{node_ids, random_partitions, Simulation};
+
+    fn cluster(size: usize, seed: u64) -> Simulation<Raft<KvState>> {
+        cluster_with_snapshots(size, seed, SNAPSHOT_ENTRIES)
+    }
+
+    fn cluster_with_snapshots(
+        size: usize,
+        seed: u64,
+        snapshot_entries: u64,
+    ) -> Simulation<Raft<KvState>> {
+        cluster_reading(size, seed, snapshot_entries, ReadMode::Log)
+    }
+
+    fn cluster_reading(
+        size: usize,
+        seed: u64,
+        snapshot_entries: u64,
+        read_mode: ReadMode,
+    ) -> Simulation<Raft<KvState>> {
+        let node_ids = node_ids(size);
+        let processes = node_ids
+            .iter()
+            .map(|id| {
+                let raft = Raft::new(id.clone(), node_ids.clone(), KvState::new())
+                    .with_seed(seed)
+                    .with_snapshot_entries(snapshot_entries)
+                    .with_read_mode(read_mode);
+                (id.clone(), raft)
+            })
+            .collect();
+        Simulation::new(seed, processes)
+    }
+
+    /// `members` nodes configured from the start, then `joining` with no configuration
+    fn cluster_with_joiners(
+        members: usize,
+        joining: usize,
+        seed: u64,
+    ) -> Simulation<Raft<KvState>> {
+        let node_ids = node_ids(members);
+        let processes = (1..=members + joining)
+            .map(|i| {
+                let id = format!("n{}", i);
+                let config = if i <= members {
+                    node_ids.clone()
+                } else {
+                    vec![]
+                };
+                let raft = Raft::new(id.clone(), config, KvState::new())
+                    .with_seed(seed)
+                    .with_snapshot_entries(20);
+                (id, raft)
+            })
+            .collect();
+        Simulation::new(seed, processes)
+    }
+
+    fn membership_request(dest: &str, msg_type: &str, node_id: &str, msg_id: u64) -> Value {
+        json!({
+            "src": "admin",
+            "dest": dest,
+            "body": {"type": msg_type, "node_id": node_id, "msg_id": msg_id}
+        })
+    }
+
+    /// Whether a leader has committed a configuration of exactly `node_ids`
+    fn config_committed(sim: &Simulation<Raft<KvState>>, node_ids: &[&str]) -> bool {
+        let mut wanted: Vec<&str> = node_ids.to_vec();
+        wanted.sort();
+        sim.processes().any(|(_, raft)| {
+            let mut config: Vec<&str> = raft.config().iter().map(|id| id.as_str()).collect();
+            config.sort();
+            raft.role() == Role::Leader && !raft.config_pending() && config == wanted
+        })
+    }
+
+    /// Makes each change in turn, asking a different node every half second until
+    /// a leader has committed it. Returns how many changes are done so far.
+    fn membership_changes(
+        changes: Vec<(&'static str, &'static str)>,
+    ) -> impl FnMut(&mut Simulation<Raft<KvState>>) -> usize {
+        let mut config = vec!["n1", "n2", "n3"];
+        let mut done = 0;
+        let mut next_attempt = 0;
+        let mut msg_id = 0;
+        move |sim| {
+            while let Some((msg_type, node_id)) = changes.get(done) {
+                let mut target = config.clone();
+                match *msg_type {
+                    "add_server" => target.push(node_id),
+                    _ => target.retain(|id| id != node_id),
+                }
+                if !config_committed(sim, &target) {
+                    break;
+                }
+                config = target;
+                done += 1;
+            }
+            if done < changes.len() && sim.now() >= next_attempt {
+                let (msg_type, node_id) = changes[done];
+                let node_ids = sim.node_ids();
+                msg_id += 1;
+                let dest = &node_ids[msg_id as usize % node_ids.len()];
+                sim.send(membership_request(dest, msg_type, node_id, msg_id));
+                next_attempt = sim.now() + 500;
+            }
+            done
+        }
+    }
+
+    fn assert_all_agree(sim: &Simulation<Raft<KvState>>) {
+        let (_, first) = sim.processes().next().unwrap();
+        for (_, raft) in sim.processes() {
+            assert_eq!(raft.commit_index(), first.commit_index());
+            assert_eq!(raft.state(), first.state());
+        }
+    }
+
+    #[test]
+    fn test_elects_one_leader() {
+        let mut sim = cluster(3, 1);
+        sim.run_for(3000);
+        let leaders: Vec<&String> = sim
+            .processes()
+            .filter(|(_, raft)| raft.role() == Role::Leader)
+            .map(|(id, _)| id)
+            .collect();
+        assert_eq!(leaders.len(), 1);
+        for (_, raft) in sim.processes() {
+            assert_eq!(raft.leader(), Some(leaders[0].as_str()));
+        }
+    }
+
+    #[test]
+    fn test_no_leader_means_temporarily_unavailable() {
+        let mut raft = Raft::new(
+            "n1".to_string(),
+            vec!["n1".to_string(), "n2".to_string()],
+            KvState::new(),
+        );
+        let out = raft.on_message(json!({
+            "src": "c1",
+            "dest": "n1",
+            "body": {"type": "read", "key": 1, "msg_id": 4}
+        }));
+        assert_eq!(out[0]["dest"], "c1");
+        assert_eq!(out[0]["body"]["code"], 11);
+        assert_eq!(out[0]["body"]["in_reply_to"], 4);
+    }
+
+    #[test]
+    fn test_replicates_to_every_node() {
+        let mut sim = cluster(3, 2);
+        sim.run_for(2000);
+        let settings = KvClients {
+            duration_ms: 3000,
+            ..KvClients::default()
+        };
+        let history = linearizability::run_kv_clients(&mut sim, &settings, |_| ());
+        sim.run_for(1000);
+        linearizability::check(&history).unwrap();
+        let ok = history
+            .iter()
+            .filter(|op| matches!(op.outcome, Outcome::Ok(_)))
+            .count();
+        assert!(ok > 50, "only {} requests succeeded", ok);
+        assert_all_agree(&sim);
+    }
+
+    #[test]
+    fn test_commit_index_never_moves_back() {
+        let mut raft = Raft::new("n2".to_string(), node_ids(3), KvState::new());
+        let append = |entries: u64, leader_commit: u64| {
+            let entries: Vec<Value> = (0..entries)
+                .map(|_| json!({"term": 1, "request": null}))
+                .collect();
+            json!({
+                "src": "n1",
+                "dest": "n2",
+                "body": {
+                    "type": "append_entries",
+                    "term": 1,
+                    "prev_log_index": 0,
+                    "prev_log_term": 0,
+                    "entries": entries,
+                    "leader_commit": leader_commit,
+                }
+            })
+        };
+        raft.on_message(append(4, 3));
+        assert_eq!(raft.commit_index(), 3);
+        // An older, shorter append arrives late, with a later commit index
+        raft.on_message(append(1, 4));
+        assert_eq!(raft.commit_index(), 3);
+        raft.on_message(append(4, 4));
+        assert_eq!(raft.commit_index(), 4);
+    }
+
+    #[test]
+    fn test_log_is_compacted() {
+        let mut sim = cluster_with_snapshots(3, 3, 10);
+        sim.run_for(2000);
+        let settings = KvClients {
+            duration_ms: 3000,
+            ..KvClients::default()
+        };
+        let history = linearizability::run_kv_clients(&mut sim, &settings, |_| ());
+        sim.run_for(1000);
+        linearizability::check(&history).unwrap();
+        assert_all_agree(&sim);
+        for (_, raft) in sim.processes() {
+            assert!(raft.commit_index() > 50);
+            assert!(raft.log_len() < 20, "log holds {} entries", raft.log_len());
+        }
+    }
+
+    #[test]
+    fn test_lagging_follower_is_sent_a_snapshot() {
+        let mut sim = cluster_with_snapshots(3, 4, 5);
+        sim.run_for(2000);
+        let n3 = vec!["n3".to_string()];
+        sim.partition(&n3, &["n1".to_string(), "n2".to_string()]);
+        let settings = KvClients {
+            duration_ms: 3000,
+            ..KvClients::default()
+        };
+        let mut history = linearizability::run_kv_clients(&mut sim, &settings, |_| ());
+        let behind = sim.process("n3").unwrap().commit_index();
+        sim.heal();
+        sim.run_for(2000);
+        let settings = KvClients {
+            duration_ms: 1000,
+            seed: 1,
+            ..KvClients::default()
+        };
+        history.extend(linearizability::run_kv_clients(&mut sim, &settings, |_| ()));
+        sim.run_for(1000);
+        linearizability::check(&history).unwrap();
+        assert_all_agree(&sim);
+        assert!(sim.process("n3").unwrap().commit_index() > behind + 5);
+    }
+
+    #[test]
+    fn test_reads_skip_the_log() {
+        for read_mode in [ReadMode::ReadIndex, ReadMode::Lease] {
+            let mut sim = cluster_reading(3, 6, SNAPSHOT_ENTRIES, read_mode);
+            sim.run_for(2000);
+            let settings = KvClients {
+                duration_ms: 3000,
+                ..KvClients::default()
+            };
+            let history = linearizability::run_kv_clients(&mut sim, &settings, |_| ());
+            sim.run_for(1000);
+            linearizability::check(&history).unwrap();
+            let reads = history
+                .iter()
+                .filter(|op| matches!(op.op, KvOp::Read))
+                .filter(|op| matches!(op.outcome, Outcome::Ok(_)))
+                .count();
+            assert!(reads > 20, "only {} reads succeeded", reads);
+            // The log holds the updates and a no-op for each term, but no reads
+            let updates = history
+                .iter()
+                .filter(|op| !matches!(op.op, KvOp::Read))
+                .count() as u64;
+            for (_, raft) in sim.processes() {
+                assert!(raft.commit_index() <= updates + raft.term());
+            }
+        }
+    }
+
+    #[test]
+    fn test_votes_are_refused_while_a_leader_is_heard() {
+        let node_ids: Vec<String> = ["n1", "n2", "n3"].map(String::from).to_vec();
+        let mut raft = Raft::new("n2".to_string(), node_ids, KvState::new());
+        let out = raft.on_message(json!({
+            "src": "n1",
+            "dest": "n2",
+            "body": {
+                "type": "append_entries", "term": 1, "prev_log_index": 0,
+                "prev_log_term": 0, "entries": [], "leader_commit": 0, "read_round": 3
+            }
+        }));
+        assert_eq!(out[0]["body"]["read_round"], 3);
+        let request_vote = json!({
+            "src": "n3",
+            "dest": "n2",
+            "body": {"type": "request_vote", "term": 2, "last_log_index": 0, "last_log_term": 0}
+        });
+        assert!(raft.on_message(request_vote.clone()).is_empty());
+        assert_eq!(raft.term(), 1);
+        for _ in 0..ELECTION_TIMEOUT_TICKS {
+            raft.on_tick();
+        }
+        let out = raft.on_message(request_vote);
+        assert_eq!(out[0]["body"]["vote_granted"], true);
+        assert_eq!(raft.term(), 2);
+    }
+
+    #[test]
+    fn test_linearizable_under_partitions_and_loss() {
+        let read_modes = [ReadMode::Log, ReadMode::ReadIndex, ReadMode::Lease];
+        for (seed, read_mode) in (0..6).zip(read_modes.into_iter().cycle()) {
+            // Small snapshots, so that some nodes will need them sent over
+            let mut sim = cluster_reading(5, seed, 20, read_mode)
+                .with_loss(0.05)
+                .with_latency(10);
+            let settings = KvClients {
+                duration_ms: 10_000,
+                seed,
+                ..KvClients::default()
+            };
+            let mut history =
+                linearizability::run_kv_clients(&mut sim, &settings, random_partitions(seed));
+            // Once the network heals, requests succeed again
+            sim.heal();
+            sim.run_for(3000);
+            let settings = KvClients {
+                duration_ms: 2000,
+                seed: seed + 100,
+                ..KvClients::default()
+            };
+            let after = linearizability::run_kv_clients(&mut sim, &settings, |_| ());
+            assert!(after.iter().any(|op| matches!(op.outcome, Outcome::Ok(_))));
+            history.extend(after);
+            linearizability::check(&history).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_membership_changes_are_answered_once_committed() {
+        let mut sim = cluster_with_joiners(3, 1, 5);
+        sim.run_for(2000);
+        sim.send(membership_request("n2", "add_server", "n4", 1));
+        sim.run_for(3000);
+        let replies = sim.take_client_messages();
+        assert_eq!(replies.len(), 1, "{:?}", replies);
+        assert_eq!(replies[0]["body"]["type"], "add_server_ok");
+        assert_eq!(replies[0]["body"]["in_reply_to"], 1);
+        for (_, raft) in sim.processes() {
+            assert_eq!(raft.config(), ["n1", "n2", "n3", "n4"]);
+        }
+
+        // The leader can remove itself, after which the others elect a new one
+        let leader = sim.process("n4").unwrap().leader().unwrap().to_string();
+        sim.send(membership_request(&leader, "remove_server", &leader, 2));
+        sim.run_for(3000);
+        let replies = sim.take_client_messages();
+        assert_eq!(replies.len(), 1, "{:?}", replies);
+        assert_eq!(replies[0]["body"]["type"], "remove_server_ok");
+        assert_eq!(replies[0]["body"]["in_reply_to"], 2);
+        let members: Vec<&str> = ["n1", "n2", "n3", "n4"]
+            .into_iter()
+            .filter(|id| *id != leader)
+            .collect();
+        assert!(config_committed(&sim, &members));
+        assert_ne!(sim.process(&leader).unwrap().role(), Role::Leader);
+
+        // Asking again is answered straight away
+        sim.send(membership_request("n4", "add_server", "n4", 3));
+        sim.run_for(500);
+        let replies = sim.take_client_messages();
+        assert_eq!(replies[0]["body"]["type"], "add_server_ok");
+    }
+
+    #[test]
+    fn test_linearizable_through_membership_changes() {
+        for seed in 0..3 {
+            let mut sim = cluster_with_joiners(3, 2, seed)
+                .with_loss(0.05)
+                .with_latency(10);
+            let mut changes = membership_changes(vec![
+                ("add_server", "n4"),
+                ("add_server", "n5"),
+                ("remove_server", "n1"),
+                ("remove_server", "n2"),
+                ("add_server", "n1"),
+            ]);
+            let mut split = random_partitions(seed);
+            let settings = KvClients {
+                duration_ms: 10_000,
+                seed,
+                ..KvClients::default()
+            };
+            let mut history = linearizability::run_kv_clients(&mut sim, &settings, |sim| {
+                split(sim);
+                changes(sim);
+            });
+            // Once the network heals, the changes all go through
+            sim.heal();
+            let mut done = changes(&mut sim);
+            for _ in 0..40 {
+                sim.run_for(500);
+                done = changes(&mut sim);
+            }
+            assert_eq!(done, 5, "seed {}", seed);
+            let settings = KvClients {
+                duration_ms: 2000,
+                seed: seed + 100,
+                ..KvClients::default()
+            };
+            let after = linearizability::run_kv_clients(&mut sim, &settings, |_| ());
+            assert!(after.iter().any(|op| matches!(op.outcome, Outcome::Ok(_))));
+            history.extend(after);
+            linearizability::check(&history).unwrap();
+        }
+    }
+}
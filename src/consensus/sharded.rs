@@ -0,0 +1,294 @@
+/// Sharding over several consensus groups, with a consistent hashing ring (Karger et
+/// al., "Consistent Hashing and Random Trees") deciding which group has each key.
+///
+/// The nodes from `init` are placed on a ring by hash and split into groups of nodes
+/// next to each other (`shard_groups`), each of which replicates its own copy of the
+/// state machine with its own consensus instance. Keys are placed on a second ring,
+/// on which every group has VIRTUAL_POINTS points, and belong to the group with the
+/// first point at or after them, so adding a group only moves the keys it takes
+/// over. A node takes part in its own group alone: requests for another group's keys
+/// are forwarded to that group's nodes in turn, and their consensus instance passes
+/// them to its leader as it would a client's. Each group has its own leader, so
+/// throughput grows with the number of groups rather than being capped by one leader.
+use std::collections::BTreeMap;
+use std::marker::PhantomData;
+
+use serde_json::Value;
+
+use crate::consensus::{Consensus, StateMachine};
+use crate::node::{stable_hash, Forwarder, Process, FORWARD_EXPIRE_TICKS};
+
+/// Points each group has on the ring of keys
+pub const VIRTUAL_POINTS: usize = 64;
+
+/// FNV-1a, then splitmix64's finalizer to spread short names evenly round the ring
+fn ring_hash(name: &str) -> u64 {
+    let mut hash = stable_hash(name);
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
+
+/// A consistent hashing ring over members numbered from 0
+#[derive(Clone, Debug)]
+pub struct HashRing {
+    points: BTreeMap<u64, usize>,
+}
+
+impl HashRing {
+    pub fn new(members: usize, points_per_member: usize) -> Self {
+        let mut points = BTreeMap::new();
+        for member in 0..members {
+            for point in 0..points_per_member {
+                points.insert(ring_hash(&format!("{}#{}", member, point)), member);
+            }
+        }
+        Self { points }
+    }
+
+    /// The member with the first point at or after `key`, going round
+    pub fn owner(&self, key: &str) -> Option<usize> {
+        let hash = ring_hash(key);
+        self.points
+            .range(hash..)
+            .next()
+            .or_else(|| self.points.iter().next())
+            .map(|(_, member)| *member)
+    }
+}
+
+/// Split `node_ids` into groups of `group_size` or a little more (and at least one
+/// group), each a run of nodes next to each other on the ring
+pub fn shard_groups(node_ids: &[String], group_size: usize) -> Vec<Vec<String>> {
+    let mut ring: Vec<&String> = node_ids.iter().collect();
+    ring.sort_by_key(|id| (ring_hash(id), *id));
+    let count = (node_ids.len() / group_size.max(1)).max(1);
+    (0..count)
+        .map(|i| {
+            let run = i * ring.len() / count..(i + 1) * ring.len() / count;
+            ring[run].iter().map(|id| id.to_string()).collect()
+        })
+        .collect()
+}
+
+pub struct Sharded<S: StateMachine, C: Consensus> {
+    node_id: String,
+    groups: Vec<Vec<String>>,
+    // Our own group, whose consensus instance we run
+    own: usize,
+    ring: HashRing,
+    group: C,
+    // Spreads forwarded requests over the other groups' nodes
+    next_member: usize,
+    forwarder: Forwarder,
+    outbox: Vec<Value>,
+    _state: PhantomData<fn() -> S>,
+}
+
+impl<S: StateMachine, C: Consensus> Sharded<S, C> {
+    /// `group` replicates the state of the group with `node_id` in it
+    pub fn new(node_id: String, groups: Vec<Vec<String>>, group: C) -> Self {
+        let mut forwarder = Forwarder::new(FORWARD_EXPIRE_TICKS);
+        forwarder.set_node_id(node_id.clone());
+        let own = groups
+            .iter()
+            .position(|members| members.contains(&node_id))
+            .unwrap_or(0);
+        let ring = HashRing::new(groups.len(), VIRTUAL_POINTS);
+        Self {
+            node_id,
+            groups,
+            own,
+            ring,
+            group,
+            next_member: 0,
+            forwarder,
+            outbox: vec![],
+            _state: PhantomData,
+        }
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    pub fn groups(&self) -> &[Vec<String>] {
+        &self.groups
+    }
+
+    /// Our own group's consensus instance
+    pub fn group(&self) -> &C {
+        &self.group
+    }
+
+    /// The group which has `key`
+    pub fn group_of(&self, key: &str) -> usize {
+        self.ring.owner(key).unwrap_or(self.own)
+    }
+
+    fn route(&mut self, msg: Value) {
+        let owner = S::parse_request(&msg["body"])
+            .and_then(|request| S::key(&request))
+            .map(|key| self.group_of(&key));
+        match owner {
+            Some(owner) if owner != self.own => {
+                let members = &self.groups[owner];
+                self.next_member = (self.next_member + 1) % members.len();
+                let member = members[self.next_member].clone();
+                let forwarded = self.forwarder.forward_msg(&msg, &member);
+                self.outbox.push(forwarded);
+            }
+            // Ours, or not a request for any one key: our group deals with it
+            _ => {
+                let out = self.group.on_message(msg);
+                self.outbox.extend(out);
+            }
+        }
+    }
+}
+
+impl<S: StateMachine, C: Consensus> Process for Sharded<S, C> {
+    fn on_message(&mut self, msg: Value) -> Vec<Value> {
+        match self.forwarder.complete_msg(&msg) {
+            Some(reply) => self.outbox.push(reply),
+            None => self.route(msg),
+        }
+        std::mem::take(&mut self.outbox)
+    }
+
+    fn on_tick(&mut self) -> Vec<Value> {
+        self.forwarder.tick();
+        let out = self.group.on_tick();
+        self.outbox.extend(out);
+        std::mem::take(&mut self.outbox)
+    }
+}
+
+impl<S: StateMachine, C: Consensus> Consensus for Sharded<S, C> {
+    /// Our own group's leader
+    fn leader(&self) -> Option<&str> {
+        self.group.leader()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+    use crate::algorithms::lin_kv::KvState;
+    use crate::consensus::raft::Raft;
+    use crate::sim::linearizability::{self, KvClients, Outcome};
+    use crate::sim::{node_ids, random_partitions, Simulation};
+
+    type ShardedRaft = Sharded<KvState, Raft<KvState>>;
+
+    fn cluster(size: usize, group_size: usize, seed: u64) -> Simulation<ShardedRaft> {
+        let groups = shard_groups(&node_ids(size), group_size);
+        let processes = groups
+            .iter()
+            .flat_map(|members| members.iter().map(move |id| (id, members)))
+            .map(|(id, members)| {
+                let raft = Raft::new(id.clone(), members.clone(), KvState::new()).with_seed(seed);
+                (id.clone(), Sharded::new(id.clone(), groups.clone(), raft))
+            })
+            .collect();
+        Simulation::new(seed, processes)
+    }
+
+    #[test]
+    fn test_ring_spreads_keys_and_moves_few() {
+        let keys: Vec<String> = (0..1000).map(|key| key.to_string()).collect();
+        let four = HashRing::new(4, VIRTUAL_POINTS);
+        for member in 0..4 {
+            let owned = keys
+                .iter()
+                .filter(|key| four.owner(key) == Some(member))
+                .count();
+            assert!((150..350).contains(&owned), "{} has {} keys", member, owned);
+        }
+        // A fifth member only takes keys; none move between the other four
+        let five = HashRing::new(5, VIRTUAL_POINTS);
+        for key in keys.iter() {
+            let (before, after) = (four.owner(key), five.owner(key));
+            assert!(before == after || after == Some(4));
+        }
+    }
+
+    #[test]
+    fn test_groups_split_every_node_once() {
+        let groups = shard_groups(&node_ids(7), 3);
+        assert_eq!(groups.len(), 2);
+        let mut all: Vec<String> = groups.concat();
+        all.sort();
+        let mut expected = node_ids(7);
+        expected.sort();
+        assert_eq!(all, expected);
+        assert!(groups.iter().all(|members| members.len() >= 3));
+        assert_eq!(shard_groups(&node_ids(2), 3).len(), 1);
+    }
+
+    #[test]
+    fn test_each_group_keeps_its_own_keys() {
+        let mut sim = cluster(6, 3, 1);
+        sim.run_for(2000);
+        let settings = KvClients {
+            keys: 10,
+            duration_ms: 3000,
+            ..KvClients::default()
+        };
+        let history = linearizability::run_kv_clients(&mut sim, &settings, |_| ());
+        sim.run_for(1000);
+        linearizability::check(&history).unwrap();
+        let ok = history
+            .iter()
+            .filter(|op| matches!(op.outcome, Outcome::Ok(_)))
+            .count();
+        assert!(ok > 50, "only {} requests succeeded", ok);
+        let mut groups_used = vec![false; 2];
+        for (_, node) in sim.processes() {
+            for key in 0..settings.keys {
+                let owner = node.group_of(&Value::from(key).to_string());
+                let own = node
+                    .groups()
+                    .iter()
+                    .position(|members| members.contains(&node.node_id().to_string()));
+                let held = node.group().state().get(&Value::from(key)).is_some();
+                assert!(
+                    !held || own == Some(owner),
+                    "key {} is in the wrong group",
+                    key
+                );
+                groups_used[owner] |= held;
+            }
+        }
+        assert_eq!(groups_used, vec![true, true]);
+    }
+
+    #[test]
+    fn test_linearizable_under_partitions_and_loss() {
+        for seed in 0..3 {
+            let mut sim = cluster(6, 3, seed).with_loss(0.05).with_latency(10);
+            let settings = KvClients {
+                keys: 6,
+                duration_ms: 10_000,
+                seed,
+                ..KvClients::default()
+            };
+            let mut history =
+                linearizability::run_kv_clients(&mut sim, &settings, random_partitions(seed));
+            sim.heal();
+            sim.run_for(3000);
+            let settings = KvClients {
+                keys: 6,
+                duration_ms: 2000,
+                seed: seed + 100,
+                ..KvClients::default()
+            };
+            let after = linearizability::run_kv_clients(&mut sim, &settings, |_| ());
+            assert!(after.iter().any(|op| matches!(op.outcome, Outcome::Ok(_))));
+            history.extend(after);
+            linearizability::check(&history).unwrap();
+        }
+    }
+}
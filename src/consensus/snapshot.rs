@@ -0,0 +1,100 @@
+/// Where a node keeps its latest snapshot. Only the latest is ever needed: it
+/// stands in for all of the log before it.
+use std::fs;
+use std::path::PathBuf;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::errors;
+use crate::rpc::raft::Snapshot;
+
+pub trait SnapshotStore<T>: Send {
+    fn save(&mut self, snapshot: &Snapshot<T>) -> Result<(), errors::ErrorMsg>;
+    fn load(&mut self) -> Result<Option<Snapshot<T>>, errors::ErrorMsg>;
+}
+
+/// Keeps the snapshot in memory: nothing survives the process
+#[derive(Clone, Debug)]
+pub struct MemorySnapshots<T> {
+    latest: Option<Snapshot<T>>,
+}
+
+impl<T> Default for MemorySnapshots<T> {
+    fn default() -> Self {
+        Self { latest: None }
+    }
+}
+
+impl<T> MemorySnapshots<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<T: Clone + Send> SnapshotStore<T> for MemorySnapshots<T> {
+    fn save(&mut self, snapshot: &Snapshot<T>) -> Result<(), errors::ErrorMsg> {
+        self.latest = Some(snapshot.clone());
+        Ok(())
+    }
+
+    fn load(&mut self) -> Result<Option<Snapshot<T>>, errors::ErrorMsg> {
+        Ok(self.latest.clone())
+    }
+}
+
+/// Keeps the snapshot as JSON in one file. We write a new file alongside and
+/// rename it over the old one, so a crash part way leaves the old snapshot intact.
+#[derive(Clone, Debug)]
+pub struct FileSnapshots {
+    path: PathBuf,
+}
+
+impl FileSnapshots {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> SnapshotStore<T> for FileSnapshots {
+    fn save(&mut self, snapshot: &Snapshot<T>) -> Result<(), errors::ErrorMsg> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).map_err(errors::ErrorMsg::crash_error)?;
+        }
+        let json = serde_json::to_vec(snapshot).map_err(errors::ErrorMsg::json_dumps_error)?;
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, json).map_err(errors::ErrorMsg::crash_error)?;
+        fs::rename(&tmp, &self.path).map_err(errors::ErrorMsg::crash_error)
+    }
+
+    fn load(&mut self) -> Result<Option<Snapshot<T>>, errors::ErrorMsg> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+        let json = fs::read(&self.path).map_err(errors::ErrorMsg::crash_error)?;
+        serde_json::from_slice(&json)
+            .map(Some)
+            .map_err(errors::ErrorMsg::json_parse_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_snapshots_round_trip() {
+        let path =
+            std::env::temp_dir().join(format!("maelstrom-snapshot-{}/n1.json", std::process::id()));
+        let mut store = FileSnapshots::new(path.clone());
+        assert_eq!(SnapshotStore::<Vec<u64>>::load(&mut store).unwrap(), None);
+        let snapshot = Snapshot {
+            last_included_index: 7,
+            last_included_term: 2,
+            node_ids: vec!["n1".to_string(), "n2".to_string()],
+            state: vec![1u64, 2, 3],
+        };
+        store.save(&snapshot).unwrap();
+        assert_eq!(store.load().unwrap(), Some(snapshot));
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -1,7 +1,7 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 // "Codes 0-999 are reserved for Maelstrom's use; codes 1000 and above are free for your own purposes."
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ErrorType {
     Timeout = 0,
     NodeNotFound = 1,
@@ -16,6 +16,40 @@ pub enum ErrorType {
     TxnConflict = 30,
 }
 
+impl ErrorType {
+    pub fn from_code(code: u64) -> Option<Self> {
+        match code {
+            0 => Some(ErrorType::Timeout),
+            1 => Some(ErrorType::NodeNotFound),
+            10 => Some(ErrorType::NotSupported),
+            11 => Some(ErrorType::TemporarilyUnavailable),
+            12 => Some(ErrorType::MalformedRequest),
+            13 => Some(ErrorType::Crash),
+            14 => Some(ErrorType::Abort),
+            20 => Some(ErrorType::KeyDoesNotExist),
+            21 => Some(ErrorType::KeyAlreadyExists),
+            22 => Some(ErrorType::PreconditionFailed),
+            30 => Some(ErrorType::TxnConflict),
+            _ => None,
+        }
+    }
+}
+
+/// Maelstrom sends and expects error codes as plain integers
+impl Serialize for ErrorType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(*self as u64)
+    }
+}
+
+impl<'de> Deserialize<'de> for ErrorType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let code = u64::deserialize(deserializer)?;
+        ErrorType::from_code(code)
+            .ok_or_else(|| serde::de::Error::custom(format!("unknown error code {}", code)))
+    }
+}
+
 #[derive(Clone, Debug, Serialize)]
 #[serde(rename_all = "snake_case")]
 enum ErrorMessageType {
@@ -67,3 +101,21 @@ impl ErrorMsg {
         ErrorMsg::new(None, ErrorType::Crash, "Unrecoverable error".to_string())
     }
 }
+
+/// An error on its way back to whoever sent us a request
+#[derive(Clone, Debug, Serialize)]
+pub struct ErrorMsgOut {
+    pub src: String,
+    pub dest: String,
+    pub body: ErrorMsg,
+}
+
+impl ErrorMsgOut {
+    pub fn new(src: String, dest: String, body: ErrorMsg) -> Self {
+        ErrorMsgOut { src, dest, body }
+    }
+
+    pub fn to_json(&self) -> Result<String, ErrorMsg> {
+        serde_json::to_string(self).map_err(ErrorMsg::json_dumps_error)
+    }
+}
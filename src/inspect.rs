@@ -0,0 +1,270 @@
+/// Tools for looking at IDs made by the unique-ids workload after the fact:
+/// decoding a snowflake ID into its parts, and scanning a log of messages
+/// (one JSON message per line, e.g. a node's captured stdout) for
+/// duplicate or out-of-order IDs.
+use std::collections::HashMap;
+use std::fmt;
+use std::io::BufRead;
+
+use serde::Deserialize;
+
+use crate::algorithms::unique_ids::SnowflakeLayout;
+use crate::errors;
+use crate::rpc::unique_ids::GeneratedId;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DecodedId {
+    pub id: u64,
+    // milliseconds since the layout's epoch
+    pub timestamp: u64,
+    pub node_id: u64,
+    pub sequence: u64,
+    unix_millis: u64,
+}
+
+impl DecodedId {
+    pub fn new(layout: &SnowflakeLayout, id: u64) -> Self {
+        let (timestamp, node_id, sequence) = layout.decompose(id);
+        DecodedId {
+            id,
+            timestamp,
+            node_id,
+            sequence,
+            unix_millis: layout.epoch_ms + timestamp,
+        }
+    }
+}
+
+impl fmt::Display for DecodedId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "id:        {}", self.id)?;
+        writeln!(
+            f,
+            "timestamp: {} ({} ms after epoch)",
+            format_utc(self.unix_millis),
+            self.timestamp
+        )?;
+        writeln!(f, "node:      n{}", self.node_id)?;
+        write!(f, "sequence:  {}", self.sequence)
+    }
+}
+
+/// Render milliseconds since the UNIX epoch as an ISO 8601 UTC timestamp.
+/// Civil-from-days conversion from http://howardhinnant.github.io/date_algorithms.html
+fn format_utc(unix_millis: u64) -> String {
+    let secs = unix_millis / 1000;
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60,
+        unix_millis % 1000
+    )
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Anomaly {
+    Duplicate {
+        id: GeneratedId,
+        first_line: usize,
+        line: usize,
+    },
+    // An ID from a node which does not sort after the previous ID from that node
+    OutOfOrder {
+        node: String,
+        previous: GeneratedId,
+        id: GeneratedId,
+        line: usize,
+    },
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Anomaly::Duplicate {
+                id,
+                first_line,
+                line,
+            } => write!(
+                f,
+                "line {}: duplicate id {} (first seen on line {})",
+                line,
+                display_id(id),
+                first_line
+            ),
+            Anomaly::OutOfOrder {
+                node,
+                previous,
+                id,
+                line,
+            } => write!(
+                f,
+                "line {}: id {} from {} does not follow {}",
+                line,
+                display_id(id),
+                node,
+                display_id(previous)
+            ),
+        }
+    }
+}
+
+fn display_id(id: &GeneratedId) -> String {
+    match id {
+        GeneratedId::Numeric(id) => id.to_string(),
+        GeneratedId::Text(id) => id.clone(),
+    }
+}
+
+/// Numeric IDs compare by value. String IDs compare by length and then
+/// lexicographically: that orders ULIDs, UUIDv7s and "n1-9" before "n1-10".
+fn id_order(id: &GeneratedId) -> (u8, u64, usize, &str) {
+    match id {
+        GeneratedId::Numeric(id) => (0, *id, 0, ""),
+        GeneratedId::Text(id) => (1, 0, id.len(), id.as_str()),
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ScanReport {
+    pub ids: usize,
+    pub anomalies: Vec<Anomaly>,
+}
+
+impl ScanReport {
+    pub fn is_clean(&self) -> bool {
+        self.anomalies.is_empty()
+    }
+}
+
+impl fmt::Display for ScanReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for anomaly in self.anomalies.iter() {
+            writeln!(f, "{}", anomaly)?;
+        }
+        write!(
+            f,
+            "{} ids scanned, {} anomalies",
+            self.ids,
+            self.anomalies.len()
+        )
+    }
+}
+
+/// The parts of a generate_ok message we care about
+#[derive(Deserialize)]
+struct GenerateOkLine {
+    src: String,
+    body: GenerateOkBody,
+}
+
+#[derive(Deserialize)]
+struct GenerateOkBody {
+    #[serde(rename = "type")]
+    typ: String,
+    id: GeneratedId,
+}
+
+/// Look at every `generate_ok` message in `reader`; all other lines are skipped
+pub fn scan_ids(reader: impl BufRead) -> Result<ScanReport, errors::ErrorMsg> {
+    let mut report = ScanReport::default();
+    let mut first_seen: HashMap<GeneratedId, usize> = HashMap::new();
+    let mut last_by_node: HashMap<String, GeneratedId> = HashMap::new();
+    for (index, line) in reader.lines().enumerate() {
+        let line = line.map_err(errors::ErrorMsg::crash_error)?;
+        let line_no = index + 1;
+        let msg = match serde_json::from_str::<GenerateOkLine>(&line) {
+            Ok(msg) if msg.body.typ == "generate_ok" => msg,
+            _ => continue,
+        };
+        report.ids += 1;
+        let id = msg.body.id;
+        if let Some(first_line) = first_seen.get(&id) {
+            report.anomalies.push(Anomaly::Duplicate {
+                id: id.clone(),
+                first_line: *first_line,
+                line: line_no,
+            });
+        } else {
+            first_seen.insert(id.clone(), line_no);
+        }
+        if let Some(previous) = last_by_node.get(&msg.src) {
+            if id_order(&id) <= id_order(previous) {
+                report.anomalies.push(Anomaly::OutOfOrder {
+                    node: msg.src.clone(),
+                    previous: previous.clone(),
+                    id: id.clone(),
+                    line: line_no,
+                });
+            }
+        }
+        last_by_node.insert(msg.src, id);
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let layout = SnowflakeLayout::default();
+        let id = layout.compose(86_400_000, 3, 17);
+        let decoded = DecodedId::new(&layout, id);
+        assert_eq!(
+            (decoded.timestamp, decoded.node_id, decoded.sequence),
+            (86_400_000, 3, 17)
+        );
+        // our epoch is 2023-01-01T01:01:01Z; this is one day later
+        assert!(decoded.to_string().contains("2023-01-02T01:01:01.000Z"));
+    }
+
+    #[test]
+    fn test_scan_ids() {
+        let history = r#"{"src":"n1","dest":"c1","body":{"type":"generate_ok","in_reply_to":1,"msg_id":2,"id":10}}
+{"src":"n2","dest":"c2","body":{"type":"generate_ok","in_reply_to":1,"msg_id":2,"id":5}}
+{"src":"c1","dest":"n1","body":{"type":"generate","msg_id":2}}
+not json at all
+{"src":"n1","dest":"c1","body":{"type":"generate_ok","in_reply_to":2,"msg_id":3,"id":12}}
+{"src":"n1","dest":"c1","body":{"type":"generate_ok","in_reply_to":3,"msg_id":4,"id":11}}
+{"src":"n2","dest":"c2","body":{"type":"generate_ok","in_reply_to":2,"msg_id":3,"id":12}}
+{"src":"n3","dest":"c2","body":{"type":"generate_ok","in_reply_to":2,"msg_id":3,"id":"n3-9"}}
+{"src":"n3","dest":"c2","body":{"type":"generate_ok","in_reply_to":2,"msg_id":3,"id":"n3-10"}}
+"#;
+        let report = scan_ids(Cursor::new(history)).unwrap();
+        assert_eq!(report.ids, 7);
+        assert_eq!(
+            report.anomalies,
+            vec![
+                Anomaly::OutOfOrder {
+                    node: "n1".to_string(),
+                    previous: GeneratedId::Numeric(12),
+                    id: GeneratedId::Numeric(11),
+                    line: 6
+                },
+                Anomaly::Duplicate {
+                    id: GeneratedId::Numeric(12),
+                    first_line: 5,
+                    line: 7
+                },
+            ]
+        );
+    }
+}
@@ -1,31 +1,61 @@
 /// Key Value Service
 /// Outputs KV instructions to stdout
 /// Based on: https://github.com/jepsen-io/maelstrom/blob/main/demo/go/kv.go
-/// 
+///
+/// Replies from the service arrive on stdin like any other message: every request
+/// method returns the `msg_id` it used so that callers can match up the reply.
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
 use crate::errors;
 
-/// KV Actions all get turned into RPC messages where the 
+/// KV Actions all get turned into RPC messages where the
 /// KV type is the `dest`
-#[derive(Clone, Debug, Serialize, Deserialize)]
-#[serde(rename_all = "kebab-case")]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum KvType {
+    #[serde(rename = "lin-kv")]
     LinKV,
+    #[serde(rename = "seq-kv")]
     SeqKV,
+    #[serde(rename = "lww-kv")]
     LwwKV,
 }
 
+impl KvType {
+    /// The node id Maelstrom gives this service
+    pub fn service_name(&self) -> &'static str {
+        match self {
+            KvType::LinKV => "lin-kv",
+            KvType::SeqKV => "seq-kv",
+            KvType::LwwKV => "lww-kv",
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct KV {
-    _type: KvType
+    _type: KvType,
+    node_id: String,
+    last_msg_id: u64,
 }
 
 impl KV {
-
     pub fn new(_type: KvType) -> Self {
-        Self { _type }
+        Self {
+            _type,
+            node_id: "n0".to_string(),
+            last_msg_id: 0,
+        }
+    }
+
+    /// Requests must come *from* our node: call this once the init message arrives
+    pub fn set_node_id(&mut self, node_id: String) {
+        self.node_id = node_id;
+    }
+
+    fn next_msg_id(&mut self) -> u64 {
+        self.last_msg_id += 1;
+        self.last_msg_id
     }
 
     pub fn send(&self, msg: KvRpc) -> Result<(), errors::ErrorMsg> {
@@ -34,79 +64,165 @@ impl KV {
         Ok(())
     }
 
-    pub fn write(&self, key: String, value: Value) -> Result<(), errors::ErrorMsg> {
+    fn request(&mut self, body: KvRpcBody) -> Result<u64, errors::ErrorMsg> {
+        let msg_id = body.msg_id();
         self.send(KvRpc {
+            src: self.node_id.clone(),
             dest: self._type.clone(),
-            body: KvRpcBody::Write(WriteRequestBody { key, value})
-        })
+            body,
+        })?;
+        Ok(msg_id)
     }
 
-    pub fn read(&self, key: String) -> Result<(), errors::ErrorMsg> {
-        self.send(KvRpc {
-            dest: self._type.clone(),
-            body: KvRpcBody::Read(ReadRequestBody { key })
-        })
+    pub fn write(&mut self, key: String, value: Value) -> Result<u64, errors::ErrorMsg> {
+        let msg_id = self.next_msg_id();
+        self.request(KvRpcBody::Write(WriteRequestBody { msg_id, key, value }))
+    }
+
+    pub fn read(&mut self, key: String) -> Result<u64, errors::ErrorMsg> {
+        let msg_id = self.next_msg_id();
+        self.request(KvRpcBody::Read(ReadRequestBody { msg_id, key }))
     }
 
-    pub fn cas(  
-        &self,  
+    pub fn cas(
+        &mut self,
         key: String,
         from: Value,
         to: Value,
-        create_if_not_exists: Option<bool>
-    ) ->  Result<(), errors::ErrorMsg> {
-        self.send(KvRpc {
-            dest: self._type.clone(),
-            body: KvRpcBody::Cas(CasRequestMsg { key, from, to, create_if_not_exists })
-        })
+        create_if_not_exists: Option<bool>,
+    ) -> Result<u64, errors::ErrorMsg> {
+        let msg_id = self.next_msg_id();
+        self.request(KvRpcBody::Cas(CasRequestMsg {
+            msg_id,
+            key,
+            from,
+            to,
+            create_if_not_exists,
+        }))
     }
-
 }
 
-
 /// RPC Messages
 /// These represent "actions" for KV
 /// As with other RPCs; they will be printed to screen
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub struct KvRpc {
+    src: String,
     dest: KvType,
-    body: KvRpcBody
+    body: KvRpcBody,
 }
 
-
-/// KV Actions all get turned into RPC messages where the 
+/// KV Actions all get turned into RPC messages where the
 /// KV type is the `dest`
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
 pub enum KvRpcBody {
     Read(ReadRequestBody),
-    ReadOk(ReadResponseBody),
     Write(WriteRequestBody),
     Cas(CasRequestMsg),
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct ReadRequestBody {
-    key: String,
+impl KvRpcBody {
+    fn msg_id(&self) -> u64 {
+        match self {
+            KvRpcBody::Read(body) => body.msg_id,
+            KvRpcBody::Write(body) => body.msg_id,
+            KvRpcBody::Cas(body) => body.msg_id,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct ReadResponseBody {
-    value: Value,
+pub struct ReadRequestBody {
+    msg_id: u64,
+    key: String,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct WriteRequestBody {
+    msg_id: u64,
     key: String,
     value: Value,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct CasRequestMsg {
+    msg_id: u64,
     key: String,
     from: Value,
     to: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
     create_if_not_exists: Option<bool>,
 }
+
+/// Replies from a KV service
+#[derive(Clone, Debug, Deserialize)]
+pub struct KvMsgIn {
+    pub src: String,
+    pub dest: String,
+    pub body: KvResponseBody,
+}
+
+impl KvMsgIn {
+    /// Returns `Ok(None)` if the message did not come from a KV service,
+    /// so callers may try this before parsing their own message types.
+    pub fn parse(msg: &str) -> Result<Option<KvMsgIn>, errors::ErrorMsg> {
+        #[derive(Deserialize)]
+        struct Source {
+            src: String,
+        }
+        let source =
+            serde_json::from_str::<Source>(msg).map_err(errors::ErrorMsg::json_parse_error)?;
+        let from_kv = [KvType::LinKV, KvType::SeqKV, KvType::LwwKV]
+            .iter()
+            .any(|kv| kv.service_name() == source.src);
+        if !from_kv {
+            return Ok(None);
+        }
+        serde_json::from_str::<KvMsgIn>(msg)
+            .map(Some)
+            .map_err(errors::ErrorMsg::json_parse_error)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum KvResponseBody {
+    ReadOk(ReadResponseBody),
+    WriteOk(OkResponseBody),
+    CasOk(OkResponseBody),
+    Error(ErrorResponseBody),
+}
+
+impl KvResponseBody {
+    pub fn in_reply_to(&self) -> Option<u64> {
+        match self {
+            KvResponseBody::ReadOk(body) => body.in_reply_to,
+            KvResponseBody::WriteOk(body) => body.in_reply_to,
+            KvResponseBody::CasOk(body) => body.in_reply_to,
+            KvResponseBody::Error(body) => body.in_reply_to,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReadResponseBody {
+    pub in_reply_to: Option<u64>,
+    pub value: Value,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OkResponseBody {
+    pub in_reply_to: Option<u64>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ErrorResponseBody {
+    pub in_reply_to: Option<u64>,
+    pub code: errors::ErrorType,
+    #[serde(default)]
+    pub text: String,
+}
@@ -1,6 +1,11 @@
 pub mod algorithms;
+pub mod consensus;
 pub mod errors;
+pub mod inspect;
 pub mod kv;
 pub mod node;
 pub mod rpc;
+pub mod sim;
+pub mod storage;
+pub mod transactions;
 pub mod workload;
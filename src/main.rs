@@ -1,20 +1,69 @@
-use clap::Parser;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::process::exit;
 
+use clap::{Parser, Subcommand};
+
+use maelstrom_challenge::inspect;
 use maelstrom_challenge::node;
 use maelstrom_challenge::workload;
 
 /// Run a Maelstrom Challenge from Fly.io
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(subcommand_negates_reqs = true)]
 struct Args {
     /// Name of the workload (challenge) to run
-    #[arg(short, long)]
+    #[arg(short, long, required = true)]
     #[arg(value_enum)]
-    workload: workload::Workload,
+    workload: Option<workload::Workload>,
+
+    #[command(flatten)]
+    options: workload::Options,
+
+    #[command(subcommand)]
+    tool: Option<Tool>,
+}
+
+/// Debugging tools which run instead of a workload
+#[derive(Subcommand, Debug)]
+enum Tool {
+    /// Decode a snowflake ID into its timestamp, node id and sequence
+    DecodeId {
+        /// ID as returned in a generate_ok message
+        id: u64,
+    },
+    /// Scan a file of JSON messages (one per line) for duplicate or out-of-order IDs
+    ScanIds {
+        /// Path to the file, e.g. captured node output
+        path: PathBuf,
+    },
 }
 
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
-    node::run(args.workload).await.unwrap_err();
+    match args.tool {
+        Some(Tool::DecodeId { id }) => {
+            let layout = args.options.snowflake_layout().unwrap_or_else(|_| exit(2));
+            println!("{}", inspect::DecodedId::new(&layout, id));
+        }
+        Some(Tool::ScanIds { path }) => {
+            let file = File::open(&path).unwrap_or_else(|err| {
+                eprintln!("Could not open {}: {}", path.display(), err);
+                exit(2)
+            });
+            let report = inspect::scan_ids(BufReader::new(file)).unwrap_or_else(|_| exit(2));
+            println!("{}", report);
+            if !report.is_clean() {
+                exit(1);
+            }
+        }
+        None => {
+            if let Some(workload) = args.workload {
+                node::run(workload, args.options).await.unwrap_err();
+            }
+        }
+    }
 }
@@ -1,4 +1,9 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
 use async_trait::async_trait;
+use serde::Serialize;
+use serde_json::Value;
 use tokio::io::{self, AsyncBufReadExt, BufReader};
 use tokio::sync::mpsc::{self, Receiver, Sender};
 use tokio::time::{self, Duration};
@@ -25,6 +30,300 @@ pub trait Node {
     async fn on_init(&mut self, msg: rpc::InitMsgIn) -> Result<(), errors::ErrorMsg>;
 }
 
+/// A node's logic with the I/O taken out: it is handed messages (whole maelstrom
+/// messages, `{"src", "dest", "body"}`) and clock ticks, and returns the messages it
+/// wants sent. A `Node` prints these; the simulator in `sim` delivers them itself,
+/// so the same logic can be run deterministically in tests.
+pub trait Process {
+    fn on_message(&mut self, msg: Value) -> Vec<Value>;
+    fn on_tick(&mut self) -> Vec<Value>;
+}
+
+/// In lieu of *sending* the messages a `Process` returns: we print them to screen
+pub fn send_messages(msgs: Vec<Value>) -> Result<(), errors::ErrorMsg> {
+    for msg in msgs {
+        let msg_str = serde_json::to_string(&msg).map_err(errors::ErrorMsg::json_dumps_error)?;
+        println!("{}", msg_str);
+    }
+    Ok(())
+}
+
+/// A message to another node which has not been acknowledged yet
+#[derive(Clone, Debug)]
+struct Unacked {
+    dest: String,
+    body: Value,
+    ticks: u64,
+}
+
+/// Messages to other nodes which are sent again every few ticks until acknowledged.
+/// Each message is given its own `msg_id`; a reply with a matching `in_reply_to`
+/// (passed to `ack`) stops the retries. Receivers must tolerate duplicates.
+#[derive(Clone, Debug)]
+pub struct Retrier {
+    node_id: String,
+    last_msg_id: u64,
+    resend_ticks: u64,
+    unacked: HashMap<u64, Unacked>,
+}
+
+impl Retrier {
+    pub fn new(resend_ticks: u64) -> Self {
+        Self {
+            node_id: "n0".to_string(),
+            last_msg_id: 0,
+            resend_ticks,
+            unacked: HashMap::new(),
+        }
+    }
+
+    pub fn set_node_id(&mut self, node_id: String) {
+        self.node_id = node_id;
+    }
+
+    /// Send `body` (which must serialize to a JSON object) to `dest` until it is acknowledged.
+    /// Returns the msg_id which the acknowledgement will be `in_reply_to`.
+    pub fn send<B: Serialize>(&mut self, dest: &str, body: &B) -> Result<u64, errors::ErrorMsg> {
+        self.last_msg_id += 1;
+        let msg_id = self.last_msg_id;
+        let mut body = serde_json::to_value(body).map_err(errors::ErrorMsg::json_dumps_error)?;
+        if let Value::Object(fields) = &mut body {
+            fields.insert("msg_id".to_string(), Value::from(msg_id));
+        }
+        let unacked = Unacked {
+            dest: dest.to_string(),
+            body,
+            ticks: 0,
+        };
+        self.transmit(&unacked)?;
+        self.unacked.insert(msg_id, unacked);
+        Ok(msg_id)
+    }
+
+    /// Returns true if this acknowledged one of our messages
+    pub fn ack(&mut self, in_reply_to: u64) -> bool {
+        self.unacked.remove(&in_reply_to).is_some()
+    }
+
+    /// Count of messages still waiting on an acknowledgement
+    pub fn pending(&self) -> usize {
+        self.unacked.len()
+    }
+
+    /// Call on every clock tick: sends again anything unacknowledged for `resend_ticks`
+    pub fn tick(&mut self) -> Result<(), errors::ErrorMsg> {
+        for unacked in self.unacked.values_mut() {
+            unacked.ticks += 1;
+        }
+        let overdue: Vec<Unacked> = self
+            .unacked
+            .values_mut()
+            .filter(|unacked| unacked.ticks >= self.resend_ticks)
+            .map(|unacked| {
+                unacked.ticks = 0;
+                unacked.clone()
+            })
+            .collect();
+        for unacked in overdue.iter() {
+            self.transmit(unacked)?;
+        }
+        Ok(())
+    }
+
+    fn transmit(&self, unacked: &Unacked) -> Result<(), errors::ErrorMsg> {
+        let msg = serde_json::json!({
+            "src": self.node_id,
+            "dest": unacked.dest,
+            "body": unacked.body,
+        });
+        let msg_str = serde_json::to_string(&msg).map_err(errors::ErrorMsg::json_dumps_error)?;
+        println!("{}", msg_str);
+        Ok(())
+    }
+}
+
+/// A request we passed on to another node, and who to answer once it replies
+#[derive(Clone, Debug)]
+struct Forwarded {
+    client: String,
+    client_msg_id: Option<u64>,
+    dest: String,
+    request_type: String,
+    ticks: u64,
+}
+
+/// FNV-1a: a hash which every node computes the same way
+pub fn stable_hash(key: &str) -> u64 {
+    key.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Forwarded requests are forgotten after this many ticks
+pub const FORWARD_EXPIRE_TICKS: u64 = 20;
+
+/// Passes requests on to another node (e.g. the owner of a key, or a leader)
+/// and proxies the reply back to whoever sent the request to us.
+/// Forwarded requests whose reply never comes are forgotten after `expire_ticks`:
+/// by then the client will have given up on them too.
+#[derive(Clone, Debug)]
+pub struct Forwarder {
+    node_id: String,
+    last_msg_id: u64,
+    expire_ticks: u64,
+    forwarded: HashMap<u64, Forwarded>,
+}
+
+impl Forwarder {
+    pub fn new(expire_ticks: u64) -> Self {
+        Self {
+            node_id: "n0".to_string(),
+            last_msg_id: 0,
+            expire_ticks,
+            forwarded: HashMap::new(),
+        }
+    }
+
+    pub fn set_node_id(&mut self, node_id: String) {
+        self.node_id = node_id;
+    }
+
+    /// Send the message `msg` (exactly as we received it) on to `dest`
+    pub fn forward(&mut self, msg: &str, dest: &str) -> Result<(), errors::ErrorMsg> {
+        let msg =
+            serde_json::from_str::<Value>(msg).map_err(errors::ErrorMsg::json_parse_error)?;
+        let msg = self.forward_msg(&msg, dest);
+        let msg_str = serde_json::to_string(&msg).map_err(errors::ErrorMsg::json_dumps_error)?;
+        println!("{}", msg_str);
+        Ok(())
+    }
+
+    /// If `msg` answers a request we forwarded, pass it back to the original sender
+    /// and return true. Replies are matched on `in_reply_to`, sender and type
+    /// (`<request type>_ok` or `error`), so other traffic between nodes is left alone.
+    pub fn try_complete(&mut self, msg: &str) -> Result<bool, errors::ErrorMsg> {
+        let msg =
+            serde_json::from_str::<Value>(msg).map_err(errors::ErrorMsg::json_parse_error)?;
+        match self.complete_msg(&msg) {
+            Some(reply) => {
+                let msg_str =
+                    serde_json::to_string(&reply).map_err(errors::ErrorMsg::json_dumps_error)?;
+                println!("{}", msg_str);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// As `forward`, but returns the message to send rather than sending it
+    pub fn forward_msg(&mut self, msg: &Value, dest: &str) -> Value {
+        let mut msg = msg.clone();
+        self.last_msg_id += 1;
+        let msg_id = self.last_msg_id;
+        let forwarded = Forwarded {
+            client: msg["src"].as_str().unwrap_or_default().to_string(),
+            client_msg_id: msg["body"]["msg_id"].as_u64(),
+            dest: dest.to_string(),
+            request_type: msg["body"]["type"].as_str().unwrap_or_default().to_string(),
+            ticks: 0,
+        };
+        msg["src"] = Value::from(self.node_id.clone());
+        msg["dest"] = Value::from(dest);
+        msg["body"]["msg_id"] = Value::from(msg_id);
+        self.forwarded.insert(msg_id, forwarded);
+        msg
+    }
+
+    /// As `try_complete`, but returns the reply to send back (if `msg` was one)
+    pub fn complete_msg(&mut self, msg: &Value) -> Option<Value> {
+        let in_reply_to = msg["body"]["in_reply_to"].as_u64()?;
+        let forwarded = self.forwarded.get(&in_reply_to)?;
+        let reply_type = msg["body"]["type"].as_str().unwrap_or_default();
+        let is_reply = msg["src"] == forwarded.dest.as_str()
+            && (reply_type == "error" || reply_type == format!("{}_ok", forwarded.request_type));
+        if !is_reply {
+            return None;
+        }
+        let forwarded = self.forwarded.remove(&in_reply_to).unwrap();
+        let mut msg = msg.clone();
+        msg["src"] = Value::from(self.node_id.clone());
+        msg["dest"] = Value::from(forwarded.client);
+        msg["body"]["in_reply_to"] = forwarded
+            .client_msg_id
+            .map(Value::from)
+            .unwrap_or(Value::Null);
+        Some(msg)
+    }
+
+    /// Call on every clock tick to forget requests which were never answered
+    pub fn tick(&mut self) {
+        let expire_ticks = self.expire_ticks;
+        self.forwarded.retain(|_, forwarded| {
+            forwarded.ticks += 1;
+            forwarded.ticks < expire_ticks
+        });
+    }
+}
+
+/// A request parked until it can be answered, or until `deadline` at the latest
+#[derive(Clone, Debug)]
+pub struct Parked<T> {
+    pub request: T,
+    pub deadline: Instant,
+}
+
+impl<T> Parked<T> {
+    pub fn expired(&self, now: Instant) -> bool {
+        now >= self.deadline
+    }
+}
+
+/// Requests which we answer later rather than straight away (e.g. a long poll
+/// waiting for data to arrive). Nodes `take` parked requests when something they
+/// may be waiting on happens, and on every tick to find those past their deadline;
+/// anything taken which still cannot be answered may be parked again.
+#[derive(Clone, Debug)]
+pub struct Deferred<T> {
+    parked: Vec<Parked<T>>,
+}
+
+impl<T> Default for Deferred<T> {
+    fn default() -> Self {
+        Self { parked: vec![] }
+    }
+}
+
+impl<T> Deferred<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn park(&mut self, request: T, deadline: Instant) {
+        self.parked.push(Parked { request, deadline });
+    }
+
+    pub fn len(&self) -> usize {
+        self.parked.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.parked.is_empty()
+    }
+
+    /// Remove and return (oldest first) the requests `wanted` picks out,
+    /// together with every request which has reached its deadline
+    pub fn take<F>(&mut self, now: Instant, wanted: F) -> Vec<Parked<T>>
+    where
+        F: Fn(&T) -> bool,
+    {
+        let (taken, kept) = std::mem::take(&mut self.parked)
+            .into_iter()
+            .partition(|parked| parked.expired(now) || wanted(&parked.request));
+        self.parked = kept;
+        taken
+    }
+}
+
 async fn run_clock(tx: Sender<workload::Command>) {
     let mut interval = time::interval(Duration::from_millis(150));
     loop {
@@ -33,26 +332,50 @@ async fn run_clock(tx: Sender<workload::Command>) {
     }
 }
 
-pub async fn run(workload: workload::Workload) -> Result<(), errors::ErrorMsg> {
+pub async fn run(
+    workload: workload::Workload,
+    options: workload::Options,
+) -> Result<(), errors::ErrorMsg> {
     let (tx, rx) = mpsc::channel(1000);
 
     let mut node = match workload {
         workload::Workload::Echo => {
             Box::new(algorithms::echo::EchoNode::new(1, rx)) as Box<dyn Node + Send>
         }
-        workload::Workload::UniqueIds => {
-            Box::new(algorithms::unique_ids::UniqueIdGenerator::new(1, rx)) as Box<dyn Node + Send>
-        }
-        workload::Workload::Broadcast => {
-            Box::new(algorithms::broadcast::Broadcast::new(1, rx)) as Box<dyn Node + Send>
-        }
+        workload::Workload::UniqueIds => Box::new(
+            algorithms::unique_ids::UniqueIdGenerator::new(1, rx)
+                .with_strategy(algorithms::unique_ids::id_strategy(&options)?),
+        ) as Box<dyn Node + Send>,
+        workload::Workload::Broadcast => Box::new(
+            algorithms::broadcast::Broadcast::new(1, rx).with_order(options.broadcast_order),
+        ) as Box<dyn Node + Send>,
         workload::Workload::GCounter =>  Box::new(algorithms::gcounter::GCounter::new(1, rx)) as Box<dyn Node + Send>,
         workload::Workload::GSet => todo!(),
-        workload::Workload::Kafka => todo!(),
-        workload::Workload::LinKV => todo!(),
+        workload::Workload::Kafka => Box::new(
+            algorithms::kafka::Kafka::new(1, rx)
+                .with_offsets(options.kafka_offsets)
+                .with_storage(options.kafka_log_store()),
+        ) as Box<dyn Node + Send>,
+        workload::Workload::LinKV => {
+            Box::new(algorithms::lin_kv::LinKv::new(1, rx).with_config(options.consensus_config()))
+                as Box<dyn Node + Send>
+        }
         workload::Workload::PNCounter => todo!(),
-        workload::Workload::TxnListAppend => todo!(),
-        workload::Workload::TxnRwRegister => todo!(),
+        workload::Workload::TxnListAppend => {
+            Box::new(
+                algorithms::txn_list_append::TxnListAppend::new(1, rx)
+                    .with_config(options.txn_config()),
+            )
+                as Box<dyn Node + Send>
+        }
+        workload::Workload::TxnRwRegister => {
+            Box::new(
+                algorithms::txn_rw_register::TxnRwRegister::new(1, rx)
+                    .with_isolation(options.txn_isolation)
+                    .with_config(options.txn_config()),
+            )
+                as Box<dyn Node + Send>
+        }
     };
 
     let mut initialized = false;
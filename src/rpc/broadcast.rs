@@ -2,7 +2,7 @@ use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 
 use crate::errors;
-use crate::rpc::{self, IntoReplyBody, MessageType};
+use crate::rpc::{self, IntoReplyBody};
 
 /// Our Broadcast node will *send* and *receive* these,
 /// so need to be able to serialize them too.
@@ -31,9 +31,33 @@ impl BroadcastMsgIn {
         BroadcastMsgIn { src, dest, body }
     }
 
+    /// Hand a value to the sequencer so that it can be given a place in the total order
+    pub fn new_sequence(src: String, dest: String, value: u64) -> BroadcastMsgIn {
+        let body = BroadcastMsgRequestBody::Sequence(SequenceRequestMsg { message: value });
+        BroadcastMsgIn { src, dest, body }
+    }
+
+    /// Ship a contiguous run of the sequencer's log, beginning at position `start`
+    pub fn new_deliver(
+        src: String,
+        dest: String,
+        start: u64,
+        messages: Vec<u64>,
+    ) -> BroadcastMsgIn {
+        let body = BroadcastMsgRequestBody::Deliver(DeliverRequestMsg { start, messages });
+        BroadcastMsgIn { src, dest, body }
+    }
+
+    /// Tell the sequencer how much of its log we now hold
+    pub fn new_deliver_ok(src: String, dest: String, delivered: u64) -> BroadcastMsgIn {
+        let body = BroadcastMsgRequestBody::DeliverOk(DeliverOkMsg { delivered });
+        BroadcastMsgIn { src, dest, body }
+    }
+
     pub fn into_response(
         self,
         value: Option<HashSet<u64>>,
+        ordered: Option<Vec<u64>>,
         outbound_msg_id: u64,
     ) -> Option<BroadcastMsgOut> {
         match self.body.into_reply(outbound_msg_id) {
@@ -43,6 +67,9 @@ impl BroadcastMsgIn {
                 if _value.is_some() {
                     body.set_value(_value.take());
                 }
+                if ordered.is_some() {
+                    body.set_ordered(ordered);
+                }
                 Some(BroadcastMsgOut {
                     src: self.dest,
                     dest: self.src,
@@ -55,9 +82,10 @@ impl BroadcastMsgIn {
     pub fn into_str_response(
         self,
         value: Option<HashSet<u64>>,
+        ordered: Option<Vec<u64>>,
         outbound_msg_id: u64,
     ) -> Result<String, errors::ErrorMsg> {
-        match self.into_response(value, outbound_msg_id) {
+        match self.into_response(value, ordered, outbound_msg_id) {
             None => Ok("".to_string()),
             Some(msg_out) => {
                 serde_json::to_string(&msg_out).map_err(errors::ErrorMsg::json_dumps_error)
@@ -74,6 +102,10 @@ pub enum BroadcastMsgRequestBody {
     Broadcast(BroadcastRequestMsg),
     BroadcastOk(BroadcastReceivedOkMsg),
     Read(ReadRequestMsg),
+    // The following are only exchanged between nodes in total-order mode
+    Sequence(SequenceRequestMsg),
+    Deliver(DeliverRequestMsg),
+    DeliverOk(DeliverOkMsg),
 }
 
 impl rpc::IntoReplyBody for BroadcastMsgRequestBody {
@@ -91,6 +123,10 @@ impl rpc::IntoReplyBody for BroadcastMsgRequestBody {
                 BroadcastMsgResponseBody::Read(resp.into_reply(outbound_msg_id))
             }
             BroadcastMsgRequestBody::BroadcastOk(_) => BroadcastMsgResponseBody::NoOp,
+            // Internal messages are acknowledged (if at all) by the node itself
+            BroadcastMsgRequestBody::Sequence(_) => BroadcastMsgResponseBody::NoOp,
+            BroadcastMsgRequestBody::Deliver(_) => BroadcastMsgResponseBody::NoOp,
+            BroadcastMsgRequestBody::DeliverOk(_) => BroadcastMsgResponseBody::NoOp,
         }
     }
 }
@@ -122,6 +158,12 @@ impl BroadcastMsgResponseBody {
             BroadcastMsgResponseBody::NoOp => (),
         }
     }
+
+    pub fn set_ordered(&mut self, ordered: Option<Vec<u64>>) {
+        if let BroadcastMsgResponseBody::Read(ref mut body) = self {
+            body.ordered = ordered
+        }
+    }
 }
 
 impl rpc::Reply for BroadcastMsgResponseBody {}
@@ -135,11 +177,6 @@ pub enum MsgType {
     ReadOk,
 }
 
-/// The only allowed values for our messages below
-/// are in these newtypes. These are not-public.
-#[derive(Deserialize, Debug)]
-struct Topology(MessageType);
-
 /// Topology Request inbound
 #[derive(Serialize, Deserialize, Debug)]
 pub struct TopologyRequestMsg {
@@ -173,9 +210,6 @@ pub struct TopologyResponseMsg {
 impl rpc::Reply for TopologyResponseMsg {}
 
 /// Broadcast Request inbound
-#[derive(Serialize, Deserialize, Debug)]
-struct Broadcast(MessageType);
-
 #[derive(Serialize, Deserialize, Debug)]
 pub struct BroadcastRequestMsg {
     msg_id: Option<u64>,
@@ -214,9 +248,6 @@ impl rpc::IntoReplyBody for BroadcastRequestMsg {
 impl rpc::Reply for BroadcastResponseMsg {}
 
 /// Read Request inbound
-#[derive(Serialize, Deserialize, Debug)]
-struct Read(MessageType);
-
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ReadRequestMsg {
     msg_id: Option<u64>,
@@ -229,6 +260,7 @@ impl rpc::IntoReplyBody for ReadRequestMsg {
             msg_id: outbound_msg_id,
             in_reply_to: self.msg_id,
             messages: HashSet::new(),
+            ordered: None,
         }
     }
 }
@@ -244,6 +276,28 @@ pub struct ReadResponseMsg {
     in_reply_to: Option<u64>,
     msg_id: u64,
     messages: HashSet<u64>,
+    // Only present in total-order mode: values in delivery order
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ordered: Option<Vec<u64>>,
 }
 
 impl rpc::Reply for ReadResponseMsg {}
+
+/// Sequence Request: a node hands a value to the sequencer
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SequenceRequestMsg {
+    pub message: u64,
+}
+
+/// Deliver: the sequencer ships its log, starting from `start`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeliverRequestMsg {
+    pub start: u64,
+    pub messages: Vec<u64>,
+}
+
+/// Deliver Ok: number of log entries the sender has delivered so far
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeliverOkMsg {
+    pub delivered: u64,
+}
@@ -0,0 +1,46 @@
+use serde::{Deserialize, Serialize};
+
+use crate::rpc::txn::MicroOp;
+
+/// A batch of transactions, as kept in the log in lin-kv. `id` names the batch, so
+/// that a batch which ends up in the log twice is only carried out once.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Batch {
+    pub id: String,
+    pub txns: Vec<Sequenced>,
+}
+
+/// A transaction, with the node its client asked: that node answers it
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Sequenced {
+    pub origin: String,
+    pub txn: Vec<MicroOp>,
+}
+
+/// Messages from the nodes owning a transaction's keys to the node answering it
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct CalvinMessage {
+    pub src: String,
+    pub dest: String,
+    pub body: CalvinMessageBody,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum CalvinMessageBody {
+    Executed(ExecutedMsg),
+    ExecutedOk(ExecutedOkMsg),
+}
+
+/// An owner's share of transaction `txn_id`, carried out, with its reads filled in
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ExecutedMsg {
+    pub txn_id: String,
+    pub ops: Vec<MicroOp>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ExecutedOkMsg {
+    pub txn_id: String,
+}
@@ -0,0 +1,74 @@
+use serde::{Deserialize, Serialize};
+
+use crate::rpc::raft::ClientRequest;
+
+/// Chain replication messages between nodes, after van Renesse & Schneider's "Chain
+/// Replication for Supporting High Throughput and Availability". `R` is the client
+/// request type of the replicated state machine and `T` its snapshot type.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ChainMessage<R, T> {
+    pub src: String,
+    pub dest: String,
+    pub body: ChainMessageBody<R, T>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum ChainMessageBody<R, T> {
+    ChainSubmit(SubmitMsg<R>),
+    ChainForward(ForwardMsg<R>),
+    ChainAck(AckMsg),
+    ChainSync(SyncMsg<T>),
+}
+
+/// Message types above, so that other traffic can be told apart without parsing it all
+pub const MESSAGE_TYPES: [&str; 4] = ["chain_submit", "chain_forward", "chain_ack", "chain_sync"];
+
+/// The chain, as kept under one key in maelstrom's lin-kv service. Each change
+/// bumps the epoch, and is made with a cas from the configuration it replaces.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct ChainConfig {
+    pub epoch: u64,
+    pub chain: Vec<String>,
+}
+
+/// An update which a node took from a client, sent to the head to be sequenced
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct SubmitMsg<R> {
+    pub request: ClientRequest<R>,
+}
+
+/// An update in the order the head gave it
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct ChainEntry<R> {
+    pub seq: u64,
+    pub request: ClientRequest<R>,
+}
+
+/// Entries passed down the chain. Also sent every tick, as a heartbeat.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ForwardMsg<R> {
+    pub epoch: u64,
+    pub entries: Vec<ChainEntry<R>>,
+}
+
+/// Passed back up the chain: every entry up to `seq` has reached the sender, and
+/// every entry up to `committed` has reached the tail
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct AckMsg {
+    pub epoch: u64,
+    pub seq: u64,
+    pub committed: u64,
+    // The sender has just joined, and needs the state before it can take entries
+    #[serde(default)]
+    pub needs_sync: bool,
+}
+
+/// The state with every entry up to `seq` applied, for a node which has just joined
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct SyncMsg<T> {
+    pub epoch: u64,
+    pub seq: u64,
+    pub state: T,
+}
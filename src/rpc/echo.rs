@@ -6,11 +6,6 @@ use crate::rpc::{self, MessageType};
 
 use super::IntoReplyBody;
 
-/// The only allowed values for our messages below
-/// are in these newtypes. These are not-public.
-#[derive(Deserialize)]
-struct Echo(MessageType);
-
 /// Outbound message type strings
 #[derive(Serialize)]
 #[serde(rename_all = "snake_case")]
@@ -58,7 +53,7 @@ pub struct EchoMsgOut {
 #[derive(Deserialize)]
 pub struct EchoRequestMsg {
     #[serde(rename = "type")]
-    _typ: Echo,
+    _typ: MessageType,
     pub msg_id: u64,
     pub echo: Value,
 }
@@ -66,7 +61,7 @@ pub struct EchoRequestMsg {
 impl EchoRequestMsg {
     pub fn new(msg_id: u64, echo: Value) -> Self {
         EchoRequestMsg {
-            _typ: Echo(MessageType::Echo),
+            _typ: MessageType::Echo,
             msg_id,
             echo,
         }
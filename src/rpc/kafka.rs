@@ -0,0 +1,218 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+
+use crate::errors;
+use crate::rpc;
+
+/// Kafka-style log messages: see maelstrom kafka docs
+/// https://github.com/jepsen-io/maelstrom/blob/main/doc/workloads.md#workload-kafka
+#[derive(Serialize, Deserialize, Debug)]
+pub struct KafkaMessage {
+    pub src: String,
+    pub dest: String,
+    pub body: KafkaMessageBody,
+}
+
+impl KafkaMessage {
+    /// Swap source and destination to answer this message with `body`
+    pub fn reply(&self, body: KafkaMessageBody) -> KafkaMessage {
+        KafkaMessage {
+            src: self.dest.clone(),
+            dest: self.src.clone(),
+            body,
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String, errors::ErrorMsg> {
+        serde_json::to_string(self).map_err(errors::ErrorMsg::json_dumps_error)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum KafkaMessageBody {
+    Send(SendRequestMsg),
+    SendOk(SendResponseMsg),
+    Poll(PollRequestMsg),
+    PollOk(PollResponseMsg),
+    CommitOffsets(CommitOffsetsRequestMsg),
+    CommitOffsetsOk(CommitOffsetsResponseMsg),
+    ListCommittedOffsets(ListCommittedOffsetsRequestMsg),
+    ListCommittedOffsetsOk(ListCommittedOffsetsResponseMsg),
+    // Between nodes: copies of messages which have been given an offset
+    Replicate(ReplicateRequestMsg),
+    ReplicateOk(ReplicateResponseMsg),
+}
+
+impl KafkaMessageBody {
+    pub fn msg_id(&self) -> Option<u64> {
+        match self {
+            KafkaMessageBody::Send(body) => body.msg_id,
+            KafkaMessageBody::Poll(body) => body.msg_id,
+            KafkaMessageBody::CommitOffsets(body) => body.msg_id,
+            KafkaMessageBody::ListCommittedOffsets(body) => body.msg_id,
+            KafkaMessageBody::Replicate(body) => body.msg_id,
+            KafkaMessageBody::SendOk(body) => Some(body.msg_id),
+            KafkaMessageBody::PollOk(body) => Some(body.msg_id),
+            KafkaMessageBody::CommitOffsetsOk(body) => Some(body.msg_id),
+            KafkaMessageBody::ListCommittedOffsetsOk(body) => Some(body.msg_id),
+            KafkaMessageBody::ReplicateOk(_) => None,
+        }
+    }
+}
+
+/// Send: append `msg` to the log for `key`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SendRequestMsg {
+    pub msg_id: Option<u64>,
+    pub key: String,
+    pub msg: Value,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SendResponseMsg {
+    pub in_reply_to: Option<u64>,
+    pub msg_id: u64,
+    pub offset: u64,
+}
+
+impl SendResponseMsg {
+    pub fn new(in_reply_to: Option<u64>, msg_id: u64, offset: u64) -> Self {
+        Self {
+            in_reply_to,
+            msg_id,
+            offset,
+        }
+    }
+}
+
+impl rpc::Reply for SendResponseMsg {}
+
+/// Poll: messages from each key's log, starting at the given offsets
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct PollRequestMsg {
+    pub msg_id: Option<u64>,
+    pub offsets: HashMap<String, u64>,
+    // Extension: at most this many messages in all (the node has its own limit too)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_messages: Option<usize>,
+    // Extension: if there is nothing to return, wait up to this long for messages
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wait_ms: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PollResponseMsg {
+    pub in_reply_to: Option<u64>,
+    pub msg_id: u64,
+    // key -> [[offset, msg], ...]
+    pub msgs: HashMap<String, Vec<(u64, Value)>>,
+}
+
+impl PollResponseMsg {
+    pub fn new(
+        in_reply_to: Option<u64>,
+        msg_id: u64,
+        msgs: HashMap<String, Vec<(u64, Value)>>,
+    ) -> Self {
+        Self {
+            in_reply_to,
+            msg_id,
+            msgs,
+        }
+    }
+}
+
+impl rpc::Reply for PollResponseMsg {}
+
+/// Commit Offsets: the client has processed each key's log up to these offsets
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CommitOffsetsRequestMsg {
+    pub msg_id: Option<u64>,
+    pub offsets: HashMap<String, u64>,
+    // Extension: consumer group; without one we use the single offset per key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CommitOffsetsResponseMsg {
+    pub in_reply_to: Option<u64>,
+    pub msg_id: u64,
+}
+
+impl CommitOffsetsResponseMsg {
+    pub fn new(in_reply_to: Option<u64>, msg_id: u64) -> Self {
+        Self {
+            in_reply_to,
+            msg_id,
+        }
+    }
+}
+
+impl rpc::Reply for CommitOffsetsResponseMsg {}
+
+/// List Committed Offsets: the latest committed offset for each of `keys`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListCommittedOffsetsRequestMsg {
+    pub msg_id: Option<u64>,
+    pub keys: Vec<String>,
+    // Extension: consumer group, as for commit_offsets
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListCommittedOffsetsResponseMsg {
+    pub in_reply_to: Option<u64>,
+    pub msg_id: u64,
+    pub offsets: HashMap<String, u64>,
+}
+
+impl ListCommittedOffsetsResponseMsg {
+    pub fn new(in_reply_to: Option<u64>, msg_id: u64, offsets: HashMap<String, u64>) -> Self {
+        Self {
+            in_reply_to,
+            msg_id,
+            offsets,
+        }
+    }
+}
+
+impl rpc::Reply for ListCommittedOffsetsResponseMsg {}
+
+/// Replicate: store `msg` at `offset` in the log for `key`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReplicateRequestMsg {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub msg_id: Option<u64>,
+    pub key: String,
+    pub offset: u64,
+    pub msg: Value,
+}
+
+impl ReplicateRequestMsg {
+    pub fn new(key: String, offset: u64, msg: Value) -> Self {
+        Self {
+            msg_id: None,
+            key,
+            offset,
+            msg,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReplicateResponseMsg {
+    pub in_reply_to: Option<u64>,
+}
+
+impl ReplicateResponseMsg {
+    pub fn new(in_reply_to: Option<u64>) -> Self {
+        Self { in_reply_to }
+    }
+}
+
+impl rpc::Reply for ReplicateResponseMsg {}
@@ -0,0 +1,148 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::errors;
+use crate::rpc;
+
+/// Linearizable key-value messages: see maelstrom lin-kv docs
+/// https://github.com/jepsen-io/maelstrom/blob/main/doc/workloads.md#workload-lin-kv
+/// Keys and values may be any JSON value.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct LinKvMessage {
+    pub src: String,
+    pub dest: String,
+    pub body: LinKvMessageBody,
+}
+
+impl LinKvMessage {
+    /// Swap source and destination to answer this message with `body`
+    pub fn reply(&self, body: LinKvMessageBody) -> LinKvMessage {
+        LinKvMessage {
+            src: self.dest.clone(),
+            dest: self.src.clone(),
+            body,
+        }
+    }
+
+    /// Answer this message with an error instead
+    pub fn reply_error(&self, error: errors::ErrorMsg) -> errors::ErrorMsgOut {
+        errors::ErrorMsgOut::new(self.dest.clone(), self.src.clone(), error)
+    }
+
+    pub fn to_json(&self) -> Result<String, errors::ErrorMsg> {
+        serde_json::to_string(self).map_err(errors::ErrorMsg::json_dumps_error)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum LinKvMessageBody {
+    Read(ReadRequestMsg),
+    ReadOk(ReadResponseMsg),
+    Write(WriteRequestMsg),
+    WriteOk(WriteResponseMsg),
+    Cas(CasRequestMsg),
+    CasOk(CasResponseMsg),
+}
+
+impl LinKvMessageBody {
+    pub fn msg_id(&self) -> Option<u64> {
+        match self {
+            LinKvMessageBody::Read(body) => body.msg_id,
+            LinKvMessageBody::Write(body) => body.msg_id,
+            LinKvMessageBody::Cas(body) => body.msg_id,
+            LinKvMessageBody::ReadOk(body) => Some(body.msg_id),
+            LinKvMessageBody::WriteOk(body) => Some(body.msg_id),
+            LinKvMessageBody::CasOk(body) => Some(body.msg_id),
+        }
+    }
+
+    /// True for the requests a client sends (rather than our replies)
+    pub fn is_request(&self) -> bool {
+        matches!(
+            self,
+            LinKvMessageBody::Read(_) | LinKvMessageBody::Write(_) | LinKvMessageBody::Cas(_)
+        )
+    }
+}
+
+/// Read: the current value of `key`
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ReadRequestMsg {
+    pub msg_id: Option<u64>,
+    pub key: Value,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ReadResponseMsg {
+    pub in_reply_to: Option<u64>,
+    pub msg_id: u64,
+    pub value: Value,
+}
+
+impl ReadResponseMsg {
+    pub fn new(in_reply_to: Option<u64>, msg_id: u64, value: Value) -> Self {
+        Self {
+            in_reply_to,
+            msg_id,
+            value,
+        }
+    }
+}
+
+impl rpc::Reply for ReadResponseMsg {}
+
+/// Write: set `key` to `value`
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct WriteRequestMsg {
+    pub msg_id: Option<u64>,
+    pub key: Value,
+    pub value: Value,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct WriteResponseMsg {
+    pub in_reply_to: Option<u64>,
+    pub msg_id: u64,
+}
+
+impl WriteResponseMsg {
+    pub fn new(in_reply_to: Option<u64>, msg_id: u64) -> Self {
+        Self {
+            in_reply_to,
+            msg_id,
+        }
+    }
+}
+
+impl rpc::Reply for WriteResponseMsg {}
+
+/// Cas: set `key` to `to` only if it currently holds `from`
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct CasRequestMsg {
+    pub msg_id: Option<u64>,
+    pub key: Value,
+    pub from: Value,
+    pub to: Value,
+    // As for maelstrom's own lin-kv service: create the key if it is missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub create_if_not_exists: Option<bool>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct CasResponseMsg {
+    pub in_reply_to: Option<u64>,
+    pub msg_id: u64,
+}
+
+impl CasResponseMsg {
+    pub fn new(in_reply_to: Option<u64>, msg_id: u64) -> Self {
+        Self {
+            in_reply_to,
+            msg_id,
+        }
+    }
+}
+
+impl rpc::Reply for CasResponseMsg {}
@@ -1,7 +1,15 @@
 pub mod broadcast;
+pub mod calvin;
+pub mod chain;
 pub mod echo;
 pub mod gcounter;
 pub mod gset;
+pub mod kafka;
+pub mod lin_kv;
+pub mod paxos;
+pub mod raft;
+pub mod two_phase;
+pub mod txn;
 pub mod unique_ids;
 
 use serde::{Deserialize, Serialize};
@@ -38,8 +46,6 @@ pub trait IntoReplyBody {
     fn into_reply(self, outbound_msg_id: u64) -> Self::Item;
 }
 
-///
-
 #[derive(Clone, Debug, Deserialize)]
 pub struct InitMsgIn {
     pub src: String,
@@ -74,9 +80,6 @@ pub struct InitMsgOut {
     pub body: InitResponseMsg,
 }
 
-#[derive(Clone, Deserialize, Debug)]
-struct Init(MessageType);
-
 #[derive(Serialize, Debug)]
 #[serde(rename_all = "snake_case")]
 enum InitMessageResp {
@@ -89,7 +92,7 @@ struct InitOk(InitMessageResp);
 #[derive(Clone, Deserialize, Debug)]
 pub struct InitRequestMsg {
     #[serde(rename = "type")]
-    _typ: Init,
+    _typ: MessageType,
     pub msg_id: u64,
     pub node_id: String,
     pub node_ids: Vec<String>,
@@ -98,7 +101,7 @@ pub struct InitRequestMsg {
 impl InitRequestMsg {
     pub fn new(msg_id: u64, node_id: String, node_ids: Vec<String>) -> Self {
         InitRequestMsg {
-            _typ: Init(MessageType::Init),
+            _typ: MessageType::Init,
             msg_id,
             node_id,
             node_ids,
@@ -0,0 +1,91 @@
+use serde::{Deserialize, Serialize};
+
+use crate::rpc::raft::ClientRequest;
+
+/// Multi-Paxos messages between nodes, after Lamport's "Paxos Made Simple" and
+/// van Renesse & Altinbuken's "Paxos Made Moderately Complex". `R` is the client
+/// request type of the replicated state machine.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct PaxosMessage<R> {
+    pub src: String,
+    pub dest: String,
+    pub body: PaxosMessageBody<R>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum PaxosMessageBody<R> {
+    Prepare(PrepareMsg),
+    Promise(PromiseMsg<R>),
+    Accept(AcceptMsg<R>),
+    Accepted(AcceptedMsg),
+}
+
+/// Message types above, so that other traffic can be told apart without parsing it all
+pub const MESSAGE_TYPES: [&str; 4] = ["prepare", "promise", "accept", "accepted"];
+
+/// Ballots are ordered by round, then by node id, so no two nodes share one
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Ballot {
+    pub round: u64,
+    pub node: String,
+}
+
+impl Ballot {
+    pub fn new(round: u64, node: String) -> Self {
+        Self { round, node }
+    }
+}
+
+/// A value for one slot of the log: a client request, or None for a no-op
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Proposal<R> {
+    pub slot: u64,
+    pub request: Option<ClientRequest<R>>,
+}
+
+/// A proposal which an acceptor has accepted, and in which ballot
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct AcceptedProposal<R> {
+    pub ballot: Ballot,
+    pub proposal: Proposal<R>,
+}
+
+/// Phase 1a: a would-be leader asks for a promise covering every slot from `from`
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct PrepareMsg {
+    pub ballot: Ballot,
+    pub from: u64,
+}
+
+/// Phase 1b: the promise (or, if `ok` is false, the higher ballot already promised)
+/// along with everything accepted from `from` on
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct PromiseMsg<R> {
+    pub ballot: Ballot,
+    pub ok: bool,
+    pub promised: Ballot,
+    pub accepted: Vec<AcceptedProposal<R>>,
+    pub executed: u64,
+}
+
+/// Phase 2a: proposals in the leader's ballot, batched. Also sent as a heartbeat.
+/// Every slot up to `executed` is chosen.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct AcceptMsg<R> {
+    pub ballot: Ballot,
+    pub proposals: Vec<Proposal<R>>,
+    pub executed: u64,
+}
+
+/// Phase 2b: the slots we accepted, or if `ok` is false the higher ballot we promised
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct AcceptedMsg {
+    pub ballot: Ballot,
+    pub ok: bool,
+    pub promised: Ballot,
+    pub slots: Vec<u64>,
+    // Every slot up to here is chosen and applied by us
+    pub executed: u64,
+}
@@ -0,0 +1,206 @@
+use serde::{Deserialize, Serialize};
+
+/// Raft messages between nodes, after the Raft paper (Ongaro & Ousterhout):
+/// https://raft.github.io/raft.pdf
+/// `R` is the client request type of the replicated state machine and `T` the
+/// type of its snapshots.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct RaftMessage<R, T> {
+    pub src: String,
+    pub dest: String,
+    pub body: RaftMessageBody<R, T>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum RaftMessageBody<R, T> {
+    RequestVote(RequestVoteMsg),
+    RequestVoteOk(RequestVoteResponseMsg),
+    AppendEntries(AppendEntriesMsg<R>),
+    AppendEntriesOk(AppendEntriesResponseMsg),
+    InstallSnapshot(InstallSnapshotMsg<T>),
+    InstallSnapshotOk(InstallSnapshotResponseMsg),
+}
+
+impl<R, T> RaftMessageBody<R, T> {
+    pub fn term(&self) -> u64 {
+        match self {
+            RaftMessageBody::RequestVote(body) => body.term,
+            RaftMessageBody::RequestVoteOk(body) => body.term,
+            RaftMessageBody::AppendEntries(body) => body.term,
+            RaftMessageBody::AppendEntriesOk(body) => body.term,
+            RaftMessageBody::InstallSnapshot(body) => body.term,
+            RaftMessageBody::InstallSnapshotOk(body) => body.term,
+        }
+    }
+}
+
+/// Message types above, so that other traffic can be told apart without parsing it all
+pub const MESSAGE_TYPES: [&str; 6] = [
+    "request_vote",
+    "request_vote_ok",
+    "append_entries",
+    "append_entries_ok",
+    "install_snapshot",
+    "install_snapshot_ok",
+];
+
+/// A client request in the log, with enough to answer it once applied
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct ClientRequest<R> {
+    // The node which accepted the request: only it replies
+    pub origin: String,
+    // Where that node sends the reply (a client, or a node which forwarded to it)
+    pub client: String,
+    pub body: R,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Entry<R> {
+    pub term: u64,
+    // None for the no-op each new leader appends to commit entries from earlier terms
+    pub request: Option<ClientRequest<R>>,
+    // Set for membership changes, which carry no client request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config: Option<ConfigChange>,
+}
+
+/// A new configuration in the log. Each node uses the latest configuration in its
+/// log, committed or not, from the moment it appends it.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct ConfigChange {
+    pub node_ids: Vec<String>,
+    // As for ClientRequest: who answers the change, and where to
+    pub origin: String,
+    pub client: String,
+    pub request: MembershipRequest,
+}
+
+/// Membership changes, one server at a time (section 4.1 of Ongaro's thesis,
+/// "Consensus: Bridging Theory and Practice"). Sent to any node by an administrator
+/// and answered once the new configuration is committed.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum MembershipRequest {
+    AddServer(ServerMsg),
+    RemoveServer(ServerMsg),
+}
+
+impl MembershipRequest {
+    pub fn node_id(&self) -> &str {
+        match self {
+            MembershipRequest::AddServer(body) => &body.node_id,
+            MembershipRequest::RemoveServer(body) => &body.node_id,
+        }
+    }
+
+    pub fn reply(&self, msg_id: u64) -> MembershipResponse {
+        match self {
+            MembershipRequest::AddServer(body) => {
+                MembershipResponse::AddServerOk(ServerResponseMsg::new(msg_id, body.msg_id))
+            }
+            MembershipRequest::RemoveServer(body) => {
+                MembershipResponse::RemoveServerOk(ServerResponseMsg::new(msg_id, body.msg_id))
+            }
+        }
+    }
+}
+
+pub const MEMBERSHIP_TYPES: [&str; 2] = ["add_server", "remove_server"];
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct ServerMsg {
+    pub msg_id: Option<u64>,
+    pub node_id: String,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum MembershipResponse {
+    AddServerOk(ServerResponseMsg),
+    RemoveServerOk(ServerResponseMsg),
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ServerResponseMsg {
+    pub msg_id: u64,
+    pub in_reply_to: Option<u64>,
+}
+
+impl ServerResponseMsg {
+    pub fn new(msg_id: u64, in_reply_to: Option<u64>) -> Self {
+        Self {
+            msg_id,
+            in_reply_to,
+        }
+    }
+}
+
+/// Request Vote: a candidate asks for our vote in `term`
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct RequestVoteMsg {
+    pub term: u64,
+    pub last_log_index: u64,
+    pub last_log_term: u64,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct RequestVoteResponseMsg {
+    pub term: u64,
+    pub vote_granted: bool,
+}
+
+/// Append Entries: the leader's log from `prev_log_index + 1`, also used as a heartbeat
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct AppendEntriesMsg<R> {
+    pub term: u64,
+    pub prev_log_index: u64,
+    pub prev_log_term: u64,
+    pub entries: Vec<Entry<R>>,
+    pub leader_commit: u64,
+    // Counts the leader's rounds of append_entries, so it knows which were answered
+    #[serde(default)]
+    pub read_round: u64,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct AppendEntriesResponseMsg {
+    pub term: u64,
+    pub success: bool,
+    // On success, the last index we now hold which matches the leader
+    pub match_index: u64,
+    // On failure, where the leader should try again from
+    pub next_index: u64,
+    // The read_round we are answering
+    #[serde(default)]
+    pub read_round: u64,
+}
+
+/// The state machine as of `last_included_index`, standing in for the log up to there
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Snapshot<T> {
+    pub last_included_index: u64,
+    pub last_included_term: u64,
+    // The configuration as of `last_included_index`
+    #[serde(default)]
+    pub node_ids: Vec<String>,
+    pub state: T,
+}
+
+/// Install Snapshot: the leader's snapshot, for a follower which needs entries the
+/// leader has compacted away. Unlike the paper, snapshots are sent whole.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct InstallSnapshotMsg<T> {
+    pub term: u64,
+    pub snapshot: Snapshot<T>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct InstallSnapshotResponseMsg {
+    pub term: u64,
+    // The last index we now hold, as for append_entries_ok
+    pub match_index: u64,
+}
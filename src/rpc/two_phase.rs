@@ -0,0 +1,54 @@
+use serde::{Deserialize, Serialize};
+
+use crate::rpc::txn::MicroOp;
+
+/// Two-phase commit messages between a transaction's coordinator (the node the
+/// client asked) and its participants (the nodes owning the keys it touches).
+/// Everything is matched up by `txn_id`, so any message may be sent again.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct TwoPhaseMessage {
+    pub src: String,
+    pub dest: String,
+    pub body: TwoPhaseMessageBody,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum TwoPhaseMessageBody {
+    Prepare(PrepareMsg),
+    PrepareOk(VoteMsg),
+    Commit(DecisionMsg),
+    Abort(DecisionMsg),
+    DecisionOk(DecisionMsg),
+    Query(DecisionMsg),
+}
+
+/// Prepare: lock the keys of `ops` (the transaction's micro-ops on keys the
+/// participant owns, in order), carry them out without installing them, and vote
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct PrepareMsg {
+    pub txn_id: String,
+    pub ops: Vec<MicroOp>,
+}
+
+/// A participant's vote, with its ops' reads filled in if it voted to commit
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct VoteMsg {
+    pub txn_id: String,
+    pub commit: bool,
+    pub ops: Vec<MicroOp>,
+}
+
+/// Commit or abort from the coordinator, the participant's acknowledgement, or a
+/// prepared participant asking what was decided
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct DecisionMsg {
+    pub txn_id: String,
+}
+
+impl DecisionMsg {
+    pub fn new(txn_id: String) -> Self {
+        Self { txn_id }
+    }
+}
@@ -0,0 +1,238 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::errors;
+use crate::rpc;
+use crate::workload::Isolation;
+
+/// Transaction messages: see maelstrom txn-rw-register docs
+/// https://github.com/jepsen-io/maelstrom/blob/main/doc/workloads.md#workload-txn-rw-register
+/// A transaction is a list of micro-ops, each a `[function, key, value]` triple.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct TxnMessage {
+    pub src: String,
+    pub dest: String,
+    pub body: TxnMessageBody,
+}
+
+impl TxnMessage {
+    /// Swap source and destination to answer this message with `body`
+    pub fn reply(&self, body: TxnMessageBody) -> TxnMessage {
+        TxnMessage {
+            src: self.dest.clone(),
+            dest: self.src.clone(),
+            body,
+        }
+    }
+
+    /// Answer this message with an error instead
+    pub fn reply_error(&self, error: errors::ErrorMsg) -> errors::ErrorMsgOut {
+        errors::ErrorMsgOut::new(self.dest.clone(), self.src.clone(), error)
+    }
+
+    pub fn to_json(&self) -> Result<String, errors::ErrorMsg> {
+        serde_json::to_string(self).map_err(errors::ErrorMsg::json_dumps_error)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum TxnMessageBody {
+    Txn(TxnRequestMsg),
+    TxnOk(TxnResponseMsg),
+    Replicate(ReplicateRequestMsg),
+    ReplicateOk(ReplicateResponseMsg),
+    Certify(CertifyRequestMsg),
+    CertifyOk(CertifyResponseMsg),
+}
+
+impl TxnMessageBody {
+    pub fn msg_id(&self) -> Option<u64> {
+        match self {
+            TxnMessageBody::Txn(body) => body.msg_id,
+            TxnMessageBody::TxnOk(body) => Some(body.msg_id),
+            TxnMessageBody::Replicate(body) => body.msg_id,
+            TxnMessageBody::ReplicateOk(_) => None,
+            TxnMessageBody::Certify(body) => body.msg_id,
+            TxnMessageBody::CertifyOk(_) => None,
+        }
+    }
+}
+
+/// One step of a transaction. On the wire a read is `["r", key, null]`, which the
+/// reply fills in with the value read (null if the key has never been written), and
+/// a write is `["w", key, value]`. The txn-list-append workload reads lists, and
+/// adds to them with `["append", key, element]` in place of writes.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(try_from = "(String, Value, Value)", into = "(String, Value, Value)")]
+pub enum MicroOp {
+    Read { key: Value, value: Option<Value> },
+    Write { key: Value, value: Value },
+    Append { key: Value, element: Value },
+}
+
+impl MicroOp {
+    pub fn key(&self) -> &Value {
+        match self {
+            MicroOp::Read { key, .. } => key,
+            MicroOp::Write { key, .. } => key,
+            MicroOp::Append { key, .. } => key,
+        }
+    }
+}
+
+impl TryFrom<(String, Value, Value)> for MicroOp {
+    type Error = String;
+
+    fn try_from((function, key, value): (String, Value, Value)) -> Result<Self, Self::Error> {
+        match function.as_str() {
+            "r" => Ok(MicroOp::Read {
+                key,
+                value: (!value.is_null()).then_some(value),
+            }),
+            "w" => Ok(MicroOp::Write { key, value }),
+            "append" => Ok(MicroOp::Append {
+                key,
+                element: value,
+            }),
+            other => Err(format!("unknown micro-op {}", other)),
+        }
+    }
+}
+
+impl From<MicroOp> for (String, Value, Value) {
+    fn from(op: MicroOp) -> Self {
+        match op {
+            MicroOp::Read { key, value } => ("r".to_string(), key, value.unwrap_or(Value::Null)),
+            MicroOp::Write { key, value } => ("w".to_string(), key, value),
+            MicroOp::Append { key, element } => ("append".to_string(), key, element),
+        }
+    }
+}
+
+/// Txn: carry out every micro-op in `txn`, as one atomic step, at `isolation` if
+/// given (otherwise at the node's own level)
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct TxnRequestMsg {
+    pub msg_id: Option<u64>,
+    pub txn: Vec<MicroOp>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub isolation: Option<Isolation>,
+}
+
+/// The transaction as carried out, with every read's value filled in
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct TxnResponseMsg {
+    pub in_reply_to: Option<u64>,
+    pub msg_id: u64,
+    pub txn: Vec<MicroOp>,
+}
+
+impl TxnResponseMsg {
+    pub fn new(in_reply_to: Option<u64>, msg_id: u64, txn: Vec<MicroOp>) -> Self {
+        Self {
+            in_reply_to,
+            msg_id,
+            txn,
+        }
+    }
+}
+
+impl rpc::Reply for TxnResponseMsg {}
+
+/// Orders transactions' writes: by counter (a Lamport clock), then by node
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp {
+    pub counter: u64,
+    pub node: String,
+}
+
+impl Timestamp {
+    pub fn new(counter: u64, node: String) -> Self {
+        Self { counter, node }
+    }
+}
+
+/// Replicate: the final value of every key a committed transaction wrote, all
+/// stamped with the transaction's timestamp. Transactions which were certified
+/// carry their place (`seq`) in the certifier's order too.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ReplicateRequestMsg {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub msg_id: Option<u64>,
+    pub timestamp: Timestamp,
+    pub writes: Vec<(Value, Value)>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+}
+
+impl ReplicateRequestMsg {
+    pub fn new(timestamp: Timestamp, writes: Vec<(Value, Value)>, seq: Option<u64>) -> Self {
+        Self {
+            msg_id: None,
+            timestamp,
+            writes,
+            seq,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ReplicateResponseMsg {
+    pub in_reply_to: Option<u64>,
+}
+
+impl ReplicateResponseMsg {
+    pub fn new(in_reply_to: Option<u64>) -> Self {
+        Self { in_reply_to }
+    }
+}
+
+impl rpc::Reply for ReplicateResponseMsg {}
+
+/// Certify: commit `writes` only if every key in `checks` still holds the version
+/// (the writing transaction's timestamp, or null for none) the transaction saw.
+/// `waiting_since` is the oldest certify its sender is still waiting on, if not this
+/// one: the sender has the answer to every request before it.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct CertifyRequestMsg {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub msg_id: Option<u64>,
+    pub checks: Vec<(Value, Option<Timestamp>)>,
+    pub writes: Vec<(Value, Value)>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub waiting_since: Option<u64>,
+}
+
+impl CertifyRequestMsg {
+    pub fn new(
+        checks: Vec<(Value, Option<Timestamp>)>,
+        writes: Vec<(Value, Value)>,
+        waiting_since: Option<u64>,
+    ) -> Self {
+        Self {
+            msg_id: None,
+            checks,
+            writes,
+            waiting_since,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct CertifyResponseMsg {
+    pub in_reply_to: Option<u64>,
+    pub committed: bool,
+}
+
+impl CertifyResponseMsg {
+    pub fn new(in_reply_to: Option<u64>, committed: bool) -> Self {
+        Self {
+            in_reply_to,
+            committed,
+        }
+    }
+}
+
+impl rpc::Reply for CertifyResponseMsg {}
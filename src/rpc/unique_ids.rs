@@ -21,7 +21,7 @@ impl GenerateMsgIn {
 
     pub fn parse_msg_to_str_response(
         msg: &str,
-        value: String,
+        value: GeneratedId,
         outbound_msg_id: u64,
     ) -> Result<String, errors::ErrorMsg> {
         let msg_out = serde_json::from_str::<Self>(msg)
@@ -35,6 +35,14 @@ impl GenerateMsgIn {
     }
 }
 
+/// IDs go out as JSON numbers or strings, depending on the strategy which made them
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum GeneratedId {
+    Numeric(u64),
+    Text(String),
+}
+
 #[derive(Serialize, Debug)]
 pub struct GenerateMsgOut {
     pub src: String,
@@ -49,25 +57,20 @@ pub enum GenerateMsgType {
     GenerateOk,
 }
 
-/// The only allowed values for our messages below
-/// are in these newtypes. These are not-public.
-#[derive(Deserialize, Debug)]
-struct Generate(MessageType);
-
 #[derive(Serialize, Debug)]
 struct GenerateOk(GenerateMsgType);
 
 #[derive(Deserialize, Debug)]
 pub struct GenerateRequestMsg {
     #[serde(rename = "type")]
-    _typ: Generate,
+    _typ: MessageType,
     pub msg_id: u64,
 }
 
 impl GenerateRequestMsg {
     pub fn new(msg_id: u64) -> Self {
         GenerateRequestMsg {
-            _typ: Generate(MessageType::Generate),
+            _typ: MessageType::Generate,
             msg_id,
         }
     }
@@ -80,7 +83,7 @@ impl rpc::IntoReplyBody for GenerateRequestMsg {
             typ: GenerateOk(GenerateMsgType::GenerateOk),
             msg_id: outbound_msg_id,
             in_reply_to: Some(self.msg_id),
-            value: "".to_string(),
+            value: GeneratedId::Numeric(0),
         }
     }
 }
@@ -92,6 +95,6 @@ pub struct GenerateResponseMsg {
     in_reply_to: Option<u64>,
     msg_id: u64,
     #[serde(rename = "id")]
-    pub value: String,
+    pub value: GeneratedId,
 }
 impl rpc::Reply for GenerateResponseMsg {}
@@ -0,0 +1,387 @@
+/// Random lin-kv clients for the simulator, and a checker for their histories.
+///
+/// The checker is Wing & Gong's search with memoisation (after Lowe, "Testing for
+/// linearizability"): it looks for an order of the operations, consistent with
+/// real time, in which every result makes sense for a single register. Keys are
+/// independent, so each key's operations are checked on their own.
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde_json::{json, Value};
+
+use super::Simulation;
+use crate::errors::ErrorType;
+use crate::node::Process;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum KvOp {
+    Read,
+    Write(Value),
+    Cas(Value, Value),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Outcome {
+    // A read's value (None when the key did not exist), or a write or cas which happened
+    Ok(Option<Value>),
+    // A cas which found a different value (or no value: KeyDoesNotExist)
+    CasFailed,
+    // No answer: it may or may not have happened
+    Unknown,
+}
+
+#[derive(Clone, Debug)]
+pub struct Operation {
+    pub client: usize,
+    pub key: u64,
+    pub op: KvOp,
+    pub invoke: u64,
+    // u64::MAX for operations we never heard back about
+    pub complete: u64,
+    pub outcome: Outcome,
+}
+
+impl Operation {
+    /// The register's value after this operation, or None if its result rules out `value`
+    fn step(&self, value: &Option<Value>) -> Option<Option<Value>> {
+        match (&self.op, &self.outcome) {
+            (KvOp::Read, Outcome::Ok(read)) => (read == value).then(|| value.clone()),
+            (KvOp::Read, _) => Some(value.clone()),
+            (KvOp::Write(to), _) => Some(Some(to.clone())),
+            (KvOp::Cas(from, to), Outcome::Ok(_)) => {
+                (value.as_ref() == Some(from)).then(|| Some(to.clone()))
+            }
+            (KvOp::Cas(from, _), Outcome::CasFailed) => {
+                (value.as_ref() != Some(from)).then(|| value.clone())
+            }
+            // Unknown: if it happened it succeeded exactly when the value matched
+            (KvOp::Cas(from, to), Outcome::Unknown) => match value.as_ref() == Some(from) {
+                true => Some(Some(to.clone())),
+                false => Some(value.clone()),
+            },
+        }
+    }
+}
+
+/// Check every key's operations; on failure, name a key which cannot be linearized
+pub fn check(history: &[Operation]) -> Result<(), String> {
+    let mut by_key: BTreeMap<u64, Vec<&Operation>> = BTreeMap::new();
+    for op in history.iter() {
+        // Reads with no answer tell us nothing
+        if op.op == KvOp::Read && op.outcome == Outcome::Unknown {
+            continue;
+        }
+        by_key.entry(op.key).or_default().push(op);
+    }
+    for (key, ops) in by_key {
+        if !Search::new(&ops).run() {
+            return Err(format!(
+                "history for key {} is not linearizable: {:?}",
+                key, ops
+            ));
+        }
+    }
+    Ok(())
+}
+
+struct Search<'a> {
+    ops: &'a [&'a Operation],
+    // Sets of linearized operations (as bitsets) and register values already explored
+    seen: HashSet<(Vec<u64>, Option<String>)>,
+}
+
+impl<'a> Search<'a> {
+    fn new(ops: &'a [&'a Operation]) -> Self {
+        Self {
+            ops,
+            seen: HashSet::new(),
+        }
+    }
+
+    fn run(&mut self) -> bool {
+        let done = vec![0u64; self.ops.len().div_ceil(64)];
+        self.search(done, None)
+    }
+
+    fn is_done(done: &[u64], i: usize) -> bool {
+        done[i / 64] & (1 << (i % 64)) != 0
+    }
+
+    fn search(&mut self, done: Vec<u64>, value: Option<Value>) -> bool {
+        let pending: Vec<usize> = (0..self.ops.len())
+            .filter(|i| !Self::is_done(&done, *i))
+            .collect();
+        if pending
+            .iter()
+            .all(|i| self.ops[*i].outcome == Outcome::Unknown)
+        {
+            return true;
+        }
+        if !self
+            .seen
+            .insert((done.clone(), value.as_ref().map(Value::to_string)))
+        {
+            return false;
+        }
+        // Anything invoked before the earliest pending completion could go next
+        let deadline = pending
+            .iter()
+            .map(|i| self.ops[*i].complete)
+            .min()
+            .unwrap_or(u64::MAX);
+        for i in pending {
+            let op = self.ops[i];
+            if op.invoke > deadline {
+                continue;
+            }
+            if let Some(next) = op.step(&value) {
+                let mut done = done.clone();
+                done[i / 64] |= 1 << (i % 64);
+                if self.search(done, next) {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
+
+/// Settings for `run_kv_clients`
+#[derive(Clone, Debug)]
+pub struct KvClients {
+    pub clients: usize,
+    pub keys: u64,
+    pub duration_ms: u64,
+    // Give up on a request (its outcome is Unknown) after this long
+    pub timeout_ms: u64,
+    pub seed: u64,
+}
+
+impl Default for KvClients {
+    fn default() -> Self {
+        Self {
+            clients: 5,
+            keys: 3,
+            duration_ms: 5000,
+            timeout_ms: 1000,
+            seed: 0,
+        }
+    }
+}
+
+struct Pending {
+    client: usize,
+    key: u64,
+    op: KvOp,
+    invoke: u64,
+}
+
+/// Run clients, each with at most one request outstanding, which send random
+/// reads, writes and cases to random nodes. Every value written is new, so that a
+/// stale read cannot pass for a later write of the same value; cases expect the
+/// value last seen for their key. `nemesis` is called every step to
+/// interfere with the network. Returns the history of every request made.
+pub fn run_kv_clients<P, F>(
+    sim: &mut Simulation<P>,
+    settings: &KvClients,
+    mut nemesis: F,
+) -> Vec<Operation>
+where
+    P: Process,
+    F: FnMut(&mut Simulation<P>),
+{
+    const STEP_MS: u64 = 5;
+    let mut rng = StdRng::seed_from_u64(settings.seed);
+    let node_ids = sim.node_ids();
+    let mut history = vec![];
+    let mut pending: HashMap<u64, Pending> = HashMap::new();
+    let mut idle: Vec<usize> = (0..settings.clients).collect();
+    let mut last_msg_id = 0;
+    // Histories from runs with different seeds can be checked together
+    let mut last_value = settings.seed << 32;
+    let mut seen: HashMap<u64, Value> = HashMap::new();
+    let start = sim.now();
+    let end = start + settings.duration_ms;
+    while sim.now() < end || !pending.is_empty() {
+        if sim.now() < end {
+            nemesis(sim);
+            for client in idle.drain(..) {
+                let key = rng.gen_range(0..settings.keys);
+                last_value += 1;
+                let op = match rng.gen_range(0..10) {
+                    0..=3 => KvOp::Read,
+                    4..=6 => KvOp::Write(Value::from(last_value)),
+                    _ => {
+                        let from = seen.get(&key).cloned().unwrap_or(Value::from(0));
+                        KvOp::Cas(from, Value::from(last_value))
+                    }
+                };
+                let body = match &op {
+                    KvOp::Read => json!({"type": "read", "key": key}),
+                    KvOp::Write(value) => json!({"type": "write", "key": key, "value": value}),
+                    KvOp::Cas(from, to) => {
+                        json!({"type": "cas", "key": key, "from": from, "to": to})
+                    }
+                };
+                last_msg_id += 1;
+                let mut body = body;
+                body["msg_id"] = Value::from(last_msg_id);
+                let node = &node_ids[rng.gen_range(0..node_ids.len())];
+                sim.send(json!({"src": format!("c{}", client), "dest": node, "body": body}));
+                let invoke = sim.now();
+                pending.insert(
+                    last_msg_id,
+                    Pending {
+                        client,
+                        key,
+                        op,
+                        invoke,
+                    },
+                );
+            }
+        }
+        sim.run_for(STEP_MS);
+        let now = sim.now();
+        for reply in sim.take_client_messages() {
+            let request = match reply["body"]["in_reply_to"]
+                .as_u64()
+                .and_then(|msg_id| pending.remove(&msg_id))
+            {
+                Some(request) => request,
+                None => continue,
+            };
+            let code = reply["body"]["code"]
+                .as_u64()
+                .and_then(ErrorType::from_code);
+            let outcome = match (reply["body"]["type"].as_str(), code, &request.op) {
+                (Some("read_ok"), _, _) => Some(Outcome::Ok(Some(reply["body"]["value"].clone()))),
+                (Some("write_ok"), _, _) | (Some("cas_ok"), _, _) => Some(Outcome::Ok(None)),
+                (Some("error"), Some(ErrorType::KeyDoesNotExist), KvOp::Read) => {
+                    Some(Outcome::Ok(None))
+                }
+                (Some("error"), Some(ErrorType::KeyDoesNotExist), KvOp::Cas(..))
+                | (Some("error"), Some(ErrorType::PreconditionFailed), KvOp::Cas(..)) => {
+                    Some(Outcome::CasFailed)
+                }
+                // Any other error means the request was not carried out
+                _ => None,
+            };
+            if let Some(outcome) = outcome {
+                match (&request.op, &outcome) {
+                    (KvOp::Read, Outcome::Ok(Some(value)))
+                    | (KvOp::Write(value), Outcome::Ok(_))
+                    | (KvOp::Cas(_, value), Outcome::Ok(_)) => {
+                        seen.insert(request.key, value.clone());
+                    }
+                    _ => (),
+                }
+                history.push(Operation {
+                    client: request.client,
+                    key: request.key,
+                    op: request.op,
+                    invoke: request.invoke,
+                    complete: now,
+                    outcome,
+                });
+            }
+            if now < end {
+                idle.push(request.client);
+            }
+        }
+        let timed_out: Vec<u64> = pending
+            .iter()
+            .filter(|(_, request)| now >= request.invoke + settings.timeout_ms)
+            .map(|(msg_id, _)| *msg_id)
+            .collect();
+        for msg_id in timed_out {
+            let request = pending.remove(&msg_id).unwrap();
+            history.push(Operation {
+                client: request.client,
+                key: request.key,
+                op: request.op,
+                invoke: request.invoke,
+                complete: u64::MAX,
+                outcome: Outcome::Unknown,
+            });
+            if now < end {
+                idle.push(request.client);
+            }
+        }
+        idle.sort();
+    }
+    history
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn op(invoke: u64, complete: u64, op: KvOp, outcome: Outcome) -> Operation {
+        Operation {
+            client: 0,
+            key: 0,
+            op,
+            invoke,
+            complete,
+            outcome,
+        }
+    }
+
+    fn write(value: u64) -> KvOp {
+        KvOp::Write(Value::from(value))
+    }
+
+    fn read(value: u64) -> Outcome {
+        Outcome::Ok(Some(Value::from(value)))
+    }
+
+    #[test]
+    fn test_concurrent_operations_may_go_either_way() {
+        let history = vec![
+            op(0, 10, write(1), Outcome::Ok(None)),
+            op(5, 20, write(2), Outcome::Ok(None)),
+            op(15, 25, KvOp::Read, read(1)),
+        ];
+        assert!(check(&history).is_ok());
+    }
+
+    #[test]
+    fn test_stale_read_is_caught() {
+        let history = vec![
+            op(0, 10, write(1), Outcome::Ok(None)),
+            op(11, 20, write(2), Outcome::Ok(None)),
+            op(21, 30, KvOp::Read, read(1)),
+        ];
+        assert!(check(&history).is_err());
+    }
+
+    #[test]
+    fn test_unknown_writes_may_or_may_not_happen() {
+        let cas = KvOp::Cas(Value::from(1), Value::from(3));
+        let history = vec![
+            op(0, 10, write(1), Outcome::Ok(None)),
+            op(11, u64::MAX, write(2), Outcome::Unknown),
+            op(12, 20, KvOp::Read, read(1)),
+            op(21, 30, cas.clone(), Outcome::Ok(None)),
+            op(31, 40, KvOp::Read, read(2)),
+        ];
+        assert!(check(&history).is_ok());
+        // ...but a failed cas must have seen some other value
+        let history = vec![
+            op(0, 10, write(1), Outcome::Ok(None)),
+            op(11, 20, cas, Outcome::CasFailed),
+        ];
+        assert!(check(&history).is_err());
+    }
+
+    #[test]
+    fn test_missing_keys_read_as_none() {
+        let history = vec![
+            op(0, 10, KvOp::Read, Outcome::Ok(None)),
+            op(11, 20, write(1), Outcome::Ok(None)),
+            op(21, 30, KvOp::Read, Outcome::Ok(None)),
+        ];
+        assert!(check(&history).is_err());
+    }
+}
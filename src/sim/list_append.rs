@@ -0,0 +1,480 @@
+/// Random txn-list-append clients for the simulator, and a checker for their
+/// histories.
+///
+/// Every element appended is new, so each element names the transaction which
+/// appended it. The checker takes each key's order from the longest list any
+/// committed transaction read: every other read must be a prefix of it, no aborted
+/// append may show up in it, and no committed append may be missing from a read
+/// which began after it completed. From those orders it builds the dependency
+/// graph of Adya's "Weak Consistency": write-write, write-read and read-write
+/// edges between transactions, with real-time edges from each transaction to those
+/// invoked after it completed. If the graph has no cycle, the history is strictly
+/// serializable.
+use std::collections::HashMap;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde_json::{json, Value};
+
+use super::Simulation;
+use crate::errors::ErrorType;
+use crate::node::Process;
+use crate::rpc::txn::MicroOp;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TxnOutcome {
+    // The transaction as the node answered it, with every read filled in
+    Committed(Vec<MicroOp>),
+    // An error which means it was not carried out
+    Aborted,
+    // No answer, or an error which leaves it open: it may or may not have happened
+    Unknown,
+}
+
+#[derive(Clone, Debug)]
+pub struct Transaction {
+    pub client: usize,
+    pub txn: Vec<MicroOp>,
+    pub invoke: u64,
+    // u64::MAX for transactions we never heard back about
+    pub complete: u64,
+    pub outcome: TxnOutcome,
+}
+
+impl Transaction {
+    pub fn committed(&self) -> bool {
+        matches!(self.outcome, TxnOutcome::Committed(_))
+    }
+
+    fn appends(&self) -> impl Iterator<Item = (&Value, &Value)> {
+        self.txn.iter().filter_map(|op| match op {
+            MicroOp::Append { key, element } => Some((key, element)),
+            _ => None,
+        })
+    }
+
+    fn reads(&self) -> impl Iterator<Item = (&Value, Vec<Value>)> {
+        let ops = match &self.outcome {
+            TxnOutcome::Committed(ops) => ops.as_slice(),
+            _ => &[],
+        };
+        ops.iter().filter_map(|op| match op {
+            MicroOp::Read { key, value } => Some((
+                key,
+                value
+                    .as_ref()
+                    .and_then(Value::as_array)
+                    .cloned()
+                    .unwrap_or_default(),
+            )),
+            _ => None,
+        })
+    }
+}
+
+/// Check a history; on failure, say what went wrong
+pub fn check(history: &[Transaction]) -> Result<(), String> {
+    // Which transaction appended each element (elements may be any JSON value)
+    let mut appender: HashMap<String, usize> = HashMap::new();
+    for (index, txn) in history.iter().enumerate() {
+        for (_, element) in txn.appends() {
+            appender.insert(element.to_string(), index);
+        }
+    }
+    let mut orders: HashMap<String, Vec<Value>> = HashMap::new();
+    for txn in history.iter() {
+        for (key, list) in txn.reads() {
+            let order = orders.entry(key.to_string()).or_default();
+            if list.len() > order.len() {
+                *order = list;
+            }
+        }
+    }
+    for txn in history.iter() {
+        for (key, list) in txn.reads() {
+            let order = &orders[&key.to_string()];
+            if order[..list.len()] != list[..] {
+                return Err(format!(
+                    "reads of key {} disagree: {:?} and {:?}",
+                    key, list, order
+                ));
+            }
+        }
+    }
+    for (key, order) in orders.iter() {
+        for (position, element) in order.iter().enumerate() {
+            match appender.get(&element.to_string()) {
+                None => {
+                    return Err(format!(
+                        "key {} has {}, which nobody appended",
+                        key, element
+                    ))
+                }
+                Some(index) if history[*index].outcome == TxnOutcome::Aborted => {
+                    return Err(format!(
+                        "key {} has {}, from an aborted transaction",
+                        key, element
+                    ))
+                }
+                _ => (),
+            }
+            if order[..position].contains(element) {
+                return Err(format!("key {} has {} twice", key, element));
+            }
+        }
+    }
+    for txn in history.iter().filter(|txn| txn.committed()) {
+        for (key, element) in txn.appends() {
+            let order = orders.get(&key.to_string());
+            if order.is_some_and(|order| order.contains(element)) {
+                continue;
+            }
+            let stale = history.iter().find(|reader| {
+                reader.invoke > txn.complete && reader.reads().any(|(read, _)| read == key)
+            });
+            if let Some(reader) = stale {
+                return Err(format!(
+                    "{:?} missed {} on key {}, appended before it began",
+                    reader.txn, element, key
+                ));
+            }
+        }
+    }
+    find_cycle(history, &orders, &appender)
+}
+
+/// Look for a cycle in the dependency graph, with the real-time order threaded
+/// through one node per completion time to keep it linear in size
+fn find_cycle(
+    history: &[Transaction],
+    orders: &HashMap<String, Vec<Value>>,
+    appender: &HashMap<String, usize>,
+) -> Result<(), String> {
+    let mut completions: Vec<u64> = history
+        .iter()
+        .filter(|txn| txn.committed())
+        .map(|txn| txn.complete)
+        .collect();
+    completions.sort();
+    completions.dedup();
+    let count = history.len();
+    let mut edges: Vec<Vec<usize>> = vec![vec![]; count + completions.len()];
+    for time in 1..completions.len() {
+        edges[count + time - 1].push(count + time);
+    }
+    for (index, txn) in history.iter().enumerate() {
+        if let Ok(time) = completions.binary_search(&txn.complete) {
+            edges[index].push(count + time);
+        }
+        let before = completions.partition_point(|complete| *complete < txn.invoke);
+        if before > 0 {
+            edges[count + before - 1].push(index);
+        }
+    }
+    let writer = |element: &Value| appender[&element.to_string()];
+    for order in orders.values() {
+        for pair in order.windows(2) {
+            edges[writer(&pair[0])].push(writer(&pair[1]));
+        }
+    }
+    for (index, txn) in history.iter().enumerate() {
+        for (key, list) in txn.reads() {
+            // Reads after the transaction's own appends see them as well
+            let seen = list
+                .iter()
+                .rposition(|element| writer(element) != index)
+                .map_or(0, |last| last + 1);
+            if seen > 0 {
+                edges[writer(&list[seen - 1])].push(index);
+            }
+            let order = &orders[&key.to_string()];
+            if let Some(next) = order[seen..].iter().map(writer).find(|next| *next != index) {
+                edges[index].push(next);
+            }
+        }
+    }
+    // Iterative depth-first search: 0 unvisited, 1 on the stack, 2 done
+    let mut state = vec![0u8; edges.len()];
+    for start in 0..edges.len() {
+        if state[start] != 0 {
+            continue;
+        }
+        let mut stack = vec![(start, 0)];
+        state[start] = 1;
+        while let Some((node, next)) = stack.pop() {
+            match edges[node].get(next) {
+                Some(&to) => {
+                    stack.push((node, next + 1));
+                    if to == node {
+                        continue;
+                    }
+                    match state[to] {
+                        0 => {
+                            state[to] = 1;
+                            stack.push((to, 0));
+                        }
+                        1 => {
+                            let cycle: Vec<&Vec<MicroOp>> = stack
+                                .iter()
+                                .map(|(node, _)| *node)
+                                .chain([to])
+                                .filter(|node| *node < count)
+                                .map(|node| &history[node].txn)
+                                .collect();
+                            return Err(format!("dependency cycle through {:?}", cycle));
+                        }
+                        _ => (),
+                    }
+                }
+                None => state[node] = 2,
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Settings for `run_txn_clients`
+#[derive(Clone, Debug)]
+pub struct TxnClients {
+    pub clients: usize,
+    pub keys: u64,
+    // Each transaction has between one and this many micro-ops
+    pub max_ops: usize,
+    pub duration_ms: u64,
+    // Give up on a transaction (its outcome is Unknown) after this long
+    pub timeout_ms: u64,
+    pub seed: u64,
+}
+
+impl Default for TxnClients {
+    fn default() -> Self {
+        Self {
+            clients: 5,
+            keys: 8,
+            max_ops: 4,
+            duration_ms: 5000,
+            timeout_ms: 2000,
+            seed: 0,
+        }
+    }
+}
+
+struct Pending {
+    client: usize,
+    txn: Vec<MicroOp>,
+    invoke: u64,
+}
+
+/// Run clients, each with at most one transaction outstanding, which send random
+/// reads and appends of new elements to random nodes. `nemesis` is called every
+/// step to interfere with the network. Returns the history of every transaction.
+pub fn run_txn_clients<P, F>(
+    sim: &mut Simulation<P>,
+    settings: &TxnClients,
+    mut nemesis: F,
+) -> Vec<Transaction>
+where
+    P: Process,
+    F: FnMut(&mut Simulation<P>),
+{
+    const STEP_MS: u64 = 5;
+    let mut rng = StdRng::seed_from_u64(settings.seed);
+    let node_ids = sim.node_ids();
+    let mut history = vec![];
+    let mut pending: HashMap<u64, Pending> = HashMap::new();
+    let mut idle: Vec<usize> = (0..settings.clients).collect();
+    let mut last_msg_id = 0;
+    let mut last_element = settings.seed << 32;
+    let start = sim.now();
+    let end = start + settings.duration_ms;
+    while sim.now() < end || !pending.is_empty() {
+        if sim.now() < end {
+            nemesis(sim);
+            for client in idle.drain(..) {
+                let txn: Vec<MicroOp> = (0..rng.gen_range(1..=settings.max_ops))
+                    .map(|_| {
+                        let key = Value::from(rng.gen_range(0..settings.keys));
+                        if rng.gen_bool(0.5) {
+                            MicroOp::Read { key, value: None }
+                        } else {
+                            last_element += 1;
+                            let element = Value::from(last_element);
+                            MicroOp::Append { key, element }
+                        }
+                    })
+                    .collect();
+                last_msg_id += 1;
+                let body = json!({"type": "txn", "msg_id": last_msg_id, "txn": txn});
+                let node = &node_ids[rng.gen_range(0..node_ids.len())];
+                sim.send(json!({"src": format!("c{}", client), "dest": node, "body": body}));
+                let invoke = sim.now();
+                pending.insert(
+                    last_msg_id,
+                    Pending {
+                        client,
+                        txn,
+                        invoke,
+                    },
+                );
+            }
+        }
+        sim.run_for(STEP_MS);
+        let now = sim.now();
+        for reply in sim.take_client_messages() {
+            let request = match reply["body"]["in_reply_to"]
+                .as_u64()
+                .and_then(|msg_id| pending.remove(&msg_id))
+            {
+                Some(request) => request,
+                None => continue,
+            };
+            let code = reply["body"]["code"]
+                .as_u64()
+                .and_then(ErrorType::from_code);
+            let outcome = match (reply["body"]["type"].as_str(), code) {
+                (Some("txn_ok"), _) => match serde_json::from_value(reply["body"]["txn"].clone()) {
+                    Ok(txn) => TxnOutcome::Committed(txn),
+                    Err(_) => TxnOutcome::Unknown,
+                },
+                (
+                    Some("error"),
+                    Some(ErrorType::TxnConflict)
+                    | Some(ErrorType::TemporarilyUnavailable)
+                    | Some(ErrorType::NotSupported)
+                    | Some(ErrorType::Abort),
+                ) => TxnOutcome::Aborted,
+                _ => TxnOutcome::Unknown,
+            };
+            let complete = match outcome {
+                TxnOutcome::Unknown => u64::MAX,
+                _ => now,
+            };
+            history.push(Transaction {
+                client: request.client,
+                txn: request.txn,
+                invoke: request.invoke,
+                complete,
+                outcome,
+            });
+            if now < end {
+                idle.push(request.client);
+            }
+        }
+        let timed_out: Vec<u64> = pending
+            .iter()
+            .filter(|(_, request)| now >= request.invoke + settings.timeout_ms)
+            .map(|(msg_id, _)| *msg_id)
+            .collect();
+        for msg_id in timed_out {
+            let request = pending.remove(&msg_id).unwrap();
+            history.push(Transaction {
+                client: request.client,
+                txn: request.txn,
+                invoke: request.invoke,
+                complete: u64::MAX,
+                outcome: TxnOutcome::Unknown,
+            });
+            if now < end {
+                idle.push(request.client);
+            }
+        }
+        idle.sort();
+    }
+    history
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn txn(invoke: u64, complete: u64, ops: Value, outcome: Option<Value>) -> Transaction {
+        Transaction {
+            client: 0,
+            txn: serde_json::from_value(ops).unwrap(),
+            invoke,
+            complete,
+            outcome: match outcome {
+                Some(ops) => TxnOutcome::Committed(serde_json::from_value(ops).unwrap()),
+                None => TxnOutcome::Aborted,
+            },
+        }
+    }
+
+    #[test]
+    fn test_serial_history_passes() {
+        let history = vec![
+            txn(
+                0,
+                10,
+                json!([["append", 1, 1]]),
+                Some(json!([["append", 1, 1]])),
+            ),
+            txn(
+                11,
+                20,
+                json!([["r", 1, null], ["append", 1, 2], ["append", 2, 3]]),
+                Some(json!([["r", 1, [1]], ["append", 1, 2], ["append", 2, 3]])),
+            ),
+            txn(15, 18, json!([["append", 2, 4]]), None),
+            txn(
+                21,
+                30,
+                json!([["r", 1, null], ["r", 2, null]]),
+                Some(json!([["r", 1, [1, 2]], ["r", 2, [3]]])),
+            ),
+        ];
+        assert_eq!(check(&history), Ok(()));
+    }
+
+    #[test]
+    fn test_aborted_and_lost_appends_are_caught() {
+        let aborted = vec![
+            txn(0, 10, json!([["append", 1, 1]]), None),
+            txn(
+                11,
+                20,
+                json!([["r", 1, null]]),
+                Some(json!([["r", 1, [1]]])),
+            ),
+        ];
+        assert!(check(&aborted).is_err());
+        let lost = vec![
+            txn(
+                0,
+                10,
+                json!([["append", 1, 1]]),
+                Some(json!([["append", 1, 1]])),
+            ),
+            txn(
+                11,
+                20,
+                json!([["r", 1, null]]),
+                Some(json!([["r", 1, null]])),
+            ),
+        ];
+        assert!(check(&lost).is_err());
+    }
+
+    #[test]
+    fn test_half_seen_transaction_is_a_cycle() {
+        // The reader sees the first append to key 1 but not its partner on key 2,
+        // which a later read shows did happen
+        let both = json!([["append", 1, 1], ["append", 2, 2]]);
+        let history = vec![
+            txn(0, 100, both.clone(), Some(both)),
+            txn(
+                5,
+                50,
+                json!([["r", 1, null], ["r", 2, null]]),
+                Some(json!([["r", 1, [1]], ["r", 2, null]])),
+            ),
+            txn(
+                101,
+                110,
+                json!([["r", 2, null]]),
+                Some(json!([["r", 2, [2]]])),
+            ),
+        ];
+        assert!(check(&history).unwrap_err().contains("cycle"));
+    }
+}
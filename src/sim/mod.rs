@@ -0,0 +1,333 @@
+/// A deterministic, in-process network for testing `node::Process`es.
+///
+/// Simulated time is in milliseconds. Every process is ticked every TICK_MS (as the
+/// real runtime does) and messages between processes take a random latency, may be
+/// lost, and are dropped while their nodes are partitioned from each other. Services
+/// (like maelstrom's lin-kv) are ticked too, but their messages are never lost or
+/// cut off. Messages to anything else are for clients and wait in an inbox. All of
+/// the randomness comes from one seed, so a failing run can be replayed exactly.
+pub mod linearizability;
+pub mod list_append;
+
+use std::collections::{BTreeMap, HashSet};
+
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use serde_json::Value;
+
+use crate::algorithms::lin_kv::KvState;
+use crate::consensus::StateMachine;
+use crate::node::Process;
+
+/// As for `node::run_clock`
+pub const TICK_MS: u64 = 150;
+
+enum Event {
+    Deliver(Value),
+    Tick(String),
+}
+
+pub struct Simulation<P: Process> {
+    processes: BTreeMap<String, P>,
+    services: BTreeMap<String, Box<dyn Process>>,
+    // (time, sequence number) -> event: the sequence number keeps ties in order
+    events: BTreeMap<(u64, u64), Event>,
+    last_seq: u64,
+    now: u64,
+    rng: StdRng,
+    max_latency_ms: u64,
+    loss: f64,
+    // (from, to) pairs which cannot reach each other
+    cut: HashSet<(String, String)>,
+    client_inbox: Vec<Value>,
+    messages_sent: u64,
+}
+
+impl<P: Process> Simulation<P> {
+    pub fn new(seed: u64, processes: Vec<(String, P)>) -> Self {
+        let mut sim = Self {
+            processes: BTreeMap::new(),
+            services: BTreeMap::new(),
+            events: BTreeMap::new(),
+            last_seq: 0,
+            now: 0,
+            rng: StdRng::seed_from_u64(seed),
+            max_latency_ms: 5,
+            loss: 0.0,
+            cut: HashSet::new(),
+            client_inbox: vec![],
+            messages_sent: 0,
+        };
+        for (node_id, process) in processes {
+            // Nodes start at different times, so their clocks are out of step
+            let first_tick = sim.rng.gen_range(0..TICK_MS);
+            sim.schedule(first_tick, Event::Tick(node_id.clone()));
+            sim.processes.insert(node_id, process);
+        }
+        sim
+    }
+
+    /// Messages between processes take up to this long to arrive
+    pub fn with_latency(mut self, max_latency_ms: u64) -> Self {
+        self.max_latency_ms = max_latency_ms;
+        self
+    }
+
+    /// Chance of losing each message between processes
+    pub fn with_loss(mut self, loss: f64) -> Self {
+        self.loss = loss;
+        self
+    }
+
+    /// Add a service, which every process can always reach
+    pub fn with_service(mut self, node_id: &str, service: Box<dyn Process>) -> Self {
+        let first_tick = self.rng.gen_range(0..TICK_MS);
+        self.schedule(first_tick, Event::Tick(node_id.to_string()));
+        self.services.insert(node_id.to_string(), service);
+        self
+    }
+
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    pub fn node_ids(&self) -> Vec<String> {
+        self.processes.keys().cloned().collect()
+    }
+
+    pub fn process(&self, node_id: &str) -> Option<&P> {
+        self.processes.get(node_id)
+    }
+
+    pub fn processes(&self) -> impl Iterator<Item = (&String, &P)> {
+        self.processes.iter()
+    }
+
+    /// Count of messages processes have sent each other (including lost ones)
+    pub fn messages_sent(&self) -> u64 {
+        self.messages_sent
+    }
+
+    /// Cut every node in `a` off from every node in `b`, both ways
+    pub fn partition(&mut self, a: &[String], b: &[String]) {
+        for x in a.iter() {
+            for y in b.iter() {
+                self.cut.insert((x.clone(), y.clone()));
+                self.cut.insert((y.clone(), x.clone()));
+            }
+        }
+    }
+
+    pub fn heal(&mut self) {
+        self.cut.clear();
+    }
+
+    /// A message from a client to a process; these are never lost
+    pub fn send(&mut self, msg: Value) {
+        let latency = self.rng.gen_range(0..=self.max_latency_ms);
+        self.schedule(self.now + latency, Event::Deliver(msg));
+    }
+
+    /// Messages which processes have sent to clients since we last looked
+    pub fn take_client_messages(&mut self) -> Vec<Value> {
+        std::mem::take(&mut self.client_inbox)
+    }
+
+    /// Run everything which happens in the next `ms` milliseconds
+    pub fn run_for(&mut self, ms: u64) {
+        let until = self.now + ms;
+        while let Some(entry) = self.events.first_entry() {
+            let (time, _) = *entry.key();
+            if time > until {
+                break;
+            }
+            let event = entry.remove();
+            self.now = time;
+            match event {
+                Event::Deliver(msg) => {
+                    let dest = msg["dest"].as_str().unwrap_or_default().to_string();
+                    if let Some(process) = self.processes.get_mut(&dest) {
+                        let out = process.on_message(msg);
+                        self.route(out);
+                    } else if let Some(service) = self.services.get_mut(&dest) {
+                        let out = service.on_message(msg);
+                        self.route(out);
+                    }
+                }
+                Event::Tick(node_id) => {
+                    if let Some(process) = self.processes.get_mut(&node_id) {
+                        let out = process.on_tick();
+                        self.route(out);
+                    } else if let Some(service) = self.services.get_mut(&node_id) {
+                        let out = service.on_tick();
+                        self.route(out);
+                    }
+                    self.schedule(self.now + TICK_MS, Event::Tick(node_id));
+                }
+            }
+        }
+        self.now = until;
+    }
+
+    fn schedule(&mut self, time: u64, event: Event) {
+        self.last_seq += 1;
+        self.events.insert((time, self.last_seq), event);
+    }
+
+    fn route(&mut self, msgs: Vec<Value>) {
+        for msg in msgs {
+            let src = msg["src"].as_str().unwrap_or_default().to_string();
+            let dest = msg["dest"].as_str().unwrap_or_default().to_string();
+            if self.services.contains_key(&dest) || self.services.contains_key(&src) {
+                let latency = self.rng.gen_range(0..=self.max_latency_ms);
+                self.schedule(self.now + latency, Event::Deliver(msg));
+                continue;
+            }
+            if !self.processes.contains_key(&dest) {
+                self.client_inbox.push(msg);
+                continue;
+            }
+            if self.processes.contains_key(&src) {
+                self.messages_sent += 1;
+                let lost = self.rng.gen_bool(self.loss);
+                if lost || self.cut.contains(&(src, dest)) {
+                    continue;
+                }
+            }
+            let latency = self.rng.gen_range(0..=self.max_latency_ms);
+            self.schedule(self.now + latency, Event::Deliver(msg));
+        }
+    }
+}
+
+/// Maelstrom's lin-kv service, for processes which keep state in it: one map,
+/// serving one request at a time
+pub struct LinKvService {
+    node_id: String,
+    state: KvState,
+    last_msg_id: u64,
+}
+
+impl LinKvService {
+    pub fn new(node_id: &str) -> Self {
+        Self {
+            node_id: node_id.to_string(),
+            state: KvState::new(),
+            last_msg_id: 0,
+        }
+    }
+}
+
+impl Process for LinKvService {
+    fn on_message(&mut self, msg: Value) -> Vec<Value> {
+        let request = match KvState::parse_request(&msg["body"]) {
+            Some(request) => request,
+            None => return vec![],
+        };
+        self.last_msg_id += 1;
+        let body = StateMachine::apply(&mut self.state, &request, self.last_msg_id);
+        vec![serde_json::json!({"src": self.node_id, "dest": msg["src"], "body": body})]
+    }
+
+    fn on_tick(&mut self) -> Vec<Value> {
+        vec![]
+    }
+}
+
+/// Ids for `count` nodes, named as maelstrom names them: n1, n2, ...
+pub fn node_ids(count: usize) -> Vec<String> {
+    (1..=count).map(|n| format!("n{}", n)).collect()
+}
+
+/// A nemesis for `linearizability::run_kv_clients`: splits the nodes at random every
+/// second or few, long enough for a majority to elect a new leader, and heals every
+/// so often
+pub fn random_partitions<P: Process>(seed: u64) -> impl FnMut(&mut Simulation<P>) {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut next_change = 0;
+    move |sim| {
+        if sim.now() < next_change {
+            return;
+        }
+        next_change = sim.now() + rng.gen_range(1000..3000);
+        sim.heal();
+        if rng.gen_bool(0.3) {
+            return;
+        }
+        let mut node_ids = sim.node_ids();
+        node_ids.shuffle(&mut rng);
+        let minority = rng.gen_range(1..=node_ids.len() / 2);
+        let (a, b) = node_ids.split_at(minority);
+        sim.partition(a, b);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    /// Passes each message on to the next node, counting what it has seen
+    struct Relay {
+        next: String,
+        seen: u64,
+    }
+
+    impl Process for Relay {
+        fn on_message(&mut self, msg: Value) -> Vec<Value> {
+            self.seen += 1;
+            let mut msg = msg;
+            msg["src"] = msg["dest"].clone();
+            msg["dest"] = Value::from(self.next.clone());
+            vec![msg]
+        }
+
+        fn on_tick(&mut self) -> Vec<Value> {
+            vec![]
+        }
+    }
+
+    fn relays() -> Simulation<Relay> {
+        let ring = ["n1", "n2", "n3"];
+        let processes = ring
+            .iter()
+            .zip(ring.iter().cycle().skip(1))
+            .map(|(id, next)| {
+                let relay = Relay {
+                    next: next.to_string(),
+                    seen: 0,
+                };
+                (id.to_string(), relay)
+            })
+            .collect();
+        Simulation::new(7, processes)
+    }
+
+    #[test]
+    fn test_messages_go_round_until_partitioned() {
+        let mut sim = relays();
+        sim.send(json!({"src": "c1", "dest": "n1", "body": {}}));
+        sim.run_for(100);
+        assert!(sim.process("n3").unwrap().seen > 3);
+        sim.partition(&["n3".to_string()], &["n1".to_string()]);
+        sim.run_for(100);
+        let seen = sim.process("n1").unwrap().seen;
+        sim.run_for(100);
+        assert_eq!(sim.process("n1").unwrap().seen, seen);
+    }
+
+    #[test]
+    fn test_runs_replay_exactly() {
+        let run = || {
+            let mut sim = relays().with_loss(0.1).with_latency(20);
+            sim.send(json!({"src": "c1", "dest": "n1", "body": {}}));
+            sim.run_for(1000);
+            sim.processes()
+                .map(|(_, relay)| relay.seen)
+                .collect::<Vec<u64>>()
+        };
+        assert_eq!(run(), run());
+    }
+}
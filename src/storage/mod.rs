@@ -0,0 +1,206 @@
+/// Storage engines for append-only logs, such as the kafka workload's per-key logs.
+///
+/// Messages may be offered out of order (replication does not preserve it), but
+/// reads only ever return an unbroken run of offsets: a store never hands out a
+/// message while an earlier one is missing.
+pub mod segment;
+
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+
+use serde_json::Value;
+
+use crate::errors;
+
+pub trait LogStore: Send {
+    /// Store `msg` at `offset`. Storing the same offset twice keeps the first message.
+    fn insert(&mut self, offset: u64, msg: &Value) -> Result<(), errors::ErrorMsg>;
+    /// Up to `max` messages from `offset` onwards, stopping at the first gap.
+    /// If `offset` has been dropped by retention, we start from the oldest message kept.
+    fn read_from(&mut self, offset: u64, max: usize)
+        -> Result<Vec<(u64, Value)>, errors::ErrorMsg>;
+    /// Discard old messages as the store's retention policy allows.
+    /// Messages at or above `committed` are always kept.
+    fn apply_retention(&mut self, committed: u64, now: SystemTime) -> Result<(), errors::ErrorMsg>;
+    /// One past the newest message stored: where a reopened log carries on from.
+    fn end_offset(&self) -> u64;
+}
+
+/// Everything in memory, kept forever
+#[derive(Clone, Debug, Default)]
+pub struct MemoryLog {
+    entries: BTreeMap<u64, Value>,
+}
+
+impl MemoryLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl LogStore for MemoryLog {
+    fn insert(&mut self, offset: u64, msg: &Value) -> Result<(), errors::ErrorMsg> {
+        self.entries.entry(offset).or_insert_with(|| msg.clone());
+        Ok(())
+    }
+
+    fn read_from(
+        &mut self,
+        offset: u64,
+        max: usize,
+    ) -> Result<Vec<(u64, Value)>, errors::ErrorMsg> {
+        Ok(self
+            .entries
+            .range(offset..)
+            .zip(offset..)
+            .take_while(|((offset, _), expected)| **offset == *expected)
+            .take(max)
+            .map(|((offset, msg), _)| (*offset, msg.clone()))
+            .collect())
+    }
+
+    fn apply_retention(
+        &mut self,
+        _committed: u64,
+        _now: SystemTime,
+    ) -> Result<(), errors::ErrorMsg> {
+        Ok(())
+    }
+
+    fn end_offset(&self) -> u64 {
+        self.entries
+            .keys()
+            .next_back()
+            .map_or(0, |offset| offset + 1)
+    }
+}
+
+/// Which store to open for each log
+#[derive(Clone, Debug, Default)]
+pub enum LogStoreConfig {
+    #[default]
+    Memory,
+    // Each log gets its own directory beneath `dir`
+    Segments(segment::SegmentConfig),
+}
+
+impl LogStoreConfig {
+    /// Give a node its own directory. It is emptied only if the config is `fresh`:
+    /// otherwise the node picks up the logs it left there last time.
+    pub fn for_node(&self, node_id: &str) -> Result<Self, errors::ErrorMsg> {
+        match self {
+            LogStoreConfig::Memory => Ok(LogStoreConfig::Memory),
+            LogStoreConfig::Segments(config) => {
+                let dir = config.dir.join(node_id);
+                if config.fresh && dir.exists() {
+                    std::fs::remove_dir_all(&dir).map_err(errors::ErrorMsg::crash_error)?;
+                }
+                Ok(LogStoreConfig::Segments(segment::SegmentConfig {
+                    dir,
+                    ..config.clone()
+                }))
+            }
+        }
+    }
+
+    /// Names of the logs already kept in our directory
+    pub fn existing(&self) -> Result<Vec<String>, errors::ErrorMsg> {
+        let config = match self {
+            LogStoreConfig::Memory => return Ok(vec![]),
+            LogStoreConfig::Segments(config) => config,
+        };
+        if !config.dir.exists() {
+            return Ok(vec![]);
+        }
+        let mut names = vec![];
+        for entry in std::fs::read_dir(&config.dir).map_err(errors::ErrorMsg::crash_error)? {
+            let entry = entry.map_err(errors::ErrorMsg::crash_error)?;
+            let hex = entry.file_name().to_string_lossy().to_string();
+            let bytes: Option<Vec<u8>> = (0..hex.len())
+                .step_by(2)
+                .map(|i| {
+                    hex.get(i..i + 2)
+                        .and_then(|b| u8::from_str_radix(b, 16).ok())
+                })
+                .collect();
+            if let Some(name) = bytes.and_then(|bytes| String::from_utf8(bytes).ok()) {
+                names.push(name);
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    pub fn open(&self, name: &str) -> Result<Box<dyn LogStore>, errors::ErrorMsg> {
+        match self {
+            LogStoreConfig::Memory => Ok(Box::new(MemoryLog::new())),
+            LogStoreConfig::Segments(config) => {
+                // Log names are client-chosen, so keep them out of the path as hex
+                let hex: String = name.bytes().map(|b| format!("{:02x}", b)).collect();
+                let config = segment::SegmentConfig {
+                    dir: config.dir.join(hex),
+                    ..config.clone()
+                };
+                Ok(Box::new(segment::SegmentLog::open(config)?))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(dir: &std::path::Path, fresh: bool) -> LogStoreConfig {
+        LogStoreConfig::Segments(segment::SegmentConfig {
+            segment_bytes: 64,
+            fresh,
+            ..segment::SegmentConfig::new(dir.to_path_buf())
+        })
+    }
+
+    fn write_log(config: &LogStoreConfig, name: &str, count: u64) {
+        let mut log = config.for_node("n1").unwrap().open(name).unwrap();
+        for offset in 0..count {
+            log.insert(offset, &Value::from(offset)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_for_node_reopens_existing_segments() {
+        let dir = std::env::temp_dir().join(format!("maelstrom-storage-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let config = config(&dir, false);
+        write_log(&config, "a/b", 10);
+        write_log(&config, "c", 1);
+        // As a node would on its next start
+        let node = config.for_node("n1").unwrap();
+        let existing = node.existing().unwrap();
+        let mut log = node.open("a/b").unwrap();
+        let msgs = log.read_from(0, 100).unwrap();
+        let end = log.end_offset();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(existing, vec!["a/b".to_string(), "c".to_string()]);
+        assert_eq!(
+            msgs,
+            (0..10)
+                .map(|offset| (offset, Value::from(offset)))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(end, 10);
+    }
+
+    #[test]
+    fn test_for_node_empties_only_fresh_directories() {
+        let dir = std::env::temp_dir().join(format!("maelstrom-fresh-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        write_log(&config(&dir, true), "a", 3);
+        let existing = config(&dir, true)
+            .for_node("n1")
+            .unwrap()
+            .existing()
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(existing.is_empty());
+    }
+}
@@ -0,0 +1,401 @@
+/// An on-disk log made of segment files, in the style of Kafka.
+///
+/// Each segment is a file of JSON lines, `[offset, msg]`, named after the first
+/// offset it holds. Only the newest (active) segment is appended to: once it
+/// reaches `segment_bytes` we start another. Every `index_interval_bytes` we note
+/// an offset's position in its segment; reads binary-search this sparse index and
+/// seek to the nearest noted position at or before the offset they want.
+///
+/// Messages which arrive ahead of a gap wait in memory until the gap is filled,
+/// so the segment files always hold an unbroken run of offsets.
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use serde_json::Value;
+
+use super::LogStore;
+use crate::errors;
+
+const SEGMENT_SUFFIX: &str = "log";
+
+#[derive(Clone, Debug)]
+pub struct SegmentConfig {
+    pub dir: PathBuf,
+    pub segment_bytes: u64,
+    pub index_interval_bytes: u64,
+    // Retention: old segments go once the log is over this size...
+    pub retention_bytes: Option<u64>,
+    // ...or once nothing has been written to them for this long
+    pub retention: Option<Duration>,
+    // Empty each node's directory when it starts. Only for directories we made up
+    // ourselves: otherwise the logs already there are reopened.
+    pub fresh: bool,
+}
+
+impl SegmentConfig {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            segment_bytes: 1024 * 1024,
+            index_interval_bytes: 4096,
+            retention_bytes: None,
+            retention: None,
+            fresh: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Segment {
+    base_offset: u64,
+    next_offset: u64,
+    path: PathBuf,
+    size: u64,
+    last_append: SystemTime,
+    // (offset, byte position in the file), in ascending order
+    index: Vec<(u64, u64)>,
+    bytes_since_index: u64,
+}
+
+impl Segment {
+    fn path_for(dir: &std::path::Path, base_offset: u64) -> PathBuf {
+        dir.join(format!("{:020}.{}", base_offset, SEGMENT_SUFFIX))
+    }
+
+    /// Note `offset` at `position` if we have gone far enough since the last entry
+    fn maybe_index(&mut self, offset: u64, position: u64, interval: u64) {
+        if self.index.is_empty() || self.bytes_since_index >= interval {
+            self.index.push((offset, position));
+            self.bytes_since_index = 0;
+        }
+    }
+
+    /// Byte position to start reading from to find `offset`
+    fn seek_position(&self, offset: u64) -> u64 {
+        match self
+            .index
+            .partition_point(|(indexed, _)| *indexed <= offset)
+        {
+            0 => 0,
+            found => self.index[found - 1].1,
+        }
+    }
+}
+
+pub struct SegmentLog {
+    config: SegmentConfig,
+    // oldest first; the last is the active segment
+    segments: Vec<Segment>,
+    active: Option<File>,
+    pending: BTreeMap<u64, Value>,
+}
+
+impl SegmentLog {
+    /// Open the log in `config.dir`, picking up any segments already there
+    pub fn open(config: SegmentConfig) -> Result<Self, errors::ErrorMsg> {
+        fs::create_dir_all(&config.dir).map_err(errors::ErrorMsg::crash_error)?;
+        let mut base_offsets = vec![];
+        for entry in fs::read_dir(&config.dir).map_err(errors::ErrorMsg::crash_error)? {
+            let path = entry.map_err(errors::ErrorMsg::crash_error)?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(SEGMENT_SUFFIX) {
+                continue;
+            }
+            if let Some(base) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u64>().ok())
+            {
+                base_offsets.push(base);
+            }
+        }
+        base_offsets.sort();
+        let mut log = SegmentLog {
+            config,
+            segments: vec![],
+            active: None,
+            pending: BTreeMap::new(),
+        };
+        for base in base_offsets {
+            let segment = log.recover_segment(base)?;
+            log.segments.push(segment);
+        }
+        if let Some(segment) = log.segments.last() {
+            log.active = Some(Self::open_for_append(&segment.path)?);
+        }
+        Ok(log)
+    }
+
+    fn open_for_append(path: &PathBuf) -> Result<File, errors::ErrorMsg> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(errors::ErrorMsg::crash_error)
+    }
+
+    /// Rebuild a segment's index and bounds by reading it through
+    fn recover_segment(&self, base_offset: u64) -> Result<Segment, errors::ErrorMsg> {
+        let path = Segment::path_for(&self.config.dir, base_offset);
+        let file = File::open(&path).map_err(errors::ErrorMsg::crash_error)?;
+        let last_append = file
+            .metadata()
+            .and_then(|meta| meta.modified())
+            .unwrap_or_else(|_| SystemTime::now());
+        let mut segment = Segment {
+            base_offset,
+            next_offset: base_offset,
+            path,
+            size: 0,
+            last_append,
+            index: vec![],
+            bytes_since_index: 0,
+        };
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(errors::ErrorMsg::crash_error)?;
+            let (offset, _) = serde_json::from_str::<(u64, Value)>(&line)
+                .map_err(errors::ErrorMsg::json_parse_error)?;
+            let len = line.len() as u64 + 1;
+            segment.maybe_index(offset, segment.size, self.config.index_interval_bytes);
+            segment.size += len;
+            segment.bytes_since_index += len;
+            segment.next_offset = offset + 1;
+        }
+        Ok(segment)
+    }
+
+    fn start_offset(&self) -> u64 {
+        self.segments.first().map(|s| s.base_offset).unwrap_or(0)
+    }
+
+    fn append(&mut self, offset: u64, msg: &Value) -> Result<(), errors::ErrorMsg> {
+        let needs_roll = match self.segments.last() {
+            None => true,
+            Some(segment) => segment.size >= self.config.segment_bytes,
+        };
+        if needs_roll {
+            let path = Segment::path_for(&self.config.dir, offset);
+            self.active = Some(Self::open_for_append(&path)?);
+            self.segments.push(Segment {
+                base_offset: offset,
+                next_offset: offset,
+                path,
+                size: 0,
+                last_append: SystemTime::now(),
+                index: vec![],
+                bytes_since_index: 0,
+            });
+        }
+        let mut line =
+            serde_json::to_string(&(offset, msg)).map_err(errors::ErrorMsg::json_dumps_error)?;
+        line.push('\n');
+        let file = self.active.as_mut().expect("active segment is open");
+        file.write_all(line.as_bytes())
+            .map_err(errors::ErrorMsg::crash_error)?;
+        let interval = self.config.index_interval_bytes;
+        let segment = self.segments.last_mut().expect("active segment exists");
+        segment.maybe_index(offset, segment.size, interval);
+        segment.size += line.len() as u64;
+        segment.bytes_since_index += line.len() as u64;
+        segment.next_offset = offset + 1;
+        segment.last_append = SystemTime::now();
+        Ok(())
+    }
+
+    fn total_size(&self) -> u64 {
+        self.segments.iter().map(|s| s.size).sum()
+    }
+}
+
+impl LogStore for SegmentLog {
+    /// One past the last offset in the segment files
+    fn end_offset(&self) -> u64 {
+        self.segments.last().map(|s| s.next_offset).unwrap_or(0)
+    }
+
+    fn insert(&mut self, offset: u64, msg: &Value) -> Result<(), errors::ErrorMsg> {
+        let end = self.end_offset();
+        if offset < end {
+            return Ok(());
+        }
+        if offset > end {
+            self.pending.entry(offset).or_insert_with(|| msg.clone());
+            return Ok(());
+        }
+        self.append(offset, msg)?;
+        // The gap may have been holding messages back
+        while let Some(msg) = self.pending.remove(&self.end_offset()) {
+            self.append(self.end_offset(), &msg)?;
+        }
+        Ok(())
+    }
+
+    fn read_from(
+        &mut self,
+        offset: u64,
+        max: usize,
+    ) -> Result<Vec<(u64, Value)>, errors::ErrorMsg> {
+        let start = offset.max(self.start_offset());
+        let mut msgs = vec![];
+        let first = self
+            .segments
+            .partition_point(|segment| segment.next_offset <= start);
+        for segment in self.segments[first..].iter() {
+            if msgs.len() >= max {
+                break;
+            }
+            let mut file = File::open(&segment.path).map_err(errors::ErrorMsg::crash_error)?;
+            file.seek(SeekFrom::Start(segment.seek_position(start)))
+                .map_err(errors::ErrorMsg::crash_error)?;
+            for line in BufReader::new(file).lines() {
+                let line = line.map_err(errors::ErrorMsg::crash_error)?;
+                let (offset, msg) = serde_json::from_str::<(u64, Value)>(&line)
+                    .map_err(errors::ErrorMsg::json_parse_error)?;
+                if offset < start {
+                    continue;
+                }
+                msgs.push((offset, msg));
+                if msgs.len() >= max {
+                    break;
+                }
+            }
+        }
+        Ok(msgs)
+    }
+
+    fn apply_retention(&mut self, committed: u64, now: SystemTime) -> Result<(), errors::ErrorMsg> {
+        // The active segment always stays
+        while self.segments.len() > 1 {
+            let oldest = &self.segments[0];
+            if oldest.next_offset > committed {
+                break;
+            }
+            let over_size = self
+                .config
+                .retention_bytes
+                .map(|max| self.total_size() > max)
+                .unwrap_or(false);
+            let expired = self
+                .config
+                .retention
+                .map(|max| {
+                    now.duration_since(oldest.last_append)
+                        .map(|age| age > max)
+                        .unwrap_or(false)
+                })
+                .unwrap_or(false);
+            if !over_size && !expired {
+                break;
+            }
+            fs::remove_file(&oldest.path).map_err(errors::ErrorMsg::crash_error)?;
+            self.segments.remove(0);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory for each test, removed when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "maelstrom-segment-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = fs::remove_dir_all(&dir);
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn small_config(dir: &TempDir) -> SegmentConfig {
+        SegmentConfig {
+            segment_bytes: 200,
+            index_interval_bytes: 40,
+            ..SegmentConfig::new(dir.0.clone())
+        }
+    }
+
+    #[test]
+    fn test_read_across_segments() {
+        let dir = TempDir::new("read");
+        let mut log = SegmentLog::open(small_config(&dir)).unwrap();
+        for offset in 0..100 {
+            log.insert(offset, &Value::from(offset * 10)).unwrap();
+        }
+        assert!(log.segments.len() > 2);
+        for start in [0, 1, 17, 50, 99] {
+            let msgs = log.read_from(start, 10).unwrap();
+            let expected: Vec<(u64, Value)> = (start..(start + 10).min(100))
+                .map(|offset| (offset, Value::from(offset * 10)))
+                .collect();
+            assert_eq!(msgs, expected);
+        }
+        assert!(log.read_from(100, 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_out_of_order_inserts_wait_for_the_gap() {
+        let dir = TempDir::new("gap");
+        let mut log = SegmentLog::open(small_config(&dir)).unwrap();
+        for offset in [0, 2, 3, 1, 1, 5] {
+            log.insert(offset, &Value::from(offset)).unwrap();
+        }
+        let offsets: Vec<u64> = log
+            .read_from(0, 10)
+            .unwrap()
+            .iter()
+            .map(|(o, _)| *o)
+            .collect();
+        assert_eq!(offsets, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_retention_keeps_uncommitted() {
+        let dir = TempDir::new("retention");
+        let config = SegmentConfig {
+            retention_bytes: Some(0),
+            ..small_config(&dir)
+        };
+        let mut log = SegmentLog::open(config).unwrap();
+        for offset in 0..100 {
+            log.insert(offset, &Value::from(offset)).unwrap();
+        }
+        log.apply_retention(30, SystemTime::now()).unwrap();
+        let start = log.start_offset();
+        assert!(start > 0 && start <= 30);
+        // Reading from a dropped offset starts at the oldest message kept
+        assert_eq!(log.read_from(0, 1).unwrap()[0].0, start);
+        log.apply_retention(1000, SystemTime::now()).unwrap();
+        assert_eq!(log.segments.len(), 1);
+    }
+
+    #[test]
+    fn test_reopen() {
+        let dir = TempDir::new("reopen");
+        {
+            let mut log = SegmentLog::open(small_config(&dir)).unwrap();
+            for offset in 0..40 {
+                log.insert(offset, &Value::from(offset)).unwrap();
+            }
+        }
+        let mut log = SegmentLog::open(small_config(&dir)).unwrap();
+        assert_eq!(log.end_offset(), 40);
+        assert_eq!(log.read_from(25, 1).unwrap(), vec![(25, Value::from(25))]);
+        log.insert(40, &Value::from(40)).unwrap();
+        assert_eq!(log.read_from(39, 5).unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,610 @@
+/// Deterministic transaction scheduling, after Calvin (Thomson et al., "Calvin: Fast
+/// Distributed Transactions for Partitioned Database Systems"), over keys sharded
+/// across the nodes as for two-phase commit.
+///
+/// Each node gathers the transactions its clients send it into a batch, and appends
+/// the batch to a log kept in maelstrom's lin-kv service. Slot n of the log is the
+/// key `calvin-n`, and a node claims a slot with a cas from nothing: if another node
+/// got there first, it tries the next. A node only tries a slot once it knows every
+/// slot before it is taken, so the log has no gaps. Each node's epoch is a tick: it
+/// appends at most one batch a tick, so the busier the node the bigger its batches,
+/// and the log grows no faster than the nodes can read it.
+///
+/// Every node reads the log in order, and carries out each transaction in it
+/// against the keys it owns, installing its share at once. A micro-op never depends
+/// on what another reads, so no owner needs to hear from the others, and nothing
+/// ever aborts: every node applies the same transactions in the same order, and that
+/// order is the serial order. Each owner sends its share, reads filled in, to the
+/// node the client asked, which answers once it has every share. A transaction
+/// enters the log after its client sent it, and is answered after it is in the log,
+/// so transactions are strictly serializable.
+///
+/// Nodes read the next READ_AHEAD slots of the log on every tick, and read on at
+/// once each time a read finds one. A batch written twice (when a cas was answered
+/// too late, and made again) is only carried out the first time.
+use std::collections::{BTreeMap, HashSet};
+
+use serde_json::{json, Value};
+
+use super::{Owners, Shard};
+use crate::errors;
+use crate::node::Process;
+use crate::rpc::calvin::{
+    Batch, CalvinMessage, CalvinMessageBody, ExecutedMsg, ExecutedOkMsg, Sequenced,
+};
+use crate::rpc::txn::{MicroOp, TxnMessage, TxnMessageBody, TxnRequestMsg, TxnResponseMsg};
+
+/// Where the log is kept, and the prefix of each slot's key
+pub const LOG_SERVICE: &str = "lin-kv";
+pub const LOG_KEY: &str = "calvin";
+
+/// Give up waiting on lin-kv after this many ticks, and ask again
+const LOG_TIMEOUT_TICKS: u64 = 10;
+
+/// Most slots of the log we read at once
+const READ_AHEAD: u64 = 16;
+
+/// Ticks before sending again an executed share which has not been acknowledged
+const RESEND_TICKS: u64 = 2;
+
+/// A batch being appended to the log
+#[derive(Clone, Debug)]
+struct Appending {
+    msg_id: u64,
+    slot: u64,
+    batch: Batch,
+    sent: u64,
+}
+
+/// A transaction a client asked us for, waiting on its owners
+#[derive(Clone, Debug)]
+struct Waiting {
+    client: String,
+    in_reply_to: Option<u64>,
+    txn: Vec<MicroOp>,
+    // Each owner's share of the transaction, as places in `txn`
+    shares: BTreeMap<String, Vec<usize>>,
+    // Each share carried out, by owner
+    executed: BTreeMap<String, Vec<MicroOp>>,
+}
+
+/// A share we have carried out, which its transaction's node has not acknowledged
+#[derive(Clone, Debug)]
+struct Unacked {
+    origin: String,
+    ops: Vec<MicroOp>,
+    ticks: u64,
+}
+
+pub struct Calvin<S: Shard> {
+    node_id: String,
+    owners: Owners,
+    shard: S,
+    ticks: u64,
+    last_msg_id: u64,
+    last_batch: u64,
+    // Transactions which have arrived since our last batch
+    pending: Vec<(String, TxnRequestMsg)>,
+    appending: Option<Appending>,
+    // Reads of the log in flight, by msg_id: the slot, and when it was sent
+    reading: BTreeMap<u64, (u64, u64)>,
+    // The next slot of the log to carry out
+    next_slot: u64,
+    // Batches we know are in the log, from `next_slot` on
+    log: BTreeMap<u64, Batch>,
+    carried_out: HashSet<String>,
+    waiting: BTreeMap<String, Waiting>,
+    unacked: BTreeMap<String, Unacked>,
+    outbox: Vec<Value>,
+}
+
+impl<S: Shard> Calvin<S> {
+    pub fn new(node_id: String, node_ids: Vec<String>, shard: S) -> Self {
+        Self {
+            node_id,
+            owners: Owners::new(node_ids),
+            shard,
+            ticks: 0,
+            last_msg_id: 0,
+            last_batch: 0,
+            pending: vec![],
+            appending: None,
+            reading: BTreeMap::new(),
+            next_slot: 1,
+            log: BTreeMap::new(),
+            carried_out: HashSet::new(),
+            waiting: BTreeMap::new(),
+            unacked: BTreeMap::new(),
+            outbox: vec![],
+        }
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    pub fn owners(&self) -> &Owners {
+        &self.owners
+    }
+
+    pub fn shard(&self) -> &S {
+        &self.shard
+    }
+
+    /// Slots of the log this node has carried out
+    pub fn carried_out(&self) -> u64 {
+        self.next_slot - 1
+    }
+
+    /// Transactions we have been asked for and not yet answered
+    pub fn waiting(&self) -> usize {
+        self.pending.len()
+            + self
+                .appending
+                .as_ref()
+                .map_or(0, |appending| appending.batch.txns.len())
+            + self.waiting.len()
+    }
+
+    fn next_msg_id(&mut self) -> u64 {
+        self.last_msg_id += 1;
+        self.last_msg_id
+    }
+
+    /// The first slot we do not know to be taken
+    fn log_end(&self) -> u64 {
+        self.log
+            .keys()
+            .next_back()
+            .map_or(self.next_slot, |slot| self.next_slot.max(slot + 1))
+    }
+
+    fn slot_key(slot: u64) -> String {
+        format!("{}-{}", LOG_KEY, slot)
+    }
+
+    fn txn_id(batch: &Batch, index: usize) -> String {
+        format!("{}-{}", batch.id, index)
+    }
+
+    /// Messages to ourselves are handled at once, rather than sent
+    fn send(&mut self, dest: &str, body: CalvinMessageBody) {
+        if dest == self.node_id {
+            let src = self.node_id.clone();
+            return self.handle(&src, body);
+        }
+        let msg = CalvinMessage {
+            src: self.node_id.clone(),
+            dest: dest.to_string(),
+            body,
+        };
+        match serde_json::to_value(&msg) {
+            Ok(msg) => self.outbox.push(msg),
+            Err(err) => eprintln!("{:?}", err),
+        }
+    }
+
+    fn reply(&mut self, dest: &str, body: Value) {
+        self.outbox.push(json!({
+            "src": self.node_id,
+            "dest": dest,
+            "body": body,
+        }));
+    }
+
+    /// A client's transaction: it goes in our next batch
+    fn begin(&mut self, msg: &TxnMessage, request: &TxnRequestMsg) {
+        if !request.txn.iter().all(|op| self.shard.supports(op)) {
+            let error = errors::ErrorMsg::new(
+                request.msg_id,
+                errors::ErrorType::NotSupported,
+                "this workload does not have that micro-op".to_string(),
+            );
+            match serde_json::to_value(error) {
+                Ok(body) => self.reply(&msg.src, body),
+                Err(err) => eprintln!("{:?}", err),
+            }
+            return;
+        }
+        self.pending.push((msg.src.clone(), request.clone()));
+    }
+
+    /// Put what has arrived since our last batch into a new one, and append it
+    fn append(&mut self) {
+        if self.appending.is_some() || self.pending.is_empty() {
+            return;
+        }
+        self.last_batch += 1;
+        let mut batch = Batch {
+            id: format!("{}-{}", self.node_id, self.last_batch),
+            txns: vec![],
+        };
+        for (client, request) in std::mem::take(&mut self.pending) {
+            let mut shares: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+            for (index, op) in request.txn.iter().enumerate() {
+                let owner = self.owners.owner(op.key()).to_string();
+                shares.entry(owner).or_default().push(index);
+            }
+            let waiting = Waiting {
+                client,
+                in_reply_to: request.msg_id,
+                txn: request.txn.clone(),
+                shares,
+                executed: BTreeMap::new(),
+            };
+            self.waiting
+                .insert(Self::txn_id(&batch, batch.txns.len()), waiting);
+            batch.txns.push(Sequenced {
+                origin: self.node_id.clone(),
+                txn: request.txn,
+            });
+        }
+        let slot = self.log_end();
+        self.send_append(slot, batch);
+    }
+
+    fn send_append(&mut self, slot: u64, batch: Batch) {
+        let msg_id = self.next_msg_id();
+        let body = json!({
+            "type": "cas",
+            "key": Self::slot_key(slot),
+            "from": null,
+            "to": batch,
+            "create_if_not_exists": true,
+            "msg_id": msg_id,
+        });
+        self.appending = Some(Appending {
+            msg_id,
+            slot,
+            batch,
+            sent: self.ticks,
+        });
+        self.reply(LOG_SERVICE, body);
+    }
+
+    /// Read the slots we are missing, up to READ_AHEAD past the next to carry out
+    fn read_log(&mut self) {
+        let reading: HashSet<u64> = self.reading.values().map(|(slot, _)| *slot).collect();
+        for slot in self.next_slot..self.next_slot + READ_AHEAD {
+            if self.log.contains_key(&slot) || reading.contains(&slot) {
+                continue;
+            }
+            let msg_id = self.next_msg_id();
+            self.reading.insert(msg_id, (slot, self.ticks));
+            let body = json!({"type": "read", "key": Self::slot_key(slot), "msg_id": msg_id});
+            self.reply(LOG_SERVICE, body);
+        }
+    }
+
+    fn handle_log_reply(&mut self, msg: &Value) {
+        let in_reply_to = msg["body"]["in_reply_to"].as_u64();
+        let code = msg["body"]["code"]
+            .as_u64()
+            .and_then(errors::ErrorType::from_code);
+        let appending = self.appending.take();
+        match appending {
+            Some(appending) if in_reply_to == Some(appending.msg_id) => {
+                match (msg["body"]["type"].as_str(), code) {
+                    // A read may have found the batch there already
+                    (Some("cas_ok"), _) => {
+                        if appending.slot >= self.next_slot {
+                            self.log.insert(appending.slot, appending.batch);
+                        }
+                        self.carry_out();
+                    }
+                    // Somebody else has the slot: we learn what is in it from the
+                    // log, and try the one after
+                    (Some("error"), Some(errors::ErrorType::PreconditionFailed)) => {
+                        let slot = self.log_end().max(appending.slot + 1);
+                        self.send_append(slot, appending.batch);
+                        self.read_log();
+                    }
+                    // Try the same slot again
+                    _ => self.send_append(appending.slot, appending.batch),
+                }
+                return;
+            }
+            other => self.appending = other,
+        }
+        let slot = match in_reply_to.and_then(|msg_id| self.reading.remove(&msg_id)) {
+            Some((slot, _)) => slot,
+            None => return,
+        };
+        // Anything else means the slot is not taken yet: we look again next tick
+        if msg["body"]["type"] == "read_ok" {
+            match serde_json::from_value::<Batch>(msg["body"]["value"].clone()) {
+                Ok(batch) => {
+                    if slot >= self.next_slot {
+                        self.log.insert(slot, batch);
+                    }
+                    self.carry_out();
+                    self.read_log();
+                }
+                Err(err) => eprintln!("{:?}", err),
+            }
+        }
+    }
+
+    /// Carry out every batch we have, in log order, until the first we are missing
+    fn carry_out(&mut self) {
+        while let Some(batch) = self.log.remove(&self.next_slot) {
+            self.next_slot += 1;
+            if !self.carried_out.insert(batch.id.clone()) {
+                continue;
+            }
+            for (index, sequenced) in batch.txns.iter().enumerate() {
+                let share: Vec<MicroOp> = sequenced
+                    .txn
+                    .iter()
+                    .filter(|op| self.owners.owner(op.key()) == self.node_id)
+                    .cloned()
+                    .collect();
+                if share.is_empty() {
+                    continue;
+                }
+                let ops = self.shard.execute(&share);
+                self.shard.install(&share);
+                let txn_id = Self::txn_id(&batch, index);
+                let unacked = Unacked {
+                    origin: sequenced.origin.clone(),
+                    ops: ops.clone(),
+                    ticks: 0,
+                };
+                self.unacked.insert(txn_id.clone(), unacked);
+                self.send(
+                    &sequenced.origin,
+                    CalvinMessageBody::Executed(ExecutedMsg { txn_id, ops }),
+                );
+            }
+            // Transactions which touch no keys are answered once they are in order
+            let empty: Vec<String> = (0..batch.txns.len())
+                .map(|index| Self::txn_id(&batch, index))
+                .filter(|txn_id| {
+                    self.waiting
+                        .get(txn_id)
+                        .is_some_and(|waiting| waiting.shares.is_empty())
+                })
+                .collect();
+            for txn_id in empty {
+                self.answer(&txn_id);
+            }
+        }
+    }
+
+    /// As the node the client asked: an owner's share, carried out
+    fn executed(&mut self, src: &str, msg: ExecutedMsg) {
+        let ready = match self.waiting.get_mut(&msg.txn_id) {
+            Some(waiting) => {
+                waiting.executed.insert(src.to_string(), msg.ops);
+                waiting.executed.len() == waiting.shares.len()
+            }
+            None => false,
+        };
+        if ready {
+            self.answer(&msg.txn_id);
+        }
+        let ack = ExecutedOkMsg { txn_id: msg.txn_id };
+        self.send(src, CalvinMessageBody::ExecutedOk(ack));
+    }
+
+    fn answer(&mut self, txn_id: &str) {
+        let waiting = match self.waiting.remove(txn_id) {
+            Some(waiting) => waiting,
+            None => return,
+        };
+        let mut txn = waiting.txn;
+        for (owner, executed) in waiting.executed.iter() {
+            for (index, op) in waiting.shares[owner].iter().zip(executed) {
+                txn[*index] = op.clone();
+            }
+        }
+        let msg_id = self.next_msg_id();
+        let msg = TxnMessage {
+            src: self.node_id.clone(),
+            dest: waiting.client,
+            body: TxnMessageBody::TxnOk(TxnResponseMsg::new(waiting.in_reply_to, msg_id, txn)),
+        };
+        match serde_json::to_value(&msg) {
+            Ok(msg) => self.outbox.push(msg),
+            Err(err) => eprintln!("{:?}", err),
+        }
+    }
+
+    fn handle(&mut self, src: &str, body: CalvinMessageBody) {
+        match body {
+            CalvinMessageBody::Executed(msg) => self.executed(src, msg),
+            CalvinMessageBody::ExecutedOk(msg) => {
+                self.unacked.remove(&msg.txn_id);
+            }
+        }
+    }
+}
+
+impl<S: Shard> Process for Calvin<S> {
+    fn on_message(&mut self, msg: Value) -> Vec<Value> {
+        if msg["src"] == LOG_SERVICE {
+            self.handle_log_reply(&msg);
+        } else if let Ok(msg) = serde_json::from_value::<CalvinMessage>(msg.clone()) {
+            self.handle(&msg.src, msg.body);
+        } else if let Ok(msg) = serde_json::from_value::<TxnMessage>(msg) {
+            if let TxnMessageBody::Txn(request) = &msg.body {
+                self.begin(&msg, request);
+            }
+        }
+        std::mem::take(&mut self.outbox)
+    }
+
+    fn on_tick(&mut self) -> Vec<Value> {
+        self.ticks += 1;
+        let ticks = self.ticks;
+        let expired = |sent: u64| ticks >= sent + LOG_TIMEOUT_TICKS;
+        self.reading.retain(|_, (_, sent)| !expired(*sent));
+        if let Some(appending) = self.appending.take() {
+            if expired(appending.sent) {
+                self.send_append(appending.slot, appending.batch);
+            } else {
+                self.appending = Some(appending);
+            }
+        }
+        self.append();
+        self.read_log();
+        let mut resend = vec![];
+        for (txn_id, unacked) in self.unacked.iter_mut() {
+            unacked.ticks += 1;
+            if unacked.ticks.is_multiple_of(RESEND_TICKS) {
+                let msg = ExecutedMsg {
+                    txn_id: txn_id.clone(),
+                    ops: unacked.ops.clone(),
+                };
+                resend.push((unacked.origin.clone(), msg));
+            }
+        }
+        for (origin, msg) in resend {
+            self.send(&origin, CalvinMessageBody::Executed(msg));
+        }
+        std::mem::take(&mut self.outbox)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::algorithms::txn_list_append::ListState;
+    use crate::sim::list_append::{check, run_txn_clients, Transaction, TxnClients, TxnOutcome};
+    use crate::sim::{node_ids, random_partitions, LinKvService, Simulation};
+    use crate::transactions::two_phase::TwoPhase;
+
+    fn cluster(seed: u64, count: usize) -> Simulation<Calvin<ListState>> {
+        let processes = node_ids(count)
+            .into_iter()
+            .map(|node_id| {
+                let node = Calvin::new(node_id.clone(), node_ids(count), ListState::new());
+                (node_id, node)
+            })
+            .collect();
+        Simulation::new(seed, processes)
+            .with_service(LOG_SERVICE, Box::new(LinKvService::new(LOG_SERVICE)))
+    }
+
+    /// How many transactions `settings`' clients commit on five nodes under each engine
+    fn throughput(settings: &TxnClients) -> (usize, usize) {
+        let committed = |history: Vec<Transaction>| {
+            assert_eq!(check(&history), Ok(()));
+            history.iter().filter(|txn| txn.committed()).count()
+        };
+        let mut calvin = cluster(1, 5).with_latency(20);
+        let processes = node_ids(5)
+            .into_iter()
+            .map(|node_id| {
+                let node = TwoPhase::new(node_id.clone(), node_ids(5), ListState::new());
+                (node_id, node)
+            })
+            .collect();
+        let mut two_phase = Simulation::new(1, processes).with_latency(20);
+        (
+            committed(run_txn_clients(&mut calvin, settings, |_| ())),
+            committed(run_txn_clients(&mut two_phase, settings, |_| ())),
+        )
+    }
+
+    #[test]
+    fn test_throughput_against_two_phase_commit() {
+        // Few clients on many keys: 2PC commits in a couple of round trips, where
+        // Calvin waits for the next epoch and for the owners to read the log
+        let settings = TxnClients {
+            clients: 5,
+            keys: 32,
+            ..TxnClients::default()
+        };
+        let (calvin, two_phase) = throughput(&settings);
+        assert!(two_phase > calvin, "calvin {} 2pc {}", calvin, two_phase);
+        // Many clients on few keys: 2PC aborts most transactions on their locks,
+        // while Calvin's batches grow and nothing aborts
+        let settings = TxnClients {
+            clients: 50,
+            keys: 8,
+            ..TxnClients::default()
+        };
+        let (calvin, two_phase) = throughput(&settings);
+        assert!(
+            calvin > 2 * two_phase,
+            "calvin {} 2pc {}",
+            calvin,
+            two_phase
+        );
+    }
+
+    #[test]
+    fn test_strictly_serializable_under_partitions_and_loss() {
+        let mut sim = cluster(5, 5).with_loss(0.05).with_latency(20);
+        let settings = TxnClients {
+            clients: 20,
+            ..TxnClients::default()
+        };
+        let history = run_txn_clients(&mut sim, &settings, random_partitions(5));
+        assert_eq!(check(&history), Ok(()));
+        assert!(history.iter().all(|txn| txn.outcome != TxnOutcome::Aborted));
+        // Once the network heals, every node has carried out the whole log, and every
+        // transaction is answered
+        sim.heal();
+        sim.run_for(5000);
+        let slots = sim.process("n1").unwrap().carried_out();
+        assert!(slots > 0);
+        for (_, node) in sim.processes() {
+            assert_eq!((node.carried_out(), node.waiting()), (slots, 0));
+        }
+        for txn in history.iter() {
+            for op in txn.txn.iter() {
+                if let MicroOp::Append { key, element } = op {
+                    let owner = sim.process("n1").unwrap().owners().owner(key).to_string();
+                    let list = sim.process(&owner).unwrap().shard().get(key);
+                    let installed = list.is_some_and(|list| list.contains(element));
+                    if txn.committed() {
+                        assert!(installed);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_batch_logged_twice_is_carried_out_once() {
+        let mut node = Calvin::new("n1".to_string(), node_ids(1), ListState::new());
+        let mut log = LinKvService::new(LOG_SERVICE);
+        let txn = json!({"src": "c1", "dest": "n1", "body": {
+            "type": "txn", "msg_id": 1, "txn": [["append", 1, 5]]
+        }});
+        node.on_message(txn);
+        let cas = node.on_tick();
+        assert_eq!(cas[0]["body"]["type"], "cas");
+        // The cas lands, but its answer is lost: after a while we make it again, and
+        // find the slot taken
+        log.on_message(cas[0].clone());
+        let mut out = vec![];
+        for _ in 0..LOG_TIMEOUT_TICKS {
+            out = node.on_tick();
+        }
+        let mut answers = vec![];
+        while !out.is_empty() {
+            let mut next = vec![];
+            for msg in out {
+                match msg["dest"].as_str() {
+                    Some(LOG_SERVICE) => {
+                        for reply in log.on_message(msg) {
+                            next.extend(node.on_message(reply));
+                        }
+                    }
+                    _ => answers.push(msg),
+                }
+            }
+            out = next;
+        }
+        node.on_tick();
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0]["body"]["txn"], json!([["append", 1, 5]]));
+        assert_eq!(node.carried_out(), 2);
+        assert_eq!(node.shard().get(&json!(1)), Some(&vec![json!(5)]));
+    }
+}
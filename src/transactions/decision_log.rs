@@ -0,0 +1,155 @@
+/// Where a two-phase commit coordinator records what it has decided, so that it
+/// can finish its transactions after a restart. Only commits are recorded: a
+/// transaction with no record was aborted (presumed abort).
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::errors;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum LogEntry {
+    // The coordinator started up: counting these keeps its transaction ids unique
+    Start,
+    // Every participant voted to commit `txn_id`
+    Commit {
+        txn_id: String,
+        participants: Vec<String>,
+    },
+}
+
+pub trait DecisionLog: Send {
+    /// Record `entry`: once this returns, it will survive a crash
+    fn append(&mut self, entry: &LogEntry) -> Result<(), errors::ErrorMsg>;
+    /// Everything recorded so far, oldest first
+    fn load(&mut self) -> Result<Vec<LogEntry>, errors::ErrorMsg>;
+}
+
+/// Keeps the log in memory: nothing survives the process
+#[derive(Clone, Debug, Default)]
+pub struct MemoryDecisionLog {
+    entries: Vec<LogEntry>,
+}
+
+impl MemoryDecisionLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl DecisionLog for MemoryDecisionLog {
+    fn append(&mut self, entry: &LogEntry) -> Result<(), errors::ErrorMsg> {
+        self.entries.push(entry.clone());
+        Ok(())
+    }
+
+    fn load(&mut self) -> Result<Vec<LogEntry>, errors::ErrorMsg> {
+        Ok(self.entries.clone())
+    }
+}
+
+/// Keeps the log as one JSON entry per line in a file, synced after every append.
+/// A crash part way through an append leaves a torn last line: we end it before
+/// appending anything more, and skip it when loading.
+#[derive(Debug)]
+pub struct FileDecisionLog {
+    path: PathBuf,
+    file: Option<File>,
+}
+
+impl FileDecisionLog {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path, file: None }
+    }
+}
+
+impl DecisionLog for FileDecisionLog {
+    fn append(&mut self, entry: &LogEntry) -> Result<(), errors::ErrorMsg> {
+        if self.file.is_none() {
+            if let Some(dir) = self.path.parent() {
+                fs::create_dir_all(dir).map_err(errors::ErrorMsg::crash_error)?;
+            }
+            let mut file = OpenOptions::new()
+                .create(true)
+                .read(true)
+                .append(true)
+                .open(&self.path)
+                .map_err(errors::ErrorMsg::crash_error)?;
+            if file
+                .seek(SeekFrom::End(0))
+                .map_err(errors::ErrorMsg::crash_error)?
+                > 0
+            {
+                let mut last = [0u8];
+                file.seek(SeekFrom::End(-1))
+                    .and_then(|_| file.read_exact(&mut last))
+                    .map_err(errors::ErrorMsg::crash_error)?;
+                if last[0] != b'\n' {
+                    file.write_all(b"\n")
+                        .map_err(errors::ErrorMsg::crash_error)?;
+                }
+            }
+            self.file = Some(file);
+        }
+        let mut line = serde_json::to_vec(entry).map_err(errors::ErrorMsg::json_dumps_error)?;
+        line.push(b'\n');
+        if let Some(file) = self.file.as_mut() {
+            file.write_all(&line)
+                .map_err(errors::ErrorMsg::crash_error)?;
+            file.sync_data().map_err(errors::ErrorMsg::crash_error)?;
+        }
+        Ok(())
+    }
+
+    fn load(&mut self) -> Result<Vec<LogEntry>, errors::ErrorMsg> {
+        if !self.path.exists() {
+            return Ok(vec![]);
+        }
+        let file = File::open(&self.path).map_err(errors::ErrorMsg::crash_error)?;
+        let mut entries = vec![];
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(errors::ErrorMsg::crash_error)?;
+            if let Ok(entry) = serde_json::from_str(&line) {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_log_survives_reopening() {
+        let path =
+            std::env::temp_dir().join(format!("maelstrom-decisions-{}/n1.log", std::process::id()));
+        let commit = LogEntry::Commit {
+            txn_id: "n1-0-1".to_string(),
+            participants: vec!["n1".to_string(), "n2".to_string()],
+        };
+        let mut log = FileDecisionLog::new(path.clone());
+        assert_eq!(log.load().unwrap(), vec![]);
+        log.append(&LogEntry::Start).unwrap();
+        log.append(&commit).unwrap();
+        // A torn write at the end is not an entry
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"type\":\"com").unwrap();
+        let mut reopened = FileDecisionLog::new(path.clone());
+        assert_eq!(
+            reopened.load().unwrap(),
+            vec![LogEntry::Start, commit.clone()]
+        );
+        reopened.append(&LogEntry::Start).unwrap();
+        assert_eq!(
+            reopened.load().unwrap(),
+            vec![LogEntry::Start, commit, LogEntry::Start]
+        );
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
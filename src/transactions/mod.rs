@@ -0,0 +1,81 @@
+/// Engines which commit transactions over keys sharded across the nodes, for both
+/// transactional workloads. Each node owns the keys the hashing ring gives it, and
+/// keeps their data in a `Shard`; the engine decides how a transaction touching
+/// several nodes' keys commits.
+pub mod calvin;
+pub mod decision_log;
+pub mod two_phase;
+
+use std::path::PathBuf;
+
+use serde_json::Value;
+
+use crate::consensus::sharded::{HashRing, VIRTUAL_POINTS};
+use crate::node::Process;
+use crate::rpc::txn::MicroOp;
+use crate::workload::TxnEngine;
+use calvin::Calvin;
+use decision_log::FileDecisionLog;
+use two_phase::TwoPhase;
+
+/// The data for the keys one node owns
+pub trait Shard: Send {
+    /// False for micro-ops this data has no meaning for
+    fn supports(&self, op: &MicroOp) -> bool;
+    /// Carry out `ops`, all on keys owned here, without changing anything: returns
+    /// them with every read filled in. Reads see earlier ops' changes.
+    fn execute(&self, ops: &[MicroOp]) -> Vec<MicroOp>;
+    /// Make the changes `ops` make
+    fn install(&mut self, ops: &[MicroOp]);
+}
+
+/// Which engine transactional nodes use, and where it keeps anything durable
+#[derive(Clone, Debug, Default)]
+pub struct TxnConfig {
+    pub engine: TxnEngine,
+    pub decision_dir: Option<PathBuf>,
+}
+
+/// Which of `node_ids` owns each key
+#[derive(Clone, Debug)]
+pub struct Owners {
+    node_ids: Vec<String>,
+    ring: HashRing,
+}
+
+impl Owners {
+    pub fn new(node_ids: Vec<String>) -> Self {
+        let ring = HashRing::new(node_ids.len(), VIRTUAL_POINTS);
+        Self { node_ids, ring }
+    }
+
+    pub fn owner(&self, key: &Value) -> &str {
+        let member = self.ring.owner(&key.to_string()).unwrap_or(0);
+        &self.node_ids[member]
+    }
+
+    pub fn node_ids(&self) -> &[String] {
+        &self.node_ids
+    }
+}
+
+/// The engine `config` asks for, over `shard`; or none for the workload's own way
+pub fn start_engine<S: Shard + 'static>(
+    config: &TxnConfig,
+    node_id: String,
+    node_ids: Vec<String>,
+    shard: S,
+) -> Option<Box<dyn Process + Send>> {
+    match config.engine {
+        TxnEngine::Workload => None,
+        TxnEngine::TwoPhaseCommit => {
+            let mut engine = TwoPhase::new(node_id.clone(), node_ids, shard);
+            if let Some(dir) = &config.decision_dir {
+                let path = dir.join(format!("{}.log", node_id));
+                engine = engine.with_decision_log(Box::new(FileDecisionLog::new(path)));
+            }
+            Some(Box::new(engine))
+        }
+        TxnEngine::Calvin => Some(Box::new(Calvin::new(node_id, node_ids, shard))),
+    }
+}
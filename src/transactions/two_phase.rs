@@ -0,0 +1,643 @@
+/// Two-phase commit (Gray, "Notes on Data Base Operating Systems"), over keys
+/// sharded across the nodes.
+///
+/// The node a client asks coordinates its transaction: it splits the micro-ops by
+/// the node owning each key, and sends each participant a prepare with its share.
+/// A participant locks its keys, carries out its ops without installing them, and
+/// votes. Locks are taken without waiting: a participant finding a key locked by
+/// another transaction votes to abort, so transactions never wait on each other in
+/// a cycle. Reads are locked as well as writes, until the decision, so committed
+/// transactions are strictly serializable.
+///
+/// If every participant votes to commit, the coordinator records the commit in its
+/// decision log before anyone hears of it, then answers the client and tells the
+/// participants, which install their ops and unlock. Anything else aborts: a vote
+/// against, or votes missing after PREPARE_TIMEOUT_TICKS. The client then gets
+/// `TxnConflict`. Aborts are never recorded: a transaction the log does not have
+/// as committed was aborted (presumed abort). Every message is sent again until
+/// it is answered, and a participant which has been prepared for QUERY_TICKS asks
+/// the coordinator what was decided. A coordinator restarted with the same log
+/// tells the participants of each commit in it again, and answers queries from it.
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use serde_json::{json, Value};
+
+use super::decision_log::{DecisionLog, LogEntry, MemoryDecisionLog};
+use super::{Owners, Shard};
+use crate::errors;
+use crate::node::Process;
+use crate::rpc::two_phase::{
+    DecisionMsg, PrepareMsg, TwoPhaseMessage, TwoPhaseMessageBody, VoteMsg,
+};
+use crate::rpc::txn::{MicroOp, TxnMessage, TxnMessageBody, TxnRequestMsg, TxnResponseMsg};
+
+/// Ticks a coordinator waits for every vote before it aborts
+const PREPARE_TIMEOUT_TICKS: u64 = 10;
+
+/// Ticks before sending again a prepare or decision which has not been answered
+const RESEND_TICKS: u64 = 2;
+
+/// Ticks a prepared participant waits to hear the decision before asking for it
+const QUERY_TICKS: u64 = 20;
+
+/// A transaction we are coordinating
+#[derive(Clone, Debug)]
+struct Coordinating {
+    // Who to answer, and their msg_id: none for commits recovered from the log
+    client: Option<(String, Option<u64>)>,
+    txn: Vec<MicroOp>,
+    // Each participant's share of the transaction, as places in `txn`
+    shares: BTreeMap<String, Vec<usize>>,
+    // Each participant which voted to commit, with its ops' reads filled in
+    votes: BTreeMap<String, Vec<MicroOp>>,
+    decision: Option<bool>,
+    // Participants which have acknowledged the decision
+    acked: BTreeSet<String>,
+    ticks: u64,
+}
+
+/// A transaction we have voted to commit, as a participant
+#[derive(Clone, Debug)]
+struct Prepared {
+    coordinator: String,
+    ops: Vec<MicroOp>,
+    executed: Vec<MicroOp>,
+    ticks: u64,
+}
+
+pub struct TwoPhase<S: Shard> {
+    node_id: String,
+    owners: Owners,
+    shard: S,
+    log: Box<dyn DecisionLog>,
+    // How many times a coordinator with our log has started: part of every txn_id
+    incarnation: usize,
+    last_txn: u64,
+    last_msg_id: u64,
+    coordinating: BTreeMap<String, Coordinating>,
+    // Every commit in our log
+    committed: HashSet<String>,
+    // key -> the transaction holding its lock
+    locks: HashMap<String, String>,
+    prepared: BTreeMap<String, Prepared>,
+    // Transactions we have committed or aborted as a participant, so that a late
+    // prepare does not take locks again
+    finished: HashSet<String>,
+    outbox: Vec<Value>,
+}
+
+impl<S: Shard> TwoPhase<S> {
+    pub fn new(node_id: String, node_ids: Vec<String>, shard: S) -> Self {
+        let mut two_phase = Self {
+            node_id,
+            owners: Owners::new(node_ids),
+            shard,
+            log: Box::new(MemoryDecisionLog::new()),
+            incarnation: 0,
+            last_txn: 0,
+            last_msg_id: 0,
+            coordinating: BTreeMap::new(),
+            committed: HashSet::new(),
+            locks: HashMap::new(),
+            prepared: BTreeMap::new(),
+            finished: HashSet::new(),
+            outbox: vec![],
+        };
+        two_phase.recover();
+        two_phase
+    }
+
+    /// Keep decisions in `log`, finishing any commits it already has
+    pub fn with_decision_log(mut self, log: Box<dyn DecisionLog>) -> Self {
+        self.log = log;
+        self.recover();
+        self
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    pub fn owners(&self) -> &Owners {
+        &self.owners
+    }
+
+    pub fn shard(&self) -> &S {
+        &self.shard
+    }
+
+    /// Transactions we coordinate which are not yet decided, or not yet acknowledged
+    pub fn coordinating(&self) -> usize {
+        self.coordinating.len()
+    }
+
+    /// Keys locked by prepared transactions
+    pub fn locked(&self) -> usize {
+        self.locks.len()
+    }
+
+    fn recover(&mut self) {
+        let entries = self.log.load().unwrap_or_else(|err| {
+            eprintln!("{:?}", err);
+            vec![]
+        });
+        self.incarnation = 0;
+        for entry in entries {
+            match entry {
+                LogEntry::Start => self.incarnation += 1,
+                LogEntry::Commit {
+                    txn_id,
+                    participants,
+                } => {
+                    let recovered = Coordinating {
+                        client: None,
+                        txn: vec![],
+                        shares: participants
+                            .into_iter()
+                            .map(|node| (node, vec![]))
+                            .collect(),
+                        votes: BTreeMap::new(),
+                        decision: Some(true),
+                        acked: BTreeSet::new(),
+                        ticks: 0,
+                    };
+                    self.coordinating.insert(txn_id.clone(), recovered);
+                    self.committed.insert(txn_id);
+                }
+            }
+        }
+        if let Err(err) = self.log.append(&LogEntry::Start) {
+            eprintln!("{:?}", err);
+        }
+    }
+
+    fn next_msg_id(&mut self) -> u64 {
+        self.last_msg_id += 1;
+        self.last_msg_id
+    }
+
+    /// Messages to ourselves are handled at once, rather than sent
+    fn send(&mut self, dest: &str, body: TwoPhaseMessageBody) {
+        if dest == self.node_id {
+            let src = self.node_id.clone();
+            return self.handle(&src, body);
+        }
+        let msg = TwoPhaseMessage {
+            src: self.node_id.clone(),
+            dest: dest.to_string(),
+            body,
+        };
+        match serde_json::to_value(&msg) {
+            Ok(msg) => self.outbox.push(msg),
+            Err(err) => eprintln!("{:?}", err),
+        }
+    }
+
+    fn reply(&mut self, dest: &str, body: Value) {
+        self.outbox.push(json!({
+            "src": self.node_id,
+            "dest": dest,
+            "body": body,
+        }));
+    }
+
+    fn reply_error(&mut self, dest: &str, error: errors::ErrorMsg) {
+        match serde_json::to_value(error) {
+            Ok(body) => self.reply(dest, body),
+            Err(err) => eprintln!("{:?}", err),
+        }
+    }
+
+    /// A client's transaction: we coordinate it
+    fn begin(&mut self, msg: &TxnMessage, request: &TxnRequestMsg) {
+        if !request.txn.iter().all(|op| self.shard.supports(op)) {
+            let error = errors::ErrorMsg::new(
+                request.msg_id,
+                errors::ErrorType::NotSupported,
+                "this workload does not have that micro-op".to_string(),
+            );
+            return self.reply_error(&msg.src, error);
+        }
+        self.last_txn += 1;
+        let txn_id = format!("{}-{}-{}", self.node_id, self.incarnation, self.last_txn);
+        let mut shares: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+        for (index, op) in request.txn.iter().enumerate() {
+            let owner = self.owners.owner(op.key()).to_string();
+            shares.entry(owner).or_default().push(index);
+        }
+        let coordinating = Coordinating {
+            client: Some((msg.src.clone(), request.msg_id)),
+            txn: request.txn.clone(),
+            shares,
+            votes: BTreeMap::new(),
+            decision: None,
+            acked: BTreeSet::new(),
+            ticks: 0,
+        };
+        if coordinating.shares.is_empty() {
+            self.coordinating.insert(txn_id.clone(), coordinating);
+            return self.decide(&txn_id, true);
+        }
+        let participants: Vec<String> = coordinating.shares.keys().cloned().collect();
+        self.coordinating.insert(txn_id.clone(), coordinating);
+        for participant in participants {
+            self.send_prepare(&txn_id, &participant);
+        }
+    }
+
+    fn send_prepare(&mut self, txn_id: &str, participant: &str) {
+        let ops = match self.coordinating.get(txn_id) {
+            Some(coordinating) => coordinating.shares[participant]
+                .iter()
+                .map(|index| coordinating.txn[*index].clone())
+                .collect(),
+            None => return,
+        };
+        let body = TwoPhaseMessageBody::Prepare(PrepareMsg {
+            txn_id: txn_id.to_string(),
+            ops,
+        });
+        self.send(participant, body);
+    }
+
+    fn send_decision(&mut self, txn_id: &str, participant: &str, commit: bool) {
+        let msg = DecisionMsg::new(txn_id.to_string());
+        let body = match commit {
+            true => TwoPhaseMessageBody::Commit(msg),
+            false => TwoPhaseMessageBody::Abort(msg),
+        };
+        self.send(participant, body);
+    }
+
+    /// Decide `txn_id`, tell its client, and then its participants
+    fn decide(&mut self, txn_id: &str, commit: bool) {
+        let participants: Vec<String> = match self.coordinating.get(txn_id) {
+            Some(coordinating) if coordinating.decision.is_none() => {
+                coordinating.shares.keys().cloned().collect()
+            }
+            _ => return,
+        };
+        let mut commit = commit;
+        if commit {
+            let entry = LogEntry::Commit {
+                txn_id: txn_id.to_string(),
+                participants: participants.clone(),
+            };
+            // A commit nobody can find after a crash never happened
+            match self.log.append(&entry) {
+                Ok(()) => {
+                    self.committed.insert(txn_id.to_string());
+                }
+                Err(err) => {
+                    eprintln!("{:?}", err);
+                    commit = false;
+                }
+            }
+        }
+        let msg_id = self.next_msg_id();
+        let coordinating = match self.coordinating.get_mut(txn_id) {
+            Some(coordinating) => coordinating,
+            None => return,
+        };
+        coordinating.decision = Some(commit);
+        coordinating.ticks = 0;
+        let answer = coordinating.client.take().map(|(client, in_reply_to)| {
+            let mut txn = coordinating.txn.clone();
+            for (participant, executed) in coordinating.votes.iter() {
+                for (index, op) in coordinating.shares[participant].iter().zip(executed) {
+                    txn[*index] = op.clone();
+                }
+            }
+            (client, in_reply_to, txn)
+        });
+        if let Some((client, in_reply_to, txn)) = answer {
+            if commit {
+                let msg = TxnMessage {
+                    src: self.node_id.clone(),
+                    dest: client,
+                    body: TxnMessageBody::TxnOk(TxnResponseMsg::new(in_reply_to, msg_id, txn)),
+                };
+                match serde_json::to_value(&msg) {
+                    Ok(msg) => self.outbox.push(msg),
+                    Err(err) => eprintln!("{:?}", err),
+                }
+            } else {
+                let error = errors::ErrorMsg::new(
+                    in_reply_to,
+                    errors::ErrorType::TxnConflict,
+                    "a participant could not prepare the transaction".to_string(),
+                );
+                self.reply_error(&client, error);
+            }
+        }
+        if participants.is_empty() {
+            self.coordinating.remove(txn_id);
+        }
+        for participant in participants {
+            self.send_decision(txn_id, &participant, commit);
+        }
+    }
+
+    /// As coordinator: a participant's vote
+    fn voted(&mut self, src: &str, vote: VoteMsg) {
+        let coordinating = match self.coordinating.get_mut(&vote.txn_id) {
+            Some(coordinating) => coordinating,
+            None => {
+                // Long since finished: make sure the voter is not left holding locks
+                let commit = self.committed.contains(&vote.txn_id);
+                return self.send_decision(&vote.txn_id, src, commit);
+            }
+        };
+        if let Some(commit) = coordinating.decision {
+            return self.send_decision(&vote.txn_id, src, commit);
+        }
+        if !vote.commit {
+            return self.decide(&vote.txn_id, false);
+        }
+        coordinating.votes.insert(src.to_string(), vote.ops);
+        if coordinating.votes.len() == coordinating.shares.len() {
+            self.decide(&vote.txn_id, true);
+        }
+    }
+
+    /// As coordinator: a participant has the decision, or wants it
+    fn acked(&mut self, src: &str, txn_id: &str) {
+        if let Some(coordinating) = self.coordinating.get_mut(txn_id) {
+            coordinating.acked.insert(src.to_string());
+            if coordinating.acked.len() == coordinating.shares.len() {
+                self.coordinating.remove(txn_id);
+            }
+        }
+    }
+
+    fn queried(&mut self, src: &str, txn_id: &str) {
+        match self.coordinating.get(txn_id).map(|c| c.decision) {
+            Some(Some(commit)) => self.send_decision(txn_id, src, commit),
+            // The participant has waited long enough: give up on the transaction
+            Some(None) => self.decide(txn_id, false),
+            None => {
+                let commit = self.committed.contains(txn_id);
+                self.send_decision(txn_id, src, commit);
+            }
+        }
+    }
+
+    /// As participant: lock our keys and vote
+    fn prepare(&mut self, src: &str, prepare: PrepareMsg) {
+        let txn_id = prepare.txn_id;
+        let (commit, ops) = if let Some(prepared) = self.prepared.get(&txn_id) {
+            (true, prepared.executed.clone())
+        } else if self.finished.contains(&txn_id) {
+            (false, vec![])
+        } else {
+            let keys: Vec<String> = prepare.ops.iter().map(|op| op.key().to_string()).collect();
+            let free = keys
+                .iter()
+                .all(|key| self.locks.get(key).is_none_or(|holder| *holder == txn_id));
+            if free {
+                for key in keys {
+                    self.locks.insert(key, txn_id.clone());
+                }
+                let executed = self.shard.execute(&prepare.ops);
+                let prepared = Prepared {
+                    coordinator: src.to_string(),
+                    ops: prepare.ops,
+                    executed: executed.clone(),
+                    ticks: 0,
+                };
+                self.prepared.insert(txn_id.clone(), prepared);
+                (true, executed)
+            } else {
+                (false, vec![])
+            }
+        };
+        let vote = VoteMsg {
+            txn_id,
+            commit,
+            ops,
+        };
+        self.send(src, TwoPhaseMessageBody::PrepareOk(vote));
+    }
+
+    /// As participant: the coordinator's decision
+    fn finish(&mut self, src: &str, txn_id: String, commit: bool) {
+        if let Some(prepared) = self.prepared.remove(&txn_id) {
+            if commit {
+                self.shard.install(&prepared.ops);
+            }
+            self.locks.retain(|_, holder| *holder != txn_id);
+        }
+        self.finished.insert(txn_id.clone());
+        self.send(
+            src,
+            TwoPhaseMessageBody::DecisionOk(DecisionMsg::new(txn_id)),
+        );
+    }
+
+    fn handle(&mut self, src: &str, body: TwoPhaseMessageBody) {
+        match body {
+            TwoPhaseMessageBody::Prepare(prepare) => self.prepare(src, prepare),
+            TwoPhaseMessageBody::PrepareOk(vote) => self.voted(src, vote),
+            TwoPhaseMessageBody::Commit(msg) => self.finish(src, msg.txn_id, true),
+            TwoPhaseMessageBody::Abort(msg) => self.finish(src, msg.txn_id, false),
+            TwoPhaseMessageBody::DecisionOk(msg) => self.acked(src, &msg.txn_id),
+            TwoPhaseMessageBody::Query(msg) => self.queried(src, &msg.txn_id),
+        }
+    }
+}
+
+impl<S: Shard> Process for TwoPhase<S> {
+    fn on_message(&mut self, msg: Value) -> Vec<Value> {
+        if let Ok(msg) = serde_json::from_value::<TwoPhaseMessage>(msg.clone()) {
+            self.handle(&msg.src, msg.body);
+        } else if let Ok(msg) = serde_json::from_value::<TxnMessage>(msg) {
+            if let TxnMessageBody::Txn(request) = &msg.body {
+                self.begin(&msg, request);
+            }
+        }
+        std::mem::take(&mut self.outbox)
+    }
+
+    fn on_tick(&mut self) -> Vec<Value> {
+        let mut expired = vec![];
+        let mut resend = vec![];
+        for (txn_id, coordinating) in self.coordinating.iter_mut() {
+            coordinating.ticks += 1;
+            match coordinating.decision {
+                None if coordinating.ticks >= PREPARE_TIMEOUT_TICKS => expired.push(txn_id.clone()),
+                _ if coordinating.ticks % RESEND_TICKS != 0 => (),
+                None => resend.extend(
+                    coordinating
+                        .shares
+                        .keys()
+                        .filter(|node| !coordinating.votes.contains_key(*node))
+                        .map(|node| (txn_id.clone(), node.clone(), None)),
+                ),
+                Some(commit) => resend.extend(
+                    coordinating
+                        .shares
+                        .keys()
+                        .filter(|node| !coordinating.acked.contains(*node))
+                        .map(|node| (txn_id.clone(), node.clone(), Some(commit))),
+                ),
+            }
+        }
+        for txn_id in expired {
+            self.decide(&txn_id, false);
+        }
+        for (txn_id, node, decision) in resend {
+            match decision {
+                None => self.send_prepare(&txn_id, &node),
+                Some(commit) => self.send_decision(&txn_id, &node, commit),
+            }
+        }
+        let mut queries = vec![];
+        for (txn_id, prepared) in self.prepared.iter_mut() {
+            prepared.ticks += 1;
+            if prepared.ticks.is_multiple_of(QUERY_TICKS) {
+                queries.push((txn_id.clone(), prepared.coordinator.clone()));
+            }
+        }
+        for (txn_id, coordinator) in queries {
+            self.send(
+                &coordinator,
+                TwoPhaseMessageBody::Query(DecisionMsg::new(txn_id)),
+            );
+        }
+        std::mem::take(&mut self.outbox)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::algorithms::txn_list_append::ListState;
+    use crate::sim::list_append::{check, run_txn_clients, TxnClients, TxnOutcome};
+    use crate::sim::{node_ids, random_partitions, Simulation};
+    use crate::transactions::decision_log::FileDecisionLog;
+
+    fn cluster(seed: u64, count: usize) -> Simulation<TwoPhase<ListState>> {
+        let processes = node_ids(count)
+            .into_iter()
+            .map(|node_id| {
+                let node = TwoPhase::new(node_id.clone(), node_ids(count), ListState::new());
+                (node_id, node)
+            })
+            .collect();
+        Simulation::new(seed, processes)
+    }
+
+    /// A key `owner` owns
+    fn key_owned_by(owners: &Owners, owner: &str) -> Value {
+        (0..)
+            .map(Value::from)
+            .find(|key| owners.owner(key) == owner)
+            .unwrap()
+    }
+
+    fn append_txn(msg_id: u64, appends: &[(Value, u64)]) -> Value {
+        let txn: Vec<Value> = appends
+            .iter()
+            .map(|(key, element)| json!(["append", key, element]))
+            .collect();
+        json!({"src": "c1", "dest": "n1", "body": {"type": "txn", "msg_id": msg_id, "txn": txn}})
+    }
+
+    #[test]
+    fn test_transactions_are_atomic_and_strictly_serializable() {
+        let mut sim = cluster(3, 5).with_loss(0.05).with_latency(20);
+        let settings = TxnClients::default();
+        let mut history = run_txn_clients(&mut sim, &settings, |_| ());
+        let committed = history.iter().filter(|txn| txn.committed()).count();
+        assert!(
+            committed > history.len() / 4,
+            "{} of {}",
+            committed,
+            history.len()
+        );
+        // Transactions touching a node cut off from their coordinator time out
+        let settings = TxnClients {
+            seed: 1,
+            ..settings
+        };
+        history.extend(run_txn_clients(&mut sim, &settings, random_partitions(3)));
+        assert_eq!(check(&history), Ok(()));
+        // Once the network heals, every decision reaches every participant
+        sim.heal();
+        sim.run_for(10_000);
+        for (_, node) in sim.processes() {
+            assert_eq!((node.coordinating(), node.locked()), (0, 0));
+        }
+        for txn in history.iter() {
+            for op in txn.txn.iter() {
+                if let MicroOp::Append { key, element } = op {
+                    let owner = sim.process("n1").unwrap().owners().owner(key).to_string();
+                    let list = sim.process(&owner).unwrap().shard().get(key);
+                    let installed = list.is_some_and(|list| list.contains(element));
+                    match txn.outcome {
+                        TxnOutcome::Committed(_) => assert!(installed),
+                        TxnOutcome::Aborted => assert!(!installed),
+                        TxnOutcome::Unknown => (),
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_missing_votes_abort_and_unlock() {
+        let mut n1 = TwoPhase::new("n1".to_string(), node_ids(2), ListState::new());
+        let (local, remote) = (
+            key_owned_by(n1.owners(), "n1"),
+            key_owned_by(n1.owners(), "n2"),
+        );
+        // n2 never answers its prepare
+        let out = n1.on_message(append_txn(1, &[(local, 1), (remote, 2)]));
+        assert_eq!(out.len(), 1);
+        assert_eq!(n1.locked(), 1);
+        let mut replies = vec![];
+        for _ in 0..PREPARE_TIMEOUT_TICKS {
+            replies.extend(n1.on_tick());
+        }
+        let error = replies.iter().find(|msg| msg["dest"] == "c1").unwrap();
+        assert_eq!(error["body"]["code"], json!(30));
+        assert_eq!(n1.locked(), 0);
+        assert!(n1.shard().get(&key_owned_by(n1.owners(), "n1")).is_none());
+    }
+
+    #[test]
+    fn test_restarted_coordinator_finishes_logged_commits() {
+        let dir = std::env::temp_dir().join(format!("maelstrom-2pc-{}", std::process::id()));
+        let log = || Box::new(FileDecisionLog::new(dir.join("n1.log")));
+        let mut n1 =
+            TwoPhase::new("n1".to_string(), node_ids(2), ListState::new()).with_decision_log(log());
+        let mut n2 = TwoPhase::new("n2".to_string(), node_ids(2), ListState::new());
+        let key = key_owned_by(n1.owners(), "n2");
+        let prepare = n1.on_message(append_txn(1, &[(key.clone(), 7)]));
+        let vote = n2.on_message(prepare[0].clone());
+        assert_eq!(n2.locked(), 1);
+        let decided = n1.on_message(vote[0].clone());
+        let reply = decided.iter().find(|msg| msg["dest"] == "c1").unwrap();
+        assert_eq!(reply["body"]["type"], "txn_ok");
+        // n1 crashes before its commit reaches n2, and comes back with the same log
+        let mut n1 =
+            TwoPhase::new("n1".to_string(), node_ids(2), ListState::new()).with_decision_log(log());
+        assert_eq!(n1.coordinating(), 1);
+        let mut resent = vec![];
+        for _ in 0..RESEND_TICKS {
+            resent.extend(n1.on_tick());
+        }
+        assert_eq!(resent[0]["body"]["type"], "commit");
+        let acked = n2.on_message(resent[0].clone());
+        assert_eq!(n2.shard().get(&key), Some(&vec![json!(7)]));
+        assert_eq!(n2.locked(), 0);
+        n1.on_message(acked[0].clone());
+        assert_eq!(n1.coordinating(), 0);
+        // New transactions do not reuse the ids from before the crash
+        let prepare = n1.on_message(append_txn(2, &[(key, 8)]));
+        assert_ne!(prepare[0]["body"]["txn_id"], vote[0]["body"]["txn_id"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
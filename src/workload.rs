@@ -1,5 +1,12 @@
+use crate::algorithms::unique_ids;
+use crate::consensus::ConsensusConfig;
+use crate::errors;
 use crate::rpc;
+use crate::storage::{segment, LogStoreConfig};
+use crate::transactions::TxnConfig;
 use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(clap::ValueEnum, Clone, Debug)]
 pub enum Workload {
@@ -15,6 +22,219 @@ pub enum Workload {
     UniqueIds,     // simple workload for ID generation systems
 }
 
+/// Options which select between alternative behaviours of a workload.
+/// These are passed through from the command line to the node.
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct Options {
+    /// Delivery order for broadcast values
+    #[arg(long, value_enum, default_value_t = BroadcastOrder::Unordered)]
+    pub broadcast_order: BroadcastOrder,
+
+    /// How the unique-ids workload makes its IDs
+    #[arg(long, value_enum, default_value_t = IdStrategyKind::Snowflake)]
+    pub id_strategy: IdStrategyKind,
+
+    /// Bits of each unique ID given to the millisecond timestamp
+    #[arg(long, default_value_t = 41)]
+    pub id_timestamp_bits: u8,
+
+    /// Bits of each unique ID given to the node id
+    #[arg(long, default_value_t = 10)]
+    pub id_node_bits: u8,
+
+    /// Bits of each unique ID given to the per-millisecond sequence
+    #[arg(long, default_value_t = 12)]
+    pub id_sequence_bits: u8,
+
+    /// Epoch for unique ID timestamps, in milliseconds since the UNIX epoch
+    #[arg(long, default_value_t = unique_ids::DEFAULT_EPOCH_MS)]
+    pub id_epoch_ms: u64,
+
+    /// Number of IDs leased from lin-kv at a time by the range-lease strategy
+    #[arg(long, default_value_t = 1000)]
+    pub id_lease_size: u64,
+
+    /// How kafka nodes agree on the offset of each message
+    #[arg(long, value_enum, default_value_t = KafkaOffsets::LinKv)]
+    pub kafka_offsets: KafkaOffsets,
+
+    /// Where kafka nodes keep their logs
+    #[arg(long, value_enum, default_value_t = KafkaStorage::Memory)]
+    pub kafka_storage: KafkaStorage,
+
+    /// Directory for segment files; each node uses a subdirectory named after itself,
+    /// and reopens the logs it finds there
+    /// [default: a new maelstrom-kafka-<pid> in the system temp directory]
+    #[arg(long)]
+    pub kafka_data_dir: Option<PathBuf>,
+
+    /// Size at which a log segment is closed and a new one started
+    #[arg(long, default_value_t = 1024 * 1024)]
+    pub kafka_segment_bytes: u64,
+
+    /// Drop old committed segments once a log is bigger than this
+    #[arg(long)]
+    pub kafka_retention_bytes: Option<u64>,
+
+    /// Drop committed segments which have not been written for this long
+    #[arg(long)]
+    pub kafka_retention_ms: Option<u64>,
+
+    /// Snapshot the replicated state (and drop the log before it) every this many entries
+    #[arg(long, default_value_t = 1000)]
+    pub raft_snapshot_entries: u64,
+
+    /// Directory to keep each node's latest snapshot in, as <node id>.json. A node
+    /// starts from the snapshot it finds there
+    #[arg(long)]
+    pub raft_snapshot_dir: Option<PathBuf>,
+
+    /// How lin-kv nodes replicate their map when there is more than one
+    #[arg(long, value_enum, default_value_t = ConsensusKind::Raft)]
+    pub consensus: ConsensusKind,
+
+    /// How replicated lin-kv nodes serve reads (Raft only)
+    #[arg(long, value_enum, default_value_t = ReadMode::Log)]
+    pub read_mode: ReadMode,
+
+    /// Shard lin-kv keys over replication groups of about this many nodes each
+    #[arg(long)]
+    pub shard_group_size: Option<usize>,
+
+    /// Isolation level for transactions which do not ask for one themselves
+    #[arg(long, value_enum, default_value_t = Isolation::ReadCommitted)]
+    pub txn_isolation: Isolation,
+
+    /// How transactional nodes share their keys and commit transactions
+    #[arg(long, value_enum, default_value_t = TxnEngine::Workload)]
+    pub txn_engine: TxnEngine,
+
+    /// Directory to keep each node's two-phase commit decisions in, as <node id>.log
+    #[arg(long)]
+    pub txn_decision_dir: Option<PathBuf>,
+}
+
+impl Options {
+    pub fn snowflake_layout(&self) -> Result<unique_ids::SnowflakeLayout, errors::ErrorMsg> {
+        unique_ids::SnowflakeLayout::new(
+            self.id_timestamp_bits,
+            self.id_node_bits,
+            self.id_sequence_bits,
+            self.id_epoch_ms,
+        )
+    }
+
+    pub fn consensus_config(&self) -> ConsensusConfig {
+        ConsensusConfig {
+            snapshot_entries: self.raft_snapshot_entries,
+            snapshot_dir: self.raft_snapshot_dir.clone(),
+            read_mode: self.read_mode,
+            kind: self.consensus,
+            group_size: self.shard_group_size,
+        }
+    }
+
+    pub fn txn_config(&self) -> TxnConfig {
+        TxnConfig {
+            engine: self.txn_engine,
+            decision_dir: self.txn_decision_dir.clone(),
+        }
+    }
+
+    pub fn kafka_log_store(&self) -> LogStoreConfig {
+        match self.kafka_storage {
+            KafkaStorage::Memory => LogStoreConfig::Memory,
+            KafkaStorage::Segments => {
+                // Without a directory of their own, each run gets a fresh one of ours
+                let (dir, fresh) = match &self.kafka_data_dir {
+                    Some(dir) => (dir.clone(), false),
+                    None => {
+                        let name = format!("maelstrom-kafka-{}", std::process::id());
+                        (std::env::temp_dir().join(name), true)
+                    }
+                };
+                LogStoreConfig::Segments(segment::SegmentConfig {
+                    segment_bytes: self.kafka_segment_bytes,
+                    retention_bytes: self.kafka_retention_bytes,
+                    retention: self.kafka_retention_ms.map(Duration::from_millis),
+                    fresh,
+                    ..segment::SegmentConfig::new(dir)
+                })
+            }
+        }
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BroadcastOrder {
+    #[default]
+    Unordered, // every node eventually sees every value, in any order
+    Total, // every node delivers values in the same order
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IdStrategyKind {
+    #[default]
+    Snowflake, // 64 bit integers: timestamp, node id, sequence
+    UuidV7,      // RFC 9562 time-ordered UUID strings
+    Ulid,        // lexicographically sortable base32 strings
+    NodeCounter, // node id plus a local counter, e.g. "n1-42"
+    RangeLease,  // dense integers from blocks leased out of lin-kv
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KafkaOffsets {
+    Local, // each node counts for itself: only correct with a single node
+    #[default]
+    LinKv, // offsets are claimed with a compare-and-set on lin-kv
+    Owner, // each key has an owner node which hands out its offsets
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KafkaStorage {
+    #[default]
+    Memory, // logs live only as long as the node
+    Segments, // logs are written to segment files on disk
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ConsensusKind {
+    #[default]
+    Raft, // a leader with a log, elected by term
+    MultiPaxos, // a stable leader proposing in numbered slots, elected by ballot
+    Chain,      // updates pass from head to tail, and the tail serves reads
+    Craq,       // as Chain, but any node serves reads of keys with no pending update
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReadMode {
+    #[default]
+    Log, // reads are appended to the log like writes
+    ReadIndex, // the leader checks it is still leader with a round of heartbeats
+    Lease,     // the leader answers alone while a majority has heard from it recently
+}
+
+/// Transactions may also name one of these, as `isolation` in their request
+#[derive(
+    clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum Isolation {
+    ReadUncommitted, // no different here from read committed: no node ever sees an uncommitted write
+    #[default]
+    ReadCommitted, // totally available: writes are replicated after the client has its answer
+    SnapshotIsolation, // reads from a consistent snapshot; the first committer of a key wins
+    Serializable,      // commits only if nothing the transaction read or wrote has changed
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TxnEngine {
+    #[default]
+    Workload, // the workload's own: replicated registers, or thunks in lin-kv and lww-kv
+    TwoPhaseCommit, // keys are sharded over the nodes, and each transaction commits with 2PC
+    Calvin, // keys are sharded, and owners carry out transactions in the order logged in lin-kv
+}
+
 /// This enum represents internal messages
 #[derive(Clone, Debug)]
 pub enum Command {